@@ -0,0 +1,48 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use payments::{Amount, Engine, Transaction, TransactionType};
+
+const SIZES: [u32; 2] = [100_000, 1_000_000];
+
+/// Deposits spread over 10k clients, every one of which is kept in the history, so the history grows with every row
+fn deposits(rows: u32) -> Vec<Transaction> {
+    let amount = Some(Amount::from_num(1.5));
+
+    (0..rows)
+        .map(|id| Transaction::new(TransactionType::Deposit, (id % 10_000).into(), id, amount))
+        .collect()
+}
+
+/// Processing with and without the history's room reserved up front, as `--expected-rows` does, to show the time
+/// spent growing it and rehashing its index
+fn process_reserved(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reserved capacity");
+    group.sample_size(10);
+
+    for &rows in SIZES.iter() {
+        let transactions = deposits(rows);
+        group.throughput(Throughput::Elements(rows.into()));
+
+        for &(name, reserved) in [("grown", 0), ("reserved", rows as usize)].iter() {
+            group.bench_with_input(
+                BenchmarkId::new(name, rows),
+                &transactions,
+                |b, transactions| {
+                    b.iter(|| {
+                        let mut engine = Engine::with_capacity(0, reserved);
+
+                        for tx in transactions {
+                            let _ = engine.process(*tx);
+                        }
+
+                        engine
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, process_reserved);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use payments::{Amount, ClientId, Engine, Transaction, TransactionType};
+
+const SIZES: [u32; 3] = [10_000, 100_000, 1_000_000];
+
+/// The number of clients the transactions are spread over, narrow enough that every account stays hot in the cache or
+/// wide enough that most lookups miss it
+const DISTRIBUTIONS: [(&str, u32); 2] = [("narrow", 10), ("wide", 100_000)];
+
+#[derive(Clone, Copy)]
+enum Mix {
+    DepositOnly,
+    /// Half the rows are deposits, and each pair of deposits is followed by a dispute of the first and a resolve of that
+    /// dispute, so half the rows look up an earlier transaction
+    DisputeHeavy,
+}
+
+impl Mix {
+    fn name(self) -> &'static str {
+        match self {
+            Mix::DepositOnly => "deposit only",
+            Mix::DisputeHeavy => "dispute heavy",
+        }
+    }
+}
+
+/// Scatters ids over the clients so consecutive transactions rarely share an account
+fn client(id: u32, clients: u32) -> ClientId {
+    (id.wrapping_mul(2_654_435_761) % clients).into()
+}
+
+fn transactions(rows: u32, clients: u32, mix: Mix) -> Vec<Transaction> {
+    let amount = Some(Amount::from_num(1.5));
+
+    (0..rows)
+        .map(|id| match (mix, id % 4) {
+            (Mix::DisputeHeavy, 2) => Transaction::new(
+                TransactionType::Dispute,
+                client(id - 2, clients),
+                id - 2,
+                None,
+            ),
+            (Mix::DisputeHeavy, 3) => Transaction::new(
+                TransactionType::Resolve,
+                client(id - 3, clients),
+                id - 3,
+                None,
+            ),
+            _ => Transaction::new(TransactionType::Deposit, client(id, clients), id, amount),
+        })
+        .collect()
+}
+
+fn process_engine(c: &mut Criterion) {
+    for mix in [Mix::DepositOnly, Mix::DisputeHeavy].iter() {
+        let mut group = c.benchmark_group(mix.name());
+
+        for &rows in SIZES.iter() {
+            group.throughput(Throughput::Elements(rows.into()));
+
+            if rows >= 1_000_000 {
+                group.sample_size(10);
+            }
+
+            for &(distribution, clients) in DISTRIBUTIONS.iter() {
+                let transactions = transactions(rows, clients, *mix);
+                let id = BenchmarkId::new(distribution, rows);
+
+                group.bench_with_input(id, &transactions, |b, transactions| {
+                    b.iter(|| {
+                        let mut engine = Engine::new();
+
+                        for tx in transactions {
+                            let _ = engine.process(*tx);
+                        }
+
+                        engine
+                    })
+                });
+            }
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, process_engine);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use payments::{Engine, Transaction};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const ROWS: u32 = 1_000_000;
+
+/// Writes a file of deposits from 10k clients where every tenth row disputes an earlier deposit and the row after it
+/// resolves that dispute again, so most of the lookups land deep in the history
+fn write_input() -> std::path::PathBuf {
+    let path = std::env::temp_dir().join("payments_bench_history.csv");
+    let mut writer = BufWriter::new(File::create(&path).unwrap());
+    writeln!(writer, "type,client,tx,amount").unwrap();
+
+    for id in 0..ROWS {
+        let client = id % 10_000;
+
+        match id % 10 {
+            8 => writeln!(writer, "dispute,{},{},", (id - 8) % 10_000, id - 8),
+            9 => writeln!(writer, "resolve,{},{},", (id - 9) % 10_000, id - 9),
+            _ => writeln!(writer, "deposit,{},{},1.5", client, id),
+        }
+        .unwrap();
+    }
+
+    path
+}
+
+fn process_history(c: &mut Criterion) {
+    let path = write_input();
+    let transactions: Vec<Transaction> = csv::Reader::from_path(&path)
+        .unwrap()
+        .deserialize()
+        .map(Result::unwrap)
+        .collect();
+
+    let mut group = c.benchmark_group("1M rows");
+    group.sample_size(10);
+
+    group.bench_function("disputes and resolves", |b| {
+        b.iter(|| {
+            let mut engine = Engine::new();
+
+            for tx in &transactions {
+                let _ = engine.process(*tx);
+            }
+
+            engine
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, process_history);
+criterion_main!(benches);
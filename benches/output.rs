@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use payments::{Amount, Config, Engine, Transaction, TransactionType, DEFAULT_OUTPUT_BUFFER_SIZE};
+use std::fs::File;
+use std::io::BufWriter;
+
+const ACCOUNTS: u32 = 100_000;
+
+fn accounts() -> Vec<payments::Account> {
+    let deposits: Vec<Transaction> = (0..ACCOUNTS)
+        .map(|id| {
+            let amount = Amount::from_num(f64::from(id) / 7.0);
+            Transaction::new(TransactionType::Deposit, id.into(), id, Some(amount))
+        })
+        .collect();
+
+    let mut engine = Engine::new();
+    engine.apply_atomic(&deposits).unwrap();
+    engine.into_accounts()
+}
+
+fn write_accounts(c: &mut Criterion) {
+    let accounts = accounts();
+    let config = Config::default();
+    let path = std::env::temp_dir().join("payments_bench_output.csv");
+
+    let mut group = c.benchmark_group("write 100k accounts");
+    group.sample_size(20);
+
+    group.bench_function("unbuffered", |b| {
+        b.iter(|| payments::write_csv(File::create(&path).unwrap(), &accounts, &config).unwrap())
+    });
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            let file = File::create(&path).unwrap();
+            let writer = BufWriter::with_capacity(DEFAULT_OUTPUT_BUFFER_SIZE, file);
+            payments::write_csv(writer, &accounts, &config).unwrap()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, write_accounts);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! Generates the gRPC service of the `grpc` feature. The service is described here rather than compiled from
+//! `proto/payments.proto`, so building it doesn't need `protoc`. The messages are defined in `src/grpc.rs`
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc_service();
+}
+
+#[cfg(feature = "grpc")]
+fn grpc_service() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::{}", input))
+            .output_type(format!("crate::grpc::{}", output))
+            .codec_path("tonic_prost::ProstCodec")
+    };
+
+    let service = Service::builder()
+        .name("Payments")
+        .package("payments")
+        .method(
+            method(
+                "submit_transaction",
+                "SubmitTransaction",
+                "SubmitTransactionRequest",
+                "SubmitTransactionResponse",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "get_account",
+                "GetAccount",
+                "GetAccountRequest",
+                "AccountReply",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "stream_accounts",
+                "StreamAccounts",
+                "StreamAccountsRequest",
+                "AccountReply",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .build();
+
+    // The generated `connect` needs `TryInto` in the prelude, which this crate's edition doesn't have, so clients are
+    // built from a `Channel` instead
+    Builder::new().build_transport(false).compile(&[service]);
+}
@@ -0,0 +1,143 @@
+use crate::{Account, AccountStatus, Amount, ClientId, Currency};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+/// The engine's accounts, one for each client and currency, kept in the order they were opened and indexed by client
+/// id and currency, so finding the account a transaction applies to doesn't scan every account. Derefs to the accounts
+/// in order.
+///
+/// Whether an account is locked applies to the client rather than the currency, so every account of a client shares
+/// its status
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub(crate) struct Accounts {
+    list: Vec<Account>,
+    /// The position of each client's account in each currency in `list`
+    index: HashMap<(ClientId, Currency), usize>,
+    /// Every currency an account was opened in, in the order they were first seen
+    currencies: Vec<Currency>,
+    /// The credit limits of accounts that aren't open yet, given to each account as it's opened
+    credit_limits: HashMap<(ClientId, Currency), Amount>,
+}
+
+impl Accounts {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            list: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+            currencies: Vec::new(),
+            credit_limits: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&self, client: ClientId, currency: Currency) -> Option<&Account> {
+        self.index
+            .get(&(client, currency))
+            .map(|&index| &self.list[index])
+    }
+
+    pub(crate) fn get_mut(&mut self, client: ClientId, currency: Currency) -> Option<&mut Account> {
+        match self.index.get(&(client, currency)) {
+            Some(&index) => Some(&mut self.list[index]),
+            None => None,
+        }
+    }
+
+    /// The accounts of `client` in every currency, in the order the currencies were first seen
+    pub(crate) fn of_client(&self, client: ClientId) -> impl Iterator<Item = &Account> {
+        self.currencies
+            .iter()
+            .filter_map(move |&currency| self.get(client, currency))
+    }
+
+    /// The status shared by the accounts of `client`, or `None` if the client has no account
+    pub(crate) fn status(&self, client: ClientId) -> Option<AccountStatus> {
+        self.of_client(client).next().map(|account| account.status)
+    }
+
+    /// Sets the status of every account of `client`
+    pub(crate) fn set_status(&mut self, client: ClientId, status: AccountStatus) {
+        for &currency in &self.currencies {
+            if let Some(&index) = self.index.get(&(client, currency)) {
+                self.list[index].status = status;
+            }
+        }
+    }
+
+    /// The account of `client` in `currency`, opening an empty one after every existing account if there isn't one. A
+    /// new account takes the status of the client's other accounts
+    pub(crate) fn get_or_open(&mut self, client: ClientId, currency: Currency) -> &mut Account {
+        if !self.index.contains_key(&(client, currency)) {
+            let mut account = Account::new(client, currency);
+            account.status = self.status(client).unwrap_or(account.status);
+            self.push(account);
+        }
+
+        let index = self.index[&(client, currency)];
+        &mut self.list[index]
+    }
+
+    /// Adds an account after every existing account. A later account for the same client and currency replaces it in
+    /// the index, so snapshots are expected to hold one account per client and currency
+    pub(crate) fn push(&mut self, mut account: Account) {
+        if let Some(limit) = self
+            .credit_limits
+            .remove(&(account.client, account.currency))
+        {
+            account.credit_limit = limit;
+        }
+
+        if !self.currencies.contains(&account.currency) {
+            self.currencies.push(account.currency);
+        }
+
+        self.index
+            .insert((account.client, account.currency), self.list.len());
+        self.list.push(account);
+    }
+
+    /// Sets the credit limit of the account of `client` in `currency`, or of the account once it's opened if it isn't
+    /// open yet
+    pub(crate) fn set_credit_limit(&mut self, client: ClientId, currency: Currency, limit: Amount) {
+        match self.get_mut(client, currency) {
+            Some(account) => account.credit_limit = limit,
+            None => {
+                self.credit_limits.insert((client, currency), limit);
+            }
+        }
+    }
+
+    /// Removes the credit limits of the accounts that aren't open yet, to carry them over to other accounts
+    pub(crate) fn take_credit_limits(&mut self) -> HashMap<(ClientId, Currency), Amount> {
+        std::mem::take(&mut self.credit_limits)
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<Account> {
+        self.list
+    }
+}
+
+impl From<Vec<Account>> for Accounts {
+    fn from(list: Vec<Account>) -> Self {
+        let mut accounts = Self::with_capacity(list.len());
+
+        for account in list {
+            accounts.push(account);
+        }
+
+        accounts
+    }
+}
+
+impl Deref for Accounts {
+    type Target = [Account];
+
+    fn deref(&self) -> &[Account] {
+        &self.list
+    }
+}
+
+impl DerefMut for Accounts {
+    fn deref_mut(&mut self) -> &mut [Account] {
+        &mut self.list
+    }
+}
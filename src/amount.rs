@@ -0,0 +1,268 @@
+use crate::{AmountFormat, PaymentError, RoundingMode, Transaction};
+use anyhow::Error;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(not(any(feature = "high-precision", feature = "decimal")))]
+pub(crate) type Repr = fixed::types::I50F14;
+#[cfg(all(feature = "high-precision", not(feature = "decimal")))]
+pub(crate) type Repr = fixed::types::I64F64;
+#[cfg(feature = "decimal")]
+pub(crate) type Repr = rust_decimal::Decimal;
+
+/// An amount of funds. By default it's a binary fixed point number with 14 fractional bits, which is fast but can't
+/// represent every decimal amount exactly. The `high-precision` feature trades speed for a larger integer range and
+/// more fractional bits, and the `decimal` feature stores amounts as exact decimals with up to 28 significant digits
+/// instead, taking precedence over `high-precision` if both are enabled.
+///
+/// Amounts are parsed from decimal strings, ex: `"1.5"`, whichever representation is used. They display with four
+/// decimal places, ex: `"1.5000"`, and [`Amount::exact`] writes them without losing any precision
+#[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Amount(pub(crate) Repr);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(Repr::ZERO);
+    /// The largest amount that can be represented
+    pub const MAX: Amount = Amount(Repr::MAX);
+    /// The smallest, most negative, amount that can be represented
+    pub const MIN: Amount = Amount(Repr::MIN);
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Adds `other`, stopping at [`Amount::MAX`] or [`Amount::MIN`] rather than overflowing
+    pub fn saturating_add(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_add(other.0))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Amount::ZERO
+    }
+
+    /// Converts a non-negative number to the nearest amount, ex: `Amount::try_from_num(1.5)`, rejecting NaN,
+    /// infinities, negative numbers, and numbers out of the range of amounts
+    pub fn try_from_num(n: impl Into<f64>) -> Result<Amount, Error> {
+        let n = n.into();
+
+        if n.is_nan() || n < 0.0 {
+            return Err(Error::msg(format!("Invalid amount: {}", n)));
+        }
+
+        Amount::checked_from_f64(n).ok_or_else(|| Error::msg(format!("Amount out of range: {}", n)))
+    }
+
+    /// The amount with every digit it holds, ex: `"1.5"`, unlike its [`Display`](fmt::Display) which rounds to four
+    /// decimal places
+    pub fn exact(self) -> impl fmt::Display {
+        Exact(self)
+    }
+
+    /// The sum of this amount and `other` for `tx`, or an overflow error rejecting `tx` if it doesn't fit in an amount.
+    /// Every new balance is worked out this way before any of them is written, so a rejected transaction leaves the
+    /// account as it was
+    pub(crate) fn plus(self, other: Amount, tx: &Transaction) -> Result<Amount, PaymentError> {
+        self.checked_add(other).ok_or_else(|| overflow(tx))
+    }
+
+    /// The difference of this amount and `other` for `tx`, or an overflow error rejecting `tx` if it doesn't fit in an
+    /// amount
+    pub(crate) fn minus(self, other: Amount, tx: &Transaction) -> Result<Amount, PaymentError> {
+        self.checked_sub(other).ok_or_else(|| overflow(tx))
+    }
+}
+
+fn overflow(tx: &Transaction) -> PaymentError {
+    PaymentError::Overflow {
+        client: tx.client,
+        tx: tx.id,
+    }
+}
+
+#[cfg(not(feature = "decimal"))]
+impl Amount {
+    /// Converts a number to the nearest amount, ex: `Amount::from_num(1.5)`
+    ///
+    /// # Panics
+    ///
+    /// If `n` isn't finite or is out of the range of amounts
+    pub fn from_num(n: impl Into<f64>) -> Amount {
+        Amount(Repr::from_num(n.into()))
+    }
+
+    fn checked_from_f64(n: f64) -> Option<Amount> {
+        Repr::checked_from_num(n).map(Amount)
+    }
+
+    /// Converts the amount to a whole number of the smallest unit with `places` decimal places, rounding the part
+    /// below it. The whole and fractional parts are scaled separately so this can't overflow for any amount and up to
+    /// [`MAX_DECIMAL_PLACES`](crate::MAX_DECIMAL_PLACES) places
+    pub(crate) fn to_units(self, places: u32, rounding: RoundingMode) -> i128 {
+        let scale = 10i128.pow(places);
+        let bits: i128 = self.0.to_bits() as _;
+        let one = 1 << Repr::FRAC_NBITS;
+        let (whole, frac) = (bits.abs() / one, bits.abs() % one);
+        let frac = match rounding {
+            RoundingMode::HalfUp => round_div(frac * scale, one),
+            RoundingMode::Bankers => round_div_even(frac * scale, one),
+        };
+
+        bits.signum() * (whole * scale + frac)
+    }
+
+    /// Converts a whole number of the smallest unit with `places` decimal places back to an amount, saturating at the
+    /// largest or smallest amount for units that round past them
+    // The conversion to bits does nothing with the `high-precision` feature, where they are already an `i128`
+    #[allow(clippy::useless_conversion)]
+    pub(crate) fn from_units(units: i128, places: u32) -> Amount {
+        use std::convert::TryInto;
+
+        let scale = 10i128.pow(places);
+        let (whole, rem) = (units.abs() / scale, units.abs() % scale);
+        let bits = whole
+            .checked_mul(1 << Repr::FRAC_NBITS)
+            .and_then(|bits| bits.checked_add(round_div(rem << Repr::FRAC_NBITS, scale)))
+            .and_then(|bits| (units.signum() * bits).try_into().ok());
+
+        match bits {
+            Some(bits) => Amount(Repr::from_bits(bits)),
+            None if units < 0 => Amount::MIN,
+            None => Amount::MAX,
+        }
+    }
+}
+
+#[cfg(feature = "decimal")]
+impl Amount {
+    /// Converts a number to the nearest amount, ex: `Amount::from_num(1.5)`
+    ///
+    /// # Panics
+    ///
+    /// If `n` isn't finite or is out of the range of amounts
+    pub fn from_num(n: impl Into<f64>) -> Amount {
+        use rust_decimal::prelude::FromPrimitive;
+
+        Amount(Repr::from_f64(n.into()).expect("number out of the range of amounts"))
+    }
+
+    fn checked_from_f64(n: f64) -> Option<Amount> {
+        use rust_decimal::prelude::FromPrimitive;
+
+        Repr::from_f64(n).map(Amount)
+    }
+
+    /// Converts the amount to a whole number of the smallest unit with `places` decimal places, rounding the part
+    /// below it. Amounts with more than 38 digits at that scale saturate
+    pub(crate) fn to_units(self, places: u32, rounding: RoundingMode) -> i128 {
+        let strategy = match rounding {
+            RoundingMode::HalfUp => rust_decimal::RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Bankers => rust_decimal::RoundingStrategy::MidpointNearestEven,
+        };
+        let rounded = self.0.round_dp_with_strategy(places, strategy);
+
+        rounded
+            .mantissa()
+            .saturating_mul(10i128.pow(places - rounded.scale()))
+    }
+
+    /// Converts a whole number of the smallest unit with `places` decimal places back to an amount, saturating at the
+    /// largest or smallest amount for units past them
+    pub(crate) fn from_units(units: i128, places: u32) -> Amount {
+        match Repr::try_from_i128_with_scale(units, places) {
+            Ok(amount) => Amount(amount),
+            Err(_) if units < 0 => Amount::MIN,
+            Err(_) => Amount::MAX,
+        }
+    }
+}
+
+/// Integer division rounding half away from zero
+pub(crate) fn round_div(n: i128, d: i128) -> i128 {
+    if n < 0 {
+        (n - d / 2) / d
+    } else {
+        (n + d / 2) / d
+    }
+}
+
+/// Integer division of a non-negative `n` rounding half to even
+pub(crate) fn round_div_even(n: i128, d: i128) -> i128 {
+    let (quotient, rem) = (n / d, n % d);
+
+    match (2 * rem).cmp(&d) {
+        std::cmp::Ordering::Less => quotient,
+        std::cmp::Ordering::Equal => quotient + quotient % 2,
+        std::cmp::Ordering::Greater => quotient + 1,
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&AmountFormat::default().fixed(*self), f)
+    }
+}
+
+/// Writes an amount with every digit it holds
+struct Exact(Amount);
+
+impl fmt::Display for Exact {
+    #[cfg(not(feature = "decimal"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0 .0, f)
+    }
+
+    /// Decimals keep the scale they were parsed with, so they are written without trailing zeros to match the fixed
+    /// point representations
+    #[cfg(feature = "decimal")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0 .0.normalize(), f)
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.exact(), f)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Amount).map_err(Error::new)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.exact())
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl Visitor<'_> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a decimal amount")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Amount, E> {
+                value
+                    .parse()
+                    .map_err(|err| E::custom(format_args!("parse error: {}", err)))
+            }
+        }
+
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
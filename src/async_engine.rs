@@ -0,0 +1,284 @@
+use crate::{
+    Account, ClientId, Currency, Engine, EngineMetrics, LedgerEntry, PaymentError, Transaction,
+};
+use anyhow::Error;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+use tokio_stream::{Stream, StreamExt};
+
+/// An [`Engine`] shared between async tasks, for services fed by a stream of transactions such as a message queue
+/// consumer. Clones share the same accounts, so one task can process a stream while others read snapshots of the
+/// accounts as they change
+///
+/// ```
+/// use payments::{Amount, AsyncPaymentsEngine, Transaction};
+///
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// let engine = AsyncPaymentsEngine::new();
+/// let transactions = tokio_stream::iter(vec![
+///     Transaction::from_csv_line("deposit,1,1,5.0").unwrap(),
+///     Transaction::from_csv_line("withdraw,1,2,2.0").unwrap(),
+/// ]);
+/// engine.process_stream(transactions).await.unwrap();
+///
+/// let accounts = engine.accounts().await;
+/// assert_eq!(accounts[0].available(), Amount::from_num(3));
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct AsyncPaymentsEngine {
+    engine: Arc<Mutex<Engine>>,
+    /// Sends every account that changes to the receivers from [`AsyncPaymentsEngine::subscribe`]
+    changes: broadcast::Sender<Account>,
+}
+
+/// The number of account changes a receiver from [`AsyncPaymentsEngine::subscribe`] can fall behind by before it misses
+/// the oldest of them
+const CHANGES_CAPACITY: usize = 1024;
+
+impl Default for AsyncPaymentsEngine {
+    fn default() -> Self {
+        Self::from_engine(Engine::default())
+    }
+}
+
+impl AsyncPaymentsEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shares an engine that was already configured, or that holds accounts from earlier processing
+    pub fn from_engine(engine: Engine) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+        }
+    }
+
+    /// An engine configured by `config` for a long-lived service, continuing from the state in `config.resume` if there
+    /// is one
+    #[cfg(any(feature = "grpc", feature = "http", feature = "kafka"))]
+    pub(crate) fn from_config(config: &crate::Config) -> Result<Self, Error> {
+        let mut engine = crate::engine_from_config(config)?;
+
+        if let Some(path) = &config.resume {
+            engine.restore_state(std::io::BufReader::new(std::fs::File::open(path)?))?;
+        }
+
+        Ok(Self::from_engine(engine))
+    }
+
+    /// Flushes the store, if there is one, and writes the final state to `config.snapshot` if there is one, once a
+    /// service has stopped
+    #[cfg(any(feature = "grpc", feature = "http", feature = "kafka"))]
+    pub(crate) async fn shut_down(&self, config: &crate::Config) -> Result<(), Error> {
+        self.flush_store().await?;
+
+        if let Some(path) = &config.snapshot {
+            let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+            self.save_state(&mut writer).await?;
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies a single transaction. See [`Engine::process`]
+    pub async fn process(&self, tx: Transaction) -> Result<(), PaymentError> {
+        self.engine.lock().await.process(tx)
+    }
+
+    /// Applies every transaction from `transactions` as it arrives, until the stream ends. The engine is only locked
+    /// while each transaction is applied, so snapshots can be taken in between. Rejected transactions are skipped and
+    /// counted in the metrics, like rejected rows of a CSV input. Any other error, such as failing to write to a store,
+    /// stops processing and is returned. Once the stream ends the store, if there is one, is flushed
+    pub async fn process_stream<S: Stream<Item = Transaction>>(
+        &self,
+        transactions: S,
+    ) -> Result<(), Error> {
+        let mut transactions = std::pin::pin!(transactions);
+
+        while let Some(tx) = transactions.next().await {
+            match self.apply(tx).await {
+                Err(err) if !err.is::<PaymentError>() => return Err(err),
+                _ => {}
+            }
+        }
+
+        self.engine.lock().await.flush_store()
+    }
+
+    /// Applies a single transaction. Unlike [`AsyncPaymentsEngine::process`], a rejection is a [`PaymentError`] and
+    /// any other error, such as failing to write to a store, is returned as it is
+    pub(crate) async fn apply(&self, tx: Transaction) -> Result<(), Error> {
+        self.engine.lock().await.apply(tx)
+    }
+
+    /// A snapshot of every account, in the order their clients were first seen
+    pub async fn accounts(&self) -> Vec<Account> {
+        self.engine.lock().await.accounts().cloned().collect()
+    }
+
+    /// A snapshot of the account of `client`, if it has been seen
+    pub async fn account(&self, client: ClientId) -> Option<Account> {
+        self.engine
+            .lock()
+            .await
+            .accounts()
+            .find(|account| account.client == client)
+            .cloned()
+    }
+
+    /// A snapshot of the account of `client` in `currency`, if it has one
+    pub async fn account_in(&self, client: ClientId, currency: Currency) -> Option<Account> {
+        self.engine
+            .lock()
+            .await
+            .accounts
+            .get(client, currency)
+            .cloned()
+    }
+
+    /// A snapshot of the deposit or withdrawal `id`, if the engine still remembers it. Fails if reading it from where
+    /// the history spilled to fails
+    pub async fn transaction(&self, id: u32) -> Result<Option<LedgerEntry>, Error> {
+        Ok(self.engine.lock().await.history.get_mut(id)?.cloned())
+    }
+
+    /// Receives every account whose balances or status change from now on, as it is after the change, for pushing live
+    /// balances to dashboards. A receiver that falls behind by more than 1024 changes misses the oldest ones. This uses
+    /// the engine's [`Engine::on_account_change`] callback, replacing any registered before
+    pub async fn subscribe(&self) -> broadcast::Receiver<Account> {
+        let changes = self.changes.clone();
+        self.engine.lock().await.on_account_change(move |account| {
+            // There may be no receivers left, which only means no one is listening
+            let _ = changes.send(account.clone());
+        });
+
+        self.changes.subscribe()
+    }
+
+    /// A snapshot of the engine's metrics
+    pub async fn metrics(&self) -> EngineMetrics {
+        self.engine.lock().await.metrics().clone()
+    }
+
+    /// Writes everything processed so far to the store, if there is one. See [`Engine::flush_store`]
+    pub async fn flush_store(&self) -> Result<(), Error> {
+        self.engine.lock().await.flush_store()
+    }
+
+    /// Writes the state of the engine as it is now. See [`Engine::save_state`]
+    pub async fn save_state<W: Write>(&self, writer: W) -> Result<(), Error> {
+        self.engine.lock().await.save_state(writer)
+    }
+
+    /// Gives back the engine once no clones are left, or the engine itself if others still share it
+    pub fn into_engine(self) -> Result<Engine, Self> {
+        let changes = self.changes;
+
+        Arc::try_unwrap(self.engine)
+            .map(Mutex::into_inner)
+            .map_err(|engine| Self { engine, changes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Amount;
+
+    fn transactions(lines: &[&str]) -> Vec<Transaction> {
+        lines
+            .iter()
+            .map(|line| Transaction::from_csv_line(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn stream_is_applied_like_an_input_file() {
+        let engine = AsyncPaymentsEngine::new();
+        let stream = tokio_stream::iter(transactions(&[
+            "deposit,1,1,10",
+            "deposit,2,2,4",
+            "withdraw,2,3,5",
+            "dispute,1,1,",
+        ]));
+
+        engine.process_stream(stream).await.unwrap();
+
+        let account = engine.account(1).await.unwrap();
+        assert_eq!(account.held(), Amount::from_num(10));
+        assert_eq!(
+            engine.account(2).await.unwrap().available(),
+            Amount::from_num(4)
+        );
+
+        let metrics = engine.metrics().await;
+        assert_eq!(metrics.processed, 4);
+        assert_eq!(metrics.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_each_changed_account() {
+        let engine = AsyncPaymentsEngine::new();
+        let mut changes = engine.subscribe().await;
+        let stream = tokio_stream::iter(transactions(&[
+            "deposit,1,1,10",
+            "withdraw,1,2,50",
+            "dispute,1,1,",
+            "chargeback,1,1,",
+        ]));
+
+        engine.process_stream(stream).await.unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(account) = changes.try_recv() {
+            received.push((account.held(), account.status().is_locked()));
+        }
+        assert_eq!(
+            received,
+            vec![
+                (Amount::ZERO, false),
+                (Amount::from_num(10), false),
+                (Amount::ZERO, true),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots_can_be_taken_while_a_stream_is_processed() {
+        let engine = AsyncPaymentsEngine::new();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let processing = tokio::spawn({
+            let engine = engine.clone();
+            async move {
+                engine
+                    .process_stream(tokio_stream::wrappers::UnboundedReceiverStream::new(
+                        receiver,
+                    ))
+                    .await
+            }
+        });
+
+        sender.send(transactions(&["deposit,1,1,2"])[0]).unwrap();
+        while engine.accounts().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            engine.account(1).await.unwrap().total(),
+            Amount::from_num(2)
+        );
+
+        sender.send(transactions(&["deposit,1,2,3"])[0]).unwrap();
+        drop(sender);
+        processing.await.unwrap().unwrap();
+
+        let engine = engine.into_engine().unwrap();
+        assert_eq!(
+            engine.accounts().next().unwrap().total(),
+            Amount::from_num(5)
+        );
+    }
+}
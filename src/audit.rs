@@ -0,0 +1,89 @@
+//! An append-only log of every transaction the engine handles, one JSON object per line, for an audit trail of how each
+//! balance came to be
+
+use crate::{Account, Amount, ClientId, Currency, TransactionType};
+use anyhow::Error;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// The balances of an account at one point in time
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Balances {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl Balances {
+    pub(crate) fn of(account: &Account) -> Self {
+        Balances {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.status.is_locked(),
+        }
+    }
+}
+
+/// A line of the audit log
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditRecord {
+    pub(crate) tx: u32,
+    #[serde(rename = "type")]
+    pub(crate) tx_type: TransactionType,
+    pub(crate) client: ClientId,
+    pub(crate) currency: Currency,
+    pub(crate) amount: Option<Amount>,
+    /// The balances of the client's account in `currency` before the transaction, `None` if it had no account yet
+    pub(crate) before: Option<Balances>,
+    pub(crate) after: Option<Balances>,
+    /// `applied`, `expired` for a dispute resolved by the dispute expiry, or the code of the error it was rejected with
+    pub(crate) outcome: &'static str,
+}
+
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    file: File,
+    /// Records not yet written to the file. The records of an atomic batch are held back until the whole batch has
+    /// been applied, so a batch that is rolled back leaves no trace
+    pending: Vec<u8>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path` for appending, creating it if it doesn't exist, so every run adds to the same trail
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        Ok(AuditLog {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Adds a record, which is written to the file by the next [`AuditLog::flush`]
+    pub(crate) fn record(&mut self, record: &AuditRecord) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.pending, record)?;
+        self.pending.push(b'\n');
+
+        Ok(())
+    }
+
+    /// The number of bytes not yet written, to roll back to with [`AuditLog::truncate`]
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops the records added after the first `len` bytes not yet written
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.pending.truncate(len);
+    }
+
+    /// Writes every pending record to the file, unbuffered, so a crash loses nothing written so far
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        self.file.write_all(&self.pending)?;
+        self.pending.clear();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use crate::amount::{round_div, round_div_even};
+use crate::{reader_builder, Amount, ClientId, Currency, RoundingMode, SCALE_PLACES};
+use anyhow::Error;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+/// The most decimal places a rate can be written with
+const MAX_RATE_PLACES: u32 = 18;
+
+/// An exchange rate, the amount of the target currency one unit of the source currency buys, ex: `"1.0837"`. Rates are
+/// kept as the exact decimal they were parsed from rather than as an [`Amount`], as every digit of a rate scales the
+/// amounts converted with it
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct Rate {
+    /// The rate as a whole number of units with `places` decimal places
+    units: i128,
+    places: u32,
+}
+
+impl Rate {
+    /// The rate after taking a spread of `bps` basis points off it, which is what a conversion at the table's rate
+    /// actually gets
+    fn with_spread(self, bps: u32) -> Rate {
+        Rate {
+            units: self.units * (10_000 - i128::from(bps)),
+            places: self.places + 4,
+        }
+    }
+
+    /// Converts `amount` at this rate, rounding the result to the output scale. Returns `None` if the result is out of
+    /// the range of amounts
+    pub(crate) fn convert(self, amount: Amount, rounding: RoundingMode) -> Option<Amount> {
+        let product = amount
+            .to_units(SCALE_PLACES, rounding)
+            .checked_mul(self.units)?;
+        let scale = 10i128.pow(self.places);
+        let units = match rounding {
+            RoundingMode::HalfUp => round_div(product, scale),
+            RoundingMode::Bankers => round_div_even(product, scale),
+        };
+        let converted = Amount::from_units(units, SCALE_PLACES);
+
+        (converted != Amount::MAX).then_some(converted)
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scale = 10i128.pow(self.places);
+        let (whole, frac) = (self.units / scale, self.units % scale);
+
+        match self.places {
+            0 => write!(f, "{}", whole),
+            places => {
+                let frac = format!("{:0width$}", frac, width = places as usize);
+                match frac.trim_end_matches('0') {
+                    "" => write!(f, "{}", whole),
+                    frac => write!(f, "{}.{}", whole, frac),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Rate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::msg(format!("Invalid rate: {}", s));
+        let (whole, frac) = s.trim().split_once('.').unwrap_or((s.trim(), ""));
+        let digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+
+        if whole.is_empty()
+            || !digits(whole)
+            || !digits(frac)
+            || frac.len() > MAX_RATE_PLACES as usize
+        {
+            return Err(invalid());
+        }
+
+        let units: i128 = format!("{}{}", whole, frac)
+            .parse()
+            .map_err(|_| invalid())?;
+
+        if units == 0 {
+            return Err(invalid());
+        }
+
+        Ok(Rate {
+            units,
+            places: frac.len() as u32,
+        })
+    }
+}
+
+impl Serialize for Rate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RateVisitor;
+
+        impl Visitor<'_> for RateVisitor {
+            type Value = Rate;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a positive decimal rate")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Rate, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(RateVisitor)
+    }
+}
+
+/// A row of a rates file
+#[derive(Deserialize)]
+struct RateRow {
+    from: Currency,
+    to: Currency,
+    rate: Rate,
+    #[serde(default)]
+    spread_bps: u32,
+}
+
+/// The rates conversions without a rate of their own are made at, for each pair of currencies, with the spread in
+/// basis points taken off each one. A pair only converts in the direction it is listed in, so converting back needs
+/// its own row
+#[derive(Debug, Default, Clone)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), (Rate, u32)>,
+}
+
+impl RateTable {
+    /// Reads a table from CSV with `from`, `to`, `rate`, and optionally `spread_bps` columns, ex: `USD,EUR,0.92,25`
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Error> {
+        let mut table = RateTable::default();
+
+        for row in reader_builder().from_reader(reader).deserialize() {
+            let row: RateRow = row?;
+            table.insert(row.from, row.to, row.rate, row.spread_bps)?;
+        }
+
+        Ok(table)
+    }
+
+    /// Sets the rate from `from` to `to`, replacing any earlier one. The spread can be at most 10000 basis points
+    pub fn insert(
+        &mut self,
+        from: Currency,
+        to: Currency,
+        rate: Rate,
+        spread_bps: u32,
+    ) -> Result<(), Error> {
+        if spread_bps > 10_000 {
+            return Err(Error::msg(format!(
+                "The spread from {} to {} is more than 10000 basis points",
+                from, to
+            )));
+        }
+
+        self.rates.insert((from, to), (rate, spread_bps));
+
+        Ok(())
+    }
+
+    /// The rate a conversion from `from` to `to` gets, with the spread taken off, and the rate before the spread
+    pub(crate) fn get(&self, from: Currency, to: Currency) -> Option<(Rate, Rate)> {
+        self.rates
+            .get(&(from, to))
+            .map(|&(rate, spread_bps)| (rate.with_spread(spread_bps), rate))
+    }
+}
+
+/// A conversion that was applied, for the report of the spreads conversions realized
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+pub struct ConversionEvent {
+    pub tx: u32,
+    pub client: ClientId,
+    pub from: Currency,
+    pub to: Currency,
+    /// The amount taken from the account in `from`
+    pub amount: Amount,
+    /// The rate the conversion was made at
+    pub rate: Rate,
+    /// The amount credited to the account in `to`
+    pub converted: Amount,
+    /// What converting at the table's rate, before its spread, would have credited beyond `converted`, in `to`. It is
+    /// zero for a pair without a table rate, and negative for a conversion at a better rate than the table's
+    pub spread: Amount,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_convert_exactly_and_round_to_the_output_scale() {
+        let rate: Rate = "1.08375".parse().unwrap();
+        assert_eq!(rate.to_string(), "1.08375");
+
+        let amount: Amount = "2".parse().unwrap();
+        assert_eq!(
+            rate.convert(amount, RoundingMode::HalfUp),
+            Some("2.1675".parse().unwrap())
+        );
+
+        let amount: Amount = "1".parse().unwrap();
+        assert_eq!(
+            rate.convert(amount, RoundingMode::HalfUp),
+            Some("1.0838".parse().unwrap())
+        );
+        assert_eq!(
+            rate.convert(amount, RoundingMode::Bankers),
+            Some("1.0838".parse().unwrap())
+        );
+
+        let half: Rate = "1.00005".parse().unwrap();
+        assert_eq!(
+            half.convert(amount, RoundingMode::Bankers),
+            Some("1".parse().unwrap())
+        );
+
+        for invalid in ["0", "-1", "1.2.3", "", "abc", "0.0000000000000000001"].iter() {
+            assert!(invalid.parse::<Rate>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn table_rates_have_their_spread_taken_off() {
+        let table =
+            RateTable::from_reader("from,to,rate,spread_bps\nUSD,EUR,0.92,100\n".as_bytes())
+                .unwrap();
+        let usd = Currency::DEFAULT;
+        let eur: Currency = "EUR".parse().unwrap();
+
+        let (rate, mid) = table.get(usd, eur).unwrap();
+        assert_eq!(rate.to_string(), "0.9108");
+        assert_eq!(mid.to_string(), "0.92");
+        assert!(table.get(eur, usd).is_none());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::{Amount, ClientId, Currency, Timestamp};
+use std::fmt;
+
+/// Errors raised by the engine that callers may want to handle by kind rather than by message
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PaymentError {
+    /// A snapshot contained an account whose available and held funds don't add up to its total
+    CorruptSnapshot { client: ClientId },
+    /// A deposit, withdrawal, or transfer reused the id of a transaction that was charged back
+    TransactionIdReuseAfterChargeback { tx: u32 },
+    /// A transaction was disputed more times than allowed
+    DisputeLimitExceeded { tx: u32 },
+    /// A row of the input left a required field blank
+    MissingField { field: &'static str, line: u64 },
+    /// A dispute referenced an id that no earlier deposit or withdrawal used, while processing in strict order
+    DisputeBeforeDeposit { tx: u32 },
+    /// A deposit or withdrawal id was far enough below the highest id seen that the ids must have wrapped around
+    IdWraparound { tx: u32 },
+    /// A transaction was timestamped before `latest`, the latest timestamp seen, while rejecting out of order
+    /// transactions
+    OutOfOrder { tx: u32, latest: Timestamp },
+    /// A dispute, resolve, chargeback, refund, or settle was timestamped before the transaction it referenced
+    ReferencesLaterTransaction { tx: u32 },
+    /// A deposit, withdrawal, or transfer moved more than the configured maximum for a single transaction
+    AmountExceedsLimit { tx: u32, limit: Amount },
+    /// A withdrawal would have taken more than `limit` out of the account in a single day
+    DailyWithdrawalLimitExceeded {
+        client: ClientId,
+        tx: u32,
+        limit: Amount,
+    },
+    /// A deposit, withdrawal, or transfer came after `limit` others from the same client in the last minute
+    RateLimitExceeded {
+        client: ClientId,
+        tx: u32,
+        limit: u32,
+    },
+    /// A deposit, withdrawal, or transfer was made against a locked account
+    AccountLocked { client: ClientId, tx: u32 },
+    /// A deposit, withdrawal, or transfer had an amount of zero or less
+    NonPositiveAmount { tx: u32 },
+    /// A deposit, withdrawal, or transfer had no amount
+    MissingAmount { tx: u32 },
+    /// A transaction referenced a client without an account
+    AccountNotFound { client: ClientId, tx: u32 },
+    /// A dispute, resolve, chargeback, refund, or settle from `client` referenced a transaction of client `owner`
+    ClientMismatch {
+        client: ClientId,
+        owner: ClientId,
+        tx: u32,
+    },
+    /// A dispute, resolve, chargeback, refund, or settle referenced a transaction the engine doesn't know about
+    TxNotFound { tx: u32 },
+    /// A withdrawal, transfer, or refund was for more than the account's available funds
+    InsufficientFunds { client: ClientId, tx: u32 },
+    /// A withdrawal would have left less in the account than its open disputes hold
+    HeldFundsUnbacked { client: ClientId, tx: u32 },
+    /// A transaction that is already under dispute was disputed or refunded
+    AlreadyDisputed { tx: u32 },
+    /// A transaction that isn't under dispute was resolved or charged back
+    NotDisputed { tx: u32 },
+    /// A transaction that was already refunded or charged back was disputed or refunded
+    AlreadyReversed { tx: u32 },
+    /// A deposit that hasn't settled yet was disputed or refunded
+    DepositPending { tx: u32 },
+    /// A deposit that isn't pending was settled
+    DepositNotPending { tx: u32 },
+    /// A partial dispute was for nothing, or for more than the disputed transaction
+    InvalidPartialDispute { tx: u32 },
+    /// A transfer had no destination client, or named its own client as the destination
+    InvalidTransfer { tx: u32 },
+    /// An account that isn't locked was unlocked
+    AccountNotLocked { client: ClientId, tx: u32 },
+    /// `actor` isn't allowed to lock or unlock the account of `client`. Only admins may unlock accounts, or lock accounts
+    /// other than their own
+    Unauthorized {
+        actor: ClientId,
+        client: ClientId,
+        tx: u32,
+    },
+    /// A dispute, resolve, chargeback, refund, or settle named another currency than the transaction it referenced,
+    /// which is in `expected`
+    CurrencyMismatch {
+        currency: Currency,
+        expected: Currency,
+        tx: u32,
+    },
+    /// A conversion didn't name a target currency other than the currency it converts from
+    InvalidConversion { tx: u32 },
+    /// A conversion without a rate of its own was for a pair of currencies the rate table has no rate for
+    RateNotFound {
+        from: Currency,
+        to: Currency,
+        tx: u32,
+    },
+    /// A transaction would have taken a balance of `client` past the largest or smallest amount that can be stored
+    Overflow { client: ClientId, tx: u32 },
+    /// A [`crate::RiskRule`] rejected a transaction, for the reason it gave
+    RuleViolation { tx: u32, reason: String },
+    /// A transaction was rejected for a reason without its own kind, described by `reason`
+    Rejected { tx: u32, reason: String },
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentError::CorruptSnapshot { client } => write!(
+                f,
+                "Corrupt snapshot: available and held funds of client {} do not add up to total",
+                client
+            ),
+            PaymentError::TransactionIdReuseAfterChargeback { tx } => write!(
+                f,
+                "Transaction id {} was already used by a transaction that was charged back",
+                tx
+            ),
+            PaymentError::DisputeLimitExceeded { tx } => {
+                write!(f, "Transaction {} has been disputed too many times", tx)
+            }
+            PaymentError::MissingField { field, line } => {
+                write!(f, "Line {} is missing a value for {}", line, field)
+            }
+            PaymentError::DisputeBeforeDeposit { tx } => write!(
+                f,
+                "Dispute of transaction {} arrived before any deposit or withdrawal with that id",
+                tx
+            ),
+            PaymentError::IdWraparound { tx } => write!(
+                f,
+                "Transaction id {} is lower than earlier ids, the ids appear to have wrapped around",
+                tx
+            ),
+            PaymentError::OutOfOrder { tx, latest } => write!(
+                f,
+                "Transaction {} is timestamped before {}, the latest timestamp seen",
+                tx, latest
+            ),
+            PaymentError::ReferencesLaterTransaction { tx } => write!(
+                f,
+                "Transaction {} was referenced with an earlier timestamp than its own",
+                tx
+            ),
+            PaymentError::AmountExceedsLimit { tx, limit } => write!(
+                f,
+                "Transaction {} exceeds the maximum transaction amount of {}",
+                tx, limit
+            ),
+            PaymentError::DailyWithdrawalLimitExceeded { client, tx, limit } => write!(
+                f,
+                "Transaction {} exceeds the daily withdrawal limit of {} for client {}",
+                tx, limit, client
+            ),
+            PaymentError::RateLimitExceeded { client, tx, limit } => write!(
+                f,
+                "Transaction {} exceeds the limit of {} transactions per minute for client {}",
+                tx, limit, client
+            ),
+            PaymentError::AccountLocked { client, tx } => write!(
+                f,
+                "Transaction {} was rejected because the account of client {} is locked",
+                tx, client
+            ),
+            PaymentError::NonPositiveAmount { tx } => {
+                write!(f, "Transaction {} must have an amount greater than zero", tx)
+            }
+            PaymentError::MissingAmount { tx } => {
+                write!(f, "Transaction {} requires an amount", tx)
+            }
+            PaymentError::AccountNotFound { client, tx } => write!(
+                f,
+                "Account of client {} not found for transaction {}",
+                client, tx
+            ),
+            PaymentError::ClientMismatch { client, owner, tx } => write!(
+                f,
+                "Client {} referenced transaction {} of client {}",
+                client, tx, owner
+            ),
+            PaymentError::TxNotFound { tx } => write!(f, "Transaction {} not found", tx),
+            PaymentError::InsufficientFunds { client, tx } => write!(
+                f,
+                "Insufficient funds for transaction {} from client {}",
+                tx, client
+            ),
+            PaymentError::HeldFundsUnbacked { client, tx } => write!(
+                f,
+                "Transaction {} would leave held funds of client {} unbacked",
+                tx, client
+            ),
+            PaymentError::AlreadyDisputed { tx } => {
+                write!(f, "Transaction {} is already under dispute", tx)
+            }
+            PaymentError::NotDisputed { tx } => {
+                write!(f, "Transaction {} is not under dispute", tx)
+            }
+            PaymentError::AlreadyReversed { tx } => {
+                write!(f, "Transaction {} was already reversed", tx)
+            }
+            PaymentError::DepositPending { tx } => {
+                write!(f, "Deposit {} has not settled", tx)
+            }
+            PaymentError::DepositNotPending { tx } => {
+                write!(f, "Deposit {} is not pending", tx)
+            }
+            PaymentError::InvalidPartialDispute { tx } => write!(
+                f,
+                "Partial dispute of transaction {} must be positive and no more than the disputed amount",
+                tx
+            ),
+            PaymentError::InvalidTransfer { tx } => write!(
+                f,
+                "Transfer {} must name a destination client other than its own",
+                tx
+            ),
+            PaymentError::AccountNotLocked { client, tx } => write!(
+                f,
+                "Transaction {} was rejected because the account of client {} is not locked",
+                tx, client
+            ),
+            PaymentError::Unauthorized { actor, client, tx } => write!(
+                f,
+                "Transaction {} was rejected because {} isn't allowed to change the lock on the account of client {}",
+                tx, actor, client
+            ),
+            PaymentError::CurrencyMismatch {
+                currency,
+                expected,
+                tx,
+            } => write!(
+                f,
+                "Transaction {} is in {} but referenced a transaction in {}",
+                tx, currency, expected
+            ),
+            PaymentError::InvalidConversion { tx } => write!(
+                f,
+                "Conversion {} must name a target currency other than its own",
+                tx
+            ),
+            PaymentError::RateNotFound { from, to, tx } => write!(
+                f,
+                "Conversion {} has no rate and there is no rate from {} to {}",
+                tx, from, to
+            ),
+            PaymentError::Overflow { client, tx } => write!(
+                f,
+                "Transaction {} would overflow the balances of client {}",
+                tx, client
+            ),
+            PaymentError::RuleViolation { tx, reason } => {
+                write!(f, "Transaction {} was rejected by a risk rule: {}", tx, reason)
+            }
+            PaymentError::Rejected { tx, reason } => {
+                write!(f, "Transaction {} was rejected: {}", tx, reason)
+            }
+        }
+    }
+}
+
+impl PaymentError {
+    /// A short, stable name for the kind of error, such as `insufficient_funds`, for reports read by other tools
+    pub fn code(&self) -> &'static str {
+        match self {
+            PaymentError::CorruptSnapshot { .. } => "corrupt_snapshot",
+            PaymentError::TransactionIdReuseAfterChargeback { .. } => {
+                "transaction_id_reuse_after_chargeback"
+            }
+            PaymentError::DisputeLimitExceeded { .. } => "dispute_limit_exceeded",
+            PaymentError::MissingField { .. } => "missing_field",
+            PaymentError::DisputeBeforeDeposit { .. } => "dispute_before_deposit",
+            PaymentError::IdWraparound { .. } => "id_wraparound",
+            PaymentError::OutOfOrder { .. } => "out_of_order",
+            PaymentError::ReferencesLaterTransaction { .. } => "references_later_transaction",
+            PaymentError::AmountExceedsLimit { .. } => "amount_exceeds_limit",
+            PaymentError::DailyWithdrawalLimitExceeded { .. } => "daily_withdrawal_limit_exceeded",
+            PaymentError::RateLimitExceeded { .. } => "rate_limit_exceeded",
+            PaymentError::AccountLocked { .. } => "account_locked",
+            PaymentError::NonPositiveAmount { .. } => "non_positive_amount",
+            PaymentError::MissingAmount { .. } => "missing_amount",
+            PaymentError::AccountNotFound { .. } => "account_not_found",
+            PaymentError::ClientMismatch { .. } => "client_mismatch",
+            PaymentError::TxNotFound { .. } => "tx_not_found",
+            PaymentError::InsufficientFunds { .. } => "insufficient_funds",
+            PaymentError::HeldFundsUnbacked { .. } => "held_funds_unbacked",
+            PaymentError::AlreadyDisputed { .. } => "already_disputed",
+            PaymentError::NotDisputed { .. } => "not_disputed",
+            PaymentError::AlreadyReversed { .. } => "already_reversed",
+            PaymentError::DepositPending { .. } => "deposit_pending",
+            PaymentError::DepositNotPending { .. } => "deposit_not_pending",
+            PaymentError::InvalidPartialDispute { .. } => "invalid_partial_dispute",
+            PaymentError::InvalidTransfer { .. } => "invalid_transfer",
+            PaymentError::AccountNotLocked { .. } => "account_not_locked",
+            PaymentError::Unauthorized { .. } => "unauthorized",
+            PaymentError::CurrencyMismatch { .. } => "currency_mismatch",
+            PaymentError::InvalidConversion { .. } => "invalid_conversion",
+            PaymentError::RateNotFound { .. } => "rate_not_found",
+            PaymentError::Overflow { .. } => "overflow",
+            PaymentError::RuleViolation { .. } => "rule_violation",
+            PaymentError::Rejected { .. } => "rejected",
+        }
+    }
+
+    /// The kind of error a transaction was rejected with, wrapping errors that aren't a `PaymentError`, such as IO
+    /// errors, in [`PaymentError::Rejected`]
+    pub(crate) fn from_rejection(err: anyhow::Error, tx: u32) -> Self {
+        err.downcast::<PaymentError>()
+            .unwrap_or_else(|err| PaymentError::Rejected {
+                tx,
+                reason: err.to_string(),
+            })
+    }
+}
+
+impl std::error::Error for PaymentError {}
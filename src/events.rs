@@ -0,0 +1,72 @@
+//! The journal of everything applied to the accounts, which the accounts can be derived from again by folding over it
+
+use crate::{
+    Account, AccountStatus, Accounts, Amount, ClientId, Currency, Timestamp, TransactionType,
+};
+use serde::{Deserialize, Serialize};
+
+/// The balances and status an account was left with by an event
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct AccountChange {
+    pub client: ClientId,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    pub pending: Amount,
+    pub total: Amount,
+    #[serde(default, skip_serializing_if = "Amount::is_zero")]
+    pub credit_limit: Amount,
+    pub status: AccountStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<Timestamp>,
+}
+
+impl From<&Account> for AccountChange {
+    fn from(account: &Account) -> Self {
+        AccountChange {
+            client: account.client,
+            currency: account.currency,
+            available: account.available,
+            held: account.held,
+            pending: account.pending,
+            total: account.total,
+            credit_limit: account.credit_limit,
+            status: account.status,
+            last_activity: account.last_activity,
+        }
+    }
+}
+
+/// A transaction that was applied, or a dispute resolved by the dispute expiry, with every account it changed. Events
+/// are never changed once recorded
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct LedgerEvent {
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    /// The transaction's timestamp, for inputs with a timestamp column
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    /// Whether this is a dispute the dispute expiry resolved, rather than a transaction from the input
+    #[serde(default)]
+    pub expired: bool,
+    /// The accounts the event opened or changed, in the order they were opened
+    pub changes: Vec<AccountChange>,
+}
+
+impl LedgerEvent {
+    /// Applies the event to `accounts`, opening any account it changed that isn't there yet. Folding every event of a
+    /// journal in order over no accounts gives the accounts of the engine that recorded it
+    pub(crate) fn apply_to(&self, accounts: &mut Accounts) {
+        for change in &self.changes {
+            let account = accounts.get_or_open(change.client, change.currency);
+            account.available = change.available;
+            account.held = change.held;
+            account.pending = change.pending;
+            account.total = change.total;
+            account.credit_limit = change.credit_limit;
+            account.status = change.status;
+            account.last_activity = change.last_activity;
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! A gRPC service exposing an engine, for running the engine as a long-lived service. `proto/payments.proto`
+//! describes the service for clients in other languages
+
+use crate::{Account, AmountFormat, AsyncPaymentsEngine, ClientId, Config, Currency, Transaction};
+use anyhow::Error;
+use log::info;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tonic::metadata::MetadataValue;
+use tonic::{Request, Response, Status};
+
+#[allow(clippy::all)]
+mod service {
+    include!(concat!(env!("OUT_DIR"), "/payments.Payments.rs"));
+}
+
+pub use service::payments_client::PaymentsClient;
+pub use service::payments_server::PaymentsServer;
+
+/// A transaction to apply, with the same fields as a row of a CSV input
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitTransactionRequest {
+    #[prost(string, tag = "1")]
+    pub r#type: String,
+    #[prost(uint64, tag = "2")]
+    pub client: u64,
+    #[prost(uint32, tag = "3")]
+    pub tx: u32,
+    #[prost(string, optional, tag = "4")]
+    pub amount: Option<String>,
+    #[prost(uint64, optional, tag = "5")]
+    pub to_client: Option<u64>,
+    #[prost(uint64, optional, tag = "6")]
+    pub actor: Option<u64>,
+    #[prost(string, optional, tag = "7")]
+    pub currency: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    pub to_currency: Option<String>,
+    #[prost(string, optional, tag = "9")]
+    pub rate: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    pub timestamp: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubmitTransactionResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetAccountRequest {
+    #[prost(uint64, tag = "1")]
+    pub client: u64,
+    /// The server's currency if not given
+    #[prost(string, optional, tag = "2")]
+    pub currency: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamAccountsRequest {}
+
+/// An account with the same fields as a row of the CSV output, with amounts rounded to the server's decimal places
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AccountReply {
+    #[prost(uint64, tag = "1")]
+    pub client: u64,
+    #[prost(string, tag = "2")]
+    pub currency: String,
+    #[prost(string, tag = "3")]
+    pub available: String,
+    #[prost(string, tag = "4")]
+    pub held: String,
+    #[prost(string, tag = "5")]
+    pub total: String,
+    #[prost(bool, tag = "6")]
+    pub locked: bool,
+}
+
+/// Parses a field of a request, failing with `INVALID_ARGUMENT` if it doesn't parse
+fn parse<T: FromStr<Err = Error>>(field: &str, value: &str) -> Result<T, Status> {
+    value
+        .parse()
+        .map_err(|err| Status::invalid_argument(format!("Invalid {}: {}", field, err)))
+}
+
+/// Parses an optional field of a request, failing with `INVALID_ARGUMENT` if it is given and doesn't parse
+fn parse_optional<T: FromStr<Err = Error>>(
+    field: &str,
+    value: Option<&str>,
+) -> Result<Option<T>, Status> {
+    value.map(|value| parse(field, value)).transpose()
+}
+
+impl SubmitTransactionRequest {
+    fn to_transaction(&self) -> Result<Transaction, Status> {
+        Ok(Transaction {
+            tx_type: parse("type", &self.r#type)?,
+            client: self.client,
+            id: self.tx,
+            amount: parse_optional("amount", self.amount.as_deref())?,
+            to_client: self.to_client,
+            actor: self.actor,
+            currency: parse_optional("currency", self.currency.as_deref())?,
+            to_currency: parse_optional("to_currency", self.to_currency.as_deref())?,
+            rate: parse_optional("rate", self.rate.as_deref())?,
+            timestamp: parse_optional("timestamp", self.timestamp.as_deref())?,
+        })
+    }
+}
+
+/// The service backed by an engine, which other tasks can keep using through their own clones of it
+#[derive(Debug, Clone)]
+pub struct PaymentsService {
+    engine: AsyncPaymentsEngine,
+    currency: Currency,
+    format: AmountFormat,
+}
+
+impl PaymentsService {
+    /// Serves `engine`, writing amounts and defaulting currencies as `config` does for the CSV output
+    pub fn new(engine: AsyncPaymentsEngine, config: &Config) -> Self {
+        PaymentsService {
+            engine,
+            currency: config.currency,
+            format: config.amount_format(),
+        }
+    }
+
+    fn reply(&self, account: &Account) -> AccountReply {
+        AccountReply {
+            client: account.client,
+            currency: account.currency.to_string(),
+            available: self.format.trimmed(account.available).to_string(),
+            held: self.format.trimmed(account.held).to_string(),
+            total: self.format.trimmed(account.total).to_string(),
+            locked: account.status.is_locked(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl service::payments_server::Payments for PaymentsService {
+    async fn submit_transaction(
+        &self,
+        request: Request<SubmitTransactionRequest>,
+    ) -> Result<Response<SubmitTransactionResponse>, Status> {
+        let tx = request.get_ref().to_transaction()?;
+
+        match self.engine.process(tx).await {
+            Ok(()) => Ok(Response::new(SubmitTransactionResponse {})),
+            Err(err) => {
+                let mut status = Status::failed_precondition(err.to_string());
+                status
+                    .metadata_mut()
+                    .insert("payment-error", MetadataValue::from_static(err.code()));
+                Err(status)
+            }
+        }
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<GetAccountRequest>,
+    ) -> Result<Response<AccountReply>, Status> {
+        let request = request.get_ref();
+        let client: ClientId = request.client;
+        let currency =
+            parse_optional("currency", request.currency.as_deref())?.unwrap_or(self.currency);
+
+        match self.engine.account_in(client, currency).await {
+            Some(account) => Ok(Response::new(self.reply(&account))),
+            None => Err(Status::not_found(format!(
+                "Client {} has no {} account",
+                client, currency
+            ))),
+        }
+    }
+
+    type StreamAccountsStream =
+        tokio_stream::Iter<std::vec::IntoIter<Result<AccountReply, Status>>>;
+
+    async fn stream_accounts(
+        &self,
+        _request: Request<StreamAccountsRequest>,
+    ) -> Result<Response<Self::StreamAccountsStream>, Status> {
+        let replies: Vec<_> = self
+            .engine
+            .accounts()
+            .await
+            .iter()
+            .map(|account| Ok(self.reply(account)))
+            .collect();
+
+        Ok(Response::new(tokio_stream::iter(replies)))
+    }
+}
+
+/// Serves an engine configured by `config` on `addr` until `shutdown` completes, continuing from the state in
+/// `config.resume` if there is one. Once the requests being handled finish the store, if there is one, is flushed, and
+/// the final state is written to `config.snapshot` if there is one, so a later run can resume from it
+pub async fn serve(
+    addr: SocketAddr,
+    config: &Config,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let engine = AsyncPaymentsEngine::from_config(config)?;
+    let service = PaymentsService::new(engine.clone(), config);
+    info!("Serving on {}", addr);
+
+    tonic::transport::Server::builder()
+        .add_service(PaymentsServer::new(service))
+        .serve_with_shutdown(addr, shutdown)
+        .await?;
+
+    engine.shut_down(config).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn transactions_submitted_over_grpc_are_snapshotted_on_shutdown() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let snapshot = std::env::temp_dir().join("payments_grpc_snapshot.json");
+        let config = Config {
+            snapshot: Some(snapshot.to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve(addr, &config, async {
+                let _ = stopped.await;
+            })
+            .await
+        });
+
+        let mut client = loop {
+            let endpoint = tonic::transport::Endpoint::from_shared(format!("http://{}", addr));
+            match endpoint.unwrap().connect().await {
+                Ok(channel) => break PaymentsClient::new(channel),
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+
+        let deposit = |client: u64, tx: u32, amount: &str| SubmitTransactionRequest {
+            r#type: "deposit".to_string(),
+            client,
+            tx,
+            amount: Some(amount.to_string()),
+            ..SubmitTransactionRequest::default()
+        };
+        client
+            .submit_transaction(deposit(1, 1, "2.5"))
+            .await
+            .unwrap();
+        client.submit_transaction(deposit(2, 2, "1")).await.unwrap();
+
+        let rejected = client
+            .submit_transaction(SubmitTransactionRequest {
+                r#type: "withdraw".to_string(),
+                ..deposit(2, 3, "5")
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(rejected.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(
+            rejected.metadata().get("payment-error").unwrap(),
+            "insufficient_funds"
+        );
+
+        let account = client
+            .get_account(GetAccountRequest {
+                client: 1,
+                currency: None,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((account.available.as_str(), account.locked), ("2.5", false));
+
+        let missing = client
+            .get_account(GetAccountRequest {
+                client: 1,
+                currency: Some("EUR".to_string()),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+
+        let clients: Vec<u64> = client
+            .stream_accounts(StreamAccountsRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|account| account.unwrap().client)
+            .collect()
+            .await;
+        assert_eq!(clients, vec![1, 2]);
+
+        drop(client);
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let mut resumed = crate::Engine::new();
+        resumed
+            .restore_state(std::fs::File::open(&snapshot).unwrap())
+            .unwrap();
+        assert_eq!(resumed.accounts().count(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::LedgerEntry;
+use anyhow::Error;
+use std::collections::{BTreeMap, HashMap, TryReserveError, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The ledger of applied deposits and withdrawals, in the order they were applied, indexed by transaction id so
+/// disputes, resolves, and chargebacks don't scan it.
+///
+/// Every entry has a position, counting from the first entry ever recorded. With a limit, only the most recent entries
+/// are kept in memory. Older ones are written to the spill file if there is one, and dropped otherwise, except for
+/// entries under dispute, which stay in memory so their dispute can still be resolved
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub(crate) struct History {
+    /// The most recent entries, starting at position `evicted`
+    entries: VecDeque<LedgerEntry>,
+    /// The number of entries that were moved out of `entries` to keep within the limit
+    evicted: usize,
+    /// Evicted entries that are still in memory, by position, either because they were under dispute when evicted or
+    /// because they were read back from the spill file, until they're released
+    pinned: BTreeMap<usize, LedgerEntry>,
+    /// The position of the first entry with each id in the current id space, for entries in memory
+    index: HashMap<u32, usize>,
+    /// The position and spill file offset of each entry in the current id space that is only on disk
+    spilled: HashMap<u32, (usize, u64)>,
+    limit: Option<usize>,
+    spill: Option<Spill>,
+}
+
+impl History {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+            ..Self::default()
+        }
+    }
+
+    /// Reserves room for `additional` more entries, leaving the history as it was if they can't all be reserved
+    pub(crate) fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let reserved = self
+            .entries
+            .try_reserve(additional)
+            .and_then(|()| self.index.try_reserve(additional));
+
+        if reserved.is_err() {
+            self.entries.shrink_to_fit();
+            self.index.shrink_to_fit();
+        }
+
+        reserved
+    }
+
+    #[cfg(test)]
+    pub(crate) fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pinned(&self) -> usize {
+        self.pinned.len()
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Writes entries evicted by the limit to a new file at `path`, replacing any file already there, so they can
+    /// still be disputed later
+    pub(crate) fn spill_to(&mut self, path: &Path) -> Result<(), Error> {
+        self.spill = Some(Spill::create(path)?);
+
+        Ok(())
+    }
+
+    /// The position of the first entry with `id` in the current id space, if it's in memory
+    pub(crate) fn position(&self, id: u32) -> Option<usize> {
+        self.index.get(&id).copied()
+    }
+
+    /// The first entry with `id` in the current id space, reading it back into memory if it was spilled. Later entries
+    /// reusing the id can't be looked up, just as they couldn't be disputed when the ledger was scanned in order
+    pub(crate) fn get_mut(&mut self, id: u32) -> Result<Option<&mut LedgerEntry>, Error> {
+        if let Some(position) = self.position(id) {
+            return Ok(self.at_mut(position));
+        }
+
+        let (position, offset) = match (self.spilled.remove(&id), &self.spill) {
+            (Some(location), Some(_)) => location,
+            _ => return Ok(None),
+        };
+
+        let entry = self.spill.as_ref().unwrap().read(offset)?;
+        self.index.insert(id, position);
+
+        Ok(Some(self.pinned.entry(position).or_insert(entry)))
+    }
+
+    /// The entry at a position returned by [`History::position`], which stays valid across wraparounds. `None` if the
+    /// entry was evicted from memory since
+    pub(crate) fn at_mut(&mut self, position: usize) -> Option<&mut LedgerEntry> {
+        match position.checked_sub(self.evicted) {
+            Some(index) => self.entries.get_mut(index),
+            None => self.pinned.get_mut(&position),
+        }
+    }
+
+    /// The position the next pushed entry will have
+    pub(crate) fn next_position(&self) -> usize {
+        self.evicted + self.entries.len()
+    }
+
+    pub(crate) fn push(&mut self, entry: LedgerEntry) -> Result<(), Error> {
+        let position = self.next_position();
+        self.index.entry(entry.id).or_insert(position);
+        self.entries.push_back(entry);
+
+        while self.limit.is_some_and(|limit| self.entries.len() > limit) {
+            self.evict_oldest()?;
+        }
+
+        Ok(())
+    }
+
+    fn evict_oldest(&mut self) -> Result<(), Error> {
+        let entry = match self.entries.pop_front() {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let position = self.evicted;
+        self.evicted += 1;
+
+        if entry.under_dispute() {
+            self.pinned.insert(position, entry);
+            return Ok(());
+        }
+
+        if self.index.get(&entry.id) == Some(&position) {
+            self.index.remove(&entry.id);
+
+            if let Some(spill) = &self.spill {
+                let offset = spill.append(&entry)?;
+                self.spilled.insert(entry.id, (position, offset));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lets an evicted entry with `id` that was kept in memory go again once it's no longer under dispute, writing it to
+    /// the spill file if there is one, so entries that were disputed and settled don't pile up in memory
+    pub(crate) fn release(&mut self, id: u32) -> Result<(), Error> {
+        let position = match self.index.get(&id) {
+            Some(&position) if position < self.evicted => position,
+            _ => return Ok(()),
+        };
+
+        match self.pinned.get(&position) {
+            Some(entry) if !entry.under_dispute() => {}
+            _ => return Ok(()),
+        }
+
+        let entry = self.pinned.remove(&position).unwrap();
+        self.index.remove(&id);
+
+        if let Some(spill) = &self.spill {
+            let offset = spill.append(&entry)?;
+            self.spilled.insert(id, (position, offset));
+        }
+
+        Ok(())
+    }
+
+    /// Starts a new id space after the ids wrapped around. Existing entries are kept, but can no longer be looked up
+    /// by id
+    pub(crate) fn start_epoch(&mut self) {
+        self.index.clear();
+        self.spilled.clear();
+    }
+
+    /// The entries in memory, in the order they were applied. Entries that were spilled to disk or dropped by the limit
+    /// are left out
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &LedgerEntry> {
+        self.pinned.values().chain(self.entries.iter())
+    }
+}
+
+/// An append-only file of ledger entries, one JSON object per line. Copies of a [`History`] share the same file, which
+/// is safe since lines are only ever added and each copy only reads the offsets it recorded itself
+#[derive(Clone)]
+struct Spill(Arc<Mutex<SpillFile>>);
+
+struct SpillFile {
+    writer: BufWriter<File>,
+    reader: File,
+    /// The number of bytes written so far, which is the offset of the next line
+    len: u64,
+}
+
+impl Spill {
+    fn create(path: &Path) -> Result<Self, Error> {
+        let writer = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let reader = File::open(path)?;
+
+        Ok(Spill(Arc::new(Mutex::new(SpillFile {
+            writer: BufWriter::new(writer),
+            reader,
+            len: 0,
+        }))))
+    }
+
+    /// Writes an entry to the end of the file, returning the offset to read it back from
+    fn append(&self, entry: &LedgerEntry) -> Result<u64, Error> {
+        let mut file = self.0.lock().unwrap();
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.writer.write_all(&line)?;
+
+        let offset = file.len;
+        file.len += line.len() as u64;
+
+        Ok(offset)
+    }
+
+    fn read(&self, offset: u64) -> Result<LedgerEntry, Error> {
+        let mut file = self.0.lock().unwrap();
+        file.writer.flush()?;
+        file.reader.seek(SeekFrom::Start(offset))?;
+
+        let mut line = String::new();
+        BufReader::new(&file.reader).read_line(&mut line)?;
+
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+impl fmt::Debug for Spill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Spill")
+    }
+}
+
+impl PartialEq for Spill {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Spill {}
+
+impl From<Vec<LedgerEntry>> for History {
+    fn from(entries: Vec<LedgerEntry>) -> Self {
+        let mut history = Self::with_capacity(entries.len());
+
+        for entry in entries {
+            history.push(entry).unwrap();
+        }
+
+        history
+    }
+}
@@ -0,0 +1,262 @@
+//! A REST API exposing an engine over HTTP, for integrating with the engine without dropping files for it to process
+
+use crate::{
+    AsyncPaymentsEngine, ClientId, Config, Currency, JsonAccountRow, LedgerDumpEntry, Transaction,
+};
+use anyhow::Error;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::info;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+/// What every handler shares: the engine, and the options the accounts are written with
+#[derive(Clone)]
+struct ApiState {
+    engine: AsyncPaymentsEngine,
+    config: Arc<Config>,
+}
+
+/// A failed request, answered with a JSON body of the form `{"error": "...", "message": "..."}`
+type ApiError = (StatusCode, Json<Value>);
+
+fn api_error(status: StatusCode, error: &str, message: impl ToString) -> ApiError {
+    (
+        status,
+        Json(json!({ "error": error, "message": message.to_string() })),
+    )
+}
+
+#[derive(Deserialize)]
+struct CurrencyQuery {
+    currency: Option<Currency>,
+}
+
+/// The routes of the API, backed by `engine`, which other tasks can keep using through their own clones of it:
+///
+/// - `POST /transactions` applies a transaction, written as a line of a JSON Lines input. A rejected transaction is
+///   answered with `422` and the code of the error, ex: `{"error": "insufficient_funds", "message": "..."}`
+/// - `GET /accounts` lists every account, as written by `--output-format json` with a currency for each
+/// - `GET /accounts/{client}` is the account of one client, in `?currency=` or the configured currency
+/// - `GET /transactions/{id}` is a deposit or withdrawal the engine remembers, as written by `--dump-state`
+/// - `GET /events` is a WebSocket that pushes every account whose balances change or that becomes locked, as `GET
+///   /accounts/{client}` writes it, in a text message of its own
+pub fn router(engine: AsyncPaymentsEngine, config: &Config) -> Router {
+    Router::new()
+        .route("/transactions", post(submit_transaction))
+        .route("/transactions/{id}", get(get_transaction))
+        .route("/accounts", get(get_accounts))
+        .route("/accounts/{client}", get(get_account))
+        .route("/events", get(events))
+        .with_state(ApiState {
+            engine,
+            config: Arc::new(config.clone()),
+        })
+}
+
+async fn submit_transaction(
+    State(state): State<ApiState>,
+    body: String,
+) -> Result<Json<Value>, ApiError> {
+    let tx = Transaction::from_json_line(&body)
+        .map_err(|err| api_error(StatusCode::BAD_REQUEST, "invalid_transaction", err))?;
+
+    match state.engine.process(tx).await {
+        Ok(()) => Ok(Json(json!({ "result": "applied" }))),
+        Err(err) => Err(api_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            err.code(),
+            &err,
+        )),
+    }
+}
+
+async fn get_accounts(State(state): State<ApiState>) -> Json<Value> {
+    let accounts = state.engine.accounts().await;
+    let rows: Vec<JsonAccountRow> = accounts
+        .iter()
+        .map(|account| JsonAccountRow::new(account, &state.config, true))
+        .collect();
+
+    Json(json!(rows))
+}
+
+async fn get_account(
+    State(state): State<ApiState>,
+    Path(client): Path<ClientId>,
+    Query(query): Query<CurrencyQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let currency = query.currency.unwrap_or(state.config.currency);
+
+    match state.engine.account_in(client, currency).await {
+        Some(account) => Ok(Json(json!(JsonAccountRow::new(
+            &account,
+            &state.config,
+            true
+        )))),
+        None => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("Client {} has no {} account", client, currency),
+        )),
+    }
+}
+
+async fn get_transaction(
+    State(state): State<ApiState>,
+    Path(id): Path<u32>,
+) -> Result<Json<Value>, ApiError> {
+    let entry = state
+        .engine
+        .transaction(id)
+        .await
+        .map_err(|err| api_error(StatusCode::INTERNAL_SERVER_ERROR, "internal", err))?;
+
+    match entry {
+        Some(entry) => Ok(Json(json!(LedgerDumpEntry {
+            entry: &entry,
+            status: entry.status(),
+        }))),
+        None => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("Transaction {} not found", id),
+        )),
+    }
+}
+
+async fn events(State(state): State<ApiState>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(|socket| push_changes(socket, state))
+}
+
+/// Sends each change to the socket until either side closes it. Changes missed by falling behind are skipped, as the
+/// next change of an account has its latest balances anyway
+async fn push_changes(mut socket: WebSocket, state: ApiState) {
+    let mut changes = state.engine.subscribe().await;
+
+    loop {
+        tokio::select! {
+            change = changes.recv() => {
+                let account = match change {
+                    Ok(account) => account,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let event = json!(JsonAccountRow::new(&account, &state.config, true));
+
+                if socket.send(Message::Text(event.to_string().into())).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                if !matches!(message, Some(Ok(_))) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Serves the API for an engine configured by `config` on `addr` until `shutdown` completes, continuing from the state
+/// in `config.resume` if there is one. Once the requests being handled finish the store, if there is one, is flushed,
+/// and the final state is written to `config.snapshot` if there is one
+pub async fn serve(
+    addr: SocketAddr,
+    config: &Config,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Error> {
+    let engine = AsyncPaymentsEngine::from_config(config)?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Serving on {}", listener.local_addr()?);
+
+    axum::serve(listener, router(engine.clone(), config))
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    engine.shut_down(config).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn send(router: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn transactions_posted_to_the_api_change_the_accounts() {
+        let router = router(AsyncPaymentsEngine::new(), &Config::default());
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 2.5}"#,
+        )
+        .await;
+        assert_eq!(
+            (status, body),
+            (StatusCode::OK, json!({"result": "applied"}))
+        );
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/transactions",
+            r#"{"type": "withdraw", "client": 1, "tx": 2, "amount": "5"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "insufficient_funds");
+
+        let (status, _) = send(&router, "POST", "/transactions", "deposit,1,3,1").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = send(&router, "GET", "/accounts/1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            (&body["currency"], &body["available"]),
+            (&json!("USD"), &json!("2.5000"))
+        );
+
+        let (status, body) = send(&router, "GET", "/accounts", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+
+        let (status, _) = send(&router, "GET", "/accounts/1?currency=EUR", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = send(&router, "GET", "/transactions/1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            (&body["type"], &body["status"]),
+            (&json!("deposit"), &json!("applied"))
+        );
+
+        let (status, _) = send(&router, "GET", "/transactions/2", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
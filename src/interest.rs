@@ -0,0 +1,128 @@
+//! Interest on available balances, credited at the end of each period as `interest` transactions, so accrued interest
+//! goes through the ledger, audit log, and events like any other transaction
+
+use crate::{from_units, round_div, to_units, Amount, ClientId, Currency, Timestamp};
+use anyhow::Error;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// How often interest is credited. Periods end at midnight UTC, or on the first of the month
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum InterestPeriod {
+    Daily,
+    #[default]
+    Monthly,
+}
+
+impl InterestPeriod {
+    fn per_year(self) -> i128 {
+        match self {
+            InterestPeriod::Daily => 365,
+            InterestPeriod::Monthly => 12,
+        }
+    }
+
+    /// The end of the period `timestamp` is in, or `None` if it would be past the last representable timestamp
+    fn end_after(self, timestamp: Timestamp) -> Option<Timestamp> {
+        match self {
+            InterestPeriod::Daily => timestamp.next_day(),
+            InterestPeriod::Monthly => timestamp.next_month(),
+        }
+    }
+}
+
+impl FromStr for InterestPeriod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(InterestPeriod::Daily),
+            "monthly" => Ok(InterestPeriod::Monthly),
+            _ => Err(Error::msg(format!("Unknown interest period: {}", s))),
+        }
+    }
+}
+
+/// Interest credited to an account at the end of a period
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+pub struct InterestCredit {
+    /// The id of the `interest` transaction that credited it
+    pub tx: u32,
+    pub client: ClientId,
+    pub currency: Currency,
+    pub period_end: Timestamp,
+    /// The available funds the interest was earned on
+    pub balance: Amount,
+    pub amount: Amount,
+}
+
+/// Where interest accrual is up to
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Accrual {
+    apr_bps: u32,
+    period: InterestPeriod,
+    /// The end of the current period, once a timestamp has been seen
+    period_end: Option<Timestamp>,
+    /// The lowest id given to an interest transaction. Ids count down from `u32::MAX` to stay clear of the inputs' ids
+    lowest_credited: Option<u32>,
+    /// The highest id of the inputs' transactions, which interest transactions' ids must stay above
+    highest_input: Option<u32>,
+}
+
+impl Accrual {
+    pub(crate) fn new(apr_bps: u32, period: InterestPeriod) -> Self {
+        Accrual {
+            apr_bps,
+            period,
+            period_end: None,
+            lowest_credited: None,
+            highest_input: None,
+        }
+    }
+
+    /// The ends of every period that ended at or before `timestamp`, moving on to the period after them. The first
+    /// period starts at `start`
+    pub(crate) fn ended_by(&mut self, start: Timestamp, timestamp: Timestamp) -> Vec<Timestamp> {
+        let mut end = self.period_end.or_else(|| self.period.end_after(start));
+        let mut ended = Vec::new();
+
+        while let Some(period_end) = end.filter(|&end| end <= timestamp) {
+            ended.push(period_end);
+            end = self.period.end_after(period_end);
+        }
+
+        self.period_end = end;
+        ended
+    }
+
+    /// The interest a period earns on `balance`, a share of the APR, rounded half away from zero to the output scale
+    pub(crate) fn interest_on(&self, balance: Amount) -> Amount {
+        let units = to_units(balance) * i128::from(self.apr_bps);
+        from_units(round_div(units, 10_000 * self.period.per_year()))
+    }
+
+    /// Notes the id of a transaction from the inputs, returning `false` if an interest transaction already took it
+    pub(crate) fn observe(&mut self, id: u32) -> bool {
+        if self.lowest_credited.is_some_and(|lowest| id >= lowest) {
+            return false;
+        }
+
+        self.highest_input = self.highest_input.max(Some(id));
+        true
+    }
+
+    /// The id of the next interest transaction, or `None` once the ids have come down to the inputs' ids
+    pub(crate) fn next_id(&mut self) -> Option<u32> {
+        let id = match self.lowest_credited {
+            Some(lowest) => lowest.checked_sub(1)?,
+            None => u32::MAX,
+        };
+
+        if self.highest_input.is_some_and(|highest| id <= highest) {
+            return None;
+        }
+
+        self.lowest_credited = Some(id);
+        Some(id)
+    }
+}
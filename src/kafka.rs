@@ -0,0 +1,193 @@
+//! A Kafka consumer feeding an engine from a topic of transactions, for ingesting transactions as they are produced
+//! rather than from daily files
+
+use crate::{Account, AsyncPaymentsEngine, Config, JsonAccountRow, PaymentError, Transaction};
+use anyhow::Error;
+use log::{info, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::Message;
+use std::future::Future;
+use std::time::Duration;
+
+/// Where to read transactions from and publish account snapshots to
+#[derive(Debug, Clone)]
+pub struct KafkaConfig {
+    /// The `bootstrap.servers` of the cluster, ex: `localhost:9092`
+    pub brokers: String,
+    /// The consumer group offsets are committed for
+    pub group_id: String,
+    /// The topic of transactions, each a JSON payload like a line of a JSON Lines input
+    pub topic: String,
+    /// The topic every account is published to periodically and on shutdown, keyed by client id
+    pub snapshot_topic: Option<String>,
+    /// How often accounts are published to `snapshot_topic`
+    pub snapshot_interval: Duration,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            brokers: "localhost:9092".to_string(),
+            group_id: "payments".to_string(),
+            topic: "transactions".to_string(),
+            snapshot_topic: None,
+            snapshot_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Consumes transactions from `kafka.topic` into an engine configured by `config` until `shutdown` completes,
+/// continuing from the state in `config.resume` if there is one.
+///
+/// Offsets are committed only once a message has been handled, so a consumer that stops before then reads it again.
+/// A transaction the engine rejects, or a payload that doesn't parse, is logged and committed like a rejected row of a
+/// CSV input, as reading it again would only reject it again. Any other error, such as failing to write to a store,
+/// stops consuming without committing the message. Accounts are published to `kafka.snapshot_topic`, if there is one,
+/// every `kafka.snapshot_interval` and once more on shutdown, after which the store is flushed and the final state is
+/// written to `config.snapshot` if there is one
+pub async fn consume(
+    kafka: &KafkaConfig,
+    config: &Config,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let engine = AsyncPaymentsEngine::from_config(config)?;
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &kafka.brokers)
+        .set("group.id", &kafka.group_id)
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
+    let producer: Option<FutureProducer> = match &kafka.snapshot_topic {
+        Some(_) => Some(
+            ClientConfig::new()
+                .set("bootstrap.servers", &kafka.brokers)
+                .create()?,
+        ),
+        None => None,
+    };
+
+    consumer.subscribe(&[&kafka.topic])?;
+    info!("Consuming {} from {}", kafka.topic, kafka.brokers);
+
+    let mut shutdown = std::pin::pin!(shutdown);
+    let mut snapshots = tokio::time::interval(kafka.snapshot_interval);
+    // The first tick completes immediately, and there is nothing to publish yet
+    snapshots.tick().await;
+
+    loop {
+        tokio::select! {
+            message = consumer.recv() => {
+                let message = message?;
+
+                match parse_payload(message.payload()) {
+                    Ok(tx) => match engine.apply(tx).await {
+                        Err(err) if err.is::<PaymentError>() => warn!("{}", err),
+                        result => result?,
+                    },
+                    Err(err) => warn!(
+                        "Skipping message at offset {} of partition {}: {}",
+                        message.offset(),
+                        message.partition(),
+                        err
+                    ),
+                }
+
+                consumer.commit_message(&message, CommitMode::Async)?;
+            }
+            _ = snapshots.tick() => publish_accounts(&engine, producer.as_ref(), kafka, config).await?,
+            _ = &mut shutdown => break,
+        }
+    }
+
+    publish_accounts(&engine, producer.as_ref(), kafka, config).await?;
+    engine.shut_down(config).await
+}
+
+/// Reads a transaction from the payload of a message
+fn parse_payload(payload: Option<&[u8]>) -> Result<Transaction, Error> {
+    let payload = std::str::from_utf8(payload.unwrap_or_default())?;
+
+    Transaction::from_json_line(payload)
+}
+
+async fn publish_accounts(
+    engine: &AsyncPaymentsEngine,
+    producer: Option<&FutureProducer>,
+    kafka: &KafkaConfig,
+    config: &Config,
+) -> Result<(), Error> {
+    let (producer, topic) = match (producer, &kafka.snapshot_topic) {
+        (Some(producer), Some(topic)) => (producer, topic),
+        _ => return Ok(()),
+    };
+
+    for (key, payload) in snapshot_messages(&engine.accounts().await, config)? {
+        producer
+            .send(
+                FutureRecord::to(topic).key(&key).payload(&payload),
+                Duration::from_secs(0),
+            )
+            .await
+            .map_err(|(err, _)| err)?;
+    }
+
+    Ok(())
+}
+
+/// The messages publishing every account: the client id as the key, and the account as `--output-format json` writes
+/// it, with its currency, as the payload
+fn snapshot_messages(
+    accounts: &[Account],
+    config: &Config,
+) -> Result<Vec<(String, String)>, Error> {
+    accounts
+        .iter()
+        .map(|account| {
+            let row = JsonAccountRow::new(account, config, true);
+            Ok((account.client.to_string(), serde_json::to_string(&row)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, Engine};
+
+    #[test]
+    fn payloads_are_json_transactions() {
+        let tx = parse_payload(Some(
+            br#"{"type": "deposit", "client": 1, "tx": 2, "amount": 1.5}"#,
+        ))
+        .unwrap();
+        assert_eq!(tx.amount(), Some(Amount::from_num(1.5)));
+
+        assert!(parse_payload(Some(b"deposit,1,2,1.5")).is_err());
+        assert!(parse_payload(None).is_err());
+    }
+
+    #[test]
+    fn snapshots_publish_each_account_keyed_by_client() {
+        let mut engine = Engine::new();
+        engine
+            .process(Transaction::new(
+                crate::TransactionType::Deposit,
+                7,
+                1,
+                Some(Amount::from_num(2)),
+            ))
+            .unwrap();
+        let accounts: Vec<Account> = engine.accounts().cloned().collect();
+
+        let messages = snapshot_messages(&accounts, &Config::default()).unwrap();
+        assert_eq!(
+            messages,
+            vec![(
+                "7".to_string(),
+                r#"{"client":7,"currency":"USD","available":"2.0000","held":"0.0000","total":"2.0000","locked":false,"withdrawable":"2.0000"}"#.to_string()
+            )]
+        );
+    }
+}
@@ -1,388 +1,8071 @@
+mod accounts;
+mod amount;
+#[cfg(feature = "tokio")]
+mod async_engine;
+mod audit;
+mod conversion;
+mod currency;
+mod error;
+mod events;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod history;
+#[cfg(feature = "http")]
+pub mod http;
+mod interest;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+mod merge;
+#[cfg(feature = "arrow")]
+mod parquet_output;
+mod reconcile;
+mod risk;
+mod schedule;
+mod shard;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod statement;
+mod store;
+mod timestamp;
+mod validate;
+#[cfg(feature = "watch")]
+mod watch;
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::{process_sqlite, process_sqlite_connection, SqliteStore};
+pub use store::{AccountStore, Store, TransactionStore};
+
+use accounts::Accounts;
+use amount::round_div;
+pub use amount::Amount;
+#[cfg(feature = "tokio")]
+pub use async_engine::AsyncPaymentsEngine;
+use audit::{AuditLog, AuditRecord, Balances};
+pub use conversion::{ConversionEvent, Rate, RateTable};
+pub use currency::Currency;
+pub use error::PaymentError;
+pub use events::{AccountChange, LedgerEvent};
+use history::History;
+use interest::Accrual;
+pub use interest::{InterestCredit, InterestPeriod};
+pub use reconcile::{reconcile, Discrepancy};
+use risk::RiskTracker;
+pub use risk::{RapidDisputes, RiskLimits, RiskRule, RuleFlag, RuleOutcome, Structuring};
+pub use schedule::{Cadence, RecurringInstruction};
+pub use statement::{statement, StatementLine};
+pub use timestamp::Timestamp;
+pub use validate::{validate, ValidationIssue, ValidationReport};
+#[cfg(feature = "watch")]
+pub use watch::watch;
+
 use anyhow::Error;
-use csv::{ReaderBuilder, Trim, WriterBuilder};
-use fixed::traits::ToFixed;
-use fixed::types::I50F14;
-use serde::{Deserialize, Serialize};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim, Writer, WriterBuilder};
+use log::{debug, info, warn};
+use memmap2::Mmap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Serialize, Eq, PartialEq)]
-struct Account {
-    client: u16,
-    available: I50F14,
-    held: I50F14,
-    total: I50F14,
-    locked: bool,
+/// Identifies the client an account belongs to
+pub type ClientId = u64;
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct Account {
+    client: ClientId,
+    /// The currency the balances are in. A client has a separate account for each currency they hold
+    #[serde(default, skip_serializing)]
+    currency: Currency,
+    #[serde(rename = "account", default, skip_serializing_if = "Option::is_none")]
+    account_number: Option<String>,
+    available: Amount,
+    held: Amount,
+    /// Deposited funds that have not settled yet. They count towards the total but can't be withdrawn
+    #[serde(default, skip_serializing)]
+    pending: Amount,
+    total: Amount,
+    /// How far below zero withdrawals, transfers, and conversions may take the available funds
+    #[serde(default, skip_serializing)]
+    credit_limit: Amount,
+    #[serde(
+        rename = "locked",
+        serialize_with = "serialize_locked",
+        deserialize_with = "deserialize_locked"
+    )]
+    status: AccountStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// When the last transaction with a timestamp was applied to the account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_activity: Option<Timestamp>,
+}
+
+impl Account {
+    /// An empty, active account for a client seen for the first time in `currency`
+    fn new(client: ClientId, currency: Currency) -> Self {
+        Account {
+            client,
+            currency,
+            account_number: None,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            pending: Amount::ZERO,
+            total: Amount::ZERO,
+            credit_limit: Amount::ZERO,
+            status: AccountStatus::Active,
+            source: None,
+            last_activity: None,
+        }
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    pub fn pending(&self) -> Amount {
+        self.pending
+    }
+
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
+    pub fn credit_limit(&self) -> Amount {
+        self.credit_limit
+    }
+
+    pub fn status(&self) -> AccountStatus {
+        self.status
+    }
+
+    pub fn last_activity(&self) -> Option<Timestamp> {
+        self.last_activity
+    }
+
+    /// Rounds available, held, and pending funds to the output scale, keeping the total equal to their sum. Balances
+    /// too close to the largest amount to round without overflowing are left as they are
+    fn round_to_scale(&mut self) {
+        let available = round_to_scale(self.available);
+        let held = round_to_scale(self.held);
+        let pending = round_to_scale(self.pending);
+
+        if let Some(total) = available
+            .checked_add(held)
+            .and_then(|sum| sum.checked_add(pending))
+        {
+            self.available = available;
+            self.held = held;
+            self.pending = pending;
+            self.total = total;
+        }
+    }
+
+    /// The most a withdrawal, transfer, or conversion can take out of the account, its available funds and its credit
+    /// limit
+    fn spendable(&self) -> Amount {
+        self.available.saturating_add(self.credit_limit)
+    }
+
+    /// Whether the account's available, held, and pending funds add up to its total
+    fn is_consistent(&self) -> bool {
+        self.available
+            .checked_add(self.held)
+            .and_then(|sum| sum.checked_add(self.pending))
+            == Some(self.total)
+    }
+}
+
+/// The lifecycle state of an account. Any status other than `Active` is reported as locked in the CSV output
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    /// The account accepts transactions normally
+    Active,
+    /// The account was frozen by a chargeback
+    ChargedBack,
+    /// The account was frozen by an administrator
+    Frozen,
+    /// The account has been closed
+    Closed,
+}
+
+impl AccountStatus {
+    pub fn is_locked(&self) -> bool {
+        *self != AccountStatus::Active
+    }
+}
+
+fn serialize_locked<S: Serializer>(
+    status: &AccountStatus,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(status.is_locked())
+}
+
+/// The CSV output only records whether an account is locked, so a locked account is read back as charged back, the
+/// usual way an account gets locked
+fn deserialize_locked<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<AccountStatus, D::Error> {
+    match bool::deserialize(deserializer)? {
+        true => Ok(AccountStatus::ChargedBack),
+        false => Ok(AccountStatus::Active),
+    }
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq, Clone)]
-struct Transaction {
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+pub struct Transaction {
     #[serde(rename = "type")]
     tx_type: TransactionType,
-    client: u16,
+    client: ClientId,
     #[serde(rename = "tx")]
     id: u32,
-    amount: Option<I50F14>,
+    amount: Option<Amount>,
+    /// The client a transfer credits
+    #[serde(default)]
+    to_client: Option<ClientId>,
+    /// Who applied a lock or unlock, if not the client themselves
+    #[serde(default)]
+    actor: Option<ClientId>,
+    /// The currency of the amount, or of the transaction referred to. Without one, the engine's currency is assumed
     #[serde(default)]
-    under_dispute: bool,
+    currency: Option<Currency>,
+    /// The currency a conversion credits
+    #[serde(default)]
+    to_currency: Option<Currency>,
+    /// The rate a conversion is made at. Without one, the rate table's rate for the pair is used
+    #[serde(default)]
+    rate: Option<Rate>,
+    /// When the transaction happened, for inputs with a timestamp column
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq, Clone)]
-enum TransactionType {
-    #[serde(alias = "deposit")]
-    Deposit,
-    #[serde(alias = "withdraw")]
-    Withdraw,
-    #[serde(alias = "dispute")]
-    Dispute,
-    #[serde(alias = "resolve")]
-    Resolve,
-    #[serde(alias = "chargeback")]
-    Chargeback,
+/// An applied deposit or withdrawal, with only what a later dispute, resolve, or chargeback needs to know about it.
+/// [`TransactionStore`]s persist it through its `Serialize` and `Deserialize` implementations
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct LedgerEntry {
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    client: ClientId,
+    #[serde(rename = "tx")]
+    id: u32,
+    amount: Amount,
+    /// The client a transfer credited, who holds its funds while it is disputed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to_client: Option<ClientId>,
+    /// The currency of the amount. Disputes only hold funds in the account of the same currency
+    #[serde(default)]
+    currency: Currency,
+    dispute_status: DisputeStatus,
+    /// The amount moved into held funds when this transaction was disputed
+    held: Amount,
+    /// The amount of a disputed withdrawal that is only credited back if the dispute ends in a chargeback, under
+    /// [`WithdrawalDisputeMode::Deferred`]
+    #[serde(default)]
+    deferred: Amount,
+    /// Set once this deposit is reversed by a refund
+    refunded: bool,
+    /// Set while this deposit is waiting to be settled
+    pending: bool,
+    /// The number of times this transaction has been disputed
+    disputes: u32,
+    /// The number of transactions the engine had processed when the current dispute was opened
+    #[serde(skip)]
+    disputed_at: u64,
+    /// When the current dispute was opened, going by the timestamp of the dispute or, without one, the latest timestamp
+    /// seen before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disputed_time: Option<Timestamp>,
+    /// Set once the current dispute has expired under [`DisputeExpiryAction::Escalate`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    escalated: bool,
+    /// When the transaction happened, if it had a timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
 }
 
-pub fn run(input: &str, verbose: bool) -> Result<(), Error> {
-    let mut reader = ReaderBuilder::new()
-        .flexible(true)
-        .trim(Trim::All)
-        .from_path(input)?;
-    let mut history: Vec<Transaction> = Vec::new();
-    let mut accounts: Vec<Account> = Vec::new();
-
-    for result in reader.deserialize() {
-        use TransactionType::*;
-
-        let record: Transaction = result?;
-        history.push(record.clone());
+impl LedgerEntry {
+    /// Creates the entry for a transaction, or `None` for a transaction without an amount as it can't be disputed
+    fn new(tx: Transaction) -> Option<Self> {
+        Some(Self {
+            tx_type: tx.tx_type,
+            client: tx.client,
+            id: tx.id,
+            amount: tx.amount?,
+            to_client: tx.to_client,
+            currency: tx.currency.unwrap_or_default(),
+            dispute_status: DisputeStatus::None,
+            held: Amount::ZERO,
+            deferred: Amount::ZERO,
+            refunded: false,
+            pending: false,
+            disputes: 0,
+            disputed_at: 0,
+            disputed_time: None,
+            escalated: false,
+            timestamp: tx.timestamp,
+        })
+    }
 
-        let res = match record.tx_type {
-            Deposit => deposit(&mut accounts, record),
-            Withdraw => withdraw(&mut accounts, record),
-            Dispute => dispute(&mut accounts, record, &mut history),
-            Resolve => resolve(&mut accounts, record, &mut history),
-            Chargeback => chargeback(&mut accounts, record, &mut history),
-        };
+    fn under_dispute(&self) -> bool {
+        self.dispute_status == DisputeStatus::Disputed
+    }
 
-        if let Err(err) = res {
-            if verbose {
-                println!("{:?}; Error: {}", history.last().unwrap(), err);
-            }
-        };
+    /// The client whose account a dispute of this transaction holds funds in, which is the recipient of a transfer and
+    /// the client of anything else
+    fn holder(&self) -> ClientId {
+        self.to_client.unwrap_or(self.client)
     }
 
-    write_output(accounts)?;
+    /// Where the transaction is in its lifecycle, including refunds and settling as well as disputes
+    fn status(&self) -> &'static str {
+        match self.dispute_status {
+            DisputeStatus::ChargedBack => "charged_back",
+            _ if self.refunded => "refunded",
+            _ if self.pending => "pending",
+            DisputeStatus::Disputed => "disputed",
+            DisputeStatus::None => "applied",
+            DisputeStatus::Resolved => "resolved",
+        }
+    }
+}
 
-    Ok(())
+/// Where a deposit or withdrawal is in the dispute lifecycle. A resolved transaction may be disputed again, while a
+/// chargeback is final
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeStatus {
+    /// Never disputed
+    #[default]
+    None,
+    Disputed,
+    /// The last dispute was resolved, or expired, and the held funds were released
+    Resolved,
+    ChargedBack,
 }
 
-fn write_output(accounts: Vec<Account>) -> Result<(), Error> {
-    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+impl DisputeStatus {
+    /// The status after a dispute, resolve, or chargeback of transaction `tx`, or the error for a transition that isn't
+    /// allowed from this status
+    fn transition(self, tx_type: TransactionType, tx: u32) -> Result<Self, PaymentError> {
+        use DisputeStatus::*;
 
-    for account in accounts {
-        writer.serialize(account)?;
+        match (self, tx_type) {
+            (None | Resolved, TransactionType::Dispute) => Ok(Disputed),
+            (Disputed, TransactionType::Dispute) => Err(PaymentError::AlreadyDisputed { tx }),
+            (ChargedBack, TransactionType::Dispute) => Err(PaymentError::AlreadyReversed { tx }),
+            (Disputed, TransactionType::Resolve) => Ok(Resolved),
+            (Disputed, TransactionType::Chargeback) => Ok(ChargedBack),
+            (_, TransactionType::Resolve | TransactionType::Chargeback) => {
+                Err(PaymentError::NotDisputed { tx })
+            }
+            (_, tx_type) => Err(PaymentError::Rejected {
+                tx,
+                reason: format!("{} does not change the dispute status", tx_type),
+            }),
+        }
     }
+}
 
-    writer.flush()?;
+/// Everything a later run needs to continue from where an engine left off, as written by [`Engine::save_state`]
+#[derive(Serialize, Deserialize)]
+struct EngineState {
+    accounts: Vec<AccountState>,
+    /// The deposits and withdrawals the engine remembers, in the order they were applied
+    history: Vec<LedgerEntry>,
+    highest_id: Option<u32>,
+    /// The latest timestamp seen, so a resumed run keeps rejecting transactions from before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latest_timestamp: Option<Timestamp>,
+    /// The client and id of every transaction applied, when skipping duplicates, so a resumed run keeps skipping them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    applied: Option<Vec<(ClientId, u32)>>,
+}
 
-    Ok(())
+/// An account with every field written out, unlike the CSV output which only says whether it is locked. This is how
+/// [`AccountStore`]s persist accounts, converting to and from [`Account`] with `From`
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct AccountState {
+    client: ClientId,
+    #[serde(default)]
+    currency: Currency,
+    account: Option<String>,
+    available: Amount,
+    held: Amount,
+    pending: Amount,
+    total: Amount,
+    #[serde(default, skip_serializing_if = "Amount::is_zero")]
+    credit_limit: Amount,
+    status: AccountStatus,
+    source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_activity: Option<Timestamp>,
 }
 
-/// A deposit is a credit to the client’s asset account. It increases the available and total funds of the client account
-/// by the transaction amount
-fn deposit(accounts: &mut Vec<Account>, tx: Transaction) -> Result<(), Error> {
-    let amount = tx.amount.ok_or(Error::msg("Deposit amount required"))?;
-    match accounts.iter_mut().find(|item| item.client == tx.client) {
-        Some(account) => {
-            account.available = account.available + amount;
-            account.total = account.total + amount;
-        }
-        None => {
-            accounts.push(Account {
-                client: tx.client,
-                available: amount,
-                held: 0.to_fixed(),
-                total: amount,
-                locked: false,
-            });
+impl From<&Account> for AccountState {
+    fn from(account: &Account) -> Self {
+        AccountState {
+            client: account.client,
+            currency: account.currency,
+            account: account.account_number.clone(),
+            available: account.available,
+            held: account.held,
+            pending: account.pending,
+            total: account.total,
+            credit_limit: account.credit_limit,
+            status: account.status,
+            source: account.source.clone(),
+            last_activity: account.last_activity,
         }
-    };
-
-    Ok(())
+    }
 }
 
-/// A withdraw is a debit to the client’s asset account. It decreases the available and total funds of the client account
-/// by the transaction amount. If a client does not have sufficient available funds the withdraw will fail and the total
-/// amount of funds will not change
-fn withdraw(accounts: &mut Vec<Account>, tx: Transaction) -> Result<(), Error> {
-    let amount = tx.amount.ok_or(Error::msg("Deposit amount required"))?;
-    let account = accounts
-        .iter_mut()
-        .find(|item| item.client == tx.client)
-        .ok_or(Error::msg("Account not found"))?;
-
-    if amount <= account.available {
-        account.available = account.available - amount;
-        account.total = account.total - amount;
-        Ok(())
-    } else {
-        Err(Error::msg("Insufficient funds for withdraw"))
+impl From<AccountState> for Account {
+    fn from(state: AccountState) -> Self {
+        Account {
+            client: state.client,
+            currency: state.currency,
+            account_number: state.account,
+            available: state.available,
+            held: state.held,
+            pending: state.pending,
+            total: state.total,
+            credit_limit: state.credit_limit,
+            status: state.status,
+            source: state.source,
+            last_activity: state.last_activity,
+        }
     }
 }
 
-/// A dispute represents a claim that a transaction was erroneous and should be reversed. The transaction is not immediately
-/// reversed; instead, the disputed amount is moved from available to held. The account total does not change.
-///
-/// Both deposits and withdrawals can be disputed. The latter case would apply in a scenario such as a stolen ATM card being
-/// used to make a fraudulent withdrawal.
-///
-/// Disputes do not specify an amount. Instead they refer to a transaction by ID. If the transaction specified doesn’t exist,
-/// the dispute is ignored.
-fn dispute(
-    accounts: &mut Vec<Account>,
-    tx: Transaction,
-    history: &mut Vec<Transaction>,
-) -> Result<(), Error> {
-    let disputed_tx = history
-        .iter_mut()
-        .find(|item| item.id == tx.id)
-        .ok_or(Error::msg("Disputed transaction not found"))?;
-    let disputed_amount = disputed_tx.amount.ok_or(Error::msg(
-        "Disputed transaction does not have a valid amount",
-    ))?;
+/// A ledger entry as written by [`Engine::dump_state`], with its status spelled out
+#[derive(Serialize)]
+struct LedgerDumpEntry<'a> {
+    #[serde(flatten)]
+    entry: &'a LedgerEntry,
+    status: &'static str,
+}
 
-    if disputed_tx.under_dispute {
-        return Err(Error::msg("Transactoin already under dispute"));
+impl Transaction {
+    /// Creates a transaction for processing with [`Engine::process`]. Disputes, resolves, chargebacks, refunds, and
+    /// settles refer to an earlier transaction by `id` and don't need an amount. Transfers are created with
+    /// [`Transaction::transfer`]
+    pub fn new(
+        tx_type: TransactionType,
+        client: ClientId,
+        id: u32,
+        amount: Option<Amount>,
+    ) -> Self {
+        Self {
+            tx_type,
+            client,
+            id,
+            amount,
+            to_client: None,
+            actor: None,
+            currency: None,
+            to_currency: None,
+            rate: None,
+            timestamp: None,
+        }
     }
 
-    let account = accounts
-        .iter_mut()
-        .find(|item| item.client == tx.client && item.client == disputed_tx.client) // the dispute and disputed transaction should both should have the same client id
-        .ok_or(Error::msg("Account not found"))?;
+    /// Creates a transfer of `amount` from the account of `client` to the account of `to_client`
+    pub fn transfer(client: ClientId, to_client: ClientId, id: u32, amount: Amount) -> Self {
+        Self {
+            to_client: Some(to_client),
+            ..Self::new(TransactionType::Transfer, client, id, Some(amount))
+        }
+    }
 
-    match disputed_tx.tx_type {
-        TransactionType::Deposit => {
-            account.available = account.available - disputed_amount;
-            account.held = account.held + disputed_amount;
+    /// Creates a conversion of `amount` from the account of `client` in `from` to their account in `to`, at the rate
+    /// table's rate unless one is set with [`Transaction::with_rate`]
+    pub fn convert(
+        client: ClientId,
+        id: u32,
+        amount: Amount,
+        from: Currency,
+        to: Currency,
+    ) -> Self {
+        Self {
+            currency: Some(from),
+            to_currency: Some(to),
+            ..Self::new(TransactionType::Convert, client, id, Some(amount))
         }
-        TransactionType::Withdraw => {
-            account.held = account.held + disputed_amount;
-            account.total = account.total + disputed_amount;
+    }
+
+    /// Sets the rate a conversion is made at
+    pub fn with_rate(self, rate: Rate) -> Self {
+        Self {
+            rate: Some(rate),
+            ..self
         }
-        _ => return Err(Error::msg("Cannot dispute this type of transaction")),
-    };
+    }
 
-    disputed_tx.under_dispute = true;
+    /// Sets who applied a lock or unlock, such as an administrator's id. Without an actor, the client applied it
+    pub fn with_actor(self, actor: ClientId) -> Self {
+        Self {
+            actor: Some(actor),
+            ..self
+        }
+    }
 
-    Ok(())
-}
+    /// Sets when the transaction happened
+    pub fn with_timestamp(self, timestamp: Timestamp) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
 
-/// A resolve represents a resolution to a dispute, releasing the associated held funds. Funds that were previously disputed are
-/// no longer disputed. The clients held funds decrease by the amount no longer disputed, their available funds increase by the amount
-///  no longer disputed, and their total funds remain the same.
-///
-/// Resolves do not specify an amount. Instead they refer to a disputed transaction by ID. If the transaction specified doesn’t exist,
-/// or the transaction isn’t under dispute, the resolve is ignored.
-fn resolve(
-    accounts: &mut Vec<Account>,
-    tx: Transaction,
-    history: &mut Vec<Transaction>,
-) -> Result<(), Error> {
-    let disputed_tx = history
-        .iter_mut()
-        .find(|item| item.id == tx.id)
-        .ok_or(Error::msg("Disputed transaction not found"))?;
-    let disputed_amount = disputed_tx.amount.ok_or(Error::msg(
-        "Disputed transaction does not have a valid amount",
-    ))?;
+    /// Sets the currency of the transaction. Without one, the engine's currency is assumed
+    pub fn with_currency(self, currency: Currency) -> Self {
+        Self {
+            currency: Some(currency),
+            ..self
+        }
+    }
 
-    if !disputed_tx.under_dispute {
-        return Err(Error::msg("Cannot resolve transaction not under dispute"));
+    /// Parses a single CSV row, without a header, in the same
+    /// `type,client,tx,amount,to_client,actor,currency,to_currency,rate` format as the input files. The `to_client`
+    /// column is only needed for transfers, the `actor` column for locks and unlocks, the `currency` column for amounts
+    /// in another currency than the engine's, and the `to_currency` and `rate` columns for conversions
+    ///
+    /// ```
+    /// use payments::{Amount, Transaction, TransactionType};
+    ///
+    /// let tx = Transaction::from_csv_line("deposit,1,1,1.5").unwrap();
+    ///
+    /// assert_eq!(tx.tx_type(), TransactionType::Deposit);
+    /// assert_eq!(tx.client(), 1);
+    /// assert_eq!(tx.id(), 1);
+    /// assert_eq!(tx.amount(), Some(Amount::from_num(1.5)));
+    /// ```
+    pub fn from_csv_line(line: &str) -> Result<Self, Error> {
+        let headers = StringRecord::from(vec![
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "to_client",
+            "actor",
+            "currency",
+            "to_currency",
+            "rate",
+        ]);
+        let record = reader_builder()
+            .has_headers(false)
+            .from_reader(line.as_bytes())
+            .records()
+            .next()
+            .ok_or_else(|| Error::msg("Transaction line is empty"))??;
+
+        Transaction::from_record(&record, &headers)
     }
 
-    let account = accounts
-        .iter_mut()
-        .find(|item| item.client == tx.client && item.client == disputed_tx.client) // the dispute and disputed transaction should both should have the same client id
-        .ok_or(Error::msg("Account not found"))?;
+    /// Parses a single JSON Lines object with `type`, `client`, `tx`, and `amount` fields. The amount may be a string
+    /// or a number, which is read from its decimal text so it isn't rounded through a float
+    ///
+    /// ```
+    /// use payments::{Amount, Transaction};
+    ///
+    /// let tx = Transaction::from_json_line(r#"{"type":"deposit","client":1,"tx":1,"amount":1.5}"#).unwrap();
+    ///
+    /// assert_eq!(tx.amount(), Some(Amount::from_num(1.5)));
+    /// ```
+    pub fn from_json_line(line: &str) -> Result<Self, Error> {
+        let mut value: serde_json::Value = serde_json::from_str(line)?;
 
-    match disputed_tx.tx_type {
-        TransactionType::Deposit => {
-            account.available = account.available + disputed_amount;
-            account.held = account.held - disputed_amount;
+        if let Some(amount) = value.get_mut("amount") {
+            if let serde_json::Value::Number(number) = amount {
+                *amount = serde_json::Value::String(number.to_string());
+            }
         }
-        TransactionType::Withdraw => {
-            account.held = account.held - disputed_amount;
-            account.available = account.available + disputed_amount;
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Deserializes a CSV record, rejecting records with a blank type, client, or transaction id up front so they
+    /// fail with a clear error rather than an opaque parse error
+    fn from_record(record: &StringRecord, headers: &StringRecord) -> Result<Self, Error> {
+        for field in ["type", "client", "tx"].iter() {
+            let value = headers
+                .iter()
+                .position(|header| header == *field)
+                .and_then(|index| record.get(index));
+
+            if value.is_none_or(str::is_empty) {
+                return Err(PaymentError::MissingField {
+                    field,
+                    line: record.position().map_or(1, |position| position.line()),
+                }
+                .into());
+            }
         }
-        _ => return Err(Error::msg("Cannot resolve this type of transaction")),
-    };
 
-    disputed_tx.under_dispute = false;
+        Ok(record.deserialize(Some(headers))?)
+    }
 
-    Ok(())
+    pub fn tx_type(&self) -> TransactionType {
+        self.tx_type
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn amount(&self) -> Option<Amount> {
+        self.amount
+    }
+
+    pub fn to_client(&self) -> Option<ClientId> {
+        self.to_client
+    }
+
+    pub fn actor(&self) -> Option<ClientId> {
+        self.actor
+    }
+
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    pub fn to_currency(&self) -> Option<Currency> {
+        self.to_currency
+    }
+
+    pub fn rate(&self) -> Option<Rate> {
+        self.rate
+    }
+
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
 }
 
-/// A chargeback is the final state of a dispute and represents the client reversing a transaction. Funds that were held are now withdrawn.
-/// The clients held funds and total funds decrease by the amount previously disputed. The client account is also frozen.
-fn chargeback(
-    accounts: &mut Vec<Account>,
-    tx: Transaction,
-    history: &mut Vec<Transaction>,
-) -> Result<(), Error> {
-    let disputed_tx = history
-        .iter_mut()
-        .find(|item| item.id == tx.id)
-        .ok_or(Error::msg("Disputed transaction not found"))?;
-    let disputed_amount = disputed_tx.amount.ok_or(Error::msg(
-        "Disputed transaction does not have a valid amount",
-    ))?;
-
-    if !disputed_tx.under_dispute {
-        return Err(Error::msg(
-            "Cannot chargeback transaction not under dispute",
-        ));
+#[derive(Debug, Serialize, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    Withdraw,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Refund,
+    Settle,
+    Transfer,
+    Fee,
+    Lock,
+    Unlock,
+    Convert,
+    #[serde(rename = "set_limit")]
+    SetLimit,
+    Interest,
+}
+
+impl TransactionType {
+    /// Every spelling of each type accepted in inputs, compared ignoring case and surrounding whitespace
+    const NAMES: [(&'static str, TransactionType); 15] = [
+        ("deposit", TransactionType::Deposit),
+        ("withdraw", TransactionType::Withdraw),
+        ("withdrawal", TransactionType::Withdraw),
+        ("dispute", TransactionType::Dispute),
+        ("resolve", TransactionType::Resolve),
+        ("chargeback", TransactionType::Chargeback),
+        ("refund", TransactionType::Refund),
+        ("settle", TransactionType::Settle),
+        ("transfer", TransactionType::Transfer),
+        ("fee", TransactionType::Fee),
+        ("lock", TransactionType::Lock),
+        ("unlock", TransactionType::Unlock),
+        ("convert", TransactionType::Convert),
+        ("set_limit", TransactionType::SetLimit),
+        ("interest", TransactionType::Interest),
+    ];
+}
+
+impl FromStr for TransactionType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim();
+
+        TransactionType::NAMES
+            .iter()
+            .find(|(spelling, _)| spelling.eq_ignore_ascii_case(name))
+            .map(|(_, tx_type)| *tx_type)
+            .ok_or_else(|| Error::msg(format!("Unknown transaction type: {}", s)))
     }
+}
 
-    let account = accounts
-        .iter_mut()
-        .find(|item| item.client == tx.client && item.client == disputed_tx.client) // the dispute and disputed transaction should both should have the same client id
-        .ok_or(Error::msg("Account not found"))?;
-
-    match disputed_tx.tx_type {
-        TransactionType::Deposit => {
-            account.held = account.held - disputed_amount;
-            account.total = account.total - disputed_amount;
-            account.locked = true;
-        }
-        TransactionType::Withdraw => {
-            account.held = account.held - disputed_amount;
-            account.total = account.total - disputed_amount;
-            account.locked = true;
-        }
-        _ => return Err(Error::msg("Cannot chargeback this type of transaction")),
-    };
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TypeVisitor;
 
-    disputed_tx.under_dispute = false;
+        impl serde::de::Visitor<'_> for TypeVisitor {
+            type Value = TransactionType;
 
-    Ok(())
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a transaction type")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(TypeVisitor)
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use TransactionType::*;
 
-    #[test]
-    fn deposit_adds_to_account() {
-        let mut accounts = vec![Account {
-            client: 1,
-            available: 0.to_fixed(),
-            held: 0.to_fixed(),
-            total: 0.to_fixed(),
-            locked: false,
-        }];
+        let name = match self {
+            Deposit => "deposit",
+            Withdraw => "withdraw",
+            Dispute => "dispute",
+            Resolve => "resolve",
+            Chargeback => "chargeback",
+            Refund => "refund",
+            Settle => "settle",
+            Transfer => "transfer",
+            Fee => "fee",
+            Lock => "lock",
+            Unlock => "unlock",
+            Convert => "convert",
+            SetLimit => "set_limit",
+            Interest => "interest",
+        };
 
-        deposit(
-            &mut accounts,
-            Transaction {
-                tx_type: TransactionType::Deposit,
-                client: 1,
-                id: 1,
-                amount: Some(1.9999.to_fixed()),
-                under_dispute: false,
-            },
-        )
-        .unwrap();
+        write!(f, "{}", name)
+    }
+}
 
-        assert_eq!(
-            accounts.get(0).unwrap().available,
-            1.9999.to_fixed::<I50F14>()
-        );
-        assert_eq!(accounts.get(0).unwrap().total, 1.9999.to_fixed::<I50F14>());
+/// Options controlling how an input file is processed
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Memory-map the input file instead of reading it through buffered IO
+    pub mmap: bool,
+    /// The format the input files are written in
+    pub format: Format,
+    /// If set, only transactions of these types are processed
+    pub only: Option<Vec<TransactionType>>,
+    /// Transactions of these types are ignored
+    pub exclude: Vec<TransactionType>,
+    /// Add a `source` column naming the input file that last modified each account
+    pub tag_source: bool,
+    /// Basis points deducted from every deposit as a fee before the account is credited
+    pub deposit_fee_bps: u32,
+    /// The fees charged on every withdrawal, on top of the amount withdrawn
+    pub withdrawal_fees: FeeSchedule,
+    /// The lowest available funds a fee may leave an account with, ex: `-50`. Fees can take available funds below zero,
+    /// without any limit if unset
+    pub fee_floor: Option<Amount>,
+    /// Path to a CSV of `client,account` pairs used to add an external `account` column to the output
+    pub account_map: Option<String>,
+    /// Use the client id as the account for clients missing from the account map, instead of failing
+    pub allow_unmapped: bool,
+    /// Warn about gaps in the sequence of deposit and withdrawal ids
+    pub detect_gaps: bool,
+    /// Round balances to the output scale after every transaction instead of only when they are printed
+    pub round_each_op: bool,
+    /// Path to write a report of held funds and open disputes for each client with held funds
+    pub held_report: Option<String>,
+    /// Only count the transactions in the input by type, without computing any balances
+    pub count_only: bool,
+    /// The format the accounts are written in
+    pub output_format: OutputFormat,
+    /// Path to write the accounts to instead of `stdout`. Required for formats that can't be written to `stdout`
+    pub output: Option<String>,
+    /// The number of times a single transaction may be disputed over its lifetime
+    pub max_disputes_per_tx: Option<u32>,
+    /// Fail the run if more than this fraction of transactions are rejected, as it likely means the input is malformed
+    pub max_error_ratio: Option<f64>,
+    /// Skip and count rows that can't be parsed, such as rows with missing required fields or an amount that isn't a
+    /// number, instead of failing the run
+    pub lenient: bool,
+    /// Treat the amount on a dispute as the part of the transaction being disputed, instead of ignoring it
+    pub partial_disputes: bool,
+    /// Automatically resolve disputes that are still open after this many subsequent transactions
+    pub dispute_expiry: Option<u64>,
+    /// Automatically resolve disputes that are still open this many days after they were opened, going by the
+    /// timestamps of the transactions
+    pub dispute_expiry_days: Option<u32>,
+    /// What happens to a dispute once it expires
+    pub dispute_expiry_action: DisputeExpiryAction,
+    /// Path to write every dispute still open once the inputs are processed to, with the disputed transaction's type
+    /// and amount, its age, and the amount held
+    pub dispute_report: Option<String>,
+    /// Accrue interest at this many basis points a year on available funds, for inputs with a timestamp column. See
+    /// [`Engine::set_interest`]
+    pub interest_apr_bps: Option<u32>,
+    /// How often accrued interest is credited
+    pub interest_period: InterestPeriod,
+    /// Path to write every interest credit to once the inputs are processed
+    pub interest_report: Option<String>,
+    /// Path to a CSV of recurring instructions whose deposits and withdrawals are interleaved with the inputs by
+    /// timestamp. See [`RecurringInstruction`]
+    pub recurring: Option<String>,
+    /// Path to write every parsed transaction to as canonical CSV, for capturing a clean copy of messy input
+    pub echo_normalized: Option<String>,
+    /// Only write the accounts with the largest total balances, at most this many
+    pub top: Option<usize>,
+    /// Write the accounts in the order their clients were first seen, rather than sorted by client id
+    pub unsorted: bool,
+    /// Path to write the engine's ledger of disputable transactions to as JSON, for debugging
+    pub dump_state: Option<String>,
+    /// Path to write the accounts and disputable transactions to once all inputs are processed, so a later run can
+    /// resume from them
+    pub snapshot: Option<String>,
+    /// Path to a state written with `snapshot` to continue from, instead of starting with no accounts
+    pub resume: Option<String>,
+    /// Path to a SQLite database to keep accounts and transactions in, continuing from any already there. Requires the
+    /// `sqlite` feature
+    pub store: Option<String>,
+    /// How the `locked` column of the CSV output is written
+    pub locked_format: LockedFormat,
+    /// The number of transactions the inputs are expected to hold, used to reserve memory up front
+    pub expected_rows: Option<usize>,
+    /// Reject disputes of ids that no earlier deposit or withdrawal used, instead of treating them as not found
+    pub strict_order: bool,
+    /// Reject transactions timestamped before the latest timestamp seen
+    pub reject_out_of_order: bool,
+    /// Skip deposits, withdrawals, transfers, fees, and conversions already applied, including in the run resumed from
+    pub skip_duplicates: bool,
+    /// Record every transaction that referenced another client's transaction, and report them as potential fraud
+    pub strict_clients: bool,
+    /// Credit deposits to pending funds until a `settle` transaction clears them
+    pub pending_deposits: bool,
+    /// Path to write the engine's metrics to in the Prometheus text format once all inputs are processed
+    pub metrics_file: Option<String>,
+    /// What to do when deposit and withdrawal ids wrap around past `u32::MAX`. Wraparound isn't detected if unset
+    pub id_wraparound: Option<IdWraparound>,
+    /// Reject deposits and withdrawals of more than this amount, regardless of the account's balance
+    pub max_tx_amount: Option<Amount>,
+    /// Path to a TOML file of risk rules, such as a daily withdrawal limit, to reject transactions that break. A
+    /// `max_tx_amount` in the file applies unless `max_tx_amount` is set here. See [`RiskLimits`]
+    pub risk_rules: Option<String>,
+    /// The capacity, in bytes, of the buffer the CSV output is written through. Defaults to
+    /// [`DEFAULT_OUTPUT_BUFFER_SIZE`]
+    pub output_buffer_size: Option<usize>,
+    /// Keep at most this many deposits and withdrawals in memory for later disputes, so memory stays bounded however
+    /// large the input is
+    pub history_limit: Option<usize>,
+    /// Path to a file that deposits and withdrawals beyond the history limit are written to, so they can still be
+    /// disputed. Without it they are dropped
+    pub history_spill: Option<String>,
+    /// Which transactions locked accounts still accept
+    pub locked_accounts: LockedAccountPolicy,
+    /// How disputing a withdrawal affects the account before the dispute is decided
+    pub withdrawal_disputes: WithdrawalDisputeMode,
+    /// Path to write every rejected transaction and skipped row to, with its line, the reason code and reason it was
+    /// rejected, and its fields. Written as JSON if the path ends in `.json`, and as CSV otherwise
+    pub errors: Option<String>,
+    /// Split the clients into this many shards by client id and process them in parallel, one thread each
+    pub threads: Option<usize>,
+    /// The number of decimal places amounts are written with, at most [`MAX_DECIMAL_PLACES`]. Defaults to
+    /// [`DEFAULT_DECIMAL_PLACES`]
+    pub decimal_places: Option<u32>,
+    /// How amounts are rounded to the output's decimal places
+    pub rounding: RoundingMode,
+    /// The ids allowed to unlock accounts, and to lock accounts other than their own
+    pub admins: Vec<ClientId>,
+    /// Path to write every lock and unlock that was applied to, with who applied it
+    pub lock_audit: Option<String>,
+    /// The currency of transactions without one. The output only has a currency column if an account is in another
+    pub currency: Currency,
+    /// Path to a CSV file of the rates conversions without a rate of their own are made at. See [`RateTable`]
+    pub rates: Option<String>,
+    /// Path to a CSV file of `client,limit` rows, with an optional `currency` column, giving clients a credit limit to
+    /// withdraw down to minus. See [`Engine::set_credit_limit`]
+    pub credit_limits: Option<String>,
+    /// How converted amounts are rounded to the output scale when they fall exactly halfway
+    pub conversion_rounding: RoundingMode,
+    /// Path to write every conversion that was applied to, with the rate it was made at and the spread it realized
+    pub conversion_report: Option<String>,
+    /// Path to append a line of JSON to for every transaction handled, with the balances before and after it and
+    /// whether it was applied. See [`Engine::audit_to`]
+    pub audit: Option<String>,
+}
+
+/// A fee charged on a transaction, made up of a percentage of its amount, in basis points, and a flat amount
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct FeeSchedule {
+    pub bps: u32,
+    pub flat: Amount,
+}
+
+impl FeeSchedule {
+    /// The fee charged on a transaction of `amount`, with the percentage rounded half away from zero to the output
+    /// scale
+    pub fn fee(&self, amount: Amount) -> Amount {
+        basis_points(amount, self.bps).saturating_add(self.flat)
     }
+}
 
-    #[test]
-    fn withdraw_takes_from_account() {
-        let mut accounts = vec![Account {
-            client: 0,
-            available: 2.to_fixed(),
-            held: 0.to_fixed(),
-            total: 2.to_fixed(),
-            locked: false,
-        }];
+/// The capacity of the buffer the CSV output is written through when no size is configured
+pub const DEFAULT_OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
 
-        withdraw(
-            &mut accounts,
-            Transaction {
-                tx_type: TransactionType::Withdraw,
-                client: 0,
-                id: 1,
-                amount: Some(1.9999.to_fixed()),
-                under_dispute: false,
-            },
-        )
-        .unwrap();
+/// The number of decimal places amounts are written with when none is configured
+pub const DEFAULT_DECIMAL_PLACES: u32 = 4;
 
-        assert_eq!(
-            accounts.get(0).unwrap().available,
-            0.0001.to_fixed::<I50F14>()
-        );
-        assert_eq!(accounts.get(0).unwrap().total, 0.0001.to_fixed::<I50F14>());
+/// The most decimal places amounts can be written with. Scaling the fractional part of an amount any further could
+/// overflow
+pub const MAX_DECIMAL_PLACES: u32 = 18;
+
+/// How amounts are rounded to the output's decimal places when they fall exactly halfway
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum RoundingMode {
+    /// Round halves away from zero, ex: `0.00005` to `0.0001`
+    #[default]
+    HalfUp,
+    /// Round halves to the nearest even digit, ex: `0.00005` to `0.0000` and `0.00015` to `0.0002`
+    Bankers,
+}
+
+impl FromStr for RoundingMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "bankers" | "half-even" => Ok(RoundingMode::Bankers),
+            _ => Err(Error::msg(format!("Unknown rounding mode: {}", s))),
+        }
     }
+}
 
-    #[test]
-    fn withdraw_fails_on_insufficient_funds() {
-        let mut accounts = vec![Account {
-            client: 0,
-            available: 1.to_fixed(),
-            held: 0.to_fixed(),
-            total: 1.to_fixed(),
-            locked: false,
-        }];
+/// The formats transactions can be read in
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    /// `type,client,tx,amount` rows with a header
+    #[default]
+    Csv,
+    /// One JSON object per line with `type`, `client`, `tx`, and `amount` fields, as exported by event buses
+    Jsonl,
+}
 
-        let res = withdraw(
-            &mut accounts,
-            Transaction {
-                tx_type: TransactionType::Withdraw,
-                client: 0,
-                id: 1,
-                amount: Some(1.9999.to_fixed()),
-                under_dispute: false,
-            },
-        );
+impl FromStr for Format {
+    type Err = Error;
 
-        assert!(res.is_err());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(Error::msg(format!("Unknown input format: {}", s))),
+        }
     }
+}
 
-    #[test]
-    fn disputed_amount_should_move_to_held() {
-        let mut accounts = vec![Account {
-            client: 0,
-            available: 1.to_fixed(),
-            held: 0.to_fixed(),
-            total: 1.to_fixed(),
-            locked: false,
-        }];
+/// The formats the accounts can be written in
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A Parquet file with typed columns, available with the `arrow` feature
+    Parquet,
+    /// A JSON array of accounts, with a derived `withdrawable` field
+    Json,
+    /// The same account objects as `Json`, one per line instead of in an array
+    Ndjson,
+}
 
-        let mut history = vec![Transaction {
-            tx_type: TransactionType::Deposit,
-            client: 0,
-            id: 1,
-            amount: Some(1.to_fixed()),
-            under_dispute: false,
-        }];
+impl FromStr for OutputFormat {
+    type Err = Error;
 
-        dispute(
-            &mut accounts,
-            Transaction {
-                tx_type: TransactionType::Dispute,
-                client: 0,
-                id: 1,
-                amount: None,
-                under_dispute: false,
-            },
-            &mut history,
-        )
-        .unwrap();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            _ => Err(Error::msg(format!("Unknown output format: {}", s))),
+        }
+    }
+}
 
-        assert_eq!(accounts.get(0).unwrap().available, 0.to_fixed::<I50F14>());
-        assert_eq!(accounts.get(0).unwrap().total, 1.to_fixed::<I50F14>());
-        assert_eq!(accounts.get(0).unwrap().held, 1.to_fixed::<I50F14>());
+/// How the `locked` column of the CSV output is written
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum LockedFormat {
+    /// `true` or `false`
+    #[default]
+    Bool,
+    /// `1` or `0`
+    Int,
+    /// `yes` or `no`
+    YesNo,
+}
+
+impl LockedFormat {
+    fn format(self, locked: bool) -> &'static str {
+        match (self, locked) {
+            (LockedFormat::Bool, true) => "true",
+            (LockedFormat::Bool, false) => "false",
+            (LockedFormat::Int, true) => "1",
+            (LockedFormat::Int, false) => "0",
+            (LockedFormat::YesNo, true) => "yes",
+            (LockedFormat::YesNo, false) => "no",
+        }
+    }
+}
+
+impl FromStr for LockedFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bool" => Ok(LockedFormat::Bool),
+            "int" => Ok(LockedFormat::Int),
+            "yesno" => Ok(LockedFormat::YesNo),
+            _ => Err(Error::msg(format!("Unknown locked format: {}", s))),
+        }
+    }
+}
+
+/// Which deposits and withdrawals an account still accepts once it's locked
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum LockedAccountPolicy {
+    /// Reject both deposits and withdrawals
+    #[default]
+    RejectAll,
+    /// Accept deposits, so funds can still be returned to the client, but reject withdrawals
+    AllowDeposits,
+}
+
+impl FromStr for LockedAccountPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-all" => Ok(LockedAccountPolicy::RejectAll),
+            "allow-deposits" => Ok(LockedAccountPolicy::AllowDeposits),
+            _ => Err(Error::msg(format!("Unknown locked account policy: {}", s))),
+        }
+    }
+}
+
+/// How disputing a withdrawal affects the account before the dispute is decided
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum WithdrawalDisputeMode {
+    /// Provisionally credit the withdrawn amount to held funds, raising the total, until the dispute is resolved into
+    /// available funds or charged back out of the account again
+    #[default]
+    Provisional,
+    /// Leave the balances unchanged while the dispute is open. A resolve keeps the withdrawal, and only a chargeback
+    /// credits the withdrawn amount back to available funds
+    Deferred,
+}
+
+impl FromStr for WithdrawalDisputeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "provisional" => Ok(WithdrawalDisputeMode::Provisional),
+            "deferred" => Ok(WithdrawalDisputeMode::Deferred),
+            _ => Err(Error::msg(format!(
+                "Unknown withdrawal dispute mode: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// What happens to a dispute that is still open once it expires under [`Engine::set_dispute_expiry`] or
+/// [`Engine::set_dispute_expiry_days`]
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum DisputeExpiryAction {
+    /// Resolve the dispute, returning the held funds to available funds
+    #[default]
+    Resolve,
+    /// Leave the funds held, and flag the dispute as escalated for someone to decide. It stays open until a resolve
+    /// or chargeback arrives, and doesn't expire again
+    Escalate,
+}
+
+impl FromStr for DisputeExpiryAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resolve" => Ok(DisputeExpiryAction::Resolve),
+            "escalate" => Ok(DisputeExpiryAction::Escalate),
+            _ => Err(Error::msg(format!("Unknown dispute expiry action: {}", s))),
+        }
+    }
+}
+
+/// What the engine does when a deposit or withdrawal id is so far below the highest id seen that the feed's ids must
+/// have wrapped around past `u32::MAX`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IdWraparound {
+    /// Reject the transaction with [`PaymentError::IdWraparound`]
+    Error,
+    /// Start a new id space. Transactions from before the wraparound can no longer be disputed, resolved, charged
+    /// back, refunded, or settled, as their ids are ambiguous
+    Allow,
+}
+
+impl FromStr for IdWraparound {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(IdWraparound::Error),
+            "allow" => Ok(IdWraparound::Allow),
+            _ => Err(Error::msg(format!("Unknown id wraparound policy: {}", s))),
+        }
+    }
+}
+
+impl Config {
+    /// The scale and rounding amounts are written with
+    fn amount_format(&self) -> AmountFormat {
+        AmountFormat {
+            places: self.decimal_places.unwrap_or(DEFAULT_DECIMAL_PLACES),
+            rounding: self.rounding,
+        }
+    }
+
+    /// Whether transactions of the given type should be processed under the `only` and `exclude` filters
+    fn allows(&self, tx_type: TransactionType) -> bool {
+        let included = match &self.only {
+            Some(only) => only.contains(&tx_type),
+            None => true,
+        };
+
+        included && !self.exclude.contains(&tx_type)
+    }
+}
+
+/// A row of the credit limits file
+#[derive(Deserialize)]
+struct CreditLimit {
+    client: ClientId,
+    limit: Amount,
+    #[serde(default)]
+    currency: Option<Currency>,
+}
+
+/// Creates an engine with the settings from the config
+fn engine_from_config(config: &Config) -> Result<Engine, Error> {
+    let mut engine = Engine::new();
+
+    if let Some(rows) = config.expected_rows {
+        if let Err(err) = engine.try_reserve(rows) {
+            warn!("Ignoring the expected number of rows, {}: {}", rows, err);
+        }
+    }
+
+    engine.set_deposit_fee_bps(config.deposit_fee_bps);
+    engine.set_withdrawal_fees(config.withdrawal_fees);
+    engine.set_fee_floor(config.fee_floor);
+    engine.set_round_each_op(config.round_each_op);
+    engine.set_max_disputes_per_tx(config.max_disputes_per_tx);
+    engine.set_partial_disputes(config.partial_disputes);
+    engine.set_dispute_expiry(config.dispute_expiry);
+    engine.set_dispute_expiry_days(config.dispute_expiry_days);
+    engine.set_dispute_expiry_action(config.dispute_expiry_action);
+    engine.set_interest(config.interest_apr_bps, config.interest_period);
+    engine.set_strict_order(config.strict_order);
+    engine.set_reject_out_of_order(config.reject_out_of_order);
+    engine.set_skip_duplicates(config.skip_duplicates);
+    engine.set_strict_clients(config.strict_clients);
+    engine.set_pending_deposits(config.pending_deposits);
+    engine.set_id_wraparound(config.id_wraparound);
+    engine.set_max_tx_amount(config.max_tx_amount);
+
+    if let Some(path) = &config.risk_rules {
+        let mut limits = RiskLimits::load(path)?;
+        limits.max_tx_amount = config.max_tx_amount.or(limits.max_tx_amount);
+        engine.set_risk_limits(Some(limits));
+    }
+
+    engine.set_history_limit(config.history_limit);
+    engine.set_locked_account_policy(config.locked_accounts);
+    engine.set_withdrawal_dispute_mode(config.withdrawal_disputes);
+    engine.set_admins(config.admins.iter().copied());
+    engine.set_currency(config.currency);
+    engine.set_conversion_rounding(config.conversion_rounding);
+
+    if let Some(path) = &config.rates {
+        engine.set_rates(RateTable::from_reader(File::open(path)?)?);
+    }
+
+    if let Some(path) = &config.credit_limits {
+        for row in reader_builder()
+            .from_reader(File::open(path)?)
+            .deserialize()
+        {
+            let row: CreditLimit = row?;
+
+            if row.limit < Amount::ZERO {
+                return Err(Error::msg(format!(
+                    "Credit limit of client {} can't be negative",
+                    row.client
+                )));
+            }
+
+            engine.set_credit_limit(
+                row.client,
+                row.currency.unwrap_or(config.currency),
+                row.limit,
+            );
+        }
+    }
+
+    if let Some(path) = &config.history_spill {
+        engine.spill_history_to(path)?;
+    }
+
+    if let Some(path) = &config.audit {
+        engine.audit_to(path)?;
+    }
+
+    if let Some(path) = &config.store {
+        use_sqlite_store(&mut engine, path)?;
+    }
+
+    if config.detect_gaps {
+        engine.detect_gaps();
+    }
+
+    Ok(engine)
+}
+
+/// Reads transactions from `input` one line at a time, in the format of the input files, either `type,client,tx,amount`
+/// rows or JSON objects, and writes the balances of the affected account after each one. Transactions filtered out by
+/// type are ignored. `print` writes every account as CSV and `quit` stops reading
+pub fn run_interactive<R: BufRead, W: Write>(
+    config: &Config,
+    input: R,
+    mut output: W,
+) -> Result<(), Error> {
+    let mut engine = engine_from_config(config)?;
+    let format = config.amount_format();
+
+    for line in input.lines() {
+        let line = line?;
+
+        match line.trim() {
+            "" => continue,
+            "quit" => break,
+            "print" => {
+                let mut writer = WriterBuilder::new().from_writer(&mut output);
+                let mut accounts: Vec<&Account> = engine.accounts.iter().collect();
+
+                if !config.unsorted {
+                    accounts.sort_unstable_by_key(|account| (account.client, account.currency));
+                }
+
+                let currency_column = needs_currency_column(accounts.iter().copied(), config);
+                let credit_column = needs_credit_column(accounts.iter().copied());
+                let activity_column = needs_activity_column(accounts.iter().copied());
+
+                for account in accounts {
+                    writer.serialize(AccountRow::new(
+                        account,
+                        config,
+                        currency_column,
+                        credit_column,
+                        activity_column,
+                    ))?;
+                }
+
+                writer.flush()?;
+            }
+            line => {
+                let parsed = match config.format {
+                    Format::Csv => Transaction::from_csv_line(line),
+                    Format::Jsonl => Transaction::from_json_line(line),
+                };
+                let applied = parsed.and_then(|tx| {
+                    if config.allows(tx.tx_type) {
+                        engine.apply(tx)?;
+                    }
+
+                    Ok(tx)
+                });
+
+                match applied {
+                    Ok(tx) if !config.allows(tx.tx_type) => writeln!(
+                        output,
+                        "Ignored transaction {}, {} transactions are filtered out",
+                        tx.id, tx.tx_type
+                    )?,
+                    Ok(Transaction { client, .. }) => {
+                        for account in engine.accounts.of_client(client) {
+                            let currency = match account.currency == config.currency {
+                                true => String::new(),
+                                false => format!(" {}", account.currency),
+                            };
+
+                            writeln!(
+                                output,
+                                "client {}{}: available {}, held {}, total {}, locked {}",
+                                account.client,
+                                currency,
+                                format.trimmed(account.available),
+                                format.trimmed(account.held),
+                                format.trimmed(account.total),
+                                config.locked_format.format(account.status.is_locked())
+                            )?;
+                        }
+                    }
+                    Err(err) => writeln!(output, "Error: {}", err)?,
+                }
+            }
+        }
+    }
+
+    engine.flush_store()?;
+
+    Ok(())
+}
+
+/// Processes each input file in order against the same accounts, then writes the resulting accounts to `stdout`
+pub fn run(inputs: &[String], config: &Config) -> Result<(), Error> {
+    if config.count_only {
+        let counts = count_transactions(inputs, config)?;
+        return write_counts(&counts);
+    }
+
+    let mut engine = process_inputs(inputs, config)?;
+
+    if engine.metrics.deposit_fees > Amount::ZERO {
+        info!("Collected {} in deposit fees", engine.metrics.deposit_fees);
+    }
+
+    if engine.metrics.fees > Amount::ZERO {
+        info!("Charged {} in fees", engine.metrics.fees);
+    }
+
+    if let Some(gaps) = &engine.gaps {
+        if !gaps.missing.is_empty() {
+            warn!("Missing transaction ids: {}", gaps);
+        }
+
+        if gaps.out_of_order > 0 {
+            warn!(
+                "{} deposit or withdrawal ids were not greater than the id before them",
+                gaps.out_of_order
+            );
+        }
+    }
+
+    if engine.metrics.id_wraparounds > 0 {
+        warn!(
+            "Transaction ids wrapped around {} times, transactions from before each wraparound can no longer be disputed",
+            engine.metrics.id_wraparounds
+        );
+    }
+
+    for mismatch in engine.client_mismatches() {
+        warn!("Potential fraud: {}", mismatch);
+    }
+
+    if !engine.malformed_rows().is_empty() {
+        warn!("Skipped {} malformed rows:", engine.malformed_rows().len());
+
+        for row in engine.malformed_rows() {
+            warn!("{}", row);
+        }
+    }
+
+    if engine.metrics.duplicates > 0 {
+        warn!(
+            "Skipped {} transactions that were already applied",
+            engine.metrics.duplicates
+        );
+    }
+
+    if engine.metrics.rejected > 0 {
+        warn!(
+            "{} transactions were rejected, run with -v for details",
+            engine.metrics.rejected
+        );
+    }
+
+    if let Some(max) = config.max_error_ratio {
+        let ratio = engine.metrics.error_ratio();
+
+        if ratio > max {
+            return Err(Error::msg(format!(
+                "{} of {} transactions were rejected, exceeding the maximum error ratio of {}",
+                engine.metrics.rejected, engine.metrics.processed, max
+            )));
+        }
+    }
+
+    if let Some(path) = &config.account_map {
+        let map = load_account_map(path)?;
+        apply_account_map(&mut engine.accounts, &map, config.allow_unmapped)?;
+    }
+
+    if let Some(path) = &config.errors {
+        write_error_report(&engine, inputs, path)?;
+    }
+
+    if let Some(path) = &config.dump_state {
+        engine.dump_state(File::create(path)?)?;
+    }
+
+    if let Some(path) = &config.snapshot {
+        let mut writer = BufWriter::new(File::create(path)?);
+        engine.save_state(&mut writer)?;
+        writer.flush()?;
+    }
+
+    if let Some(path) = &config.metrics_file {
+        engine.write_prometheus(File::create(path)?)?;
+    }
+
+    if let Some(path) = &config.held_report {
+        let mut writer = WriterBuilder::new().from_path(path)?;
+
+        for row in engine.held_report() {
+            writer.serialize(row)?;
+        }
+
+        writer.flush()?;
+    }
+
+    if let Some(path) = &config.dispute_report {
+        let mut writer = WriterBuilder::new().from_path(path)?;
+
+        for row in engine.dispute_report() {
+            writer.serialize(row)?;
+        }
+
+        writer.flush()?;
+    }
+
+    if let Some(path) = &config.interest_report {
+        let mut writer = WriterBuilder::new().from_path(path)?;
+
+        for credit in engine.interest_credits() {
+            writer.serialize(credit)?;
+        }
+
+        writer.flush()?;
+    }
+
+    if engine.metrics.escalated_disputes > 0 {
+        warn!(
+            "{} disputes expired and were escalated",
+            engine.metrics.escalated_disputes
+        );
+    }
+
+    if let Some(path) = &config.lock_audit {
+        let mut writer = WriterBuilder::new().from_path(path)?;
+
+        for event in engine.lock_audit() {
+            writer.serialize(event)?;
+        }
+
+        writer.flush()?;
+    }
+
+    if let Some(path) = &config.conversion_report {
+        let mut writer = WriterBuilder::new().from_path(path)?;
+
+        for event in engine.conversions() {
+            writer.serialize(event)?;
+        }
+
+        writer.flush()?;
+    }
+
+    write_engine_accounts(engine, config)
+}
+
+/// Writes the accounts of the engine in the output format, keeping only the top accounts or leaving them unsorted if
+/// the config asks for it
+fn write_engine_accounts(engine: Engine, config: &Config) -> Result<(), Error> {
+    let accounts = match config.top {
+        Some(n) => engine
+            .top_accounts_by_total(n)
+            .into_iter()
+            .cloned()
+            .collect(),
+        None if config.unsorted => engine.into_accounts(),
+        None => engine.into_sorted_accounts(),
+    };
+
+    match config.output_format {
+        OutputFormat::Csv => write_output(accounts, config)?,
+        OutputFormat::Json | OutputFormat::Ndjson => write_json(&accounts, config)?,
+        OutputFormat::Parquet => {
+            let path = config
+                .output
+                .as_ref()
+                .ok_or_else(|| Error::msg("Parquet output requires an output path"))?;
+            write_parquet(
+                &accounts,
+                path,
+                config.amount_format(),
+                needs_currency_column(accounts.iter(), config),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A point in the inputs to write the balances at with [`balance_at`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PointInTime {
+    /// Just after the deposit, withdrawal, or other transaction with this id was applied. Disputes, resolves, and
+    /// chargebacks reusing the id later on don't count
+    Transaction(u32),
+    /// Just before the first transaction timestamped after this time, for inputs with a timestamp column
+    Timestamp(Timestamp),
+}
+
+/// Processes each input file in order against the same accounts, then writes the accounts as they were at `at`, in
+/// the output format, for answering what a balance was on a given day. The accounts are replayed from the
+/// [`LedgerEvent`]s the inputs produced, so they always start from no accounts, and the inputs are processed on one
+/// thread whatever `config.threads` is. Disputes resolved by the dispute expiry right after the transaction count as
+/// part of it
+pub fn balance_at(inputs: &[String], config: &Config, at: PointInTime) -> Result<(), Error> {
+    if config.resume.is_some() || config.store.is_some() {
+        return Err(Error::msg(
+            "Balances are replayed from no accounts, so they can't be found at a point when resuming or using a store",
+        ));
+    }
+
+    let mut engine = engine_from_config(config)?;
+    engine.set_record_events(true);
+    read_inputs(&mut engine, inputs, config, None)?;
+
+    let events = engine.events();
+    let end = match at {
+        PointInTime::Transaction(id) => {
+            let applied = events
+                .iter()
+                .position(|event| event.tx == id && !event.expired)
+                .ok_or_else(|| Error::msg(format!("Transaction {} was never applied", id)))?;
+
+            applied
+                + 1
+                + events[applied + 1..]
+                    .iter()
+                    .take_while(|event| event.expired)
+                    .count()
+        }
+        PointInTime::Timestamp(time) => events
+            .iter()
+            .position(|event| event.timestamp.is_some_and(|timestamp| timestamp > time))
+            .unwrap_or(events.len()),
+    };
+
+    write_engine_accounts(Engine::replay(events[..end].to_vec()), config)
+}
+
+/// Processes each input file in order against the same accounts and writes a summary of the totals, for daily
+/// reconciliation, to `stdout`, or to the output file if there is one. The accounts themselves aren't written
+pub fn report(inputs: &[String], config: &Config) -> Result<(), Error> {
+    let summary = process_inputs(inputs, config)?.summary();
+    write_summary(&summary, config)
+}
+
+/// Processes each input file in order against the same accounts, in parallel if the config asks for it, and returns
+/// the engine once every input is applied and its changes are saved to any store
+fn process_inputs(inputs: &[String], config: &Config) -> Result<Engine, Error> {
+    if let Some(places) = config
+        .decimal_places
+        .filter(|&places| places > MAX_DECIMAL_PLACES)
+    {
+        return Err(Error::msg(format!(
+            "Amounts can be written with at most {} decimal places, not {}",
+            MAX_DECIMAL_PLACES, places
+        )));
+    }
+
+    let mut echo = match &config.echo_normalized {
+        Some(path) => Some(WriterBuilder::new().from_path(path)?),
+        None => None,
+    };
+
+    let mut engine = match config.threads {
+        Some(threads) if threads > 1 => {
+            shard::process_sharded(inputs, config, threads, echo.as_mut())?
+        }
+        _ => {
+            let mut engine = engine_from_config(config)?;
+
+            if let Some(path) = &config.resume {
+                engine.restore_state(BufReader::new(File::open(path)?))?;
+            }
+
+            read_inputs(&mut engine, inputs, config, echo.as_mut())?;
+            engine
+        }
+    };
+
+    if let Some(writer) = &mut echo {
+        writer.flush()?;
+    }
+
+    engine.flush_store()?;
+
+    Ok(engine)
+}
+
+/// A line of the error report, for either a rejected transaction or a row that couldn't be parsed
+#[derive(Serialize)]
+struct ErrorReportRow<'a> {
+    input: &'a str,
+    line: u64,
+    code: &'static str,
+    reason: String,
+    #[serde(rename = "type")]
+    tx_type: String,
+    client: String,
+    tx: String,
+    amount: String,
+}
+
+/// Writes every rejected transaction and skipped row to `path`, in the order of the inputs they were read from
+fn write_error_report(engine: &Engine, inputs: &[String], path: &str) -> Result<(), Error> {
+    let mut rows: Vec<ErrorReportRow> = engine
+        .rejections
+        .iter()
+        .map(|rejection| ErrorReportRow {
+            input: &rejection.input,
+            line: rejection.line,
+            code: rejection.error.code(),
+            reason: rejection.error.to_string(),
+            tx_type: rejection.tx.tx_type.to_string(),
+            client: rejection.tx.client.to_string(),
+            tx: rejection.tx.id.to_string(),
+            amount: rejection
+                .tx
+                .amount
+                .map_or_else(String::new, |amount| amount.exact().to_string()),
+        })
+        .collect();
+
+    rows.extend(engine.malformed_rows.iter().map(|row| {
+        let [tx_type, client, tx, amount] = row.fields.clone();
+
+        ErrorReportRow {
+            input: &row.input,
+            line: row.line,
+            code: "malformed",
+            reason: row.message.clone(),
+            tx_type,
+            client,
+            tx,
+            amount,
+        }
+    }));
+
+    // Rejections from a sharded run come back grouped by shard, so they're put back in input order here
+    rows.sort_by_key(|row| (inputs.iter().position(|input| input == row.input), row.line));
+
+    if path.ends_with(".json") {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &rows)?;
+        writer.flush()?;
+    } else {
+        let mut writer = WriterBuilder::new().from_path(path)?;
+
+        for row in &rows {
+            writer.serialize(row)?;
+        }
+
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn use_sqlite_store(engine: &mut Engine, path: &str) -> Result<(), Error> {
+    engine.set_store(SqliteStore::open(path)?)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn use_sqlite_store(_engine: &mut Engine, _path: &str) -> Result<(), Error> {
+    Err(Error::msg(
+        "A SQLite store requires payments to be built with the sqlite feature",
+    ))
+}
+
+#[cfg(feature = "arrow")]
+use parquet_output::write_parquet;
+
+#[cfg(not(feature = "arrow"))]
+fn write_parquet(
+    _accounts: &[Account],
+    _path: &str,
+    _format: AmountFormat,
+    _currency_column: bool,
+) -> Result<(), Error> {
+    Err(Error::msg(
+        "Parquet output requires payments to be built with the arrow feature",
+    ))
+}
+
+/// The number of transactions of each type in an input, and the number of distinct clients they reference
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct TransactionCounts {
+    pub by_type: BTreeMap<TransactionType, u64>,
+    pub clients: usize,
+    /// Rows that couldn't be parsed and were skipped, when lenient
+    pub skipped: u64,
+}
+
+/// Tallies the transactions submitted to it rather than applying them
+#[derive(Default)]
+struct Tally {
+    counts: TransactionCounts,
+    clients: HashSet<ClientId>,
+}
+
+impl Sink for Tally {
+    fn start_input(&mut self, _input: &str, _config: &Config) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn submit(
+        &mut self,
+        tx: Transaction,
+        _config: &Config,
+        _input: &str,
+        _line: u64,
+    ) -> Result<(), Error> {
+        *self.counts.by_type.entry(tx.tx_type).or_insert(0) += 1;
+        self.clients.insert(tx.client);
+        Ok(())
+    }
+
+    fn skip(&mut self, _row: MalformedRow) {
+        self.counts.skipped += 1;
+    }
+}
+
+/// Parses every transaction in the inputs and tallies them, without applying any of them. Rows are read exactly as
+/// a run with the same config reads them, so the counts leave out the transactions filtered by type, and rows that
+/// can't be parsed are counted as skipped when lenient
+pub fn count_transactions(inputs: &[String], config: &Config) -> Result<TransactionCounts, Error> {
+    let mut tally = Tally::default();
+    read_inputs(&mut tally, inputs, config, None)?;
+    tally.counts.clients = tally.clients.len();
+
+    Ok(tally.counts)
+}
+
+fn write_counts(counts: &TransactionCounts) -> Result<(), Error> {
+    let mut writer = WriterBuilder::new().from_writer(std::io::stdout());
+    writer.write_record(["name", "count"])?;
+
+    for (tx_type, count) in &counts.by_type {
+        writer.write_record(&[tx_type.to_string(), count.to_string()])?;
+    }
+
+    writer.write_record(&["clients".to_string(), counts.clients.to_string()])?;
+
+    if counts.skipped > 0 {
+        writer.write_record(&["skipped".to_string(), counts.skipped.to_string()])?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Writes the summary as `name,value` CSV rows, starting with the number of transactions of each type
+fn write_summary(summary: &Summary, config: &Config) -> Result<(), Error> {
+    let format = config.amount_format();
+    let mut writer = WriterBuilder::new().from_writer(open_output(config)?);
+    writer.write_record(["name", "value"])?;
+
+    for (tx_type, count) in &summary.by_type {
+        writer.write_record(&[tx_type.to_string(), count.to_string()])?;
+    }
+
+    let rows = [
+        ("processed", summary.processed.to_string()),
+        ("rejected", summary.rejected.to_string()),
+        ("deposited", format.trimmed(summary.deposited).to_string()),
+        ("withdrawn", format.trimmed(summary.withdrawn).to_string()),
+        ("disputes_opened", summary.disputes_opened.to_string()),
+        ("disputes_resolved", summary.disputes_resolved.to_string()),
+        ("chargebacks", summary.chargebacks.to_string()),
+        ("locked_accounts", summary.locked_accounts.to_string()),
+        ("total", format.trimmed(summary.total).to_string()),
+    ];
+
+    for (name, value) in rows.iter() {
+        writer.write_record([*name, value.as_str()])?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads a CSV of `client,account` pairs mapping client ids to the keys used by downstream systems
+fn load_account_map(path: &str) -> Result<HashMap<ClientId, String>, Error> {
+    let mut reader = reader_builder().from_path(path)?;
+    let mut map = HashMap::new();
+
+    for result in reader.deserialize() {
+        let (client, account): (ClientId, String) = result?;
+        map.insert(client, account);
+    }
+
+    Ok(map)
+}
+
+fn apply_account_map(
+    accounts: &mut [Account],
+    map: &HashMap<ClientId, String>,
+    allow_unmapped: bool,
+) -> Result<(), Error> {
+    for account in accounts {
+        account.account_number = match map.get(&account.client) {
+            Some(number) => Some(number.clone()),
+            None if allow_unmapped => Some(account.client.to_string()),
+            None => {
+                return Err(Error::msg(format!(
+                    "Client {} not found in account map",
+                    account.client
+                )))
+            }
+        };
+    }
+
+    Ok(())
+}
+
+fn read_input<S: Sink>(
+    sink: &mut S,
+    input: &str,
+    config: &Config,
+    echo: Option<&mut Writer<File>>,
+) -> Result<(), Error> {
+    debug!("Reading {}", input);
+
+    if config.mmap {
+        if let Some(map) = map_input(input) {
+            return match config.format {
+                Format::Csv => process_reader(
+                    sink,
+                    input,
+                    reader_builder().from_reader(&map[..]),
+                    config,
+                    echo,
+                ),
+                Format::Jsonl => process_jsonl(sink, input, &map[..], config, echo),
+            };
+        }
+    }
+
+    match config.format {
+        Format::Csv => process_reader(
+            sink,
+            input,
+            reader_builder().from_path(input)?,
+            config,
+            echo,
+        ),
+        Format::Jsonl => process_jsonl(
+            sink,
+            input,
+            BufReader::new(File::open(input)?),
+            config,
+            echo,
+        ),
+    }
+}
+
+/// Reads the inputs into `sink` one after another, or, when there are several CSV inputs and every one has a
+/// `timestamp` column, merged into a single sequence in timestamp order. The transactions of the recurring instructions,
+/// if any, are interleaved with them by timestamp
+fn read_inputs<S: Sink>(
+    sink: &mut S,
+    inputs: &[String],
+    config: &Config,
+    echo: Option<&mut Writer<File>>,
+) -> Result<(), Error> {
+    match &config.recurring {
+        Some(path) => {
+            let mut scheduled = schedule::Scheduled::new(sink, path, schedule::load(path)?);
+            read_unscheduled(&mut scheduled, inputs, config, echo)?;
+            scheduled.finish(config)
+        }
+        None => read_unscheduled(sink, inputs, config, echo),
+    }
+}
+
+fn read_unscheduled<S: Sink>(
+    sink: &mut S,
+    inputs: &[String],
+    config: &Config,
+    mut echo: Option<&mut Writer<File>>,
+) -> Result<(), Error> {
+    if inputs.len() > 1 && config.format == Format::Csv {
+        let mut readers = Vec::with_capacity(inputs.len());
+
+        for input in inputs {
+            let mut reader = reader_builder().from_path(input)?;
+            let timestamped = reader.headers()?.iter().any(|header| header == "timestamp");
+            readers.push((reader, timestamped));
+        }
+
+        if readers.iter().all(|(_, timestamped)| *timestamped) {
+            let readers = readers.into_iter().map(|(reader, _)| reader).collect();
+            return merge::merge_by_timestamp(sink, inputs, readers, config, echo);
+        }
+
+        if readers.iter().any(|(_, timestamped)| *timestamped) {
+            warn!("Only some of the inputs have a timestamp column, so they are processed one after another");
+        }
+    }
+
+    for input in inputs {
+        sink.start_input(input, config)?;
+        read_input(sink, input, config, echo.as_deref_mut())?;
+    }
+
+    Ok(())
+}
+
+fn reader_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.flexible(true).trim(Trim::All);
+    builder
+}
+
+/// Maps the input file into memory, returning `None` when the file or platform doesn't support it so the caller can
+/// fall back to reading the file normally
+fn map_input(input: &str) -> Option<Mmap> {
+    let file = File::open(input).ok()?;
+
+    // Safety: the map is only read while parsing, and the input file is not expected to be modified while being processed
+    unsafe { Mmap::map(&file) }.ok()
+}
+
+/// Applies every transaction read from `reader`. Parsed transactions are also written to `echo`, if given, with
+/// amounts rounded to the output scale, before the type filter is applied. When lenient, rows that can't be parsed are
+/// skipped and recorded against `input`
+fn process_reader<S: Sink, R: Read>(
+    sink: &mut S,
+    input: &str,
+    mut reader: Reader<R>,
+    config: &Config,
+    mut echo: Option<&mut Writer<File>>,
+) -> Result<(), Error> {
+    let mut ignored = 0;
+    let headers = reader.headers()?.clone();
+
+    // Reading every row into the same record reuses its buffers, so parsing doesn't allocate per row
+    let mut row = StringRecord::new();
+
+    while reader.read_record(&mut row)? {
+        if !process_row(sink, input, &row, &headers, config, echo.as_deref_mut())? {
+            ignored += 1;
+        }
+    }
+
+    if ignored > 0 {
+        info!("Ignored {} transactions filtered by type", ignored);
+    }
+
+    Ok(())
+}
+
+/// Parses a row of a CSV input and applies it, returning `false` if it was filtered out by type. When lenient, a row
+/// that can't be parsed is skipped and recorded against `input`
+fn process_row<S: Sink>(
+    sink: &mut S,
+    input: &str,
+    row: &StringRecord,
+    headers: &StringRecord,
+    config: &Config,
+    echo: Option<&mut Writer<File>>,
+) -> Result<bool, Error> {
+    let line = row.position().map_or(0, |position| position.line());
+    let record = match Transaction::from_record(row, headers) {
+        Ok(record) => record,
+        Err(err) if config.lenient => {
+            info!("Skipped row; Error: {}", err);
+            sink.skip(MalformedRow {
+                input: input.to_string(),
+                line,
+                message: validate::describe_parse_error(err),
+                fields: RAW_FIELDS.map(|name| {
+                    let index = headers.iter().position(|header| header == name);
+                    index
+                        .and_then(|index| row.get(index))
+                        .unwrap_or("")
+                        .to_string()
+                }),
+            });
+            return Ok(true);
+        }
+        Err(err) => return Err(err),
+    };
+
+    process_record(sink, record, input, line, config, echo)
+}
+
+/// Applies every transaction read from `reader` as JSON Lines, one transaction object per line. Blank lines are
+/// skipped, and everything after parsing is the same as for CSV input
+fn process_jsonl<S: Sink, R: BufRead>(
+    sink: &mut S,
+    input: &str,
+    reader: R,
+    config: &Config,
+    mut echo: Option<&mut Writer<File>>,
+) -> Result<(), Error> {
+    let mut ignored = 0;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = match Transaction::from_json_line(&line) {
+            Ok(record) => record,
+            Err(err) if config.lenient => {
+                info!("Skipped line; Error: {}", err);
+                let value: serde_json::Value = serde_json::from_str(&line).unwrap_or_default();
+                sink.skip(MalformedRow {
+                    input: input.to_string(),
+                    line: index as u64 + 1,
+                    message: err.to_string(),
+                    fields: RAW_FIELDS.map(|name| match value.get(name) {
+                        Some(serde_json::Value::String(field)) => field.clone(),
+                        Some(serde_json::Value::Null) | None => String::new(),
+                        Some(field) => field.to_string(),
+                    }),
+                });
+                continue;
+            }
+            Err(err) => {
+                return Err(Error::msg(format!(
+                    "Invalid transaction on line {}: {}",
+                    index + 1,
+                    err
+                )))
+            }
+        };
+
+        if !process_record(
+            sink,
+            record,
+            input,
+            index as u64 + 1,
+            config,
+            echo.as_deref_mut(),
+        )? {
+            ignored += 1;
+        }
+    }
+
+    if ignored > 0 {
+        info!("Ignored {} transactions filtered by type", ignored);
+    }
+
+    Ok(())
+}
+
+/// The fields of a row that make up a transaction, in the order they're kept for malformed rows
+const RAW_FIELDS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Where the transactions read from an input go: straight into an engine, or to the workers of a sharded run
+trait Sink {
+    /// Notes that the transactions submitted next are read from `input`, until another input is started
+    fn start_input(&mut self, input: &str, config: &Config) -> Result<(), Error>;
+
+    /// Applies a transaction read from `line` of `input`, or queues it to be applied. Rejected transactions are logged,
+    /// and recorded for the error report if there is one, rather than returned
+    fn submit(
+        &mut self,
+        tx: Transaction,
+        config: &Config,
+        input: &str,
+        line: u64,
+    ) -> Result<(), Error>;
+
+    /// Records a row that was skipped because it couldn't be parsed, counting it as processed and rejected
+    fn skip(&mut self, row: MalformedRow);
+}
+
+impl Sink for Engine {
+    fn start_input(&mut self, input: &str, config: &Config) -> Result<(), Error> {
+        if config.tag_source {
+            self.set_source(Some(input.to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn submit(
+        &mut self,
+        tx: Transaction,
+        config: &Config,
+        input: &str,
+        line: u64,
+    ) -> Result<(), Error> {
+        // Anything but a rejection, such as failing to write to a store or spill file, can't be skipped over
+        let error = match self.apply(tx) {
+            Ok(()) => {
+                debug!("{} line {}: applied {:?}", input, line, tx);
+                return Ok(());
+            }
+            Err(err) => err.downcast::<PaymentError>()?,
+        };
+
+        info!("{} line {}: {:?}; Error: {}", input, line, tx, error);
+
+        if config.errors.is_some() {
+            self.rejections.push(Rejection {
+                input: input.to_string(),
+                line,
+                tx,
+                error,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn skip(&mut self, row: MalformedRow) {
+        self.metrics.processed += 1;
+        self.metrics.rejected += 1;
+        self.malformed_rows.push(row);
+    }
+}
+
+/// Echoes a parsed transaction and applies it unless it's filtered out by type, returning `false` if it was filtered
+/// out. Rejected transactions are logged rather than failing the run
+fn process_record<S: Sink>(
+    sink: &mut S,
+    record: Transaction,
+    input: &str,
+    line: u64,
+    config: &Config,
+    echo: Option<&mut Writer<File>>,
+) -> Result<bool, Error> {
+    if let Some(writer) = echo {
+        writer.serialize(Transaction {
+            amount: record.amount.map(round_to_scale),
+            ..record
+        })?;
+    }
+
+    if !config.allows(record.tx_type) {
+        return Ok(false);
+    }
+
+    sink.submit(record, config, input, line)?;
+
+    Ok(true)
+}
+
+/// Holds the state of every account along with the transactions that may later be disputed. Embedding services feed it
+/// transactions one at a time with [`Engine::process`] and read the balances back with [`Engine::accounts`]
+///
+/// ```
+/// use payments::{Amount, PaymentsEngine, Transaction, TransactionType};
+///
+/// let mut engine = PaymentsEngine::new();
+/// engine
+///     .process(Transaction::new(TransactionType::Deposit, 1, 1, Some(Amount::from_num(10))))
+///     .unwrap();
+/// assert!(engine
+///     .process(Transaction::new(TransactionType::Withdraw, 1, 2, Some(Amount::from_num(20))))
+///     .is_err());
+///
+/// let account = engine.accounts().next().unwrap();
+/// assert_eq!(account.client(), 1);
+/// assert_eq!(account.available(), Amount::from_num(10));
+/// ```
+#[derive(Debug, Default)]
+pub struct Engine {
+    accounts: Accounts,
+    history: History,
+    source: Option<String>,
+    deposit_fee_bps: u32,
+    withdrawal_fees: FeeSchedule,
+    fee_floor: Option<Amount>,
+    metrics: EngineMetrics,
+    gaps: Option<GapDetector>,
+    round_each_op: bool,
+    max_disputes_per_tx: Option<u32>,
+    partial_disputes: bool,
+    dispute_expiry: Option<u64>,
+    dispute_expiry_days: Option<u32>,
+    dispute_expiry_action: DisputeExpiryAction,
+    /// Positions in the history of disputed transactions with the number of processed transactions when each dispute was
+    /// opened, oldest first
+    open_disputes: VecDeque<(usize, u64)>,
+    on_lock: Option<LockHook>,
+    on_account_change: Option<AccountHook>,
+    /// Every deposit and withdrawal id seen so far, applied or not, when strict ordering is enabled
+    seen_ids: Option<HashSet<u32>>,
+    /// Every transaction that referenced another client's transaction, when strict client checking is enabled
+    client_mismatches: Option<Vec<ClientMismatch>>,
+    /// Every row of the inputs that was skipped because it couldn't be parsed, when lenient
+    malformed_rows: Vec<MalformedRow>,
+    /// Every transaction read from the inputs that was rejected, when writing an error report
+    rejections: Vec<Rejection>,
+    store: Option<StoreHandle>,
+    /// The clients and transaction ids changed since they were last saved to the store
+    unsaved: Vec<(ClientId, u32)>,
+    /// Set while a batch is applied, so its changes are only saved to the store once the whole batch succeeds
+    in_batch: bool,
+    pending_deposits: bool,
+    id_wraparound: Option<IdWraparound>,
+    /// The highest deposit or withdrawal id seen in the current id space, when wraparound is detected
+    highest_id: Option<u32>,
+    /// The latest timestamp of any transaction seen
+    latest_timestamp: Option<Timestamp>,
+    reject_out_of_order: bool,
+    /// The client and id of every deposit, withdrawal, transfer, fee, and conversion applied, when skipping duplicates
+    applied: Option<HashSet<(ClientId, u32)>>,
+    max_tx_amount: Option<Amount>,
+    /// The risk rules, with what the clients have done so far that counts towards them
+    risk: Option<RiskTracker>,
+    rules: Vec<RuleHook>,
+    /// Every transaction a rule flagged, in order
+    flagged: Vec<RuleFlag>,
+    /// The transactions of the batch being applied that weren't skipped as duplicates, to tell the rules about once the
+    /// whole batch has been applied
+    batch_applied: Vec<Transaction>,
+    interest: Option<Accrual>,
+    /// Whether the engine is applying its own interest transactions, the only ones it accepts
+    crediting_interest: bool,
+    /// Every interest credit that was applied, in order
+    interest_credits: Vec<InterestCredit>,
+    locked_accounts: LockedAccountPolicy,
+    withdrawal_disputes: WithdrawalDisputeMode,
+    /// The ids allowed to unlock accounts, and to lock accounts other than their own
+    admins: HashSet<ClientId>,
+    /// Every lock and unlock that was applied, in order
+    lock_audit: Vec<LockEvent>,
+    /// The currency of transactions without one
+    currency: Currency,
+    /// The rates conversions without a rate of their own are made at
+    rates: RateTable,
+    conversion_rounding: RoundingMode,
+    /// Every conversion that was applied, in order
+    conversions: Vec<ConversionEvent>,
+    audit: Option<AuditLog>,
+    /// Every event applied to the accounts, in order, when recording events
+    events: Option<Vec<LedgerEvent>>,
+}
+
+/// A row of an input that couldn't be parsed into a transaction, and was skipped because the run was lenient
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MalformedRow {
+    /// The input file the row is in
+    pub input: String,
+    pub line: u64,
+    /// Why the row couldn't be parsed
+    pub message: String,
+    /// The row's type, client, tx, and amount as they were written, blank where missing
+    pub fields: [String; 4],
+}
+
+impl Display for MalformedRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} line {}: {}", self.input, self.line, self.message)
+    }
+}
+
+/// A transaction the engine rejected, kept for the error report
+#[derive(Debug)]
+struct Rejection {
+    input: String,
+    line: u64,
+    tx: Transaction,
+    error: PaymentError,
+}
+
+/// A transaction that referenced a deposit or withdrawal of another client
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ClientMismatch {
+    pub tx_type: TransactionType,
+    /// The client the transaction came from
+    pub client: ClientId,
+    /// The client the referenced transaction belongs to
+    pub owner: ClientId,
+    pub tx: u32,
+}
+
+impl Display for ClientMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of transaction {} by client {}, which belongs to client {}",
+            self.tx_type, self.tx, self.client, self.owner
+        )
+    }
+}
+
+struct StoreHandle(Box<dyn Store + Send>);
+
+impl fmt::Debug for StoreHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StoreHandle")
+    }
+}
+
+/// A callback invoked with the client and transaction id whenever a transaction locks an account
+struct LockHook(Box<dyn FnMut(ClientId, u32) + Send>);
+
+impl fmt::Debug for LockHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LockHook")
+    }
+}
+
+/// A rule registered with [`Engine::add_rule`]
+struct RuleHook(Box<dyn RiskRule>);
+
+impl fmt::Debug for RuleHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RuleHook")
+    }
+}
+
+/// A callback invoked with every account whose balances or status a transaction changed
+struct AccountHook(Box<dyn FnMut(&Account) + Send>);
+
+impl fmt::Debug for AccountHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccountHook")
+    }
+}
+
+/// A row of the held funds report, summarizing the open disputes of a client with held funds
+#[derive(Debug, Serialize, Eq, PartialEq)]
+pub struct HeldReportRow {
+    pub client: ClientId,
+    pub held: Amount,
+    pub open_disputes: usize,
+    /// The ids of the disputed transactions, separated by spaces
+    pub tx_ids: String,
+}
+
+/// A row of the dispute report, describing a dispute that is still open
+#[derive(Debug, Serialize, Eq, PartialEq)]
+pub struct DisputeReportRow {
+    pub tx: u32,
+    /// The type of the disputed transaction
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    /// The client whose funds the dispute holds
+    pub client: ClientId,
+    pub currency: Currency,
+    /// The amount of the disputed transaction
+    pub amount: Amount,
+    pub held: Amount,
+    /// The number of transactions processed after the dispute was opened, counting from the start of the run for
+    /// disputes opened before resuming
+    pub age: u64,
+    /// The number of whole days from when the dispute was opened to the latest timestamp seen, blank without timestamps
+    pub age_days: Option<i64>,
+    /// Whether the dispute expired and was escalated
+    pub escalated: bool,
+}
+
+/// Totals across everything an engine processed, for reconciling a day's transactions, written by [`report`]. Amounts in
+/// different currencies are added together as they are
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Summary {
+    /// The number of transactions of each type that were applied or rejected
+    pub by_type: BTreeMap<TransactionType, u64>,
+    pub processed: u64,
+    pub rejected: u64,
+    pub deposited: Amount,
+    pub withdrawn: Amount,
+    pub disputes_opened: u64,
+    pub disputes_resolved: u64,
+    pub chargebacks: u64,
+    pub locked_accounts: usize,
+    /// The sum of the totals of every account
+    pub total: Amount,
+}
+
+/// A lock or unlock that was applied to an account, for the audit trail of who locked which account
+#[derive(Debug, Serialize, Clone, Copy, Eq, PartialEq)]
+pub struct LockEvent {
+    pub tx: u32,
+    pub client: ClientId,
+    /// `lock` or `unlock`
+    pub action: TransactionType,
+    /// Who applied it, which is the client themselves for a lock without an actor
+    pub actor: ClientId,
+}
+
+/// Tracks the ids of deposits and withdrawals, which are expected to increase by exactly one from row to row. Disputes,
+/// resolves, and chargebacks reuse earlier ids so they aren't part of the sequence
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct GapDetector {
+    last: Option<u32>,
+    /// Inclusive ranges of ids that were skipped
+    pub missing: Vec<(u32, u32)>,
+    /// The number of ids that were not greater than the id before them
+    pub out_of_order: u64,
+}
+
+impl GapDetector {
+    fn observe(&mut self, id: u32) {
+        match self.last {
+            Some(last) if id <= last => self.out_of_order += 1,
+            Some(last) => {
+                if id > last + 1 {
+                    self.missing.push((last + 1, id - 1));
+                }
+                self.last = Some(id);
+            }
+            None => self.last = Some(id),
+        }
+    }
+}
+
+impl fmt::Display for GapDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self
+            .missing
+            .iter()
+            .map(|&(start, end)| match start == end {
+                true => start.to_string(),
+                false => format!("{}-{}", start, end),
+            })
+            .collect();
+
+        write!(f, "{}", ranges.join(", "))
+    }
+}
+
+/// The name the engine is exposed under for services embedding it as a library
+pub type PaymentsEngine = Engine;
+
+/// Running totals collected by the engine while it processes transactions
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct EngineMetrics {
+    /// The sum of all fees deducted from deposits
+    pub deposit_fees: Amount,
+    /// The sum of all fees charged by fee transactions and on withdrawals
+    pub fees: Amount,
+    /// The number of transactions that were applied or rejected
+    pub processed: u64,
+    /// The number of transactions that were rejected
+    pub rejected: u64,
+    /// The number of transactions skipped because the same transaction was already applied
+    pub duplicates: u64,
+    /// The number of disputes that were resolved because they expired
+    pub expired_disputes: u64,
+    /// The number of disputes that were escalated because they expired
+    pub escalated_disputes: u64,
+    /// The number of times deposit and withdrawal ids wrapped around and a new id space was started
+    pub id_wraparounds: u64,
+    /// The number of transactions of each type that were applied or rejected
+    pub by_type: BTreeMap<TransactionType, u64>,
+    /// The number of transactions rejected for referencing another client's transaction
+    pub client_mismatches: u64,
+    /// The sum of the amounts of applied deposits, including any fees deducted from them
+    pub deposited: Amount,
+    /// The sum of the amounts of applied withdrawals, not including fees charged on them
+    pub withdrawn: Amount,
+    /// The number of disputes, resolves, and chargebacks that were applied
+    pub disputes_opened: u64,
+    pub disputes_resolved: u64,
+    pub chargebacks: u64,
+}
+
+impl EngineMetrics {
+    /// Adds the totals of another engine's metrics to these
+    fn add(&mut self, other: &EngineMetrics) {
+        self.deposit_fees = self.deposit_fees.saturating_add(other.deposit_fees);
+        self.fees = self.fees.saturating_add(other.fees);
+        self.processed += other.processed;
+        self.rejected += other.rejected;
+        self.duplicates += other.duplicates;
+        self.expired_disputes += other.expired_disputes;
+        self.escalated_disputes += other.escalated_disputes;
+        self.id_wraparounds += other.id_wraparounds;
+        self.client_mismatches += other.client_mismatches;
+        self.deposited = self.deposited.saturating_add(other.deposited);
+        self.withdrawn = self.withdrawn.saturating_add(other.withdrawn);
+        self.disputes_opened += other.disputes_opened;
+        self.disputes_resolved += other.disputes_resolved;
+        self.chargebacks += other.chargebacks;
+
+        for (tx_type, count) in &other.by_type {
+            *self.by_type.entry(*tx_type).or_insert(0) += count;
+        }
+    }
+
+    /// The fraction of processed transactions that were rejected
+    pub fn error_ratio(&self) -> f64 {
+        match self.processed {
+            0 => 0.0,
+            processed => self.rejected as f64 / processed as f64,
+        }
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an engine with room reserved for `clients` accounts and `txns` disputable transactions, so processing a
+    /// large input of a known size doesn't repeatedly grow them. Results are the same as with [`Engine::new`]. Panics if
+    /// the room can't be reserved, see [`Engine::try_reserve`] for a hint that may be too large
+    pub fn with_capacity(clients: usize, txns: usize) -> Self {
+        Self {
+            accounts: Accounts::with_capacity(clients),
+            history: History::with_capacity(txns),
+            ..Self::default()
+        }
+    }
+
+    /// Reserves room for `txns` more disputable transactions, failing rather than aborting if that much can't be
+    /// reserved, in which case the engine is left as it was
+    pub fn try_reserve(&mut self, txns: usize) -> Result<(), std::collections::TryReserveError> {
+        self.history.try_reserve(txns)
+    }
+
+    /// Restores an engine from a snapshot of accounts in the same CSV format as the program's output. A snapshot where
+    /// an account's available and held funds don't add up to its total is rejected as corrupt
+    pub fn load_snapshot<R: Read>(snapshot: R) -> Result<Self, Error> {
+        let mut reader = reader_builder().from_reader(snapshot);
+        let mut engine = Self::new();
+
+        for result in reader.deserialize() {
+            let account: Account = result?;
+
+            if !account.is_consistent() {
+                return Err(PaymentError::CorruptSnapshot {
+                    client: account.client,
+                }
+                .into());
+            }
+
+            engine.accounts.push(account);
+        }
+
+        Ok(engine)
+    }
+
+    /// Sets the name of the input that subsequent transactions are read from. Accounts modified while a source is set
+    /// are tagged with it
+    pub fn set_source(&mut self, source: Option<String>) {
+        self.source = source;
+    }
+
+    /// Sets the fee, in basis points, deducted from each deposit before it is credited
+    pub fn set_deposit_fee_bps(&mut self, bps: u32) {
+        self.deposit_fee_bps = bps;
+    }
+
+    /// Sets the fees charged on each withdrawal, which are taken from the account along with the amount withdrawn
+    pub fn set_withdrawal_fees(&mut self, fees: FeeSchedule) {
+        self.withdrawal_fees = fees;
+    }
+
+    /// Sets the lowest available funds a fee may leave an account with. Fees that would take an account below it are
+    /// rejected, along with the withdrawal they're charged on. Without a floor, fees always apply
+    pub fn set_fee_floor(&mut self, floor: Option<Amount>) {
+        self.fee_floor = floor;
+    }
+
+    pub fn metrics(&self) -> &EngineMetrics {
+        &self.metrics
+    }
+
+    /// When enabled, the balances of an account are rounded to the output scale after every transaction applied to it
+    pub fn set_round_each_op(&mut self, round_each_op: bool) {
+        self.round_each_op = round_each_op;
+    }
+
+    /// Keeps the engine's accounts and transactions in `store` from now on, loading the accounts already in it so
+    /// processing continues where an earlier engine using the same store left off. Like [`Engine::restore_state`], this
+    /// is only allowed before any transaction is processed. A stored account whose funds don't add up to its total is
+    /// rejected as corrupt
+    pub fn set_store(&mut self, mut store: impl Store + Send + 'static) -> Result<(), Error> {
+        if self.metrics.processed > 0 || !self.accounts.is_empty() {
+            return Err(Error::msg(
+                "A store can only be set on an engine that hasn't processed any transactions",
+            ));
+        }
+
+        for account in store.load_accounts()? {
+            if !account.is_consistent() {
+                return Err(PaymentError::CorruptSnapshot {
+                    client: account.client,
+                }
+                .into());
+            }
+
+            self.accounts.push(account);
+        }
+
+        self.store = Some(StoreHandle(Box::new(store)));
+
+        Ok(())
+    }
+
+    /// Saves any pending changes to the store and makes them durable, and writes the audit records of any atomic batch
+    /// applied since the last transaction to the audit log. Does nothing without a store or an audit log
+    pub fn flush_store(&mut self) -> Result<(), Error> {
+        self.save_changes()?;
+
+        if let Some(audit) = &mut self.audit {
+            audit.flush()?;
+        }
+
+        if let Some(StoreHandle(store)) = &mut self.store {
+            store.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the accounts and every deposit and withdrawal the engine remembers as JSON, so a later run can continue
+    /// from this state with [`Engine::restore_state`]. Transactions spilled to disk or dropped by the history limit
+    /// aren't included, so they can't be disputed after resuming
+    pub fn save_state<W: Write>(&self, writer: W) -> Result<(), Error> {
+        let state = EngineState {
+            accounts: self.accounts.iter().map(AccountState::from).collect(),
+            history: self.history.iter().cloned().collect(),
+            highest_id: self.highest_id,
+            latest_timestamp: self.latest_timestamp,
+            applied: self.applied.as_ref().map(|applied| {
+                let mut applied: Vec<(ClientId, u32)> = applied.iter().copied().collect();
+                applied.sort_unstable();
+                applied
+            }),
+        };
+
+        serde_json::to_writer(writer, &state)?;
+
+        Ok(())
+    }
+
+    /// Loads the accounts and transaction history written by [`Engine::save_state`] into an engine that hasn't
+    /// processed any transactions yet, keeping its settings. Disputes that were open keep their held funds, and with
+    /// dispute expiry enabled they expire after the configured number of transactions from now. A state where an
+    /// account's funds don't add up to its total is rejected as corrupt
+    pub fn restore_state<R: Read>(&mut self, reader: R) -> Result<(), Error> {
+        if self.metrics.processed > 0 || !self.accounts.is_empty() {
+            return Err(Error::msg(
+                "State can only be restored into an engine that hasn't processed any transactions",
+            ));
+        }
+
+        let state: EngineState = serde_json::from_reader(reader)?;
+        let credit_limits = self.accounts.take_credit_limits();
+        let mut accounts = Accounts::with_capacity(state.accounts.len());
+
+        for account in state.accounts {
+            let account = Account::from(account);
+
+            if !account.is_consistent() {
+                return Err(PaymentError::CorruptSnapshot {
+                    client: account.client,
+                }
+                .into());
+            }
+
+            accounts.push(account);
+        }
+
+        // Limits set before restoring, such as from the config, take precedence over those in the state
+        for ((client, currency), limit) in credit_limits {
+            accounts.set_credit_limit(client, currency, limit);
+        }
+
+        self.accounts = accounts;
+        self.highest_id = state.highest_id;
+        self.latest_timestamp = state.latest_timestamp;
+
+        // A state saved without skipping duplicates still knows the transactions it remembers were applied
+        let seed_applied = match (&mut self.applied, state.applied) {
+            (Some(applied), Some(saved)) => {
+                applied.extend(saved);
+                false
+            }
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        for entry in state.history {
+            if let Some(seen) = &mut self.seen_ids {
+                seen.insert(entry.id);
+            }
+
+            if let (true, Some(applied)) = (seed_applied, &mut self.applied) {
+                applied.insert((entry.client, entry.id));
+            }
+
+            // Open disputes count as opened now, which is what their `disputed_at` of zero means to a fresh engine
+            if entry.under_dispute() && !entry.escalated && self.expires_disputes() {
+                self.open_disputes
+                    .push_back((self.history.next_position(), entry.disputed_at));
+            }
+
+            self.history.push(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes every applied deposit and withdrawal the engine remembers as a JSON array, with its dispute state and
+    /// status. This is meant for debugging balances, and the format may change between versions
+    pub fn dump_state<W: Write>(&self, writer: W) -> Result<(), Error> {
+        let entries: Vec<LedgerDumpEntry> = self
+            .history
+            .iter()
+            .map(|entry| LedgerDumpEntry {
+                entry,
+                status: entry.status(),
+            })
+            .collect();
+
+        serde_json::to_writer_pretty(writer, &entries)?;
+
+        Ok(())
+    }
+
+    /// Writes the engine's metrics in the Prometheus text exposition format, for scraping through a textfile collector
+    pub fn write_prometheus<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(
+            writer,
+            "# HELP payments_transactions_total Transactions processed, by type"
+        )?;
+        writeln!(writer, "# TYPE payments_transactions_total counter")?;
+
+        for (tx_type, count) in &self.metrics.by_type {
+            writeln!(
+                writer,
+                "payments_transactions_total{{type=\"{}\"}} {}",
+                tx_type, count
+            )?;
+        }
+
+        let locked = self
+            .accounts
+            .iter()
+            .filter(|account| account.status.is_locked())
+            .count();
+        let held = self.accounts.iter().fold(Amount::ZERO, |held, account| {
+            held.saturating_add(account.held)
+        });
+
+        writeln!(
+            writer,
+            "# HELP payments_rejected_total Transactions that were rejected"
+        )?;
+        writeln!(writer, "# TYPE payments_rejected_total counter")?;
+        writeln!(writer, "payments_rejected_total {}", self.metrics.rejected)?;
+        writeln!(
+            writer,
+            "# HELP payments_duplicates_total Transactions skipped because they were already applied"
+        )?;
+        writeln!(writer, "# TYPE payments_duplicates_total counter")?;
+        writeln!(
+            writer,
+            "payments_duplicates_total {}",
+            self.metrics.duplicates
+        )?;
+        writeln!(
+            writer,
+            "# HELP payments_locked_accounts Accounts that are locked"
+        )?;
+        writeln!(writer, "# TYPE payments_locked_accounts gauge")?;
+        writeln!(writer, "payments_locked_accounts {}", locked)?;
+        writeln!(
+            writer,
+            "# HELP payments_held_funds Funds held across all accounts"
+        )?;
+        writeln!(writer, "# TYPE payments_held_funds gauge")?;
+        writeln!(writer, "payments_held_funds {}", round_to_scale(held))?;
+
+        Ok(())
+    }
+
+    /// The totals of everything processed so far and of the accounts as they are now
+    pub fn summary(&self) -> Summary {
+        Summary {
+            by_type: self.metrics.by_type.clone(),
+            processed: self.metrics.processed,
+            rejected: self.metrics.rejected,
+            deposited: self.metrics.deposited,
+            withdrawn: self.metrics.withdrawn,
+            disputes_opened: self.metrics.disputes_opened,
+            disputes_resolved: self.metrics.disputes_resolved,
+            chargebacks: self.metrics.chargebacks,
+            locked_accounts: self
+                .accounts
+                .iter()
+                .filter(|account| account.status.is_locked())
+                .count(),
+            total: self.accounts.iter().fold(Amount::ZERO, |total, account| {
+                total.saturating_add(account.total)
+            }),
+        }
+    }
+
+    /// Summarizes held funds and open disputes for every client with held funds, in account order
+    pub fn held_report(&self) -> Vec<HeldReportRow> {
+        self.accounts
+            .iter()
+            .filter(|account| account.held != Amount::ZERO)
+            .map(|account| {
+                let ids: Vec<String> = self
+                    .history
+                    .iter()
+                    .filter(|tx| {
+                        tx.holder() == account.client
+                            && tx.currency == account.currency
+                            && tx.under_dispute()
+                    })
+                    .map(|tx| tx.id.to_string())
+                    .collect();
+
+                HeldReportRow {
+                    client: account.client,
+                    held: account.held,
+                    open_disputes: ids.len(),
+                    tx_ids: ids.join(" "),
+                }
+            })
+            .collect()
+    }
+
+    /// Lists every dispute still open that the engine has in memory, oldest transaction first
+    pub fn dispute_report(&self) -> Vec<DisputeReportRow> {
+        self.history
+            .iter()
+            .filter(|tx| tx.under_dispute())
+            .map(|tx| DisputeReportRow {
+                tx: tx.id,
+                tx_type: tx.tx_type,
+                client: tx.holder(),
+                currency: tx.currency,
+                amount: tx.amount,
+                held: tx.held,
+                age: self.metrics.processed - tx.disputed_at,
+                age_days: tx
+                    .disputed_time
+                    .zip(self.latest_timestamp)
+                    .map(|(opened, latest)| latest.days_since(opened)),
+                escalated: tx.escalated,
+            })
+            .collect()
+    }
+
+    /// Limits how many times a single transaction may be disputed, including disputes that were later resolved
+    pub fn set_max_disputes_per_tx(&mut self, max: Option<u32>) {
+        self.max_disputes_per_tx = max;
+    }
+
+    /// When enabled, a dispute with an amount only disputes that much of the referenced transaction. Otherwise the
+    /// amount on a dispute is ignored
+    pub fn set_partial_disputes(&mut self, partial_disputes: bool) {
+        self.partial_disputes = partial_disputes;
+    }
+
+    /// When enabled, deposits are credited to pending funds, which count towards the total but can't be withdrawn or
+    /// disputed until a `settle` transaction with the same id moves them to available funds
+    pub fn set_pending_deposits(&mut self, pending_deposits: bool) {
+        self.pending_deposits = pending_deposits;
+    }
+
+    /// Sets which deposits and withdrawals locked accounts still accept. Others are rejected with
+    /// [`PaymentError::AccountLocked`]
+    pub fn set_locked_account_policy(&mut self, policy: LockedAccountPolicy) {
+        self.locked_accounts = policy;
+    }
+
+    /// Every row of the inputs skipped because it couldn't be parsed, in the order they were read
+    pub fn malformed_rows(&self) -> &[MalformedRow] {
+        &self.malformed_rows
+    }
+
+    /// Sets how disputing a withdrawal affects the account before the dispute is decided
+    pub fn set_withdrawal_dispute_mode(&mut self, mode: WithdrawalDisputeMode) {
+        self.withdrawal_disputes = mode;
+    }
+
+    /// Sets the ids allowed to unlock accounts, and to lock accounts other than their own. Clients can always lock their
+    /// own account
+    pub fn set_admins(&mut self, admins: impl IntoIterator<Item = ClientId>) {
+        self.admins = admins.into_iter().collect();
+    }
+
+    /// Every lock and unlock that was applied, in order, with who applied it
+    pub fn lock_audit(&self) -> &[LockEvent] {
+        &self.lock_audit
+    }
+
+    /// Sets the currency of transactions without one. Defaults to [`Currency::DEFAULT`]
+    pub fn set_currency(&mut self, currency: Currency) {
+        self.currency = currency;
+    }
+
+    /// Sets the rates conversions without a rate of their own are made at. Conversions between currencies the table
+    /// has no rate for are rejected with [`PaymentError::RateNotFound`]
+    pub fn set_rates(&mut self, rates: RateTable) {
+        self.rates = rates;
+    }
+
+    /// Sets how far below zero withdrawals, transfers, and conversions may take the available funds of the client's
+    /// account in `currency`, as a `set_limit` transaction does. The limit of an account that isn't open yet is kept
+    /// until it's opened, without opening it
+    pub fn set_credit_limit(&mut self, client: ClientId, currency: Currency, limit: Amount) {
+        self.accounts.set_credit_limit(client, currency, limit);
+    }
+
+    /// Sets how converted amounts are rounded to the output scale when they fall exactly halfway
+    pub fn set_conversion_rounding(&mut self, rounding: RoundingMode) {
+        self.conversion_rounding = rounding;
+    }
+
+    /// Every conversion that was applied, in order, with the rate it was made at and the spread it realized
+    pub fn conversions(&self) -> &[ConversionEvent] {
+        &self.conversions
+    }
+
+    /// Keeps at most `limit` deposits and withdrawals in memory, evicting the oldest ones once there are more. Evicted
+    /// transactions can no longer be disputed, unless they are spilled to disk with [`Engine::spill_history_to`].
+    /// Transactions under dispute are kept in memory regardless, so their disputes can still be settled
+    pub fn set_history_limit(&mut self, limit: Option<usize>) {
+        self.history.set_limit(limit);
+    }
+
+    /// Writes deposits and withdrawals evicted by the history limit to a new file at `path` instead of dropping them, and
+    /// reads them back when they are disputed. Only a few bytes per evicted transaction are kept in memory to find it
+    pub fn spill_history_to(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.history.spill_to(path.as_ref())
+    }
+
+    /// Appends a line of JSON to the file at `path` for every transaction handled from now on, creating it if needed.
+    /// Each line has the transaction's id, type, client, currency, and amount, the balances of the client's account in
+    /// that currency before and after it, and its outcome: `applied`, or the code of the error it was rejected with.
+    /// Disputes resolved by the dispute expiry are logged as resolves with the outcome `expired`, and duplicates that
+    /// are skipped aren't logged. Each line is written as soon as the transaction is applied, except those of an atomic
+    /// batch, which are written along with the next transaction or by [`Engine::flush_store`] once the whole batch
+    /// succeeds, and not at all if it's rolled back
+    pub fn audit_to(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.audit = Some(AuditLog::open(path.as_ref())?);
+
+        Ok(())
+    }
+
+    /// Sets the largest amount a single deposit or withdrawal may move. Larger ones are rejected with
+    /// [`PaymentError::AmountExceedsLimit`] before they reach the account
+    pub fn set_max_tx_amount(&mut self, max: Option<Amount>) {
+        self.max_tx_amount = max;
+    }
+
+    /// Sets the risk rules deposits, withdrawals, and transfers are checked against before they reach the account. A
+    /// transaction that breaks one is rejected with [`PaymentError::DailyWithdrawalLimitExceeded`] or
+    /// [`PaymentError::RateLimitExceeded`], and the limit on single transactions, if any, replaces the one set with
+    /// [`Engine::set_max_tx_amount`]. What clients have done so far is forgotten whenever the limits are set. Built-in
+    /// rules in the limits are registered as with [`Engine::add_rule`]
+    pub fn set_risk_limits(&mut self, mut limits: Option<RiskLimits>) {
+        if let Some(limits) = &mut limits {
+            if let Some(max) = limits.max_tx_amount {
+                self.max_tx_amount = Some(max);
+            }
+
+            if let Some(rule) = limits.structuring.take() {
+                self.add_rule(rule);
+            }
+
+            if let Some(rule) = limits.rapid_disputes.take() {
+                self.add_rule(rule);
+            }
+        }
+
+        self.risk = limits.map(RiskTracker::new);
+    }
+
+    /// Registers a rule to consult before applying each transaction, after any registered before it. The first rule
+    /// to reject a transaction rejects it with [`PaymentError::RuleViolation`], and transactions a rule flags are
+    /// logged and kept in [`Engine::flagged`]. Rules learn of the transactions of an atomic batch once the whole batch
+    /// has been applied
+    pub fn add_rule(&mut self, rule: impl RiskRule + 'static) {
+        self.rules.push(RuleHook(Box::new(rule)));
+    }
+
+    /// Every transaction a rule flagged as suspicious, in order, whether or not it was applied
+    pub fn flagged(&self) -> &[RuleFlag] {
+        &self.flagged
+    }
+
+    /// Starts accruing interest at `apr_bps` basis points a year on the available funds of active accounts, for inputs
+    /// with a timestamp column. When a transaction is timestamped after the end of a period, each account is first
+    /// credited its share of the APR for every period that ended, as an `interest` transaction timestamped at the end
+    /// of the period, with an id counting down from `u32::MAX`. Transactions reusing one of those ids are rejected, and
+    /// no more interest is credited once the ids come down to the highest id of the other transactions. With `None`,
+    /// interest isn't accrued
+    pub fn set_interest(&mut self, apr_bps: Option<u32>, period: InterestPeriod) {
+        self.interest = apr_bps.map(|apr_bps| Accrual::new(apr_bps, period));
+    }
+
+    /// Every interest credit that was applied, in order
+    pub fn interest_credits(&self) -> &[InterestCredit] {
+        &self.interest_credits
+    }
+
+    /// Sets how a deposit or withdrawal id far below the highest id seen is handled. With `None`, ids aren't checked
+    /// for wraparound
+    pub fn set_id_wraparound(&mut self, policy: Option<IdWraparound>) {
+        self.id_wraparound = policy;
+    }
+
+    /// When enabled, a dispute of an id that no deposit or withdrawal has used yet is rejected with
+    /// [`PaymentError::DisputeBeforeDeposit`], since in a chronological feed it can only be a data error
+    pub fn set_strict_order(&mut self, strict_order: bool) {
+        self.seen_ids = match strict_order {
+            true => Some(HashSet::new()),
+            false => None,
+        };
+    }
+
+    /// When enabled, every transaction rejected with [`PaymentError::ClientMismatch`] is recorded so it can be reviewed
+    /// as potential fraud with [`Engine::client_mismatches`]
+    pub fn set_strict_clients(&mut self, strict_clients: bool) {
+        self.client_mismatches = match strict_clients {
+            true => Some(Vec::new()),
+            false => None,
+        };
+    }
+
+    /// When enabled, every transaction that is applied, and every dispute resolved by the dispute expiry, is recorded as
+    /// a [`LedgerEvent`] holding the accounts it changed, so the accounts can be derived again from
+    /// [`Engine::events`], as of any point, with [`Engine::replay`]
+    pub fn set_record_events(&mut self, record: bool) {
+        self.events = match record {
+            true => Some(Vec::new()),
+            false => None,
+        };
+    }
+
+    /// Every event applied to the accounts, in the order it was applied. Empty unless recording events
+    pub fn events(&self) -> &[LedgerEvent] {
+        self.events.as_deref().unwrap_or(&[])
+    }
+
+    /// An engine whose accounts are `events` folded over no accounts in order, and which goes on recording events.
+    /// Replaying every event an engine recorded gives its accounts, in the same order, and replaying only the events up
+    /// to some point gives the accounts as they were at that point. Only the accounts are derived from the events, so
+    /// transactions from before the replay can't be disputed in the replayed engine
+    pub fn replay(events: impl IntoIterator<Item = LedgerEvent>) -> Engine {
+        let mut engine = Engine::new();
+        let mut recorded = Vec::new();
+
+        for event in events {
+            event.apply_to(&mut engine.accounts);
+            recorded.push(event);
+        }
+
+        engine.events = Some(recorded);
+        engine
+    }
+
+    /// The transactions that referenced another client's transaction, in the order they were processed. Empty unless
+    /// strict client checking is enabled
+    pub fn client_mismatches(&self) -> &[ClientMismatch] {
+        self.client_mismatches.as_deref().unwrap_or(&[])
+    }
+
+    /// When enabled, a deposit, withdrawal, transfer, fee, or conversion with the same client and id as one already
+    /// applied is skipped rather than applied again, and counted in [`EngineMetrics::duplicates`]. The transactions
+    /// applied are kept in the state [`Engine::save_state`] writes, so a file submitted again in a later run is skipped
+    /// too
+    pub fn set_skip_duplicates(&mut self, skip: bool) {
+        self.applied = match skip {
+            true => Some(HashSet::new()),
+            false => None,
+        };
+    }
+
+    /// When enabled, a transaction timestamped before the latest timestamp seen is rejected with
+    /// [`PaymentError::OutOfOrder`]. Transactions without a timestamp are never rejected for their order
+    pub fn set_reject_out_of_order(&mut self, reject: bool) {
+        self.reject_out_of_order = reject;
+    }
+
+    /// Automatically resolves a dispute once this many transactions have been processed after it without it being
+    /// resolved or charged back. The held funds are returned to available funds
+    pub fn set_dispute_expiry(&mut self, window: Option<u64>) {
+        self.dispute_expiry = window;
+    }
+
+    /// Automatically expires a dispute once the latest timestamp seen is this many days after it was opened. A dispute
+    /// opens at its own timestamp or, without one, at the latest timestamp seen before it, so disputes only expire by
+    /// age in inputs with a timestamp column
+    pub fn set_dispute_expiry_days(&mut self, days: Option<u32>) {
+        self.dispute_expiry_days = days;
+    }
+
+    /// Sets what happens to a dispute once it expires. Disputes are resolved by default
+    pub fn set_dispute_expiry_action(&mut self, action: DisputeExpiryAction) {
+        self.dispute_expiry_action = action;
+    }
+
+    /// Whether disputes expire after some number of transactions or days
+    fn expires_disputes(&self) -> bool {
+        self.dispute_expiry.is_some() || self.dispute_expiry_days.is_some()
+    }
+
+    /// Applies a batch of transactions all or nothing. If any transaction is rejected, every change made by the batch is
+    /// rolled back and the index of the rejected transaction is returned along with why it was rejected. The state is
+    /// copied before the batch is applied, so batches are best kept small relative to the number of accounts. Lock
+    /// callbacks are only invoked once the whole batch has been applied
+    pub fn apply_atomic(&mut self, txns: &[Transaction]) -> Result<(), (usize, PaymentError)> {
+        let saved = (
+            self.accounts.clone(),
+            self.history.clone(),
+            self.metrics.clone(),
+            self.gaps.clone(),
+            self.open_disputes.clone(),
+            self.seen_ids.clone(),
+            self.highest_id,
+            self.latest_timestamp,
+            self.applied.clone(),
+            self.risk.clone(),
+            self.interest,
+        );
+        let on_lock = self.on_lock.take();
+        let on_account_change = self.on_account_change.take();
+        let unsaved = self.unsaved.len();
+        let audited = self.audit.as_ref().map_or(0, AuditLog::pending);
+        let recorded = self.events.as_ref().map_or(0, Vec::len);
+        let flagged = self.flagged.len();
+        let credited = self.interest_credits.len();
+        let mismatched = self.client_mismatches.as_ref().map_or(0, Vec::len);
+        let lock_events = self.lock_audit.len();
+        let converted = self.conversions.len();
+        self.batch_applied.clear();
+        self.in_batch = true;
+
+        for (index, tx) in txns.iter().enumerate() {
+            if let Err(err) = self.apply(*tx) {
+                let (
+                    accounts,
+                    history,
+                    metrics,
+                    gaps,
+                    open_disputes,
+                    seen_ids,
+                    highest_id,
+                    latest_timestamp,
+                    applied,
+                    risk,
+                    interest,
+                ) = saved;
+                self.unsaved.truncate(unsaved);
+                self.in_batch = false;
+
+                if let Some(audit) = &mut self.audit {
+                    audit.truncate(audited);
+                }
+
+                if let Some(events) = &mut self.events {
+                    events.truncate(recorded);
+                }
+
+                self.flagged.truncate(flagged);
+                self.interest_credits.truncate(credited);
+
+                if let Some(mismatches) = &mut self.client_mismatches {
+                    mismatches.truncate(mismatched);
+                }
+
+                self.lock_audit.truncate(lock_events);
+                self.conversions.truncate(converted);
+                self.batch_applied.clear();
+
+                self.accounts = accounts;
+                self.history = history;
+                self.metrics = metrics;
+                self.gaps = gaps;
+                self.open_disputes = open_disputes;
+                self.seen_ids = seen_ids;
+                self.highest_id = highest_id;
+                self.latest_timestamp = latest_timestamp;
+                self.applied = applied;
+                self.risk = risk;
+                self.interest = interest;
+                self.on_lock = on_lock;
+                self.on_account_change = on_account_change;
+
+                return Err((index, PaymentError::from_rejection(err, tx.id)));
+            }
+        }
+
+        // The batch's changes are saved to the store, and its audit records written, along with the next transaction, or
+        // by `flush_store`
+        self.on_lock = on_lock;
+        self.on_account_change = on_account_change;
+        self.in_batch = false;
+
+        for tx in std::mem::take(&mut self.batch_applied) {
+            self.notify_rules(&tx);
+        }
+
+        if let Some(LockHook(callback)) = &mut self.on_lock {
+            for tx in txns {
+                if let TransactionType::Chargeback | TransactionType::Lock = tx.tx_type {
+                    callback(tx.client, tx.id);
+                }
+            }
+        }
+
+        if let Some(AccountHook(callback)) = &mut self.on_account_change {
+            for account in self.accounts.iter() {
+                if saved.0.get(account.client, account.currency) != Some(account) {
+                    callback(account);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Registers a callback invoked with the client and transaction id of every chargeback and lock that is applied, as
+    /// each one locks the account. Replaces any previously registered callback
+    pub fn on_lock(&mut self, callback: impl FnMut(ClientId, u32) + Send + 'static) {
+        self.on_lock = Some(LockHook(Box::new(callback)));
+    }
+
+    /// Registers a callback invoked with every account whose balances or status change, once the transaction, expired
+    /// dispute, or atomic batch that changed it has been applied. Replaces any previously registered callback
+    pub fn on_account_change(&mut self, callback: impl FnMut(&Account) + Send + 'static) {
+        self.on_account_change = Some(AccountHook(Box::new(callback)));
+    }
+
+    /// Starts tracking gaps in the sequence of deposit and withdrawal ids
+    pub fn detect_gaps(&mut self) {
+        self.gaps = Some(GapDetector::default());
+    }
+
+    /// The gaps found so far, if gap detection is enabled
+    pub fn gaps(&self) -> Option<&GapDetector> {
+        self.gaps.as_ref()
+    }
+
+    /// The `n` accounts with the largest total balance, largest first. Accounts with equal totals are ordered by client id
+    pub fn top_accounts_by_total(&self, n: usize) -> Vec<&Account> {
+        let mut accounts: Vec<&Account> = self.accounts.iter().collect();
+        accounts.sort_by(|a, b| b.total.cmp(&a.total).then(a.client.cmp(&b.client)));
+        accounts.truncate(n);
+        accounts
+    }
+
+    /// Applies a single transaction. A rejected transaction leaves every account unchanged, and the error says why it
+    /// was rejected. Either way the transaction is counted in the engine's metrics
+    pub fn process(&mut self, tx: Transaction) -> Result<(), PaymentError> {
+        self.apply(tx)
+            .map_err(|err| PaymentError::from_rejection(err, tx.id))
+    }
+
+    /// The accounts in the order their clients were first seen
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.iter()
+    }
+
+    /// Consumes the engine, returning the accounts sorted by client id, so the same transactions always give the same
+    /// output whatever order the clients appeared in
+    pub fn into_sorted_accounts(self) -> Vec<Account> {
+        let mut accounts = self.into_accounts();
+        accounts.sort_unstable_by_key(|account| (account.client, account.currency));
+        accounts
+    }
+
+    /// Consumes the engine, returning the accounts in the order their clients were first seen
+    pub fn into_accounts(self) -> Vec<Account> {
+        self.accounts.into_vec()
+    }
+
+    fn apply(&mut self, tx: Transaction) -> Result<(), Error> {
+        let key = match (&self.applied, tx.tx_type) {
+            (
+                Some(applied),
+                TransactionType::Deposit
+                | TransactionType::Withdraw
+                | TransactionType::Transfer
+                | TransactionType::Fee
+                | TransactionType::Convert,
+            ) => {
+                if applied.contains(&(tx.client, tx.id)) {
+                    debug!("Skipping {:?}, which was already applied", tx);
+                    self.metrics.duplicates += 1;
+                    return Ok(());
+                }
+
+                Some((tx.client, tx.id))
+            }
+            _ => None,
+        };
+
+        if let (Some(_), Some(timestamp)) = (&self.interest, tx.timestamp) {
+            self.accrue_interest(timestamp);
+        }
+
+        if let (
+            Some(gaps),
+            TransactionType::Deposit | TransactionType::Withdraw | TransactionType::Transfer,
+        ) = (&mut self.gaps, tx.tx_type)
+        {
+            gaps.observe(tx.id);
+        }
+
+        if let (
+            Some(seen),
+            TransactionType::Deposit | TransactionType::Withdraw | TransactionType::Transfer,
+        ) = (&mut self.seen_ids, tx.tx_type)
+        {
+            seen.insert(tx.id);
+        }
+
+        let watched = match (&self.on_account_change, &self.events, self.in_batch) {
+            (Some(_), _, false) | (_, Some(_), _) => Some(self.watch_accounts(&tx)),
+            _ => None,
+        };
+
+        let audited = self.audit.as_ref().map(|_| {
+            let currency = tx.currency.unwrap_or(self.currency);
+            (
+                currency,
+                self.accounts.get(tx.client, currency).map(Balances::of),
+            )
+        });
+
+        let (tx_type, id) = (tx.tx_type, tx.id);
+        let res = self.apply_transaction(tx);
+        // Interest credited by the engine isn't an input, so it's left out of the counts that dispute expiry, the
+        // error ratio, risk limits and rules go by
+        let from_input = !self.crediting_interest;
+
+        if from_input {
+            self.metrics.processed += 1;
+        }
+
+        if let (Some(applied), Some(key), Ok(())) = (&mut self.applied, key, &res) {
+            applied.insert(key);
+        }
+
+        if let (Some(risk), Ok(()), true) = (&mut self.risk, &res, from_input) {
+            risk.record(&tx, tx.currency.unwrap_or(self.currency));
+        }
+
+        match (&res, self.in_batch, from_input) {
+            (Ok(()), false, true) => self.notify_rules(&tx),
+            (Ok(()), true, true) => self.batch_applied.push(tx),
+            _ => {}
+        }
+
+        if let (Some((clients, before)), Ok(())) = (watched, &res) {
+            let accounts = &self.accounts;
+            let changed: Vec<&Account> = clients
+                .iter()
+                .flat_map(|&client| accounts.of_client(client))
+                .filter(|account| !before.contains(account))
+                .collect();
+
+            if let (Some(AccountHook(callback)), false) =
+                (&mut self.on_account_change, self.in_batch)
+            {
+                for account in &changed {
+                    callback(account);
+                }
+            }
+
+            if let Some(events) = &mut self.events {
+                events.push(LedgerEvent {
+                    tx: id,
+                    tx_type,
+                    timestamp: tx.timestamp,
+                    expired: false,
+                    changes: changed.into_iter().map(AccountChange::from).collect(),
+                });
+            }
+        }
+
+        if from_input {
+            *self.metrics.by_type.entry(tx_type).or_insert(0) += 1;
+        }
+
+        if let Err(err) = &res {
+            if from_input {
+                self.metrics.rejected += 1;
+            }
+
+            if let Some(&PaymentError::ClientMismatch { client, owner, tx }) = err.downcast_ref() {
+                self.metrics.client_mismatches += 1;
+
+                if let Some(mismatches) = &mut self.client_mismatches {
+                    mismatches.push(ClientMismatch {
+                        tx_type,
+                        client,
+                        owner,
+                        tx,
+                    });
+                }
+            }
+        }
+
+        if let (Some(audit), Some((currency, before))) = (&mut self.audit, audited) {
+            let outcome = match &res {
+                Ok(()) => "applied",
+                Err(err) => err
+                    .downcast_ref::<PaymentError>()
+                    .map_or("error", PaymentError::code),
+            };
+
+            audit.record(&AuditRecord {
+                tx: id,
+                tx_type,
+                client: tx.client,
+                currency,
+                amount: tx.amount,
+                before,
+                after: self.accounts.get(tx.client, currency).map(Balances::of),
+                outcome,
+            })?;
+        }
+
+        if tx_type == TransactionType::Dispute && res.is_ok() {
+            if let Some(index) = self.history.position(id) {
+                if let Some(disputed_tx) = self.history.at_mut(index) {
+                    disputed_tx.disputed_at = self.metrics.processed;
+                    disputed_tx.disputed_time = tx.timestamp.or(self.latest_timestamp);
+                    disputed_tx.escalated = false;
+                }
+
+                if self.expires_disputes() {
+                    self.open_disputes
+                        .push_back((index, self.metrics.processed));
+                }
+            }
+        }
+
+        if self.expires_disputes() {
+            self.expire_disputes()?;
+        }
+
+        if let (Some(audit), false) = (&mut self.audit, self.in_batch) {
+            audit.flush()?;
+        }
+
+        if self.store.is_some() {
+            if res.is_ok() {
+                self.unsaved.push((tx.client, id));
+
+                if let Some(to_client) = tx.to_client {
+                    self.unsaved.push((to_client, id));
+                }
+            }
+
+            if !self.in_batch {
+                self.save_changes()?;
+            }
+        }
+
+        self.history.release(id)?;
+
+        res
+    }
+
+    /// The clients whose accounts `tx` may change, which are its client, the recipient of a transfer, and the holder of
+    /// the funds of the transaction it refers to, with their accounts as they are before it is applied
+    fn watch_accounts(&mut self, tx: &Transaction) -> (Vec<ClientId>, Vec<Account>) {
+        let holder = match self.history.get_mut(tx.id) {
+            Ok(Some(entry)) => Some(entry.holder()),
+            _ => None,
+        };
+        let mut clients = vec![tx.client];
+
+        for client in tx.to_client.into_iter().chain(holder) {
+            if !clients.contains(&client) {
+                clients.push(client);
+            }
+        }
+
+        let before = clients
+            .iter()
+            .flat_map(|&client| self.accounts.of_client(client).cloned())
+            .collect();
+
+        (clients, before)
+    }
+
+    /// Saves the accounts and transactions changed since the last save to the store, if there is one
+    fn save_changes(&mut self) -> Result<(), Error> {
+        let store = match &mut self.store {
+            Some(StoreHandle(store)) => store,
+            None => return Ok(()),
+        };
+
+        for (client, id) in self.unsaved.drain(..) {
+            for account in self.accounts.of_client(client) {
+                store.save_account(account)?;
+            }
+
+            if let Some(entry) = self.history.get_mut(id)? {
+                store.save_transaction(entry)?;
+            }
+
+            self.history.release(id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the deposit or withdrawal with `id` back from the store if it isn't in memory, so it can be disputed or
+    /// its id checked for reuse. Stored transactions are keyed by id alone, so once ids have wrapped around they
+    /// can't be told apart from the current id space and aren't loaded
+    fn load_from_store(&mut self, id: u32) -> Result<(), Error> {
+        let store = match &mut self.store {
+            Some(StoreHandle(store)) if self.metrics.id_wraparounds == 0 => store,
+            _ => return Ok(()),
+        };
+
+        if self.history.get_mut(id)?.is_some() {
+            return Ok(());
+        }
+
+        if let Some(entry) = store.load_transaction(id)? {
+            if let Some(seen) = &mut self.seen_ids {
+                seen.insert(entry.id);
+            }
+
+            self.history.push(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolves or escalates the disputes that have been open for at least the dispute expiry's number of transactions
+    /// or days, oldest first. Disputes that were already resolved or charged back, or that were reopened since, are
+    /// dropped from the queue
+    fn expire_disputes(&mut self) -> Result<(), Error> {
+        while let Some(&(index, opened_at)) = self.open_disputes.front() {
+            let disputed_tx = match self.history.at_mut(index) {
+                Some(disputed_tx)
+                    if disputed_tx.under_dispute() && disputed_tx.disputed_at == opened_at =>
+                {
+                    disputed_tx
+                }
+                _ => {
+                    self.open_disputes.pop_front();
+                    continue;
+                }
+            };
+
+            let age = self.metrics.processed - opened_at;
+            let by_count = self.dispute_expiry.is_some_and(|window| age >= window);
+            let by_age = match (
+                self.dispute_expiry_days,
+                disputed_tx.disputed_time,
+                self.latest_timestamp,
+            ) {
+                (Some(days), Some(opened), Some(latest)) => {
+                    latest.days_since(opened) >= i64::from(days)
+                }
+                _ => false,
+            };
+
+            if !by_count && !by_age {
+                break;
+            }
+
+            self.open_disputes.pop_front();
+
+            if self.dispute_expiry_action == DisputeExpiryAction::Escalate {
+                warn!(
+                    "Dispute of transaction {} by client {} expired and was escalated",
+                    disputed_tx.id,
+                    disputed_tx.holder()
+                );
+                disputed_tx.escalated = true;
+                self.metrics.escalated_disputes += 1;
+
+                if self.store.is_some() {
+                    self.unsaved.push((disputed_tx.holder(), disputed_tx.id));
+                }
+
+                continue;
+            }
+
+            if let Some(account) = self
+                .accounts
+                .get_mut(disputed_tx.holder(), disputed_tx.currency)
+            {
+                let released = account
+                    .held
+                    .checked_sub(disputed_tx.held)
+                    .zip(account.available.checked_add(disputed_tx.held));
+                let before = Balances::of(account);
+
+                // A dispute that can't be released without overflowing stays open until it's decided
+                match released {
+                    Some((held, available)) => {
+                        account.held = held;
+                        account.available = available;
+
+                        if let Some(audit) = &mut self.audit {
+                            audit.record(&AuditRecord {
+                                tx: disputed_tx.id,
+                                tx_type: TransactionType::Resolve,
+                                client: account.client,
+                                currency: account.currency,
+                                amount: Some(disputed_tx.held),
+                                before: Some(before),
+                                after: Some(Balances::of(account)),
+                                outcome: "expired",
+                            })?;
+                        }
+
+                        if let Some(AccountHook(callback)) = &mut self.on_account_change {
+                            callback(account);
+                        }
+
+                        if let Some(events) = &mut self.events {
+                            events.push(LedgerEvent {
+                                tx: disputed_tx.id,
+                                tx_type: TransactionType::Resolve,
+                                timestamp: None,
+                                expired: true,
+                                changes: vec![AccountChange::from(&*account)],
+                            });
+                        }
+                    }
+                    None => {
+                        warn!(
+                            "Dispute of transaction {} can't expire without overflowing the balances of client {}",
+                            disputed_tx.id,
+                            disputed_tx.holder()
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            disputed_tx.dispute_status = DisputeStatus::Resolved;
+            disputed_tx.held = Amount::ZERO;
+            disputed_tx.deferred = Amount::ZERO;
+            self.metrics.expired_disputes += 1;
+            let id = disputed_tx.id;
+
+            if self.store.is_some() {
+                self.unsaved.push((disputed_tx.holder(), id));
+            } else {
+                self.history.release(id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Credits interest to every active account with available funds for each period that ended by `timestamp`. The
+    /// first period starts at the latest timestamp seen, including in the run resumed from
+    fn accrue_interest(&mut self, timestamp: Timestamp) {
+        // Taken while the credits are applied, so applying them doesn't accrue interest again
+        let mut accrual = match self.interest.take() {
+            Some(accrual) => accrual,
+            None => return,
+        };
+        let start = self.latest_timestamp.unwrap_or(timestamp);
+        self.crediting_interest = true;
+
+        'periods: for period_end in accrual.ended_by(start, timestamp) {
+            let earning: Vec<(ClientId, Currency, Amount)> = self
+                .accounts
+                .iter()
+                .filter(|account| !account.status.is_locked() && account.available > Amount::ZERO)
+                .map(|account| (account.client, account.currency, account.available))
+                .collect();
+
+            for (client, currency, balance) in earning {
+                let amount = accrual.interest_on(balance);
+
+                if amount <= Amount::ZERO {
+                    continue;
+                }
+
+                let id = match accrual.next_id() {
+                    Some(id) => id,
+                    None => {
+                        warn!("Interest transaction ids ran into the inputs' ids, so no more interest is credited");
+                        break 'periods;
+                    }
+                };
+                let tx = Transaction {
+                    currency: Some(currency),
+                    timestamp: Some(period_end),
+                    ..Transaction::new(TransactionType::Interest, client, id, Some(amount))
+                };
+
+                match self.apply(tx) {
+                    Ok(()) => self.interest_credits.push(InterestCredit {
+                        tx: tx.id,
+                        client,
+                        currency,
+                        period_end,
+                        balance,
+                        amount,
+                    }),
+                    Err(err) => warn!("Failed to credit interest to client {}: {}", client, err),
+                }
+            }
+        }
+
+        self.crediting_interest = false;
+        self.interest = Some(accrual);
+    }
+
+    /// Consults every rule about `tx`, recording any flags, until one rejects it
+    fn evaluate_rules(&mut self, tx: &Transaction) -> Result<(), PaymentError> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+
+        let currency = tx.currency.unwrap_or(self.currency);
+        let empty;
+        let account = match self.accounts.get(tx.client, currency) {
+            Some(account) => account,
+            None => {
+                empty = Account::new(tx.client, currency);
+                &empty
+            }
+        };
+
+        for RuleHook(rule) in &self.rules {
+            match rule.evaluate(account, tx) {
+                RuleOutcome::Allow => {}
+                RuleOutcome::Flag(reason) => {
+                    warn!("Flagged transaction {}: {}", tx.id, reason);
+                    self.flagged.push(RuleFlag {
+                        tx: tx.id,
+                        client: tx.client,
+                        reason,
+                    });
+                }
+                RuleOutcome::Reject(reason) => {
+                    return Err(PaymentError::RuleViolation { tx: tx.id, reason });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tells every rule about a transaction that was applied
+    fn notify_rules(&mut self, tx: &Transaction) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        let currency = tx.currency.unwrap_or(self.currency);
+        let empty;
+        let account = match self.accounts.get(tx.client, currency) {
+            Some(account) => account,
+            None => {
+                empty = Account::new(tx.client, currency);
+                &empty
+            }
+        };
+
+        for RuleHook(rule) in &mut self.rules {
+            rule.applied(account, tx);
+        }
+    }
+
+    fn apply_transaction(&mut self, mut tx: Transaction) -> Result<(), Error> {
+        let client = tx.client;
+        let mut fee = Amount::ZERO;
+
+        // Interest creates money, so only the engine may credit it. The accrual is taken while it does
+        if tx.tx_type == TransactionType::Interest && !self.crediting_interest {
+            return Err(PaymentError::Rejected {
+                tx: tx.id,
+                reason: "interest is only credited by the engine".to_string(),
+            }
+            .into());
+        }
+
+        if let Some(accrual) = &mut self.interest {
+            if !accrual.observe(tx.id) {
+                return Err(PaymentError::Rejected {
+                    tx: tx.id,
+                    reason: format!("transaction id {} was already used for interest", tx.id),
+                }
+                .into());
+            }
+        }
+
+        self.load_from_store(tx.id)?;
+
+        if let TransactionType::Deposit
+        | TransactionType::Withdraw
+        | TransactionType::Transfer
+        | TransactionType::Fee
+        | TransactionType::Convert
+        | TransactionType::SetLimit
+        | TransactionType::Interest = tx.tx_type
+        {
+            tx.currency = Some(tx.currency.unwrap_or(self.currency));
+        }
+
+        if let (
+            TransactionType::Deposit
+            | TransactionType::Withdraw
+            | TransactionType::Transfer
+            | TransactionType::Fee
+            | TransactionType::Convert
+            | TransactionType::Interest,
+            Some(amount),
+        ) = (tx.tx_type, tx.amount)
+        {
+            if amount <= Amount::ZERO {
+                return Err(PaymentError::NonPositiveAmount { tx: tx.id }.into());
+            }
+        }
+
+        if let (
+            TransactionType::Deposit | TransactionType::Withdraw | TransactionType::Transfer,
+            Some(amount),
+            Some(limit),
+        ) = (tx.tx_type, tx.amount, self.max_tx_amount)
+        {
+            if amount > limit {
+                return Err(PaymentError::AmountExceedsLimit { tx: tx.id, limit }.into());
+            }
+        }
+
+        // Interest credits aren't inputs, so neither risk limits nor rules are consulted about them
+        if !self.crediting_interest {
+            if let Some(risk) = &self.risk {
+                risk.check(&tx)?;
+            }
+
+            self.evaluate_rules(&tx)?;
+        }
+
+        if let Some(status) = self.accounts.status(client) {
+            let rejected = matches!(
+                (tx.tx_type, self.locked_accounts),
+                (TransactionType::Deposit, LockedAccountPolicy::RejectAll)
+                    | (
+                        TransactionType::Withdraw
+                            | TransactionType::Transfer
+                            | TransactionType::Fee
+                            | TransactionType::Convert,
+                        _
+                    )
+            );
+
+            if rejected && status.is_locked() {
+                return Err(PaymentError::AccountLocked { client, tx: tx.id }.into());
+            }
+        }
+
+        // A transfer is rejected if either account is locked, whatever the policy for deposits
+        if let (TransactionType::Transfer, Some(to_client)) = (tx.tx_type, tx.to_client) {
+            if self
+                .accounts
+                .status(to_client)
+                .is_some_and(|status| status.is_locked())
+            {
+                return Err(PaymentError::AccountLocked {
+                    client: to_client,
+                    tx: tx.id,
+                }
+                .into());
+            }
+        }
+
+        if let (
+            TransactionType::Deposit | TransactionType::Withdraw | TransactionType::Transfer,
+            Some(policy),
+        ) = (tx.tx_type, self.id_wraparound)
+        {
+            self.observe_id(tx.id, policy)?;
+        }
+
+        if let Some(timestamp) = tx.timestamp {
+            self.observe_timestamp(&tx, timestamp)?;
+        }
+
+        if let (TransactionType::Deposit, Some(amount)) = (tx.tx_type, tx.amount) {
+            fee = basis_points(amount, self.deposit_fee_bps);
+            tx.amount = Some(amount.minus(fee, &tx)?);
+        }
+
+        if tx.tx_type == TransactionType::Dispute && !self.partial_disputes {
+            tx.amount = None;
+        }
+
+        if let (TransactionType::Dispute, Some(seen)) = (tx.tx_type, &self.seen_ids) {
+            if !seen.contains(&tx.id) {
+                return Err(PaymentError::DisputeBeforeDeposit { tx: tx.id }.into());
+            }
+        }
+
+        if let (TransactionType::Dispute, Some(max)) = (tx.tx_type, self.max_disputes_per_tx) {
+            if let Some(disputed_tx) = self.history.get_mut(tx.id)? {
+                if disputed_tx.disputes >= max {
+                    return Err(PaymentError::DisputeLimitExceeded { tx: tx.id }.into());
+                }
+            }
+        }
+
+        // Credit lines are handed out by admins, as otherwise a client could give themselves one and withdraw into it
+        if let TransactionType::Lock | TransactionType::Unlock | TransactionType::SetLimit =
+            tx.tx_type
+        {
+            let actor = tx.actor.unwrap_or(client);
+            let allowed = self.admins.contains(&actor)
+                || (tx.tx_type == TransactionType::Lock && actor == client);
+
+            if !allowed {
+                return Err(PaymentError::Unauthorized {
+                    actor,
+                    client,
+                    tx: tx.id,
+                }
+                .into());
+            }
+        }
+
+        // A conversion without a rate gets the table's, and realizes the spread between it and the table's rate before
+        // its spread
+        let mid_rate = match tx.tx_type {
+            TransactionType::Convert => {
+                let (rate, mid_rate) = self.conversion_rate(&tx)?;
+                tx.rate = Some(rate);
+                Some(mid_rate)
+            }
+            _ => None,
+        };
+
+        let charged = match (tx.tx_type, tx.amount) {
+            (TransactionType::Withdraw, Some(amount)) => self.withdrawal_fees.fee(amount),
+            (TransactionType::Fee, Some(amount)) => amount,
+            _ => Amount::ZERO,
+        };
+
+        // The account the transaction applies to, which is in the currency of the transaction it refers to if it doesn't
+        // name one
+        let currency = match (tx.currency, tx.tx_type) {
+            (Some(currency), _) => currency,
+            (None, TransactionType::Lock | TransactionType::Unlock) => self.currency,
+            (None, _) => self
+                .history
+                .get_mut(tx.id)?
+                .map_or(self.currency, |entry| entry.currency),
+        };
+
+        let (tx_type, id) = (tx.tx_type, tx.id);
+        process(
+            &mut self.accounts,
+            &mut self.history,
+            tx,
+            self.pending_deposits,
+            self.withdrawal_disputes,
+            (charged, self.fee_floor),
+            self.conversion_rounding,
+        )?;
+        self.metrics.deposit_fees = self.metrics.deposit_fees.saturating_add(fee);
+        self.metrics.fees = self.metrics.fees.saturating_add(charged);
+
+        match (tx_type, tx.amount) {
+            (TransactionType::Deposit, Some(amount)) => {
+                self.metrics.deposited = self
+                    .metrics
+                    .deposited
+                    .saturating_add(amount.saturating_add(fee))
+            }
+            (TransactionType::Withdraw, Some(amount)) => {
+                self.metrics.withdrawn = self.metrics.withdrawn.saturating_add(amount)
+            }
+            (TransactionType::Dispute, _) => self.metrics.disputes_opened += 1,
+            (TransactionType::Resolve, _) => self.metrics.disputes_resolved += 1,
+            (TransactionType::Chargeback, _) => self.metrics.chargebacks += 1,
+            _ => {}
+        }
+
+        if let (TransactionType::Chargeback | TransactionType::Lock, Some(LockHook(callback))) =
+            (tx_type, &mut self.on_lock)
+        {
+            callback(client, id);
+        }
+
+        if let (Some(mid_rate), Some(amount), Some(to), Some(rate)) =
+            (mid_rate, tx.amount, tx.to_currency, tx.rate)
+        {
+            let rounding = self.conversion_rounding;
+            let converted = rate.convert(amount, rounding).unwrap_or_default();
+
+            self.conversions.push(ConversionEvent {
+                tx: id,
+                client,
+                from: currency,
+                to,
+                amount,
+                rate,
+                converted,
+                spread: mid_rate
+                    .convert(amount, rounding)
+                    .and_then(|mid| mid.checked_sub(converted))
+                    .unwrap_or_default(),
+            });
+        }
+
+        if let TransactionType::Lock | TransactionType::Unlock = tx_type {
+            self.lock_audit.push(LockEvent {
+                tx: id,
+                client,
+                action: tx_type,
+                actor: tx.actor.unwrap_or(client),
+            });
+        }
+
+        if let Some(account) = self.accounts.get_mut(client, currency) {
+            if let Some(source) = &self.source {
+                account.source = Some(source.clone());
+            }
+
+            if tx.timestamp.is_some() {
+                account.last_activity = tx.timestamp;
+            }
+
+            if self.round_each_op {
+                account.round_to_scale();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The rate `tx` converts at, and the rate it realizes a spread against. A conversion with a rate of its own
+    /// realizes its spread against the table's rate, before the table's spread, if there is one
+    fn conversion_rate(&self, tx: &Transaction) -> Result<(Rate, Rate), PaymentError> {
+        let from = tx.currency.unwrap_or(self.currency);
+        let to = tx
+            .to_currency
+            .filter(|&to| to != from)
+            .ok_or(PaymentError::InvalidConversion { tx: tx.id })?;
+
+        match (tx.rate, self.rates.get(from, to)) {
+            (Some(rate), table) => Ok((rate, table.map_or(rate, |(_, mid)| mid))),
+            (None, Some(rates)) => Ok(rates),
+            (None, None) => Err(PaymentError::RateNotFound {
+                from,
+                to,
+                tx: tx.id,
+            }),
+        }
+    }
+
+    /// Checks the timestamp of a transaction against the latest one seen, and against the transaction a dispute,
+    /// resolve, chargeback, refund, or settle refers to, which must not be from after it
+    fn observe_timestamp(&mut self, tx: &Transaction, timestamp: Timestamp) -> Result<(), Error> {
+        if let Some(latest) = self.latest_timestamp.filter(|&latest| timestamp < latest) {
+            if self.reject_out_of_order {
+                return Err(PaymentError::OutOfOrder { tx: tx.id, latest }.into());
+            }
+        }
+
+        if let TransactionType::Dispute
+        | TransactionType::Resolve
+        | TransactionType::Chargeback
+        | TransactionType::Refund
+        | TransactionType::Settle = tx.tx_type
+        {
+            let referenced = self
+                .history
+                .get_mut(tx.id)?
+                .and_then(|entry| entry.timestamp);
+
+            if referenced.is_some_and(|referenced| timestamp < referenced) {
+                return Err(PaymentError::ReferencesLaterTransaction { tx: tx.id }.into());
+            }
+        }
+
+        self.latest_timestamp = self.latest_timestamp.max(Some(timestamp));
+
+        Ok(())
+    }
+
+    /// Checks a deposit or withdrawal id against the highest id seen. An id more than half the id space below it can
+    /// only come from a feed whose ids wrapped around past `u32::MAX`
+    fn observe_id(&mut self, id: u32, policy: IdWraparound) -> Result<(), Error> {
+        let highest = match self.highest_id {
+            Some(highest) if highest > id && highest - id > u32::MAX / 2 => match policy {
+                IdWraparound::Error => return Err(PaymentError::IdWraparound { tx: id }.into()),
+                IdWraparound::Allow => {
+                    self.history.start_epoch();
+                    self.metrics.id_wraparounds += 1;
+                    id
+                }
+            },
+            Some(highest) => highest.max(id),
+            None => id,
+        };
+
+        self.highest_id = Some(highest);
+
+        Ok(())
+    }
+}
+
+/// Applies a single transaction to the accounts. Only deposits, withdrawals, and transfers that were successfully
+/// applied are recorded in the history, so a rejected transaction can never be disputed into held funds that the
+/// account never had. `fee` is the fee a withdrawal or fee transaction charges, and the floor it may take available
+/// funds down to, and `rounding` is how a conversion rounds the amount it credits
+fn process(
+    accounts: &mut Accounts,
+    history: &mut History,
+    tx: Transaction,
+    pending_deposits: bool,
+    withdrawal_disputes: WithdrawalDisputeMode,
+    fee: (Amount, Option<Amount>),
+    rounding: RoundingMode,
+) -> Result<(), Error> {
+    use TransactionType::*;
+
+    if let Deposit | Withdraw | Transfer = tx.tx_type {
+        if history
+            .get_mut(tx.id)?
+            .is_some_and(|item| item.dispute_status == DisputeStatus::ChargedBack)
+        {
+            return Err(PaymentError::TransactionIdReuseAfterChargeback { tx: tx.id }.into());
+        }
+    }
+
+    match tx.tx_type {
+        Deposit => {
+            deposit(accounts, tx, pending_deposits)?;
+            if let Some(entry) = LedgerEntry::new(tx) {
+                history.push(LedgerEntry {
+                    pending: pending_deposits,
+                    ..entry
+                })?;
+            }
+        }
+        Withdraw => {
+            withdraw(accounts, tx, fee)?;
+            if let Some(entry) = LedgerEntry::new(tx) {
+                history.push(entry)?;
+            }
+        }
+        Transfer => {
+            transfer(accounts, tx)?;
+            if let Some(entry) = LedgerEntry::new(tx) {
+                history.push(entry)?;
+            }
+        }
+        Dispute => dispute(accounts, tx, history, withdrawal_disputes)?,
+        Resolve => resolve(accounts, tx, history)?,
+        Chargeback => chargeback(accounts, tx, history)?,
+        Refund => refund(accounts, tx, history)?,
+        Settle => settle(accounts, tx, history)?,
+        Fee => charge_fee(accounts, tx, fee.1)?,
+        Lock => lock(accounts, tx)?,
+        Unlock => unlock(accounts, tx)?,
+        Convert => convert(accounts, tx, rounding)?,
+        SetLimit => set_limit(accounts, tx)?,
+        Interest => credit_interest(accounts, tx)?,
+    };
+
+    Ok(())
+}
+
+/// The number of decimal places amounts are reported with
+const SCALE_PLACES: u32 = 4;
+
+/// Calculates `bps` basis points of `amount`, rounded half away from zero to the output scale
+fn basis_points(amount: Amount, bps: u32) -> Amount {
+    let units = to_units(amount) * i128::from(bps);
+    from_units(round_div(units, 10_000))
+}
+
+/// Converts an amount to a whole number of the smallest unit at the output scale
+fn to_units(amount: Amount) -> i128 {
+    amount.to_units(SCALE_PLACES, RoundingMode::HalfUp)
+}
+
+fn round_to_scale(amount: Amount) -> Amount {
+    from_units(to_units(amount))
+}
+
+fn from_units(units: i128) -> Amount {
+    Amount::from_units(units, SCALE_PLACES)
+}
+
+/// The scale and rounding amounts are written to the output with
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct AmountFormat {
+    pub(crate) places: u32,
+    pub(crate) rounding: RoundingMode,
+}
+
+impl Default for AmountFormat {
+    fn default() -> Self {
+        AmountFormat {
+            places: DEFAULT_DECIMAL_PLACES,
+            rounding: RoundingMode::HalfUp,
+        }
+    }
+}
+
+impl AmountFormat {
+    /// Converts an amount to a whole number of the smallest unit at this format's scale, rounding the part below it
+    pub(crate) fn units(self, amount: Amount) -> i128 {
+        amount.to_units(self.places, self.rounding)
+    }
+
+    /// Formats an amount with every decimal place written out, ex: `1.5000`
+    fn fixed(self, amount: Amount) -> FormattedAmount {
+        FormattedAmount {
+            units: self.units(amount),
+            places: self.places,
+            trim: false,
+        }
+    }
+
+    /// Formats an amount without trailing zeros, ex: `1.5`, or `1` for a whole amount
+    fn trimmed(self, amount: Amount) -> FormattedAmount {
+        FormattedAmount {
+            trim: true,
+            ..self.fixed(amount)
+        }
+    }
+}
+
+/// An amount rounded to the output scale, written out without going through a `String`
+#[derive(Debug, Clone, Copy)]
+struct FormattedAmount {
+    units: i128,
+    places: u32,
+    trim: bool,
+}
+
+impl Display for FormattedAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scale = 10i128.pow(self.places);
+        let (whole, mut frac) = (self.units.abs() / scale, self.units.abs() % scale);
+        let mut places = self.places as usize;
+
+        if self.trim {
+            while places > 0 && frac % 10 == 0 {
+                frac /= 10;
+                places -= 1;
+            }
+        }
+
+        let sign = if self.units < 0 { "-" } else { "" };
+
+        match places {
+            0 => write!(f, "{}{}", sign, whole),
+            _ => write!(f, "{}{}.{:0width$}", sign, whole, frac, width = places),
+        }
+    }
+}
+
+impl Serialize for FormattedAmount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// An account as written to the CSV output, with the locked flag already in the configured representation
+#[derive(Serialize)]
+struct AccountRow<'a> {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(rename = "account", skip_serializing_if = "Option::is_none")]
+    account_number: Option<&'a str>,
+    available: FormattedAmount,
+    held: FormattedAmount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending: Option<FormattedAmount>,
+    total: FormattedAmount,
+    locked: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<FormattedAmount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+    /// Empty for an account without activity when the column is written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_activity: Option<Option<Timestamp>>,
+}
+
+impl<'a> AccountRow<'a> {
+    fn new(
+        account: &'a Account,
+        config: &Config,
+        currency_column: bool,
+        credit_column: bool,
+        activity_column: bool,
+    ) -> Self {
+        let format = config.amount_format();
+
+        AccountRow {
+            client: account.client,
+            currency: currency_column.then_some(account.currency),
+            account_number: account.account_number.as_deref(),
+            available: format.trimmed(account.available),
+            held: format.trimmed(account.held),
+            pending: match config.pending_deposits {
+                true => Some(format.trimmed(account.pending)),
+                false => None,
+            },
+            total: format.trimmed(account.total),
+            locked: config.locked_format.format(account.status.is_locked()),
+            credit_limit: credit_column.then(|| format.trimmed(account.credit_limit)),
+            source: account.source.as_deref(),
+            last_activity: activity_column.then_some(account.last_activity),
+        }
+    }
+}
+
+/// Whether the output has a currency column, which it only does if an account is in another currency than the
+/// configured one, so the output of a single currency is the same as before currencies were supported
+fn needs_currency_column<'a>(
+    mut accounts: impl Iterator<Item = &'a Account>,
+    config: &Config,
+) -> bool {
+    accounts.any(|account| account.currency != config.currency)
+}
+
+/// Whether the output has a `credit_limit` column, which it only does if one of the accounts has a credit limit
+fn needs_credit_column<'a>(mut accounts: impl Iterator<Item = &'a Account>) -> bool {
+    accounts.any(|account| !account.credit_limit.is_zero())
+}
+
+/// Whether the output has a `last_activity` column, which it only does if a transaction with a timestamp was applied to
+/// one of the accounts
+fn needs_activity_column<'a>(mut accounts: impl Iterator<Item = &'a Account>) -> bool {
+    accounts.any(|account| account.last_activity.is_some())
+}
+
+/// Opens the output file, or `stdout` if there isn't one, buffered with the configured capacity
+fn open_output(config: &Config) -> Result<BufWriter<Box<dyn Write>>, Error> {
+    let capacity = config
+        .output_buffer_size
+        .unwrap_or(DEFAULT_OUTPUT_BUFFER_SIZE);
+    let writer: Box<dyn Write> = match &config.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    Ok(BufWriter::with_capacity(capacity, writer))
+}
+
+fn write_output(accounts: Vec<Account>, config: &Config) -> Result<(), Error> {
+    write_csv(open_output(config)?, &accounts, config)
+}
+
+/// Writes the accounts to `writer` as CSV with the default output options, ex: to a file, a buffer, or a socket
+///
+/// ```
+/// use payments::{Engine, Transaction};
+///
+/// let mut engine = Engine::new();
+/// engine.process(Transaction::from_csv_line("deposit,1,1,1.5").unwrap()).unwrap();
+///
+/// let mut output = Vec::new();
+/// payments::write_accounts(&engine.into_accounts(), &mut output).unwrap();
+///
+/// assert_eq!(output, b"client,available,held,total,locked\n1,1.5,0,1.5,false\n");
+/// ```
+pub fn write_accounts<W: Write>(accounts: &[Account], writer: W) -> Result<(), Error> {
+    write_csv(writer, accounts, &Config::default())
+}
+
+/// Writes the accounts as CSV in the same format as the program's output, flushing `writer` once all of them are
+/// written. The writer isn't buffered any further, so wrap unbuffered writers such as files in a `BufWriter`
+pub fn write_csv<W: Write>(writer: W, accounts: &[Account], config: &Config) -> Result<(), Error> {
+    let mut writer = WriterBuilder::new().from_writer(writer);
+    let currency_column = needs_currency_column(accounts.iter(), config);
+    let credit_column = needs_credit_column(accounts.iter());
+    let activity_column = needs_activity_column(accounts.iter());
+
+    for account in accounts {
+        writer.serialize(AccountRow::new(
+            account,
+            config,
+            currency_column,
+            credit_column,
+            activity_column,
+        ))?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// An account as written to the JSON output, with the funds the client can currently withdraw spelled out. Amounts are
+/// strings with every decimal place of the output scale written out, so consumers don't parse them as floats
+#[derive(Serialize)]
+struct JsonAccountRow<'a> {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(rename = "account", skip_serializing_if = "Option::is_none")]
+    account_number: Option<&'a str>,
+    available: FormattedAmount,
+    held: FormattedAmount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending: Option<FormattedAmount>,
+    total: FormattedAmount,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<FormattedAmount>,
+    /// The available funds and any credit limit, or nothing while the account is locked
+    withdrawable: FormattedAmount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_activity: Option<Timestamp>,
+}
+
+impl<'a> JsonAccountRow<'a> {
+    fn new(account: &'a Account, config: &Config, currency_column: bool) -> Self {
+        let locked = account.status.is_locked();
+        let format = config.amount_format();
+
+        JsonAccountRow {
+            client: account.client,
+            currency: currency_column.then_some(account.currency),
+            account_number: account.account_number.as_deref(),
+            available: format.fixed(account.available),
+            held: format.fixed(account.held),
+            pending: match config.pending_deposits {
+                true => Some(format.fixed(account.pending)),
+                false => None,
+            },
+            total: format.fixed(account.total),
+            locked,
+            credit_limit: (!account.credit_limit.is_zero())
+                .then(|| format.fixed(account.credit_limit)),
+            withdrawable: format.fixed(match locked {
+                true => Amount::ZERO,
+                false => account.spendable(),
+            }),
+            source: account.source.as_deref(),
+            last_activity: account.last_activity,
+        }
+    }
+}
+
+/// Writes the accounts as a JSON array, or as one JSON object per line for [`OutputFormat::Ndjson`]
+fn write_json(accounts: &[Account], config: &Config) -> Result<(), Error> {
+    let currency_column = needs_currency_column(accounts.iter(), config);
+    let rows = accounts
+        .iter()
+        .map(|account| JsonAccountRow::new(account, config, currency_column));
+
+    let mut writer = open_output(config)?;
+
+    if config.output_format == OutputFormat::Ndjson {
+        for row in rows {
+            serde_json::to_writer(&mut writer, &row)?;
+            writeln!(writer)?;
+        }
+    } else {
+        serde_json::to_writer(&mut writer, &rows.collect::<Vec<_>>())?;
+        writeln!(writer)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// A deposit is a credit to the client’s asset account. It increases the available and total funds of the client account
+/// by the transaction amount. A pending deposit increases the pending funds instead of the available funds, until it is
+/// settled
+fn deposit(accounts: &mut Accounts, tx: Transaction, pending: bool) -> Result<(), Error> {
+    let amount = tx.amount.ok_or(PaymentError::MissingAmount { tx: tx.id })?;
+
+    let currency = tx.currency.unwrap_or_default();
+
+    // The balances are checked before the account is opened, so a deposit that overflows leaves no trace
+    let (available, pending_funds, total) = match accounts.get(tx.client, currency) {
+        Some(account) => (account.available, account.pending, account.total),
+        None => (Amount::ZERO, Amount::ZERO, Amount::ZERO),
+    };
+    let (available, pending_funds) = match pending {
+        true => (available, pending_funds.plus(amount, &tx)?),
+        false => (available.plus(amount, &tx)?, pending_funds),
+    };
+    let total = total.plus(amount, &tx)?;
+
+    let account = accounts.get_or_open(tx.client, currency);
+    account.available = available;
+    account.pending = pending_funds;
+    account.total = total;
+
+    Ok(())
+}
+
+/// A settle clears a pending deposit, referenced by id. The clients pending funds decrease by the amount of the deposit,
+/// their available funds increase by the same amount, and their total funds remain the same.
+fn settle(accounts: &mut Accounts, tx: Transaction, history: &mut History) -> Result<(), Error> {
+    let settled_tx = history
+        .get_mut(tx.id)?
+        .filter(|item| item.tx_type == TransactionType::Deposit)
+        .ok_or(PaymentError::TxNotFound { tx: tx.id })?;
+    check_owner(&tx, settled_tx.client)?;
+    check_currency(&tx, settled_tx.currency)?;
+
+    if !settled_tx.pending {
+        return Err(PaymentError::DepositNotPending { tx: tx.id }.into());
+    }
+
+    let account =
+        accounts
+            .get_mut(tx.client, settled_tx.currency)
+            .ok_or(PaymentError::AccountNotFound {
+                client: tx.client,
+                tx: tx.id,
+            })?;
+
+    let pending = account.pending.minus(settled_tx.amount, &tx)?;
+    let available = account.available.plus(settled_tx.amount, &tx)?;
+
+    account.pending = pending;
+    account.available = available;
+    settled_tx.pending = false;
+
+    Ok(())
+}
+
+/// Rejects a transaction that refers to a deposit or withdrawal of another client than its own
+fn check_owner(tx: &Transaction, owner: ClientId) -> Result<(), PaymentError> {
+    match tx.client == owner {
+        true => Ok(()),
+        false => Err(PaymentError::ClientMismatch {
+            client: tx.client,
+            owner,
+            tx: tx.id,
+        }),
+    }
+}
+
+/// Rejects a transaction that names another currency than the deposit or withdrawal it refers to, which is in
+/// `expected`. A transaction without a currency refers to the transaction in whichever currency it is
+fn check_currency(tx: &Transaction, expected: Currency) -> Result<(), PaymentError> {
+    match tx.currency {
+        Some(currency) if currency != expected => Err(PaymentError::CurrencyMismatch {
+            currency,
+            expected,
+            tx: tx.id,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// A withdraw is a debit to the client’s asset account. It decreases the available and total funds of the client account
+/// by the transaction amount. If a client does not have sufficient available funds, counting their credit limit, the
+/// withdraw will fail and the total amount of funds will not change. Funds held by open disputes can never be withdrawn.
+/// Any fee on the withdrawal is taken as well, and may leave available funds negative, down to the fee floor
+fn withdraw(
+    accounts: &mut Accounts,
+    tx: Transaction,
+    (fee, floor): (Amount, Option<Amount>),
+) -> Result<(), Error> {
+    let amount = tx.amount.ok_or(PaymentError::MissingAmount { tx: tx.id })?;
+    let account = accounts
+        .get_mut(tx.client, tx.currency.unwrap_or_default())
+        .ok_or(PaymentError::AccountNotFound {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+
+    if amount > account.spendable() {
+        return Err(PaymentError::InsufficientFunds {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    let available = account.available.minus(amount, &tx)?;
+    let total = account.total.minus(amount, &tx)?;
+
+    // held funds must remain fully backed by the total after the withdraw
+    if total
+        .minus(account.pending, &tx)?
+        .saturating_add(account.credit_limit)
+        < account.held
+    {
+        return Err(PaymentError::HeldFundsUnbacked {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    let (available, total) = debit_fee(available, total, fee, floor, &tx)?;
+
+    account.available = available;
+    account.total = total;
+
+    Ok(())
+}
+
+/// A fee is a charge the platform takes from the client. It decreases the available and total funds of the client
+/// account by the transaction amount like a withdraw, but may leave available funds negative, down to the fee floor if
+/// there is one, rather than failing for insufficient funds
+fn charge_fee(
+    accounts: &mut Accounts,
+    tx: Transaction,
+    floor: Option<Amount>,
+) -> Result<(), Error> {
+    let amount = tx.amount.ok_or(PaymentError::MissingAmount { tx: tx.id })?;
+    let account = accounts
+        .get_mut(tx.client, tx.currency.unwrap_or_default())
+        .ok_or(PaymentError::AccountNotFound {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+
+    let (available, total) = debit_fee(account.available, account.total, amount, floor, &tx)?;
+
+    account.available = available;
+    account.total = total;
+
+    Ok(())
+}
+
+/// The available and total funds left after taking `fee` from them for `tx`, or an insufficient funds error if the
+/// available funds would fall below `floor`
+fn debit_fee(
+    available: Amount,
+    total: Amount,
+    fee: Amount,
+    floor: Option<Amount>,
+    tx: &Transaction,
+) -> Result<(Amount, Amount), PaymentError> {
+    if fee == Amount::ZERO {
+        return Ok((available, total));
+    }
+
+    let available = available.minus(fee, tx)?;
+
+    if floor.is_some_and(|floor| available < floor) {
+        return Err(PaymentError::InsufficientFunds {
+            client: tx.client,
+            tx: tx.id,
+        });
+    }
+
+    Ok((available, total.minus(fee, tx)?))
+}
+
+/// A lock freezes an account outside of a chargeback, such as while an operations team investigates it. The balances
+/// don't change, but the account rejects transactions as if it were charged back until it is unlocked. An account that
+/// is already locked can't be locked again. The lock applies to the client's accounts in every currency
+fn lock(accounts: &mut Accounts, tx: Transaction) -> Result<(), Error> {
+    let status = accounts
+        .status(tx.client)
+        .ok_or(PaymentError::AccountNotFound {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+
+    if status.is_locked() {
+        return Err(PaymentError::AccountLocked {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    accounts.set_status(tx.client, AccountStatus::Frozen);
+
+    Ok(())
+}
+
+/// An unlock makes a locked account active again, whether it was locked by a lock or a chargeback. Closed accounts stay
+/// closed
+fn unlock(accounts: &mut Accounts, tx: Transaction) -> Result<(), Error> {
+    let status = accounts
+        .status(tx.client)
+        .ok_or(PaymentError::AccountNotFound {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+
+    match status {
+        AccountStatus::Active => {
+            return Err(PaymentError::AccountNotLocked {
+                client: tx.client,
+                tx: tx.id,
+            }
+            .into())
+        }
+        AccountStatus::Closed => {
+            return Err(PaymentError::Rejected {
+                tx: tx.id,
+                reason: "closed accounts can't be unlocked".to_string(),
+            }
+            .into())
+        }
+        AccountStatus::ChargedBack | AccountStatus::Frozen => {}
+    }
+
+    accounts.set_status(tx.client, AccountStatus::Active);
+
+    Ok(())
+}
+
+/// A transfer moves funds from the available funds of one client to those of the client named by `to_client`. The
+/// sender's available and total funds decrease by the amount and the recipient's increase by it, opening an account for
+/// a new recipient. Both balances are worked out before either is written, so a transfer that fails, such as for
+/// insufficient funds, changes neither account
+fn transfer(accounts: &mut Accounts, tx: Transaction) -> Result<(), Error> {
+    let amount = tx.amount.ok_or(PaymentError::MissingAmount { tx: tx.id })?;
+    let to_client = tx
+        .to_client
+        .filter(|&to_client| to_client != tx.client)
+        .ok_or(PaymentError::InvalidTransfer { tx: tx.id })?;
+    let currency = tx.currency.unwrap_or_default();
+    let sender = accounts
+        .get(tx.client, currency)
+        .ok_or(PaymentError::AccountNotFound {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+
+    if amount > sender.spendable() {
+        return Err(PaymentError::InsufficientFunds {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    let available = sender.available.minus(amount, &tx)?;
+    let total = sender.total.minus(amount, &tx)?;
+
+    if total
+        .minus(sender.pending, &tx)?
+        .saturating_add(sender.credit_limit)
+        < sender.held
+    {
+        return Err(PaymentError::HeldFundsUnbacked {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    let overflow = PaymentError::Overflow {
+        client: to_client,
+        tx: tx.id,
+    };
+    let (to_available, to_total) = match accounts.get(to_client, currency) {
+        Some(recipient) => (recipient.available, recipient.total),
+        None => (Amount::ZERO, Amount::ZERO),
+    };
+    let to_available = to_available
+        .checked_add(amount)
+        .ok_or_else(|| overflow.clone())?;
+    let to_total = to_total.checked_add(amount).ok_or(overflow)?;
+
+    if let Some(sender) = accounts.get_mut(tx.client, currency) {
+        sender.available = available;
+        sender.total = total;
+    }
+
+    let recipient = accounts.get_or_open(to_client, currency);
+    recipient.available = to_available;
+    recipient.total = to_total;
+
+    Ok(())
+}
+
+/// Interest is credited to the available and total funds of an account, normally by the engine at the end of each
+/// interest period. Like a fee, it isn't recorded in the history, so it can't be disputed
+fn credit_interest(accounts: &mut Accounts, tx: Transaction) -> Result<(), Error> {
+    let amount = tx.amount.ok_or(PaymentError::MissingAmount { tx: tx.id })?;
+    let account = accounts
+        .get_mut(tx.client, tx.currency.unwrap_or_default())
+        .ok_or(PaymentError::AccountNotFound {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+
+    let available = account.available.plus(amount, &tx)?;
+    let total = account.total.plus(amount, &tx)?;
+
+    account.available = available;
+    account.total = total;
+
+    Ok(())
+}
+
+/// A set_limit gives the client's account in its currency a credit limit of its amount, opening an account if there
+/// isn't one, so withdrawals, transfers, and conversions can take the available funds down to minus the limit. A limit
+/// of zero removes the credit line. Lowering the limit below what an account already owes leaves its balances as they
+/// are, but nothing more can be taken out until it's paid back
+fn set_limit(accounts: &mut Accounts, tx: Transaction) -> Result<(), Error> {
+    let limit = tx.amount.ok_or(PaymentError::MissingAmount { tx: tx.id })?;
+
+    if limit < Amount::ZERO {
+        return Err(PaymentError::Rejected {
+            tx: tx.id,
+            reason: "credit limits can't be negative".to_string(),
+        }
+        .into());
+    }
+
+    accounts
+        .get_or_open(tx.client, tx.currency.unwrap_or_default())
+        .credit_limit = limit;
+
+    Ok(())
+}
+
+/// A conversion moves funds between the accounts of a client in two currencies. The available and total funds in the
+/// currency of the conversion decrease by its amount, and those in `to_currency` increase by the amount converted at its
+/// rate, rounded to the output scale, opening an account in that currency if needed. Like a transfer, both balances are
+/// worked out before either is written. Conversions aren't recorded in the history, so they can't be disputed
+fn convert(accounts: &mut Accounts, tx: Transaction, rounding: RoundingMode) -> Result<(), Error> {
+    let amount = tx.amount.ok_or(PaymentError::MissingAmount { tx: tx.id })?;
+    let from = tx.currency.unwrap_or_default();
+    let to = tx
+        .to_currency
+        .filter(|&to| to != from)
+        .ok_or(PaymentError::InvalidConversion { tx: tx.id })?;
+    let converted = tx
+        .rate
+        .ok_or(PaymentError::RateNotFound {
+            from,
+            to,
+            tx: tx.id,
+        })?
+        .convert(amount, rounding)
+        .ok_or(PaymentError::Overflow {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+    let account = accounts
+        .get(tx.client, from)
+        .ok_or(PaymentError::AccountNotFound {
+            client: tx.client,
+            tx: tx.id,
+        })?;
+
+    if amount > account.spendable() {
+        return Err(PaymentError::InsufficientFunds {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    let available = account.available.minus(amount, &tx)?;
+    let total = account.total.minus(amount, &tx)?;
+
+    if total
+        .minus(account.pending, &tx)?
+        .saturating_add(account.credit_limit)
+        < account.held
+    {
+        return Err(PaymentError::HeldFundsUnbacked {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    let (to_available, to_total) = match accounts.get(tx.client, to) {
+        Some(target) => (target.available, target.total),
+        None => (Amount::ZERO, Amount::ZERO),
+    };
+    let to_available = to_available.plus(converted, &tx)?;
+    let to_total = to_total.plus(converted, &tx)?;
+
+    if let Some(account) = accounts.get_mut(tx.client, from) {
+        account.available = available;
+        account.total = total;
+    }
+
+    let target = accounts.get_or_open(tx.client, to);
+    target.available = to_available;
+    target.total = to_total;
+
+    Ok(())
+}
+
+/// A dispute represents a claim that a transaction was erroneous and should be reversed. The transaction is not immediately
+/// reversed; instead, the disputed amount is moved from available to held. The account total does not change.
+///
+/// Both deposits and withdrawals can be disputed. The latter case would apply in a scenario such as a stolen ATM card being
+/// used to make a fraudulent withdrawal. How a disputed withdrawal affects the account depends on the
+/// [`WithdrawalDisputeMode`].
+///
+/// Disputes do not specify an amount. Instead they refer to a transaction by ID. If the transaction specified doesn’t exist,
+/// the dispute is ignored. A dispute that does carry an amount is a partial dispute, which only moves that much of the
+/// disputed transaction into held funds.
+///
+/// A transfer is disputed by its sender, and the disputed amount is held in the recipient's account, where the funds
+/// went.
+fn dispute(
+    accounts: &mut Accounts,
+    tx: Transaction,
+    history: &mut History,
+    withdrawal_disputes: WithdrawalDisputeMode,
+) -> Result<(), Error> {
+    let disputed_tx = history
+        .get_mut(tx.id)?
+        .ok_or(PaymentError::TxNotFound { tx: tx.id })?;
+    check_owner(&tx, disputed_tx.client)?;
+    check_currency(&tx, disputed_tx.currency)?;
+
+    let mut disputed_amount = disputed_tx.amount;
+    let status = disputed_tx.dispute_status.transition(tx.tx_type, tx.id)?;
+
+    if disputed_tx.refunded {
+        return Err(PaymentError::AlreadyReversed { tx: tx.id }.into());
+    }
+
+    if disputed_tx.pending {
+        return Err(PaymentError::DepositPending { tx: tx.id }.into());
+    }
+
+    if let Some(partial_amount) = tx.amount {
+        if partial_amount <= Amount::ZERO || partial_amount > disputed_amount {
+            return Err(PaymentError::InvalidPartialDispute { tx: tx.id }.into());
+        }
+
+        disputed_amount = partial_amount;
+    }
+
+    let holder = disputed_tx.holder();
+    let account =
+        accounts
+            .get_mut(holder, disputed_tx.currency)
+            .ok_or(PaymentError::AccountNotFound {
+                client: holder,
+                tx: tx.id,
+            })?;
+
+    let (held, deferred) = match (disputed_tx.tx_type, withdrawal_disputes) {
+        (TransactionType::Deposit | TransactionType::Transfer, _) => {
+            let available = account.available.minus(disputed_amount, &tx)?;
+            account.held = account.held.plus(disputed_amount, &tx)?;
+            account.available = available;
+            (disputed_amount, Amount::ZERO)
+        }
+        (TransactionType::Withdraw, WithdrawalDisputeMode::Provisional) => {
+            let total = account.total.plus(disputed_amount, &tx)?;
+            account.held = account.held.plus(disputed_amount, &tx)?;
+            account.total = total;
+            (disputed_amount, Amount::ZERO)
+        }
+        (TransactionType::Withdraw, WithdrawalDisputeMode::Deferred) => {
+            (Amount::ZERO, disputed_amount)
+        }
+        _ => return Err(PaymentError::TxNotFound { tx: tx.id }.into()),
+    };
+
+    disputed_tx.dispute_status = status;
+    disputed_tx.held = held;
+    disputed_tx.deferred = deferred;
+    disputed_tx.disputes += 1;
+
+    Ok(())
+}
+
+/// A resolve represents a resolution to a dispute, releasing the associated held funds. Funds that were previously disputed are
+/// no longer disputed. The clients held funds decrease by the amount no longer disputed, their available funds increase by the amount
+///  no longer disputed, and their total funds remain the same.
+///
+/// Resolves do not specify an amount. Instead they refer to a disputed transaction by ID. If the transaction specified doesn’t exist,
+/// or the transaction isn’t under dispute, the resolve is ignored. The amount released is exactly the amount the dispute moved
+/// into held funds, from the account that holds them.
+fn resolve(accounts: &mut Accounts, tx: Transaction, history: &mut History) -> Result<(), Error> {
+    let disputed_tx = history
+        .get_mut(tx.id)?
+        .ok_or(PaymentError::TxNotFound { tx: tx.id })?;
+    check_owner(&tx, disputed_tx.client)?;
+    check_currency(&tx, disputed_tx.currency)?;
+
+    let status = disputed_tx.dispute_status.transition(tx.tx_type, tx.id)?;
+
+    let holder = disputed_tx.holder();
+    let account =
+        accounts
+            .get_mut(holder, disputed_tx.currency)
+            .ok_or(PaymentError::AccountNotFound {
+                client: holder,
+                tx: tx.id,
+            })?;
+
+    let held = account.held.minus(disputed_tx.held, &tx)?;
+    let available = account.available.plus(disputed_tx.held, &tx)?;
+
+    account.held = held;
+    account.available = available;
+    disputed_tx.dispute_status = status;
+    disputed_tx.held = Amount::ZERO;
+    disputed_tx.deferred = Amount::ZERO;
+
+    Ok(())
+}
+
+/// A refund reverses a deposit by id without going through a dispute. The clients available and total funds decrease
+/// by the amount of the deposit, and the refund fails if the client no longer has that much available, such as when the
+/// funds were already withdrawn. A deposit can only be refunded once, and not while it is under dispute.
+fn refund(accounts: &mut Accounts, tx: Transaction, history: &mut History) -> Result<(), Error> {
+    let refunded_tx = history
+        .get_mut(tx.id)?
+        .filter(|item| item.tx_type == TransactionType::Deposit)
+        .ok_or(PaymentError::TxNotFound { tx: tx.id })?;
+    check_owner(&tx, refunded_tx.client)?;
+    check_currency(&tx, refunded_tx.currency)?;
+
+    if refunded_tx.refunded || refunded_tx.dispute_status == DisputeStatus::ChargedBack {
+        return Err(PaymentError::AlreadyReversed { tx: tx.id }.into());
+    }
+
+    if refunded_tx.under_dispute() {
+        return Err(PaymentError::AlreadyDisputed { tx: tx.id }.into());
+    }
+
+    if refunded_tx.pending {
+        return Err(PaymentError::DepositPending { tx: tx.id }.into());
+    }
+
+    let account =
+        accounts
+            .get_mut(tx.client, refunded_tx.currency)
+            .ok_or(PaymentError::AccountNotFound {
+                client: tx.client,
+                tx: tx.id,
+            })?;
+
+    if refunded_tx.amount > account.available {
+        return Err(PaymentError::InsufficientFunds {
+            client: tx.client,
+            tx: tx.id,
+        }
+        .into());
+    }
+
+    let available = account.available.minus(refunded_tx.amount, &tx)?;
+    let total = account.total.minus(refunded_tx.amount, &tx)?;
+
+    account.available = available;
+    account.total = total;
+    refunded_tx.refunded = true;
+
+    Ok(())
+}
+
+/// A chargeback is the final state of a dispute and represents the client reversing a transaction. Funds that were held are now withdrawn.
+/// The clients held funds and total funds decrease by the amount the dispute moved into held funds. A withdrawal disputed under
+/// [`WithdrawalDisputeMode::Deferred`] instead has its amount credited back to available funds. The client's accounts are also frozen, in every currency.
+///
+/// A chargeback of a transfer reverses both legs: the funds held in the recipient's account are withdrawn from it and
+/// credited back to the sender's available funds, and the sender's account is frozen.
+fn chargeback(
+    accounts: &mut Accounts,
+    tx: Transaction,
+    history: &mut History,
+) -> Result<(), Error> {
+    let disputed_tx = history
+        .get_mut(tx.id)?
+        .ok_or(PaymentError::TxNotFound { tx: tx.id })?;
+    check_owner(&tx, disputed_tx.client)?;
+    check_currency(&tx, disputed_tx.currency)?;
+
+    let status = disputed_tx.dispute_status.transition(tx.tx_type, tx.id)?;
+
+    let currency = disputed_tx.currency;
+    let not_found = |client| PaymentError::AccountNotFound { client, tx: tx.id };
+    let sender = accounts
+        .get(tx.client, currency)
+        .ok_or_else(|| not_found(tx.client))?;
+
+    // The sender of a transfer gets back what its recipient loses, checked before either account changes
+    let returned = match disputed_tx.tx_type {
+        TransactionType::Transfer => Some((
+            sender.available.plus(disputed_tx.held, &tx)?,
+            sender.total.plus(disputed_tx.held, &tx)?,
+        )),
+        _ => None,
+    };
+
+    let holder = disputed_tx.holder();
+    let account = accounts
+        .get_mut(holder, currency)
+        .ok_or_else(|| not_found(holder))?;
+
+    let held = account.held.minus(disputed_tx.held, &tx)?;
+    let available = account.available.plus(disputed_tx.deferred, &tx)?;
+    let total = account
+        .total
+        .minus(disputed_tx.held, &tx)?
+        .plus(disputed_tx.deferred, &tx)?;
+
+    account.held = held;
+    account.available = available;
+    account.total = total;
+
+    if let (Some(sender), Some((available, total))) =
+        (accounts.get_mut(tx.client, currency), returned)
+    {
+        sender.available = available;
+        sender.total = total;
+    }
+
+    accounts.set_status(tx.client, AccountStatus::ChargedBack);
+
+    disputed_tx.dispute_status = status;
+    disputed_tx.held = Amount::ZERO;
+    disputed_tx.deferred = Amount::ZERO;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(
+        tx_type: TransactionType,
+        client: ClientId,
+        id: u32,
+        amount: Option<Amount>,
+    ) -> Transaction {
+        Transaction::new(tx_type, client, id, amount)
+    }
+
+    #[test]
+    fn transaction_type_accepts_every_spelling() {
+        for spelling in [
+            "withdraw",
+            "withdrawal",
+            "Withdrawal",
+            "WITHDRAW",
+            "WithDraw",
+            " withdrawal ",
+            "\twithdraw",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                spelling.parse::<TransactionType>().unwrap(),
+                TransactionType::Withdraw
+            );
+
+            let csv = Transaction::from_csv_line(&format!("{},1,2,1.5", spelling)).unwrap();
+            assert_eq!(csv.tx_type, TransactionType::Withdraw);
+
+            let json = Transaction::from_json_line(&format!(
+                r#"{{"type":"{}","client":1,"tx":2,"amount":"1.5"}}"#,
+                spelling.replace('\t', "\\t")
+            ))
+            .unwrap();
+            assert_eq!(json.tx_type, TransactionType::Withdraw);
+        }
+
+        assert_eq!(
+            "ChargeBack".parse::<TransactionType>().unwrap(),
+            TransactionType::Chargeback
+        );
+        assert!("withdrawals".parse::<TransactionType>().is_err());
+        assert!(Transaction::from_csv_line("withdrawals,1,2,1.5").is_err());
+    }
+
+    #[test]
+    fn deposit_adds_to_account() {
+        let mut accounts = Accounts::from(vec![Account {
+            client: 1,
+            currency: Currency::default(),
+            account_number: None,
+            available: Amount::from_num(0),
+            held: Amount::from_num(0),
+            pending: Amount::ZERO,
+            total: Amount::from_num(0),
+            credit_limit: Amount::ZERO,
+            status: AccountStatus::Active,
+            source: None,
+            last_activity: None,
+        }]);
+
+        deposit(
+            &mut accounts,
+            transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(1.9999)),
+            ),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            accounts.first().unwrap().available,
+            Amount::from_num(1.9999)
+        );
+        assert_eq!(accounts.first().unwrap().total, Amount::from_num(1.9999));
+    }
+
+    #[test]
+    fn withdraw_takes_from_account() {
+        let mut accounts = Accounts::from(vec![Account {
+            client: 0,
+            currency: Currency::default(),
+            account_number: None,
+            available: Amount::from_num(2),
+            held: Amount::from_num(0),
+            pending: Amount::ZERO,
+            total: Amount::from_num(2),
+            credit_limit: Amount::ZERO,
+            status: AccountStatus::Active,
+            source: None,
+            last_activity: None,
+        }]);
+
+        withdraw(
+            &mut accounts,
+            transaction(
+                TransactionType::Withdraw,
+                0,
+                1,
+                Some("1.9999".parse().unwrap()),
+            ),
+            (Amount::ZERO, None),
+        )
+        .unwrap();
+
+        let remaining: Amount = "0.0001".parse().unwrap();
+        assert_eq!(accounts.first().unwrap().available, remaining);
+        assert_eq!(accounts.first().unwrap().total, remaining);
+    }
+
+    #[test]
+    fn withdraw_fails_on_insufficient_funds() {
+        let mut accounts = Accounts::from(vec![Account {
+            client: 0,
+            currency: Currency::default(),
+            account_number: None,
+            available: Amount::from_num(1),
+            held: Amount::from_num(0),
+            pending: Amount::ZERO,
+            total: Amount::from_num(1),
+            credit_limit: Amount::ZERO,
+            status: AccountStatus::Active,
+            source: None,
+            last_activity: None,
+        }]);
+
+        let res = withdraw(
+            &mut accounts,
+            transaction(
+                TransactionType::Withdraw,
+                0,
+                1,
+                Some(Amount::from_num(1.9999)),
+            ),
+            (Amount::ZERO, None),
+        );
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn disputed_amount_should_move_to_held() {
+        let mut accounts = Accounts::from(vec![Account {
+            client: 0,
+            currency: Currency::default(),
+            account_number: None,
+            available: Amount::from_num(1),
+            held: Amount::from_num(0),
+            pending: Amount::ZERO,
+            total: Amount::from_num(1),
+            credit_limit: Amount::ZERO,
+            status: AccountStatus::Active,
+            source: None,
+            last_activity: None,
+        }]);
+
+        let mut history = History::from(vec![LedgerEntry::new(transaction(
+            TransactionType::Deposit,
+            0,
+            1,
+            Some(Amount::from_num(1)),
+        ))
+        .unwrap()]);
+
+        dispute(
+            &mut accounts,
+            transaction(TransactionType::Dispute, 0, 1, None),
+            &mut history,
+            WithdrawalDisputeMode::Provisional,
+        )
+        .unwrap();
+
+        assert_eq!(accounts.first().unwrap().available, Amount::from_num(0));
+        assert_eq!(accounts.first().unwrap().total, Amount::from_num(1));
+        assert_eq!(accounts.first().unwrap().held, Amount::from_num(1));
+    }
+
+    #[test]
+    fn disputing_rejected_withdrawal_does_not_create_held_funds() {
+        let mut accounts = Accounts::default();
+        let mut history = History::default();
+
+        process(
+            &mut accounts,
+            &mut history,
+            transaction(TransactionType::Deposit, 0, 1, Some(Amount::from_num(1))),
+            false,
+            WithdrawalDisputeMode::Provisional,
+            (Amount::ZERO, None),
+            RoundingMode::HalfUp,
+        )
+        .unwrap();
+        process(
+            &mut accounts,
+            &mut history,
+            transaction(TransactionType::Withdraw, 0, 2, Some(Amount::from_num(5))),
+            false,
+            WithdrawalDisputeMode::Provisional,
+            (Amount::ZERO, None),
+            RoundingMode::HalfUp,
+        )
+        .unwrap_err();
+
+        let res = process(
+            &mut accounts,
+            &mut history,
+            transaction(TransactionType::Dispute, 0, 2, None),
+            false,
+            WithdrawalDisputeMode::Provisional,
+            (Amount::ZERO, None),
+            RoundingMode::HalfUp,
+        );
+
+        assert!(res.is_err());
+        assert_eq!(accounts.first().unwrap().available, Amount::from_num(1));
+        assert_eq!(accounts.first().unwrap().held, Amount::from_num(0));
+        assert_eq!(accounts.first().unwrap().total, Amount::from_num(1));
+    }
+
+    #[test]
+    fn locked_column_is_true_for_non_active_statuses() {
+        let statuses = [
+            (AccountStatus::Active, "false"),
+            (AccountStatus::ChargedBack, "true"),
+            (AccountStatus::Frozen, "true"),
+            (AccountStatus::Closed, "true"),
+        ];
+
+        for (status, locked) in statuses.iter() {
+            let mut writer = WriterBuilder::new().has_headers(false).from_writer(vec![]);
+            writer
+                .serialize(Account {
+                    client: 1,
+                    currency: Currency::default(),
+                    account_number: None,
+                    available: Amount::from_num(1),
+                    held: Amount::from_num(0),
+                    pending: Amount::ZERO,
+                    total: Amount::from_num(1),
+                    credit_limit: Amount::ZERO,
+                    status: *status,
+                    source: None,
+                    last_activity: None,
+                })
+                .unwrap();
+
+            let row = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+            assert_eq!(row, format!("1,1,0,1,{}\n", locked));
+        }
+    }
+
+    #[test]
+    fn load_snapshot_restores_accounts() {
+        let snapshot = "client,available,held,total,locked\n1,1.5,0.5,2,false\n2,0,0,0,true\n";
+
+        let engine = Engine::load_snapshot(snapshot.as_bytes()).unwrap();
+
+        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.accounts[0].held, Amount::from_num(0.5));
+        assert_eq!(engine.accounts[1].status, AccountStatus::ChargedBack);
+    }
+
+    #[test]
+    fn restored_state_keeps_open_disputes() {
+        let mut engine = Engine::new();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+
+        let mut state = Vec::new();
+        engine.save_state(&mut state).unwrap();
+
+        let mut resumed = Engine::new();
+        resumed.set_dispute_expiry(Some(1));
+        resumed.restore_state(&state[..]).unwrap();
+        assert_eq!(resumed.accounts, engine.accounts);
+
+        // The open dispute expires after the first transaction of the resumed run
+        resumed
+            .apply(transaction(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(Amount::from_num(1)),
+            ))
+            .unwrap();
+        assert_eq!(resumed.accounts[0].available, Amount::from_num(5));
+        assert_eq!(resumed.metrics.expired_disputes, 1);
+
+        assert!(resumed.restore_state(&state[..]).is_err());
+    }
+
+    /// A store that keeps everything in maps shared between its clones, so a test can hand the same store to several
+    /// engines in turn
+    #[derive(Clone, Default)]
+    struct MapStore(std::sync::Arc<std::sync::Mutex<StoredMaps>>);
+
+    type StoredMaps = (BTreeMap<usize, Account>, HashMap<u32, LedgerEntry>);
+
+    impl AccountStore for MapStore {
+        fn load_accounts(&mut self) -> Result<Vec<Account>, Error> {
+            Ok(self.0.lock().unwrap().0.values().cloned().collect())
+        }
+
+        fn save_account(&mut self, account: &Account) -> Result<(), Error> {
+            let accounts = &mut self.0.lock().unwrap().0;
+            let index = accounts
+                .iter()
+                .find(|(_, stored)| stored.client == account.client)
+                .map_or(accounts.len(), |(index, _)| *index);
+            accounts.insert(index, account.clone());
+
+            Ok(())
+        }
+    }
+
+    impl TransactionStore for MapStore {
+        fn load_transaction(&mut self, id: u32) -> Result<Option<LedgerEntry>, Error> {
+            Ok(self.0.lock().unwrap().1.get(&id).cloned())
+        }
+
+        fn save_transaction(&mut self, entry: &LedgerEntry) -> Result<(), Error> {
+            self.0.lock().unwrap().1.insert(entry.id, entry.clone());
+
+            Ok(())
+        }
+    }
+
+    impl Store for MapStore {}
+
+    #[test]
+    fn store_keeps_state_for_later_engines() {
+        let store = MapStore::default();
+
+        let mut engine = Engine::new();
+        engine.set_store(store.clone()).unwrap();
+        for (client, id) in [(1, 1), (2, 2)].iter() {
+            engine
+                .apply(transaction(
+                    TransactionType::Deposit,
+                    *client,
+                    *id,
+                    Some(Amount::from_num(5)),
+                ))
+                .unwrap();
+        }
+        drop(engine);
+
+        // The resumed engine starts with no history, so the deposit being disputed has to come from the store
+        let mut resumed = Engine::new();
+        resumed.set_store(store.clone()).unwrap();
+        assert_eq!(resumed.accounts.len(), 2);
+
+        resumed
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        assert_eq!(resumed.accounts[0].held, Amount::from_num(5));
+
+        let (accounts, transactions) = &*store.0.lock().unwrap();
+        assert_eq!(accounts[&0].held, Amount::from_num(5));
+        assert!(transactions[&1].under_dispute());
+    }
+
+    #[test]
+    fn load_snapshot_rejects_inconsistent_account() {
+        let snapshot = "client,available,held,total,locked\n1,1,0,1,false\n2,5,1,10,false\n";
+
+        let err = Engine::load_snapshot(snapshot.as_bytes()).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::CorruptSnapshot { client: 2 })
+        );
+    }
+
+    #[test]
+    fn deposit_fee_is_deducted_before_crediting() {
+        let mut engine = Engine::new();
+        engine.set_deposit_fee_bps(100);
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].available, Amount::from_num(99));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(99));
+        assert_eq!(engine.metrics().deposit_fees, Amount::from_num(1));
+    }
+
+    #[test]
+    fn fees_may_overdraw_down_to_the_floor() {
+        let mut engine = Engine::new();
+        engine.set_fee_floor(Some(Amount::from_num(-5)));
+
+        for line in ["deposit,1,1,2", "fee,1,2,4", "fee,1,3,3.5"] {
+            let _ = engine.apply(Transaction::from_csv_line(line).unwrap());
+        }
+
+        // The second fee would leave -5.5 available, below the floor
+        assert_eq!(engine.accounts[0].available, Amount::from_num(-2));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(-2));
+        assert_eq!(engine.metrics().fees, Amount::from_num(4));
+        assert!(engine
+            .apply(Transaction::from_csv_line("withdraw,1,4,1").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn withdrawal_fees_are_charged_on_top_of_the_amount() {
+        let mut engine = Engine::new();
+        engine.set_withdrawal_fees(FeeSchedule {
+            bps: 100,
+            flat: Amount::from_num(0.5),
+        });
+        engine.set_fee_floor(Some(Amount::ZERO));
+
+        for line in ["deposit,1,1,100", "withdraw,1,2,50", "withdraw,1,3,48.5"] {
+            let _ = engine.apply(Transaction::from_csv_line(line).unwrap());
+        }
+
+        // 50 plus 1% and 0.5 in fees, while the second withdrawal's fees of 0.985 would overdraw the account
+        assert_eq!(engine.accounts[0].available, Amount::from_num(49));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(49));
+        assert_eq!(engine.metrics().fees, Amount::from_num(1));
+    }
+
+    #[test]
+    fn amounts_display_four_places_and_keep_every_digit_exactly() {
+        let amount: Amount = "1.5".parse().unwrap();
+
+        assert_eq!(amount.to_string(), "1.5000");
+        assert_eq!(amount.exact().to_string(), "1.5");
+        assert_eq!(Amount::from_num(-0.25).to_string(), "-0.2500");
+        assert_eq!(serde_json::to_string(&amount).unwrap(), r#""1.5""#);
+        assert_eq!(serde_json::from_str::<Amount>(r#""1.5""#).unwrap(), amount);
+    }
+
+    #[test]
+    fn amounts_are_only_built_from_valid_non_negative_numbers() {
+        assert_eq!(Amount::try_from_num(2.5).unwrap(), Amount::from_num(2.5));
+        assert_eq!(Amount::try_from_num(0).unwrap(), Amount::ZERO);
+        assert!(Amount::try_from_num(-1).is_err());
+        assert!(Amount::try_from_num(f64::NAN).is_err());
+        assert!(Amount::try_from_num(f64::INFINITY).is_err());
+        assert!(Amount::try_from_num(1e300).is_err());
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn decimal_amounts_are_exact() {
+        let mut engine = Engine::new();
+
+        for id in 1..=10 {
+            engine
+                .apply(Transaction::from_csv_line(&format!("deposit,1,{},0.1", id)).unwrap())
+                .unwrap();
+        }
+        engine
+            .apply(Transaction::from_csv_line("withdraw,1,11,0.12345678").unwrap())
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].total.exact().to_string(), "0.87654322");
+        assert_eq!(to_units(engine.accounts[0].total), 8765);
+    }
+
+    #[cfg(feature = "high-precision")]
+    #[test]
+    fn high_precision_accepts_amounts_beyond_i50f14() {
+        let mut engine = Engine::new();
+
+        engine
+            .apply(Transaction::from_csv_line("deposit,1,1,5000000000000000.1234").unwrap())
+            .unwrap();
+        engine
+            .apply(Transaction::from_csv_line("deposit,1,2,5000000000000000.1234").unwrap())
+            .unwrap();
+
+        let total = engine.accounts[0].total;
+
+        // The largest I50F14 is just below 2^49
+        assert!(total > Amount::from_num(2f64.powi(49)));
+        assert_eq!(to_units(total), 100_000_000_000_000_002_468);
+        assert_eq!(
+            round_to_scale(total).exact().to_string(),
+            "10000000000000000.2468"
+        );
+    }
+
+    #[test]
+    fn fixed_scale_writes_every_decimal_place() {
+        let fixed = |amount: f64| {
+            AmountFormat::default()
+                .fixed(Amount::from_num(amount))
+                .to_string()
+        };
+
+        assert_eq!(fixed(1.5), "1.5000");
+        assert_eq!(fixed(-0.25), "-0.2500");
+        assert_eq!(fixed(0.0001), "0.0001");
+        assert_eq!(fixed(12.0), "12.0000");
+    }
+
+    #[test]
+    fn amounts_are_rounded_to_the_configured_places() {
+        let format = |places, rounding, amount: f64| {
+            let format = AmountFormat { places, rounding };
+            let amount = Amount::from_num(amount);
+            (
+                format.fixed(amount).to_string(),
+                format.trimmed(amount).to_string(),
+            )
+        };
+
+        assert_eq!(
+            format(2, RoundingMode::HalfUp, 0.125),
+            ("0.13".into(), "0.13".into())
+        );
+        assert_eq!(
+            format(2, RoundingMode::Bankers, 0.125),
+            ("0.12".into(), "0.12".into())
+        );
+        assert_eq!(
+            format(2, RoundingMode::Bankers, 0.375),
+            ("0.38".into(), "0.38".into())
+        );
+        assert_eq!(
+            format(2, RoundingMode::HalfUp, -0.125),
+            ("-0.13".into(), "-0.13".into())
+        );
+        assert_eq!(
+            format(2, RoundingMode::Bankers, -0.125),
+            ("-0.12".into(), "-0.12".into())
+        );
+        assert_eq!(
+            format(0, RoundingMode::HalfUp, 2.5),
+            ("3".into(), "3".into())
+        );
+        assert_eq!(
+            format(0, RoundingMode::Bankers, 2.5),
+            ("2".into(), "2".into())
+        );
+        assert_eq!(
+            format(4, RoundingMode::HalfUp, 1.5),
+            ("1.5000".into(), "1.5".into())
+        );
+        assert_eq!(
+            format(4, RoundingMode::HalfUp, 2.0),
+            ("2.0000".into(), "2".into())
+        );
+        assert_eq!(
+            format(1, RoundingMode::HalfUp, -0.01),
+            ("0.0".into(), "0".into())
+        );
+    }
+
+    #[test]
+    fn basis_points_round_to_output_scale() {
+        assert_eq!(
+            basis_points(Amount::from_num(1), 1),
+            Amount::from_num(0.0001)
+        );
+        assert_eq!(
+            basis_points(Amount::from_num(0.5), 1),
+            Amount::from_num(0.0001)
+        );
+        assert_eq!(basis_points(Amount::from_num(0.4), 1), Amount::from_num(0));
+    }
+
+    #[test]
+    fn unmapped_client_fails_unless_allowed() {
+        let mut engine =
+            Engine::load_snapshot("client,available,held,total,locked\n7,1,0,1,false\n".as_bytes())
+                .unwrap();
+        let map = HashMap::new();
+
+        assert!(apply_account_map(&mut engine.accounts, &map, false).is_err());
+
+        apply_account_map(&mut engine.accounts, &map, true).unwrap();
+        assert_eq!(engine.accounts[0].account_number, Some("7".to_string()));
+    }
+
+    #[test]
+    fn resolve_releases_exactly_the_held_amount() {
+        let mut accounts = Accounts::from(vec![Account {
+            client: 0,
+            currency: Currency::default(),
+            account_number: None,
+            available: Amount::from_num(6),
+            held: Amount::from_num(4),
+            pending: Amount::ZERO,
+            total: Amount::from_num(10),
+            credit_limit: Amount::ZERO,
+            status: AccountStatus::Active,
+            source: None,
+            last_activity: None,
+        }]);
+
+        let mut history = History::from(vec![LedgerEntry {
+            dispute_status: DisputeStatus::Disputed,
+            held: Amount::from_num(4),
+            ..LedgerEntry::new(transaction(
+                TransactionType::Deposit,
+                0,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap()
+        }]);
+
+        resolve(
+            &mut accounts,
+            transaction(TransactionType::Resolve, 0, 1, None),
+            &mut history,
+        )
+        .unwrap();
+
+        assert_eq!(accounts[0].available, Amount::from_num(10));
+        assert_eq!(accounts[0].held, Amount::from_num(0));
+        assert_eq!(accounts[0].total, Amount::from_num(10));
+        assert_eq!(
+            history.get_mut(1).unwrap().unwrap().held,
+            Amount::from_num(0)
+        );
+    }
+
+    #[test]
+    fn gap_detector_reports_missing_ids() {
+        let mut engine = Engine::new();
+        engine.detect_gaps();
+
+        for (tx_type, id) in [
+            (TransactionType::Deposit, 1),
+            (TransactionType::Deposit, 2),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Withdraw, 4),
+        ]
+        .iter()
+        {
+            let _ = engine.apply(transaction(*tx_type, 1, *id, Some(Amount::from_num(1))));
+        }
+
+        let gaps = engine.gaps().unwrap();
+        assert_eq!(gaps.missing, vec![(3, 3)]);
+        assert_eq!(gaps.out_of_order, 0);
+        assert_eq!(gaps.to_string(), "3");
+    }
+
+    // The drift this checks for comes from the 14 fractional bits of the default amount type
+    #[cfg(not(any(feature = "high-precision", feature = "decimal")))]
+    #[test]
+    fn rounding_each_op_differs_from_rounding_at_output() {
+        let total_after_deposits = |round_each_op| {
+            let mut engine = Engine::new();
+            engine.set_round_each_op(round_each_op);
+
+            for id in 1..=10 {
+                engine
+                    .apply(transaction(
+                        TransactionType::Deposit,
+                        1,
+                        id,
+                        Some(Amount::from_num(0.0001)),
+                    ))
+                    .unwrap();
+            }
+
+            engine.accounts[0].total
+        };
+
+        assert_eq!(total_after_deposits(true), Amount::from_num(0.001));
+        assert_eq!(total_after_deposits(false), Amount::from_num(0.0012));
+    }
+
+    #[test]
+    fn held_report_only_includes_clients_with_held_funds() {
+        let mut engine = Engine::new();
+        let txs = [
+            (TransactionType::Deposit, 1, 1, Some(5)),
+            (TransactionType::Deposit, 1, 2, Some(3)),
+            (TransactionType::Deposit, 2, 3, Some(10)),
+            (TransactionType::Dispute, 1, 1, None),
+            (TransactionType::Dispute, 1, 2, None),
+        ];
+
+        for (tx_type, client, id, amount) in txs.iter() {
+            engine
+                .apply(transaction(
+                    *tx_type,
+                    *client,
+                    *id,
+                    amount.map(|amount: i32| Amount::from_num(amount)),
+                ))
+                .unwrap();
+        }
+
+        assert_eq!(
+            engine.held_report(),
+            vec![HeldReportRow {
+                client: 1,
+                held: Amount::from_num(8),
+                open_disputes: 2,
+                tx_ids: "1 2".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn summary_totals_applied_transactions_and_accounts() {
+        let mut engine = Engine::new();
+        let txs = [
+            (TransactionType::Deposit, 1, 1, Some(5)),
+            (TransactionType::Deposit, 2, 2, Some(10)),
+            (TransactionType::Withdraw, 2, 3, Some(4)),
+            (TransactionType::Withdraw, 1, 4, Some(50)),
+            (TransactionType::Dispute, 2, 2, None),
+            (TransactionType::Resolve, 2, 2, None),
+            (TransactionType::Dispute, 1, 1, None),
+            (TransactionType::Chargeback, 1, 1, None),
+        ];
+
+        for (tx_type, client, id, amount) in txs.iter() {
+            let _ = engine.apply(transaction(
+                *tx_type,
+                *client,
+                *id,
+                amount.map(|amount: i32| Amount::from_num(amount)),
+            ));
+        }
+
+        let summary = engine.summary();
+
+        assert_eq!(summary.by_type[&TransactionType::Withdraw], 2);
+        assert_eq!((summary.processed, summary.rejected), (8, 1));
+        assert_eq!(summary.deposited, Amount::from_num(15));
+        assert_eq!(summary.withdrawn, Amount::from_num(4));
+        assert_eq!(
+            (
+                summary.disputes_opened,
+                summary.disputes_resolved,
+                summary.chargebacks
+            ),
+            (2, 1, 1)
+        );
+        assert_eq!(summary.locked_accounts, 1);
+        assert_eq!(summary.total, Amount::from_num(6));
+    }
+
+    #[test]
+    fn client_ids_above_u16_are_supported() {
+        let mut engine = Engine::new();
+        let input = "type,client,tx,amount\ndeposit,70000,1,1\n";
+        let tx: Transaction = reader_builder()
+            .from_reader(input.as_bytes())
+            .deserialize()
+            .next()
+            .unwrap()
+            .unwrap();
+
+        engine.apply(tx).unwrap();
+
+        assert_eq!(engine.accounts[0].client, 70_000);
+    }
+
+    #[test]
+    fn reusing_charged_back_id_is_rejected() {
+        let mut engine = Engine::new();
+        engine.set_locked_account_policy(LockedAccountPolicy::AllowDeposits);
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+
+        let err = engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::TransactionIdReuseAfterChargeback { tx: 1 })
+        );
+        assert_eq!(engine.accounts[0].total, Amount::from_num(0));
+    }
+
+    #[test]
+    fn illegal_dispute_transitions_are_rejected() {
+        use DisputeStatus::*;
+        use TransactionType::{Chargeback, Dispute, Resolve};
+
+        let illegal = [
+            (None, Resolve, PaymentError::NotDisputed { tx: 1 }),
+            (None, Chargeback, PaymentError::NotDisputed { tx: 1 }),
+            (Disputed, Dispute, PaymentError::AlreadyDisputed { tx: 1 }),
+            (Resolved, Resolve, PaymentError::NotDisputed { tx: 1 }),
+            (Resolved, Chargeback, PaymentError::NotDisputed { tx: 1 }),
+            (
+                ChargedBack,
+                Dispute,
+                PaymentError::AlreadyReversed { tx: 1 },
+            ),
+            (ChargedBack, Resolve, PaymentError::NotDisputed { tx: 1 }),
+            (ChargedBack, Chargeback, PaymentError::NotDisputed { tx: 1 }),
+        ];
+
+        for (status, tx_type, err) in illegal.iter().cloned() {
+            assert_eq!(status.transition(tx_type, 1), Err(err));
+        }
+
+        assert_eq!(None.transition(Dispute, 1), Ok(Disputed));
+        assert_eq!(Resolved.transition(Dispute, 1), Ok(Disputed));
+        assert_eq!(Disputed.transition(Resolve, 1), Ok(Resolved));
+        assert_eq!(Disputed.transition(Chargeback, 1), Ok(ChargedBack));
+    }
+
+    #[test]
+    fn referencing_another_clients_transaction_is_a_client_mismatch() {
+        let mut engine = Engine::new();
+        engine.set_strict_clients(true);
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+
+        for tx_type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Refund,
+        ]
+        .iter()
+        {
+            let err = engine.apply(transaction(*tx_type, 2, 1, None)).unwrap_err();
+
+            assert_eq!(
+                err.downcast_ref::<PaymentError>(),
+                Some(&PaymentError::ClientMismatch {
+                    client: 2,
+                    owner: 1,
+                    tx: 1
+                })
+            );
+        }
+
+        assert_eq!(engine.metrics.client_mismatches, 4);
+        assert_eq!(
+            engine.client_mismatches()[0],
+            ClientMismatch {
+                tx_type: TransactionType::Dispute,
+                client: 2,
+                owner: 1,
+                tx: 1
+            }
+        );
+        assert_eq!(engine.accounts[0].held, Amount::from_num(0));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(5));
+    }
+
+    #[test]
+    fn client_mismatches_of_a_rolled_back_batch_are_dropped() {
+        let mut engine = Engine::new();
+        engine.set_strict_clients(true);
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+
+        engine
+            .apply_atomic(&[
+                transaction(TransactionType::Deposit, 2, 2, Some(Amount::from_num(5))),
+                transaction(TransactionType::Dispute, 2, 1, None),
+            ])
+            .unwrap_err();
+
+        assert_eq!(engine.metrics.client_mismatches, 0);
+        assert!(engine.client_mismatches().is_empty());
+    }
+
+    #[test]
+    fn charged_back_transaction_cannot_be_disputed_again() {
+        let mut engine = Engine::new();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+
+        let err = engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::AlreadyReversed { tx: 1 })
+        );
+        assert_eq!(engine.accounts[0].held, Amount::from_num(0));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(0));
+    }
+
+    #[test]
+    fn overflowing_transactions_are_rejected_without_changing_the_account() {
+        let mut engine = Engine::new();
+        let one = Some(Amount::from_num(1));
+
+        engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::MAX),
+            ))
+            .unwrap();
+        engine
+            .process(transaction(TransactionType::Withdraw, 1, 2, one))
+            .unwrap();
+        engine
+            .process(transaction(TransactionType::Deposit, 1, 3, one))
+            .unwrap();
+        let before = engine.accounts[0].clone();
+
+        assert_eq!(
+            engine.process(transaction(TransactionType::Deposit, 1, 4, one)),
+            Err(PaymentError::Overflow { client: 1, tx: 4 })
+        );
+        // Disputing the withdrawal would credit it back on top of the largest total
+        assert_eq!(
+            engine.process(transaction(TransactionType::Dispute, 1, 2, None)),
+            Err(PaymentError::Overflow { client: 1, tx: 2 })
+        );
+        assert_eq!(engine.accounts[0], before);
+        assert_eq!(
+            engine.process(transaction(TransactionType::Resolve, 1, 2, None)),
+            Err(PaymentError::NotDisputed { tx: 2 })
+        );
+
+        // Rounding the largest total to the output scale mustn't wrap around to a negative total
+        engine.accounts[0].round_to_scale();
+        let almost_max = Amount::MAX.checked_sub(Amount::from_num(0.001)).unwrap();
+        assert!(engine.accounts[0].total >= almost_max);
+    }
+
+    #[test]
+    fn deferred_withdrawal_disputes_only_credit_back_on_chargeback() {
+        let mut engine = Engine::new();
+        engine.set_withdrawal_dispute_mode(WithdrawalDisputeMode::Deferred);
+
+        for (tx_type, id, amount) in [
+            (TransactionType::Deposit, 1, Some(10)),
+            (TransactionType::Withdraw, 2, Some(3)),
+            (TransactionType::Withdraw, 3, Some(4)),
+            (TransactionType::Dispute, 2, None),
+            (TransactionType::Dispute, 3, None),
+        ]
+        .iter()
+        {
+            engine
+                .apply(transaction(*tx_type, 1, *id, amount.map(Amount::from_num)))
+                .unwrap();
+        }
+
+        let balances = |engine: &Engine| {
+            let account = &engine.accounts[0];
+            (account.available, account.held, account.total)
+        };
+        let unchanged = (Amount::from_num(3), Amount::ZERO, Amount::from_num(3));
+        assert_eq!(balances(&engine), unchanged);
+
+        // Resolving keeps the withdrawal, while a chargeback reverses it
+        engine
+            .apply(transaction(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+        assert_eq!(balances(&engine), unchanged);
+
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, 3, None))
+            .unwrap();
+        assert_eq!(
+            balances(&engine),
+            (Amount::from_num(7), Amount::ZERO, Amount::from_num(7))
+        );
+        assert!(engine.accounts[0].status.is_locked());
+    }
+
+    #[test]
+    fn locked_account_rejects_deposits_and_withdrawals() {
+        for policy in [
+            LockedAccountPolicy::RejectAll,
+            LockedAccountPolicy::AllowDeposits,
+        ]
+        .iter()
+        {
+            let mut engine = Engine::new();
+            engine.set_locked_account_policy(*policy);
+
+            for tx in [
+                transaction(TransactionType::Deposit, 1, 1, Some(Amount::from_num(5))),
+                transaction(TransactionType::Deposit, 1, 2, Some(Amount::from_num(5))),
+                transaction(TransactionType::Dispute, 1, 1, None),
+                transaction(TransactionType::Chargeback, 1, 1, None),
+            ]
+            .iter()
+            {
+                engine.apply(*tx).unwrap();
+            }
+
+            let err = engine
+                .apply(transaction(
+                    TransactionType::Withdraw,
+                    1,
+                    3,
+                    Some(Amount::from_num(1)),
+                ))
+                .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<PaymentError>(),
+                Some(&PaymentError::AccountLocked { client: 1, tx: 3 })
+            );
+
+            let res = engine.apply(transaction(
+                TransactionType::Deposit,
+                1,
+                4,
+                Some(Amount::from_num(1)),
+            ));
+
+            match policy {
+                LockedAccountPolicy::RejectAll => {
+                    assert!(res.is_err());
+                    assert_eq!(engine.accounts[0].total, Amount::from_num(5));
+                }
+                LockedAccountPolicy::AllowDeposits => {
+                    res.unwrap();
+                    assert_eq!(engine.accounts[0].total, Amount::from_num(6));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn disputes_beyond_limit_are_rejected() {
+        let mut engine = Engine::new();
+        engine.set_max_disputes_per_tx(Some(1));
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+
+        let err = engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::DisputeLimitExceeded { tx: 1 })
+        );
+        assert_eq!(engine.accounts[0].held, Amount::from_num(0));
+    }
+
+    #[test]
+    fn from_csv_line_allows_missing_amount() {
+        let tx = Transaction::from_csv_line("dispute, 2, 7").unwrap();
+
+        assert_eq!(tx, transaction(TransactionType::Dispute, 2, 7, None));
+    }
+
+    #[test]
+    fn chargeback_of_partial_dispute_reverses_partial_amount() {
+        let mut engine = Engine::new();
+        engine.set_partial_disputes(true);
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Dispute,
+                1,
+                1,
+                Some(Amount::from_num(40)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+
+        let account = &engine.accounts[0];
+        assert_eq!(account.held, Amount::from_num(0));
+        assert_eq!(account.total, Amount::from_num(60));
+        assert_eq!(account.available, Amount::from_num(60));
+        assert!(account.status.is_locked());
+    }
+
+    #[test]
+    fn dispute_amount_is_ignored_without_partial_disputes() {
+        let mut engine = Engine::new();
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Dispute,
+                1,
+                1,
+                Some(Amount::from_num(40)),
+            ))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].held, Amount::from_num(100));
+    }
+
+    #[test]
+    fn old_disputes_are_escalated_by_age_and_reported() {
+        let day = |day: i64| Timestamp::from_unix(day * 86_400).unwrap();
+        let deposit = |id| transaction(TransactionType::Deposit, 1, id, Some(Amount::from_num(5)));
+        let dispute = |id| transaction(TransactionType::Dispute, 1, id, None);
+
+        let mut engine = Engine::new();
+        engine.set_dispute_expiry_days(Some(30));
+        engine.set_dispute_expiry_action(DisputeExpiryAction::Escalate);
+        engine.apply(deposit(1).with_timestamp(day(0))).unwrap();
+        engine.apply(deposit(2).with_timestamp(day(1))).unwrap();
+        engine.apply(dispute(1).with_timestamp(day(2))).unwrap();
+        // Without a timestamp of its own, the dispute is opened at the latest timestamp seen
+        engine.apply(dispute(2)).unwrap();
+        engine.apply(deposit(3).with_timestamp(day(40))).unwrap();
+        engine
+            .apply(transaction(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+        engine.apply(dispute(3).with_timestamp(day(45))).unwrap();
+
+        assert_eq!(engine.metrics.escalated_disputes, 2);
+        assert_eq!(engine.metrics.expired_disputes, 0);
+        assert_eq!(engine.accounts[0].held, Amount::from_num(10));
+        assert_eq!(
+            engine.dispute_report(),
+            vec![
+                DisputeReportRow {
+                    tx: 1,
+                    tx_type: TransactionType::Deposit,
+                    client: 1,
+                    currency: Currency::default(),
+                    amount: Amount::from_num(5),
+                    held: Amount::from_num(5),
+                    age: 4,
+                    age_days: Some(43),
+                    escalated: true,
+                },
+                DisputeReportRow {
+                    tx: 3,
+                    tx_type: TransactionType::Deposit,
+                    client: 1,
+                    currency: Currency::default(),
+                    amount: Amount::from_num(5),
+                    held: Amount::from_num(5),
+                    age: 0,
+                    age_days: Some(0),
+                    escalated: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_dispute_is_resolved_after_expiry() {
+        let mut engine = Engine::new();
+        engine.set_dispute_expiry(Some(2));
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].held, Amount::from_num(100));
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                2,
+                3,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].held, Amount::ZERO);
+        assert_eq!(engine.accounts[0].available, Amount::from_num(100));
+        assert_eq!(engine.metrics.expired_disputes, 1);
+        assert!(engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .is_err());
+    }
+
+    #[test]
+    fn chargeback_before_expiry_still_applies() {
+        let mut engine = Engine::new();
+        engine.set_dispute_expiry(Some(2));
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].total, Amount::ZERO);
+        assert!(engine.accounts[0].status.is_locked());
+        assert_eq!(engine.metrics.expired_disputes, 0);
+    }
+
+    #[test]
+    fn top_accounts_are_ordered_by_total_then_client() {
+        let mut engine = Engine::new();
+
+        for (client, id, amount) in [(3, 1, 5), (1, 2, 1), (4, 3, 7), (2, 4, 7)].iter() {
+            engine
+                .apply(transaction(
+                    TransactionType::Deposit,
+                    *client,
+                    *id,
+                    Some(Amount::from_num(*amount)),
+                ))
+                .unwrap();
+        }
+
+        let top: Vec<ClientId> = engine
+            .top_accounts_by_total(2)
+            .iter()
+            .map(|account| account.client)
+            .collect();
+
+        assert_eq!(top, vec![2, 4]);
+    }
+
+    #[test]
+    fn locks_freeze_accounts_until_an_admin_unlocks_them() {
+        let mut engine = Engine::new();
+        engine.set_admins(vec![900]);
+
+        let mut apply = |tx: Transaction| {
+            engine
+                .apply(tx)
+                .map_err(|err| err.downcast::<PaymentError>().unwrap())
+        };
+
+        apply(transaction(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Amount::from_num(5)),
+        ))
+        .unwrap();
+        apply(transaction(TransactionType::Lock, 1, 2, None)).unwrap();
+        assert_eq!(
+            apply(transaction(TransactionType::Lock, 1, 3, None).with_actor(900)),
+            Err(PaymentError::AccountLocked { client: 1, tx: 3 })
+        );
+        assert_eq!(
+            apply(transaction(TransactionType::Unlock, 1, 4, None)),
+            Err(PaymentError::Unauthorized {
+                actor: 1,
+                client: 1,
+                tx: 4
+            })
+        );
+        apply(transaction(TransactionType::Unlock, 1, 5, None).with_actor(900)).unwrap();
+        assert_eq!(
+            apply(transaction(TransactionType::Unlock, 1, 6, None).with_actor(900)),
+            Err(PaymentError::AccountNotLocked { client: 1, tx: 6 })
+        );
+        assert_eq!(
+            apply(transaction(TransactionType::Lock, 1, 7, None).with_actor(2)),
+            Err(PaymentError::Unauthorized {
+                actor: 2,
+                client: 1,
+                tx: 7
+            })
+        );
+
+        assert_eq!(engine.accounts[0].status, AccountStatus::Active);
+        assert_eq!(
+            engine.lock_audit(),
+            &[
+                LockEvent {
+                    tx: 2,
+                    client: 1,
+                    action: TransactionType::Lock,
+                    actor: 1,
+                },
+                LockEvent {
+                    tx: 5,
+                    client: 1,
+                    action: TransactionType::Unlock,
+                    actor: 900,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn locks_of_a_rolled_back_batch_leave_the_lock_audit() {
+        let mut engine = Engine::new();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap();
+
+        engine
+            .apply_atomic(&[
+                transaction(TransactionType::Lock, 1, 2, None),
+                transaction(TransactionType::Withdraw, 2, 3, Some(Amount::from_num(1))),
+            ])
+            .unwrap_err();
+
+        assert_eq!(engine.accounts[0].status, AccountStatus::Active);
+        assert!(engine.lock_audit().is_empty());
+    }
+
+    #[test]
+    fn currencies_have_separate_balances_and_disputes_stay_in_theirs() {
+        let eur: Currency = "eur".parse().unwrap();
+        let mut engine = Engine::new();
+
+        let mut apply = |tx: Transaction| {
+            engine
+                .apply(tx)
+                .map_err(|err| err.downcast::<PaymentError>().unwrap())
+        };
+
+        apply(transaction(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Amount::from_num(10)),
+        ))
+        .unwrap();
+        apply(
+            transaction(TransactionType::Deposit, 1, 2, Some(Amount::from_num(5)))
+                .with_currency(eur),
+        )
+        .unwrap();
+        assert_eq!(
+            apply(
+                transaction(TransactionType::Withdraw, 1, 3, Some(Amount::from_num(6)))
+                    .with_currency(eur)
+            ),
+            Err(PaymentError::InsufficientFunds { client: 1, tx: 3 })
+        );
+        assert_eq!(
+            apply(
+                transaction(TransactionType::Dispute, 1, 2, None).with_currency(Currency::DEFAULT)
+            ),
+            Err(PaymentError::CurrencyMismatch {
+                currency: Currency::DEFAULT,
+                expected: eur,
+                tx: 2
+            })
+        );
+        apply(transaction(TransactionType::Dispute, 1, 2, None)).unwrap();
+        apply(transaction(TransactionType::Chargeback, 1, 2, None)).unwrap();
+
+        let usd = engine.accounts.get(1, Currency::DEFAULT).unwrap();
+        assert_eq!(
+            (usd.available, usd.held),
+            (Amount::from_num(10), Amount::ZERO)
+        );
+        assert!(usd.status.is_locked());
+
+        let eur = engine.accounts.get(1, eur).unwrap();
+        assert_eq!((eur.total, eur.held), (Amount::ZERO, Amount::ZERO));
+        assert!(eur.status.is_locked());
+    }
+
+    #[test]
+    fn conversions_move_funds_between_currencies_and_report_their_spread() {
+        let eur: Currency = "EUR".parse().unwrap();
+        let gbp: Currency = "GBP".parse().unwrap();
+        let mut rates = RateTable::default();
+        rates
+            .insert(Currency::DEFAULT, eur, "0.9".parse().unwrap(), 100)
+            .unwrap();
+
+        let mut engine = Engine::new();
+        engine.set_rates(rates);
+
+        let mut apply = |tx: Transaction| {
+            engine
+                .apply(tx)
+                .map_err(|err| err.downcast::<PaymentError>().unwrap())
+        };
+
+        apply(transaction(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Amount::from_num(100)),
+        ))
+        .unwrap();
+        apply(Transaction::convert(
+            1,
+            2,
+            Amount::from_num(10),
+            Currency::DEFAULT,
+            eur,
+        ))
+        .unwrap();
+        apply(
+            Transaction::convert(1, 3, Amount::from_num(10), Currency::DEFAULT, eur)
+                .with_rate("0.95".parse().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(
+            apply(Transaction::convert(
+                1,
+                4,
+                Amount::from_num(1),
+                Currency::DEFAULT,
+                gbp
+            )),
+            Err(PaymentError::RateNotFound {
+                from: Currency::DEFAULT,
+                to: gbp,
+                tx: 4
+            })
+        );
+        assert_eq!(
+            apply(Transaction::convert(1, 5, Amount::from_num(1), eur, eur)),
+            Err(PaymentError::InvalidConversion { tx: 5 })
+        );
+        assert_eq!(
+            apply(Transaction::convert(
+                1,
+                6,
+                Amount::from_num(81),
+                Currency::DEFAULT,
+                eur
+            )),
+            Err(PaymentError::InsufficientFunds { client: 1, tx: 6 })
+        );
+
+        assert_eq!(
+            engine.accounts.get(1, Currency::DEFAULT).unwrap().total,
+            Amount::from_num(80)
+        );
+        assert_eq!(
+            engine.accounts.get(1, eur).unwrap().total,
+            "18.41".parse().unwrap()
+        );
+
+        let spreads: Vec<(Amount, Amount)> = engine
+            .conversions()
+            .iter()
+            .map(|event| (event.converted, event.spread))
+            .collect();
+        assert_eq!(
+            spreads,
+            vec![
+                ("8.91".parse().unwrap(), "0.09".parse().unwrap()),
+                (Amount::from_num(9.5), "-0.5".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn conversions_of_a_rolled_back_batch_leave_the_conversion_report() {
+        let eur: Currency = "eur".parse().unwrap();
+        let mut engine = Engine::new();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+
+        engine
+            .apply_atomic(&[
+                Transaction::convert(1, 2, Amount::from_num(10), Currency::DEFAULT, eur)
+                    .with_rate("0.9".parse().unwrap()),
+                transaction(
+                    TransactionType::Withdraw,
+                    1,
+                    3,
+                    Some(Amount::from_num(1000)),
+                ),
+            ])
+            .unwrap_err();
+
+        assert!(engine.accounts.get(1, eur).is_none());
+        assert!(engine.conversions().is_empty());
+    }
+
+    #[test]
+    fn output_only_has_a_currency_column_with_another_currency() {
+        let config = Config::default();
+        let mut engine = Engine::new();
+        engine
+            .apply(Transaction::from_csv_line("deposit,2,1,1.5").unwrap())
+            .unwrap();
+
+        let mut output = Vec::new();
+        write_csv(&mut output, &engine.accounts, &config).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,1.5,0,1.5,false\n"
+        );
+
+        engine
+            .apply(Transaction::from_csv_line("deposit,1,2,2,,,gbp").unwrap())
+            .unwrap();
+
+        let mut output = Vec::new();
+        write_csv(&mut output, &engine.into_sorted_accounts(), &config).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,locked\n1,GBP,2,0,2,false\n2,USD,1.5,0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn on_lock_is_called_for_each_chargeback() {
+        let locks = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        let recorded = locks.clone();
+        engine.on_lock(move |client, id| recorded.lock().unwrap().push((client, id)));
+
+        for client in 1..=2 {
+            let id = client as u32;
+            engine
+                .apply(transaction(
+                    TransactionType::Deposit,
+                    client,
+                    id,
+                    Some(Amount::from_num(1)),
+                ))
+                .unwrap();
+            engine
+                .apply(transaction(TransactionType::Dispute, client, id, None))
+                .unwrap();
+            engine
+                .apply(transaction(TransactionType::Chargeback, client, id, None))
+                .unwrap();
+        }
+
+        assert!(engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .is_err());
+        assert_eq!(*locks.lock().unwrap(), vec![(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn on_account_change_is_called_with_each_changed_account() {
+        let changes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        let recorded = changes.clone();
+        engine.on_account_change(move |account| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((account.client, account.available, account.held))
+        });
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap();
+        engine
+            .apply(Transaction::transfer(1, 2, 2, Amount::from_num(4)))
+            .unwrap();
+        assert!(engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                2,
+                3,
+                Some(Amount::from_num(5)),
+            ))
+            .is_err());
+        // A dispute of the transfer holds the recipient's funds
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 2, None))
+            .unwrap();
+        engine
+            .apply_atomic(&[transaction(TransactionType::Resolve, 1, 2, None)])
+            .unwrap();
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                (1, Amount::from_num(10), Amount::ZERO),
+                (1, Amount::from_num(6), Amount::ZERO),
+                (2, Amount::from_num(4), Amount::ZERO),
+                (2, Amount::ZERO, Amount::from_num(4)),
+                (2, Amount::from_num(4), Amount::ZERO),
+            ]
+        );
+    }
+
+    #[test]
+    fn audit_log_has_a_line_for_every_transaction_but_rolled_back_batches() {
+        let path = std::env::temp_dir().join("payments_audit_log.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let deposit = |id, amount| {
+            transaction(
+                TransactionType::Deposit,
+                1,
+                id,
+                Some(Amount::from_num(amount)),
+            )
+        };
+
+        let mut engine = Engine::new();
+        engine.audit_to(&path).unwrap();
+        engine.set_dispute_expiry(Some(1));
+        engine.apply(deposit(1, 10)).unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                1,
+                2,
+                Some(Amount::from_num(20)),
+            ))
+            .unwrap_err();
+        engine
+            .apply_atomic(&[
+                deposit(3, 1),
+                transaction(TransactionType::Withdraw, 1, 6, Some(Amount::from_num(20))),
+            ])
+            .unwrap_err();
+        engine.apply_atomic(&[deposit(4, 2)]).unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine.apply(deposit(5, 3)).unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let outcomes: Vec<(&str, u64, &str)> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line["type"].as_str().unwrap(),
+                    line["tx"].as_u64().unwrap(),
+                    line["outcome"].as_str().unwrap(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                ("deposit", 1, "applied"),
+                ("withdraw", 2, "insufficient_funds"),
+                ("deposit", 4, "applied"),
+                ("dispute", 1, "applied"),
+                ("deposit", 5, "applied"),
+                ("resolve", 1, "expired"),
+            ]
+        );
+        assert!(lines[0]["before"].is_null());
+        assert_eq!(lines[3]["before"]["available"], "12");
+        assert_eq!(lines[3]["after"]["available"], "2");
+        assert_eq!(lines[3]["after"]["held"], "10");
+        assert_eq!(lines[5]["after"]["available"], "15");
+    }
+
+    #[test]
+    fn replaying_the_events_gives_the_same_accounts() {
+        let deposit = |client, id, amount| {
+            transaction(
+                TransactionType::Deposit,
+                client,
+                id,
+                Some(Amount::from_num(amount)),
+            )
+        };
+
+        let mut engine = Engine::new();
+        engine.set_record_events(true);
+        engine.set_dispute_expiry(Some(2));
+        engine.apply(deposit(1, 1, 10)).unwrap();
+        engine.apply(deposit(2, 2, 5)).unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine.apply(deposit(3, 3, 1)).unwrap();
+        engine.apply(deposit(3, 4, 1)).unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 2, 2, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 2, 2, None))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                3,
+                5,
+                Some(Amount::from_num(5)),
+            ))
+            .unwrap_err();
+        engine
+            .apply_atomic(&[deposit(3, 6, 1), deposit(2, 7, 1)])
+            .unwrap_err();
+
+        let events = engine.events().to_vec();
+        assert_eq!(events.len(), 8);
+        assert!(events[5].expired);
+
+        let replayed = Engine::replay(events.clone());
+        assert_eq!(
+            replayed.accounts().collect::<Vec<_>>(),
+            engine.accounts().collect::<Vec<_>>()
+        );
+        assert_eq!(replayed.events(), engine.events());
+
+        // Replaying part of the events gives the accounts as they were then
+        let earlier = Engine::replay(events.into_iter().take(3));
+        let balances: Vec<(ClientId, Amount, Amount)> = earlier
+            .accounts()
+            .map(|account| (account.client, account.available, account.held))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                (1, Amount::ZERO, Amount::from_num(10)),
+                (2, Amount::from_num(5), Amount::ZERO)
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_atomic_batch_leaves_engine_unchanged() {
+        let mut engine = Engine::new();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap();
+
+        let accounts = engine.accounts.clone();
+        let history = engine.history.clone();
+        let metrics = engine.metrics.clone();
+
+        let err = engine
+            .apply_atomic(&[
+                transaction(TransactionType::Deposit, 1, 2, Some(Amount::from_num(5))),
+                transaction(TransactionType::Deposit, 2, 3, Some(Amount::from_num(5))),
+                transaction(TransactionType::Withdraw, 1, 4, Some(Amount::from_num(100))),
+                transaction(TransactionType::Deposit, 1, 5, Some(Amount::from_num(5))),
+            ])
+            .unwrap_err();
+
+        assert_eq!(
+            err,
+            (2, PaymentError::InsufficientFunds { client: 1, tx: 4 })
+        );
+        assert_eq!(engine.accounts, accounts);
+        assert_eq!(engine.history, history);
+        assert_eq!(engine.metrics, metrics);
+
+        engine
+            .apply_atomic(&[
+                transaction(TransactionType::Deposit, 1, 2, Some(Amount::from_num(5))),
+                transaction(TransactionType::Deposit, 2, 3, Some(Amount::from_num(5))),
+            ])
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].total, Amount::from_num(15));
+        assert_eq!(engine.accounts[1].total, Amount::from_num(5));
+    }
+
+    #[test]
+    fn transfer_moves_funds_between_accounts_or_neither() {
+        let mut engine = Engine::new();
+
+        engine
+            .apply(Transaction::from_csv_line("deposit,1,1,10").unwrap())
+            .unwrap();
+        engine
+            .apply(Transaction::from_csv_line("transfer,1,2,4,2").unwrap())
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].available, Amount::from_num(6));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(6));
+        assert_eq!(engine.accounts[1].client, 2);
+        assert_eq!(engine.accounts[1].available, Amount::from_num(4));
+        assert_eq!(engine.accounts[1].total, Amount::from_num(4));
+
+        let rejected = |engine: &mut Engine, tx| {
+            engine
+                .apply(tx)
+                .unwrap_err()
+                .downcast::<PaymentError>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            rejected(
+                &mut engine,
+                Transaction::transfer(1, 2, 3, Amount::from_num(7))
+            ),
+            PaymentError::InsufficientFunds { client: 1, tx: 3 }
+        );
+        assert_eq!(
+            rejected(
+                &mut engine,
+                Transaction::transfer(1, 1, 4, Amount::from_num(1))
+            ),
+            PaymentError::InvalidTransfer { tx: 4 }
+        );
+        assert_eq!(
+            rejected(
+                &mut engine,
+                Transaction::new(TransactionType::Transfer, 1, 5, Some(Amount::from_num(1)))
+            ),
+            PaymentError::InvalidTransfer { tx: 5 }
+        );
+
+        engine.accounts[1].status = AccountStatus::ChargedBack;
+        assert_eq!(
+            rejected(
+                &mut engine,
+                Transaction::transfer(1, 2, 6, Amount::from_num(1))
+            ),
+            PaymentError::AccountLocked { client: 2, tx: 6 }
+        );
+        assert_eq!(engine.accounts[0].total, Amount::from_num(6));
+        assert_eq!(engine.accounts[1].total, Amount::from_num(4));
+    }
+
+    #[test]
+    fn chargeback_of_transfer_reverses_both_legs() {
+        let mut engine = Engine::new();
+
+        for line in ["deposit,1,1,10", "transfer,1,2,4,2", "dispute,1,2,"] {
+            engine
+                .apply(Transaction::from_csv_line(line).unwrap())
+                .unwrap();
+        }
+
+        // The disputed funds are held where they went
+        assert_eq!(engine.accounts[1].available, Amount::ZERO);
+        assert_eq!(engine.accounts[1].held, Amount::from_num(4));
+        assert_eq!(engine.held_report()[0].client, 2);
+
+        engine
+            .apply(Transaction::from_csv_line("chargeback,1,2,").unwrap())
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].available, Amount::from_num(10));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(10));
+        assert!(engine.accounts[0].status.is_locked());
+        assert_eq!(engine.accounts[1].held, Amount::ZERO);
+        assert_eq!(engine.accounts[1].total, Amount::ZERO);
+        assert!(!engine.accounts[1].status.is_locked());
+    }
+
+    #[test]
+    fn refund_reverses_deposit() {
+        let mut engine = Engine::new();
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Amount::from_num(4)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Refund, 1, 1, None))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].available, Amount::from_num(4));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(4));
+        assert!(engine
+            .apply(transaction(TransactionType::Refund, 1, 1, None))
+            .is_err());
+        assert!(engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .is_err());
+    }
+
+    #[test]
+    fn refund_of_withdrawn_funds_is_rejected() {
+        let mut engine = Engine::new();
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                1,
+                2,
+                Some(Amount::from_num(7)),
+            ))
+            .unwrap();
+
+        assert!(engine
+            .apply(transaction(TransactionType::Refund, 1, 1, None))
+            .is_err());
+        assert_eq!(engine.accounts[0].available, Amount::from_num(3));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(3));
+    }
+
+    #[test]
+    fn with_capacity_matches_default_engine() {
+        let txns = [
+            transaction(TransactionType::Deposit, 1, 1, Some(Amount::from_num(10))),
+            transaction(TransactionType::Deposit, 2, 2, Some(Amount::from_num(5))),
+            transaction(TransactionType::Withdraw, 1, 3, Some(Amount::from_num(4))),
+            transaction(TransactionType::Dispute, 2, 2, None),
+            transaction(TransactionType::Chargeback, 2, 2, None),
+            transaction(TransactionType::Withdraw, 1, 4, Some(Amount::from_num(40))),
+        ];
+
+        let mut default = Engine::new();
+        let mut reserved = Engine::with_capacity(16, 1024);
+
+        for tx in txns.iter() {
+            assert_eq!(default.apply(*tx).is_ok(), reserved.apply(*tx).is_ok());
+        }
+
+        assert!(reserved.history.capacity() >= 1024);
+        assert_eq!(default.history, reserved.history);
+        assert_eq!(default.metrics, reserved.metrics);
+        assert_eq!(default.into_accounts(), reserved.into_accounts());
+    }
+
+    #[test]
+    fn dispute_before_deposit_is_rejected_under_strict_order() {
+        let dispute = transaction(TransactionType::Dispute, 1, 1, None);
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(Amount::from_num(10)));
+
+        let mut engine = Engine::new();
+        engine.set_strict_order(true);
+
+        let err = engine.apply(dispute).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::DisputeBeforeDeposit { tx: 1 })
+        );
+
+        engine.apply(deposit).unwrap();
+        engine.apply(dispute).unwrap();
+
+        let mut lenient = Engine::new();
+        let err = lenient.apply(dispute).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::TxNotFound { tx: 1 })
+        );
+    }
+
+    #[test]
+    fn timestamps_are_checked_against_earlier_transactions() {
+        let at = |seconds| Timestamp::from_unix(seconds).unwrap();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(Amount::from_num(10)));
+
+        let mut engine = Engine::new();
+        engine.apply(deposit.with_timestamp(at(200))).unwrap();
+
+        let err = engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None).with_timestamp(at(100)))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::ReferencesLaterTransaction { tx: 1 })
+        );
+
+        // Out of order transactions are only rejected when asked to
+        let late = transaction(TransactionType::Deposit, 1, 2, Some(Amount::from_num(1)));
+        engine.apply(late.with_timestamp(at(150))).unwrap();
+        assert_eq!(
+            engine.accounts().next().unwrap().last_activity(),
+            Some(at(150))
+        );
+
+        engine.set_reject_out_of_order(true);
+        let err = engine
+            .apply(Transaction { id: 3, ..late }.with_timestamp(at(199)))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::OutOfOrder {
+                tx: 3,
+                latest: at(200)
+            })
+        );
+
+        engine.apply(Transaction { id: 4, ..late }).unwrap();
+        assert_eq!(
+            engine.accounts().next().unwrap().last_activity(),
+            Some(at(150))
+        );
+    }
+
+    #[test]
+    fn transactions_already_applied_are_skipped_across_runs() {
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(Amount::from_num(10)));
+        let withdrawal = transaction(TransactionType::Withdraw, 1, 2, Some(Amount::from_num(3)));
+
+        let mut engine = Engine::new();
+        engine.set_skip_duplicates(true);
+        engine.apply(deposit).unwrap();
+        engine.apply(deposit).unwrap();
+        assert_eq!(engine.metrics.duplicates, 1);
+
+        // A rejected transaction wasn't applied, so it isn't a duplicate when it comes again
+        engine
+            .apply(Transaction {
+                id: 3,
+                amount: Some(Amount::from_num(20)),
+                ..withdrawal
+            })
+            .unwrap_err();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+
+        let mut state = Vec::new();
+        engine.save_state(&mut state).unwrap();
+
+        let mut resumed = Engine::new();
+        resumed.set_skip_duplicates(true);
+        resumed.restore_state(state.as_slice()).unwrap();
+        resumed.apply(deposit).unwrap();
+        resumed.apply(withdrawal).unwrap();
+        resumed.apply(withdrawal).unwrap();
+
+        assert_eq!(resumed.metrics.duplicates, 2);
+        assert_eq!(
+            resumed.accounts().next().unwrap().available(),
+            Amount::from_num(7)
+        );
+    }
+
+    #[test]
+    fn pending_deposit_is_available_once_settled() {
+        let mut engine = Engine::new();
+        engine.set_pending_deposits(true);
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].available, Amount::ZERO);
+        assert_eq!(engine.accounts[0].pending, Amount::from_num(10));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(10));
+        assert!(engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                1,
+                2,
+                Some(Amount::from_num(1))
+            ))
+            .is_err());
+        assert!(engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .is_err());
+
+        engine
+            .apply(transaction(TransactionType::Settle, 1, 1, None))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].available, Amount::from_num(10));
+        assert_eq!(engine.accounts[0].pending, Amount::ZERO);
+        assert_eq!(engine.accounts[0].total, Amount::from_num(10));
+        assert!(engine
+            .apply(transaction(TransactionType::Settle, 1, 1, None))
+            .is_err());
+    }
+
+    #[test]
+    fn withdraw_cannot_take_held_funds() {
+        let mut engine = Engine::new();
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+
+        let res = engine.apply(transaction(
+            TransactionType::Withdraw,
+            1,
+            2,
+            Some(Amount::from_num(50)),
+        ));
+
+        assert!(res.is_err());
+        assert_eq!(engine.accounts[0].held, Amount::from_num(100));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(100));
+    }
+
+    #[test]
+    fn non_positive_amounts_are_rejected() {
+        let mut engine = Engine::new();
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap();
+
+        for tx in [
+            transaction(TransactionType::Deposit, 1, 2, Some(Amount::from_num(-5))),
+            transaction(TransactionType::Deposit, 1, 3, Some(Amount::from_num(0))),
+            transaction(TransactionType::Withdraw, 1, 4, Some(Amount::from_num(-5))),
+            transaction(TransactionType::Withdraw, 1, 5, Some(Amount::from_num(0))),
+        ]
+        .iter()
+        {
+            let err = engine.apply(*tx).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<PaymentError>(),
+                Some(&PaymentError::NonPositiveAmount { tx: tx.id })
+            );
+        }
+
+        assert_eq!(engine.accounts[0].available, Amount::from_num(10));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(10));
+        assert_eq!(engine.history.iter().count(), 1);
+    }
+
+    #[test]
+    fn amount_above_limit_is_rejected() {
+        let mut engine = Engine::new();
+        engine.set_max_tx_amount(Some(Amount::from_num(100)));
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+
+        let account = engine.accounts[0].clone();
+
+        for tx in [
+            transaction(
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Amount::from_num(100.0001)),
+            ),
+            transaction(
+                TransactionType::Withdraw,
+                1,
+                3,
+                Some(Amount::from_num(100.0001)),
+            ),
+        ]
+        .iter()
+        {
+            let err = engine.apply(*tx).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<PaymentError>(),
+                Some(&PaymentError::AmountExceedsLimit {
+                    tx: tx.id,
+                    limit: Amount::from_num(100)
+                })
+            );
+        }
+
+        assert_eq!(*engine.accounts, [account]);
+        assert!(engine.history.iter().all(|item| item.id == 1));
+
+        engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                1,
+                4,
+                Some(Amount::from_num(100)),
+            ))
+            .unwrap();
+        assert_eq!(engine.accounts[0].total, Amount::ZERO);
+    }
+
+    #[test]
+    fn transactions_breaking_the_risk_rules_are_rejected() {
+        let mut engine = Engine::new();
+        engine.set_risk_limits(Some(
+            RiskLimits::from_toml("daily_withdrawal_limit = \"50\"\nmax_tx_per_minute = 3\n")
+                .unwrap(),
+        ));
+        let at = |seconds| Timestamp::from_unix(seconds).unwrap();
+        let withdraw = |id, amount| {
+            transaction(
+                TransactionType::Withdraw,
+                1,
+                id,
+                Some(Amount::from_num(amount)),
+            )
+        };
+
+        engine
+            .apply(
+                transaction(TransactionType::Deposit, 1, 1, Some(Amount::from_num(200)))
+                    .with_timestamp(at(0)),
+            )
+            .unwrap();
+        engine
+            .apply(withdraw(2, 30).with_timestamp(at(10)))
+            .unwrap();
+
+        let err = engine
+            .apply(withdraw(3, 25).with_timestamp(at(20)))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::DailyWithdrawalLimitExceeded {
+                client: 1,
+                tx: 3,
+                limit: Amount::from_num(50)
+            })
+        );
+
+        engine
+            .apply(withdraw(4, 20).with_timestamp(at(30)))
+            .unwrap();
+
+        let err = engine
+            .apply(
+                transaction(TransactionType::Deposit, 1, 5, Some(Amount::from_num(1)))
+                    .with_timestamp(at(40)),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::RateLimitExceeded {
+                client: 1,
+                tx: 5,
+                limit: 3
+            })
+        );
+
+        // The next day the withdrawals start over, and the deposit is more than a minute ago
+        engine
+            .apply(withdraw(6, 50).with_timestamp(at(86_400)))
+            .unwrap();
+        assert_eq!(engine.accounts[0].total, Amount::from_num(100));
+    }
+
+    #[test]
+    fn withdrawals_can_use_a_credit_limit() {
+        let mut engine = Engine::new();
+        engine.set_admins(vec![900]);
+        engine.set_credit_limit(1, Currency::default(), Amount::from_num(100));
+        assert_eq!(engine.accounts().count(), 0);
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_num(10)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                1,
+                2,
+                Some(Amount::from_num(50)),
+            ))
+            .unwrap();
+        assert_eq!(engine.accounts[0].available, Amount::from_num(-40));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(-40));
+
+        let over_limit = transaction(TransactionType::Withdraw, 1, 3, Some(Amount::from_num(70)));
+        assert_eq!(
+            engine.process(over_limit),
+            Err(PaymentError::InsufficientFunds { client: 1, tx: 3 })
+        );
+
+        assert_eq!(
+            engine.process(transaction(
+                TransactionType::SetLimit,
+                1,
+                4,
+                Some(Amount::from_num(200)),
+            )),
+            Err(PaymentError::Unauthorized {
+                actor: 1,
+                client: 1,
+                tx: 4
+            })
+        );
+        engine
+            .apply(
+                transaction(TransactionType::SetLimit, 1, 5, Some(Amount::from_num(200)))
+                    .with_actor(900),
+            )
+            .unwrap();
+        engine.apply(over_limit).unwrap();
+        assert_eq!(engine.accounts[0].available, Amount::from_num(-110));
+        assert_eq!(engine.accounts[0].credit_limit, Amount::from_num(200));
+    }
+
+    #[test]
+    fn interest_is_credited_for_each_period_that_ended() {
+        let mut engine = Engine::new();
+        engine.set_interest(Some(1200), InterestPeriod::Monthly);
+        let at = |time: &str| time.parse::<Timestamp>().unwrap();
+
+        engine
+            .apply(
+                transaction(TransactionType::Deposit, 1, 1, Some(Amount::from_num(1200)))
+                    .with_timestamp(at("2024-01-15T00:00:00Z")),
+            )
+            .unwrap();
+        engine
+            .apply(
+                transaction(TransactionType::Deposit, 2, 2, Some(Amount::from_num(50)))
+                    .with_timestamp(at("2024-01-20T00:00:00Z")),
+            )
+            .unwrap();
+        engine
+            .apply(
+                transaction(TransactionType::Withdraw, 2, 3, Some(Amount::from_num(50)))
+                    .with_timestamp(at("2024-01-31T23:59:59Z")),
+            )
+            .unwrap();
+        engine
+            .apply(
+                transaction(TransactionType::Withdraw, 1, 4, "24.12".parse().ok())
+                    .with_timestamp(at("2024-03-03T00:00:00Z")),
+            )
+            .unwrap();
+
+        let credits: Vec<(u32, ClientId, Timestamp, Amount)> = engine
+            .interest_credits()
+            .iter()
+            .map(|credit| (credit.tx, credit.client, credit.period_end, credit.amount))
+            .collect();
+        assert_eq!(
+            credits,
+            [
+                (
+                    u32::MAX,
+                    1,
+                    at("2024-02-01T00:00:00Z"),
+                    Amount::from_num(12)
+                ),
+                (
+                    u32::MAX - 1,
+                    1,
+                    at("2024-03-01T00:00:00Z"),
+                    "12.12".parse().unwrap()
+                ),
+            ]
+        );
+        assert_eq!(engine.accounts[0].total, Amount::from_num(1200));
+        assert_eq!(engine.metrics.processed, 4);
+        assert!(!engine
+            .metrics
+            .by_type
+            .contains_key(&TransactionType::Interest));
+
+        // Only the engine credits interest, and its ids can't be reused
+        assert!(engine
+            .process(transaction(
+                TransactionType::Interest,
+                1,
+                5,
+                Some(Amount::from_num(1_000_000)),
+            ))
+            .is_err());
+        assert!(engine
+            .process(transaction(
+                TransactionType::Deposit,
+                1,
+                u32::MAX - 1,
+                Some(Amount::from_num(1)),
+            ))
+            .is_err());
+        assert!(engine
+            .process(transaction(TransactionType::Dispute, 1, u32::MAX, None))
+            .is_err());
+        assert_eq!(engine.accounts[0].total, Amount::from_num(1200));
+    }
+
+    #[test]
+    fn interest_credits_do_not_count_towards_dispute_expiry() {
+        let day = |day: i64| Timestamp::from_unix(day * 86_400).unwrap();
+        let deposit = |client, id| {
+            transaction(
+                TransactionType::Deposit,
+                client,
+                id,
+                Some(Amount::from_num(100)),
+            )
+        };
+
+        let mut engine = Engine::new();
+        engine.set_interest(Some(1200), InterestPeriod::Daily);
+        engine.set_dispute_expiry(Some(2));
+        engine.apply(deposit(1, 1).with_timestamp(day(0))).unwrap();
+        engine.apply(deposit(2, 2).with_timestamp(day(0))).unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 2, 2, None).with_timestamp(day(0)))
+            .unwrap();
+        // Four periods end before this deposit, each crediting client 1
+        engine.apply(deposit(3, 3).with_timestamp(day(4))).unwrap();
+
+        assert_eq!(engine.interest_credits().len(), 4);
+        assert_eq!(engine.metrics.processed, 4);
+        assert_eq!(engine.metrics.expired_disputes, 0);
+        assert_eq!(engine.accounts[1].held, Amount::from_num(100));
+
+        engine.apply(deposit(3, 4).with_timestamp(day(4))).unwrap();
+        assert_eq!(engine.metrics.expired_disputes, 1);
+    }
+
+    #[test]
+    fn recurring_instructions_expand_into_a_transaction_per_occurrence() {
+        let at = |time: &str| time.parse::<Timestamp>().unwrap();
+        let instruction = RecurringInstruction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx: 100,
+            amount: Amount::from_num(2500),
+            cadence: Cadence::Monthly,
+            start: at("2024-01-31T09:00:00Z"),
+            end: at("2024-03-31T09:00:00Z"),
+            currency: None,
+        };
+
+        let occurrences: Vec<(u32, Option<Timestamp>)> = instruction
+            .transactions()
+            .unwrap()
+            .iter()
+            .map(|tx| (tx.id(), tx.timestamp()))
+            .collect();
+        assert_eq!(
+            occurrences,
+            [
+                (100, Some(at("2024-01-31T09:00:00Z"))),
+                (101, Some(at("2024-02-29T09:00:00Z"))),
+                (102, Some(at("2024-03-31T09:00:00Z"))),
+            ]
+        );
+
+        let weekly = RecurringInstruction {
+            cadence: Cadence::Weekly,
+            end: at("2024-02-20T00:00:00Z"),
+            ..instruction
+        };
+        assert_eq!(weekly.transactions().unwrap().len(), 3);
+
+        let dispute = RecurringInstruction {
+            tx_type: TransactionType::Dispute,
+            ..instruction
+        };
+        assert!(dispute.transactions().is_err());
+    }
+
+    #[test]
+    fn registered_rules_flag_and_reject_transactions() {
+        /// Rejects withdrawals leaving less than 100 available
+        struct MinimumBalance;
+
+        impl RiskRule for MinimumBalance {
+            fn evaluate(&self, account: &Account, tx: &Transaction) -> RuleOutcome {
+                match (tx.tx_type(), tx.amount()) {
+                    (TransactionType::Withdraw, Some(amount))
+                        if account
+                            .available()
+                            .checked_sub(amount)
+                            .is_none_or(|left| left < Amount::from_num(100)) =>
+                    {
+                        RuleOutcome::Reject("less than 100 would be left".to_string())
+                    }
+                    _ => RuleOutcome::Allow,
+                }
+            }
+        }
+
+        let mut engine = Engine::new();
+        engine.add_rule(MinimumBalance);
+        engine.add_rule(Structuring::new(
+            Amount::from_num(1000),
+            Amount::from_num(100),
+            2,
+            3600,
+        ));
+        let deposit = |id, amount| {
+            transaction(
+                TransactionType::Deposit,
+                1,
+                id,
+                Some(Amount::from_num(amount)),
+            )
+        };
+
+        for tx in [deposit(1, 950), deposit(2, 500), deposit(3, 999)].iter() {
+            engine.apply(*tx).unwrap();
+        }
+
+        assert_eq!(
+            engine
+                .flagged()
+                .iter()
+                .map(|flag| (flag.tx, flag.client))
+                .collect::<Vec<_>>(),
+            [(3, 1)]
+        );
+
+        let err = engine
+            .apply(transaction(
+                TransactionType::Withdraw,
+                1,
+                4,
+                Some(Amount::from_num(2400)),
+            ))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PaymentError>(),
+            Some(&PaymentError::RuleViolation {
+                tx: 4,
+                reason: "less than 100 would be left".to_string()
+            })
+        );
+        assert_eq!(engine.accounts[0].total, Amount::from_num(2449));
+    }
+
+    #[test]
+    fn rules_are_not_told_about_duplicates_skipped_in_a_batch() {
+        let mut engine = Engine::new();
+        engine.set_skip_duplicates(true);
+        engine.add_rule(Structuring::new(
+            Amount::from_num(1000),
+            Amount::from_num(100),
+            3,
+            3600,
+        ));
+        let deposit =
+            |id| transaction(TransactionType::Deposit, 1, id, Some(Amount::from_num(950)));
+
+        engine.apply_atomic(&[deposit(1), deposit(1)]).unwrap();
+        engine.apply(deposit(2)).unwrap();
+        assert_eq!(engine.metrics.duplicates, 1);
+        assert!(engine.flagged().is_empty());
+
+        engine.apply(deposit(3)).unwrap();
+        assert_eq!(engine.flagged().len(), 1);
+    }
+
+    #[test]
+    fn accounts_stay_in_first_seen_order() {
+        let mut engine = Engine::new();
+
+        for (id, client) in [3, 1, 2, 1, 3].iter().enumerate() {
+            engine
+                .apply(transaction(
+                    TransactionType::Deposit,
+                    *client,
+                    id as u32,
+                    Some(Amount::from_num(1)),
+                ))
+                .unwrap();
+        }
+
+        let clients: Vec<ClientId> = engine.accounts().map(Account::client).collect();
+        assert_eq!(clients, vec![3, 1, 2]);
+        assert_eq!(
+            engine.accounts.get(1, Currency::DEFAULT).unwrap().total,
+            Amount::from_num(2)
+        );
+        assert!(engine.accounts.get(4, Currency::DEFAULT).is_none());
+    }
+
+    #[test]
+    fn history_limit_evicts_oldest_transactions() {
+        let mut engine = Engine::new();
+        engine.set_history_limit(Some(2));
+
+        for id in 1..=3 {
+            engine
+                .apply(transaction(
+                    TransactionType::Deposit,
+                    1,
+                    id,
+                    Some(Amount::from_num(1)),
+                ))
+                .unwrap();
+        }
+
+        assert_eq!(engine.history.iter().count(), 2);
+        assert!(engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .is_err());
+
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 3, None))
+            .unwrap();
+        assert_eq!(engine.accounts[0].held, Amount::from_num(1));
+    }
+
+    #[test]
+    fn spilled_transactions_can_still_be_disputed() {
+        let path = std::env::temp_dir().join("payments_spilled_transactions.jsonl");
+        let mut engine = Engine::new();
+        engine.set_history_limit(Some(1));
+        engine.spill_history_to(&path).unwrap();
+
+        for (id, amount) in [(1, 10), (2, 5), (3, 1)].iter() {
+            engine
+                .apply(transaction(
+                    TransactionType::Deposit,
+                    1,
+                    *id,
+                    Some(Amount::from_num(*amount)),
+                ))
+                .unwrap();
+        }
+
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        assert_eq!(engine.accounts[0].held, Amount::from_num(10));
+
+        engine
+            .apply(transaction(
+                TransactionType::Deposit,
+                1,
+                4,
+                Some(Amount::from_num(1)),
+            ))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 2, None))
+            .unwrap();
+
+        assert_eq!(engine.accounts[0].held, Amount::from_num(5));
+        assert_eq!(engine.accounts[0].total, Amount::from_num(7));
+    }
+
+    #[test]
+    fn settled_disputes_past_the_history_limit_leave_memory() {
+        let path = std::env::temp_dir().join("payments_released_transactions.jsonl");
+        let deposit = |id| transaction(TransactionType::Deposit, 1, id, Some(Amount::from_num(1)));
+
+        for spill in [false, true].iter() {
+            let mut engine = Engine::new();
+            engine.set_history_limit(Some(2));
+
+            if *spill {
+                engine.spill_history_to(&path).unwrap();
+            }
+
+            for id in 1..=10 {
+                engine.apply(deposit(id)).unwrap();
+                engine
+                    .apply(transaction(TransactionType::Dispute, 1, id, None))
+                    .unwrap();
+            }
+
+            for id in 11..=14 {
+                engine.apply(deposit(id)).unwrap();
+            }
+
+            assert_eq!(engine.history.pinned(), 10);
+
+            for id in 1..=10 {
+                let settle = match id % 2 {
+                    0 => TransactionType::Resolve,
+                    _ => TransactionType::Chargeback,
+                };
+                engine.apply(transaction(settle, 1, id, None)).unwrap();
+            }
+
+            assert_eq!(engine.history.pinned(), 0);
+            assert_eq!(engine.accounts[0].held, Amount::ZERO);
+
+            // Read back from the spill file to be checked, a settled transaction is released again
+            assert!(engine
+                .apply(transaction(TransactionType::Dispute, 1, 1, None))
+                .is_err());
+            assert_eq!(engine.history.pinned(), 0);
+        }
+    }
+
+    fn wrapping_ids(policy: IdWraparound) -> (Engine, Result<(), Error>) {
+        let mut engine = Engine::new();
+        engine.set_id_wraparound(Some(policy));
+
+        for (id, amount) in [(5, 1), (u32::MAX - 1, 1), (u32::MAX, 1)].iter() {
+            engine
+                .apply(transaction(
+                    TransactionType::Deposit,
+                    1,
+                    *id,
+                    Some(Amount::from_num(*amount)),
+                ))
+                .unwrap();
+        }
+
+        let res = engine.apply(transaction(
+            TransactionType::Deposit,
+            1,
+            5,
+            Some(Amount::from_num(2)),
+        ));
+
+        (engine, res)
+    }
+
+    #[test]
+    fn id_wraparound_is_rejected_under_error_policy() {
+        let (engine, res) = wrapping_ids(IdWraparound::Error);
+
+        assert_eq!(
+            res.unwrap_err().downcast_ref::<PaymentError>(),
+            Some(&PaymentError::IdWraparound { tx: 5 })
+        );
+        assert_eq!(engine.accounts[0].total, Amount::from_num(3));
+        assert_eq!(engine.metrics.id_wraparounds, 0);
+    }
+
+    #[test]
+    fn id_wraparound_starts_new_id_space_under_allow_policy() {
+        let (mut engine, res) = wrapping_ids(IdWraparound::Allow);
+
+        res.unwrap();
+        assert_eq!(engine.accounts[0].total, Amount::from_num(5));
+        assert_eq!(engine.metrics.id_wraparounds, 1);
+
+        engine
+            .apply(transaction(TransactionType::Dispute, 1, 5, None))
+            .unwrap();
+        assert_eq!(engine.accounts[0].held, Amount::from_num(2));
+
+        let res = engine.apply(transaction(TransactionType::Dispute, 1, u32::MAX, None));
+        assert!(res.is_err());
     }
 }
@@ -1,11 +1,693 @@
+use clap::{ArgAction, Args, Parser, Subcommand};
+use log::LevelFilter;
+use payments::{
+    Amount, Config, Currency, DisputeExpiryAction, FeeSchedule, Format, IdWraparound,
+    InterestPeriod, LockedAccountPolicy, LockedFormat, OutputFormat, PointInTime, RoundingMode,
+    Timestamp, TransactionType, WithdrawalDisputeMode,
+};
+use std::io::Write;
+
+/// Applies a CSV of transactions to client accounts and writes the resulting accounts to `stdout`
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Process the inputs and write the resulting accounts. This is what runs when no command is given
+    Process(Box<ProcessArgs>),
+    /// Check every row of a CSV input and report problems by line, without applying any of them
+    Validate(ValidateArgs),
+    /// Replay a CSV input and write every transaction involving one client, with their balances after each, as CSV
+    Statement(StatementArgs),
+    /// Process the inputs and write totals of the transactions and accounts, instead of the accounts, as CSV
+    Report(Box<ProcessArgs>),
+    /// Process a CSV input and compare the resulting accounts to a file of expected balances, printing every difference
+    Reconcile(ReconcileArgs),
+    /// Process the inputs and write the accounts as they were at a transaction, or a time, in the inputs
+    BalanceAt(Box<BalanceAtArgs>),
+    /// Serve an engine over gRPC, or over HTTP with --port, until interrupted. Requires the grpc or http feature
+    #[cfg(any(feature = "grpc", feature = "http"))]
+    Serve(ServeArgs),
+    /// Consume transactions from a Kafka topic until interrupted. Requires the kafka feature
+    #[cfg(feature = "kafka")]
+    Consume(ConsumeArgs),
+    /// Process the input files dropped into a directory as they arrive, until interrupted. Requires the watch feature
+    #[cfg(feature = "watch")]
+    Watch(WatchArgs),
+}
+
+#[derive(Debug, Args)]
+struct ValidateArgs {
+    /// The input file to check
+    input: String,
+}
+
+#[derive(Debug, Args)]
+struct StatementArgs {
+    /// The client to write the statement of
+    #[arg(long)]
+    client: u64,
+    /// The input file to replay
+    #[arg(long, value_name = "PATH")]
+    input: String,
+    /// The currency of transactions without a currency column
+    #[arg(long, value_name = "CODE", default_value = "USD")]
+    currency: Currency,
+}
+
+#[derive(Debug, Args)]
+struct ReconcileArgs {
+    /// The balances the input is expected to produce, in the same columns as the output
+    #[arg(long, value_name = "PATH")]
+    expected: String,
+    /// The input file to process
+    #[arg(long, value_name = "PATH")]
+    input: String,
+    /// The currency of transactions and expected balances without a currency column
+    #[arg(long, value_name = "CODE", default_value = "USD")]
+    currency: Currency,
+}
+
+#[derive(Debug, Args)]
+struct BalanceAtArgs {
+    /// Write the accounts as they were just after the transaction with this id was applied
+    #[arg(
+        long,
+        value_name = "ID",
+        required_unless_present = "timestamp",
+        conflicts_with = "timestamp"
+    )]
+    tx: Option<u32>,
+    /// Write the accounts as they were just before the first transaction timestamped after this time, RFC 3339 or
+    /// seconds since the Unix epoch
+    #[arg(long, value_name = "TIME")]
+    timestamp: Option<Timestamp>,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[cfg(any(feature = "grpc", feature = "http"))]
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// The address to serve gRPC on
+    #[cfg(feature = "grpc")]
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:50051")]
+    addr: std::net::SocketAddr,
+    /// Serve the REST API on this port instead of gRPC
+    #[cfg(feature = "http")]
+    #[arg(long, value_name = "PORT")]
+    #[cfg_attr(not(feature = "grpc"), arg(default_value = "8080"))]
+    port: Option<u16>,
+    /// The address to serve the REST API on
+    #[cfg(feature = "http")]
+    #[arg(long, value_name = "IP", default_value = "127.0.0.1")]
+    host: std::net::IpAddr,
+    /// Write the accounts and disputable transactions to this file on shutdown
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<String>,
+    /// Continue from the state in this file, written by an earlier run with --snapshot
+    #[arg(long, value_name = "PATH")]
+    resume: Option<String>,
+    /// The currency of transactions without one
+    #[arg(long, value_name = "CODE", default_value = "USD")]
+    currency: Currency,
+}
+
+#[cfg(feature = "kafka")]
+#[derive(Debug, Args)]
+struct ConsumeArgs {
+    /// The brokers to connect to, as a comma-separated list of host:port
+    #[arg(long, value_name = "HOSTS", default_value = "localhost:9092")]
+    brokers: String,
+    /// The consumer group to commit offsets for
+    #[arg(long, value_name = "GROUP", default_value = "payments")]
+    group: String,
+    /// The topic of transactions to consume, each a JSON payload
+    #[arg(long, value_name = "TOPIC")]
+    topic: String,
+    /// Publish every account to this topic periodically and on shutdown, keyed by client id
+    #[arg(long, value_name = "TOPIC")]
+    snapshot_topic: Option<String>,
+    /// How often to publish the accounts, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    snapshot_interval: u64,
+    /// Write the accounts and disputable transactions to this file on shutdown
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<String>,
+    /// Continue from the state in this file, written by an earlier run with --snapshot
+    #[arg(long, value_name = "PATH")]
+    resume: Option<String>,
+    /// The currency of transactions without one
+    #[arg(long, value_name = "CODE", default_value = "USD")]
+    currency: Currency,
+}
+
+#[cfg(feature = "watch")]
+#[derive(Debug, Args)]
+struct WatchArgs {
+    /// The directory to watch. Files are moved to `processed/` or `failed/` inside it once they are read
+    dir: std::path::PathBuf,
+    /// Append the accounts each file changed to this file instead of `stdout`
+    #[arg(long, value_name = "PATH")]
+    output: Option<String>,
+    /// Write the accounts and disputable transactions to this file after each input, and continue from it on restart
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<String>,
+    /// Keep accounts and transactions in this SQLite database, continuing from any already there. Requires the sqlite
+    /// feature
+    #[arg(long, value_name = "PATH")]
+    store: Option<String>,
+    /// The format the input files are written in, which also picks the files watched for: csv or jsonl
+    #[arg(long, default_value = "csv")]
+    format: Format,
+    /// Skip rows that can't be parsed instead of moving the whole file to `failed/`
+    #[arg(long)]
+    lenient: bool,
+    /// The currency of transactions without a currency column
+    #[arg(long, value_name = "CODE", default_value = "USD")]
+    currency: Currency,
+}
+
+#[derive(Debug, Args)]
+struct ProcessArgs {
+    /// Input files, processed in order against the same accounts
+    #[arg(required_unless_present = "interactive")]
+    inputs: Vec<String>,
+    /// Print the reason each transaction was rejected, and other details, to `stderr`. Repeat to also print every
+    /// transaction applied
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Print nothing but the accounts, regardless of other flags
+    #[arg(short, long)]
+    quiet: bool,
+    /// Read transactions from `stdin` one line at a time and print the affected account after each
+    #[arg(long)]
+    interactive: bool,
+    /// The format the input files are written in: csv or jsonl
+    #[arg(long, default_value = "csv")]
+    format: Format,
+    /// Write the accounts to this file instead of `stdout`
+    #[arg(long)]
+    output: Option<String>,
+    /// The format the accounts are written in: csv, json, ndjson, or parquet
+    #[arg(long, default_value = "csv")]
+    output_format: OutputFormat,
+    /// Memory-map the input files instead of reading them through buffered IO
+    #[arg(long)]
+    mmap: bool,
+    /// Add a `source` column naming the input file that last modified each account
+    #[arg(long)]
+    tag_source: bool,
+    /// Basis points deducted from every deposit as a fee
+    #[arg(long, value_name = "BPS", default_value_t = 0)]
+    deposit_fee_bps: u32,
+    /// Basis points of every withdrawal charged as a fee, on top of the amount withdrawn
+    #[arg(long, value_name = "BPS", default_value_t = 0)]
+    withdrawal_fee_bps: u32,
+    /// A flat fee charged on every withdrawal, on top of the amount withdrawn
+    #[arg(long, value_name = "AMOUNT")]
+    withdrawal_fee_flat: Option<Amount>,
+    /// The lowest available funds fees may leave an account with, ex: -50. Fees are never limited if not set
+    #[arg(long, value_name = "AMOUNT", allow_negative_numbers = true)]
+    fee_floor: Option<Amount>,
+    /// CSV of `client,account` pairs used to add an `account` column
+    #[arg(long, value_name = "PATH")]
+    account_map: Option<String>,
+    /// Use the client id as the account for clients missing from the account map
+    #[arg(long)]
+    allow_unmapped: bool,
+    /// Warn about gaps in the sequence of deposit and withdrawal ids
+    #[arg(long)]
+    detect_gaps: bool,
+    /// Round balances to four decimal places after every transaction
+    #[arg(long)]
+    round_each_op: bool,
+    /// Only count the transactions in the inputs by type
+    #[arg(long)]
+    count_only: bool,
+    /// Skip rows that can't be parsed and list them once the inputs are processed, instead of failing
+    #[arg(long)]
+    lenient: bool,
+    /// Treat the amount on a dispute as the part of the transaction being disputed
+    #[arg(long)]
+    partial_disputes: bool,
+    /// Report disputes of ids no earlier deposit or withdrawal used as arriving out of order
+    #[arg(long)]
+    strict_order: bool,
+    /// Report transactions that referenced another client's transaction as potential fraud
+    #[arg(long)]
+    strict_clients: bool,
+    /// Reject transactions timestamped before the latest timestamp seen, for inputs with a timestamp column
+    #[arg(long)]
+    reject_out_of_order: bool,
+    /// Skip deposits, withdrawals, transfers, fees, and conversions with the client and id of one already applied,
+    /// including in the run resumed from, and report how many were skipped
+    #[arg(long)]
+    skip_duplicates: bool,
+    /// Credit deposits to pending funds until they are settled
+    #[arg(long)]
+    pending_deposits: bool,
+    /// The number of times a single transaction may be disputed
+    #[arg(long, value_name = "N")]
+    max_disputes_per_tx: Option<u32>,
+    /// Fail the run if more than this fraction of transactions are rejected
+    #[arg(long, value_name = "RATIO")]
+    max_error_ratio: Option<f64>,
+    /// Resolve disputes still open after this many subsequent transactions
+    #[arg(long, value_name = "N")]
+    dispute_expiry: Option<u64>,
+    /// Resolve disputes still open this many days after they were opened, for inputs with a timestamp column
+    #[arg(long, value_name = "DAYS")]
+    dispute_expiry_days: Option<u32>,
+    /// What happens to a dispute once it expires: resolve, or escalate to leave the funds held and flag it
+    #[arg(long, value_name = "ACTION", default_value = "resolve")]
+    dispute_expiry_action: DisputeExpiryAction,
+    /// Write every dispute still open at the end of the run, with the disputed transaction's type and amount, its age,
+    /// and the amount held, to this file
+    #[arg(long, value_name = "PATH", visible_alias = "disputes-output")]
+    dispute_report: Option<String>,
+    /// Accrue interest at this many basis points a year on available funds, for inputs with a timestamp column
+    #[arg(long, value_name = "BPS")]
+    interest_apr_bps: Option<u32>,
+    /// How often accrued interest is credited: daily or monthly
+    #[arg(long, value_name = "PERIOD", default_value = "monthly")]
+    interest_period: InterestPeriod,
+    /// Write every interest credit, with the balance it was earned on, to this file
+    #[arg(long, value_name = "PATH")]
+    interest_report: Option<String>,
+    /// Interleave the deposits and withdrawals of the recurring instructions in this CSV with the inputs by timestamp
+    #[arg(long, value_name = "PATH")]
+    recurring: Option<String>,
+    /// Write every parsed transaction to this file as canonical CSV
+    #[arg(long, value_name = "PATH")]
+    echo_normalized: Option<String>,
+    /// Only write the accounts with the largest total balances
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+    /// Write the accounts in the order their clients were first seen instead of sorted by client id
+    #[arg(long)]
+    no_sort: bool,
+    /// Write the accounts and disputable transactions to this file once the inputs are processed
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<String>,
+    /// Continue from the state in this file, written by an earlier run with --snapshot
+    #[arg(long, value_name = "PATH")]
+    resume: Option<String>,
+    /// Keep accounts and transactions in this SQLite database, continuing from any already there. Requires the sqlite
+    /// feature
+    #[arg(long, value_name = "PATH")]
+    store: Option<String>,
+    /// Write every rejected transaction and skipped row to this file, as JSON if it ends in .json and CSV otherwise
+    #[arg(long, value_name = "PATH")]
+    errors: Option<String>,
+    /// Write the ledger of disputable transactions to this file as JSON
+    #[arg(long, value_name = "PATH")]
+    dump_state: Option<String>,
+    /// The number of decimal places amounts are written with
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(0..=payments::MAX_DECIMAL_PLACES as i64))]
+    decimal_places: Option<u32>,
+    /// How amounts are rounded to the decimal places when they fall exactly halfway: half-up or bankers
+    #[arg(long, value_name = "MODE", default_value = "half-up")]
+    rounding: RoundingMode,
+    /// How the `locked` column is written: bool, int, or yesno
+    #[arg(long, default_value = "bool")]
+    locked_format: LockedFormat,
+    /// The number of transactions the inputs are expected to hold, to reserve memory up front
+    #[arg(long, value_name = "N")]
+    expected_rows: Option<usize>,
+    /// Write metrics to this file in the Prometheus text format
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<String>,
+    /// What to do when ids wrap around past `u32::MAX`: error or allow
+    #[arg(long, value_name = "POLICY")]
+    id_wraparound: Option<IdWraparound>,
+    /// Reject deposits and withdrawals of more than this amount
+    #[arg(long, value_name = "AMOUNT")]
+    max_tx_amount: Option<Amount>,
+    /// Path to a TOML file of risk rules: max_tx_amount, daily_withdrawal_limit, and max_tx_per_minute
+    #[arg(long, value_name = "PATH")]
+    risk_rules: Option<String>,
+    /// The capacity, in bytes, of the buffer the output is written through
+    #[arg(long, value_name = "BYTES")]
+    output_buffer_size: Option<usize>,
+    /// Keep at most this many deposits and withdrawals in memory for later disputes
+    #[arg(long, value_name = "N")]
+    history_limit: Option<usize>,
+    /// Write deposits and withdrawals beyond the history limit to this file so they can still be disputed
+    #[arg(long, value_name = "PATH")]
+    history_spill: Option<String>,
+    /// Which transactions locked accounts still accept: reject-all or allow-deposits
+    #[arg(long, value_name = "POLICY", default_value = "reject-all")]
+    locked_accounts: LockedAccountPolicy,
+    /// How disputing a withdrawal affects the account before the dispute is decided: provisional or deferred
+    #[arg(long, value_name = "MODE", default_value = "provisional")]
+    withdrawal_dispute_mode: WithdrawalDisputeMode,
+    /// Write a report of held funds and open disputes to this file
+    #[arg(long, value_name = "PATH")]
+    held_report: Option<String>,
+    /// Split the clients into this many shards and process them in parallel, one thread each
+    #[arg(long, value_name = "N")]
+    threads: Option<usize>,
+    /// Only process transactions of these types
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    only: Option<Vec<TransactionType>>,
+    /// Ignore transactions of these types
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    exclude: Vec<TransactionType>,
+    /// The ids allowed to unlock accounts, and to lock accounts other than their own
+    #[arg(long, value_name = "IDS", value_delimiter = ',')]
+    admins: Vec<u64>,
+    /// Write every lock and unlock that was applied, with who applied it, to this file
+    #[arg(long, value_name = "PATH")]
+    lock_audit: Option<String>,
+    /// The currency of transactions without a currency column. Accounts in other currencies add a currency column to
+    /// the output
+    #[arg(long, value_name = "CODE", default_value = "USD")]
+    currency: Currency,
+    /// A CSV file of `from,to,rate` rows, with an optional `spread_bps` column, that conversions without a rate are
+    /// made at
+    #[arg(long, value_name = "PATH")]
+    rates: Option<String>,
+    /// A CSV file of `client,limit` rows, with an optional `currency` column, giving clients a credit limit that
+    /// withdrawals can take their available funds down to minus
+    #[arg(long, value_name = "PATH")]
+    credit_limits: Option<String>,
+    /// How converted amounts are rounded when they fall exactly halfway: half-up or bankers
+    #[arg(long, value_name = "MODE", default_value = "half-up")]
+    conversion_rounding: RoundingMode,
+    /// Write every conversion that was applied, with its rate and the spread it realized, to this file
+    #[arg(long, value_name = "PATH")]
+    conversion_report: Option<String>,
+    /// Append a line of JSON to this file for every transaction, with the balances before and after it and whether it
+    /// was applied
+    #[arg(long, value_name = "PATH")]
+    audit: Option<String>,
+}
+
+impl ProcessArgs {
+    fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::Off,
+            (false, 0) => LevelFilter::Warn,
+            (false, 1) => LevelFilter::Info,
+            (false, _) => LevelFilter::Debug,
+        }
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            mmap: self.mmap,
+            format: self.format,
+            only: self.only.clone(),
+            exclude: self.exclude.clone(),
+            tag_source: self.tag_source,
+            deposit_fee_bps: self.deposit_fee_bps,
+            withdrawal_fees: FeeSchedule {
+                bps: self.withdrawal_fee_bps,
+                flat: self.withdrawal_fee_flat.unwrap_or_default(),
+            },
+            fee_floor: self.fee_floor,
+            account_map: self.account_map.clone(),
+            allow_unmapped: self.allow_unmapped,
+            detect_gaps: self.detect_gaps,
+            round_each_op: self.round_each_op,
+            held_report: self.held_report.clone(),
+            count_only: self.count_only,
+            output_format: self.output_format,
+            output: self.output.clone(),
+            max_disputes_per_tx: self.max_disputes_per_tx,
+            max_error_ratio: self.max_error_ratio,
+            lenient: self.lenient,
+            partial_disputes: self.partial_disputes,
+            dispute_expiry: self.dispute_expiry,
+            dispute_expiry_days: self.dispute_expiry_days,
+            dispute_expiry_action: self.dispute_expiry_action,
+            dispute_report: self.dispute_report.clone(),
+            interest_apr_bps: self.interest_apr_bps,
+            interest_period: self.interest_period,
+            interest_report: self.interest_report.clone(),
+            recurring: self.recurring.clone(),
+            echo_normalized: self.echo_normalized.clone(),
+            top: self.top,
+            unsorted: self.no_sort,
+            errors: self.errors.clone(),
+            dump_state: self.dump_state.clone(),
+            snapshot: self.snapshot.clone(),
+            resume: self.resume.clone(),
+            store: self.store.clone(),
+            decimal_places: self.decimal_places,
+            rounding: self.rounding,
+            locked_format: self.locked_format,
+            expected_rows: self.expected_rows,
+            strict_order: self.strict_order,
+            reject_out_of_order: self.reject_out_of_order,
+            skip_duplicates: self.skip_duplicates,
+            strict_clients: self.strict_clients,
+            pending_deposits: self.pending_deposits,
+            metrics_file: self.metrics_file.clone(),
+            id_wraparound: self.id_wraparound,
+            max_tx_amount: self.max_tx_amount,
+            risk_rules: self.risk_rules.clone(),
+            output_buffer_size: self.output_buffer_size,
+            history_limit: self.history_limit,
+            history_spill: self.history_spill.clone(),
+            locked_accounts: self.locked_accounts,
+            withdrawal_disputes: self.withdrawal_dispute_mode,
+            threads: self.threads,
+            admins: self.admins.clone(),
+            lock_audit: self.lock_audit.clone(),
+            currency: self.currency,
+            rates: self.rates.clone(),
+            credit_limits: self.credit_limits.clone(),
+            conversion_rounding: self.conversion_rounding,
+            conversion_report: self.conversion_report.clone(),
+            audit: self.audit.clone(),
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     std::env::set_var("RUST_BACKTRACE", "1");
-    let path = std::env::args().nth(1).expect("Invalid argument passed");
-    let mut verbose = false;
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Process(args)) => process(&args),
+        Some(Command::Validate(args)) => validate(&args),
+        Some(Command::Statement(args)) => statement(&args),
+        Some(Command::Report(args)) => report(&args),
+        Some(Command::Reconcile(args)) => reconcile(&args),
+        Some(Command::BalanceAt(args)) => balance_at(&args),
+        #[cfg(any(feature = "grpc", feature = "http"))]
+        Some(Command::Serve(args)) => serve(&args),
+        #[cfg(feature = "kafka")]
+        Some(Command::Consume(args)) => consume(&args),
+        #[cfg(feature = "watch")]
+        Some(Command::Watch(args)) => watch(&args),
+        None => process(&cli.process),
+    }
+}
+
+/// Prints every problem found in the input, exiting with an error status if there were any
+fn validate(args: &ValidateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let report = payments::validate(std::fs::File::open(&args.input)?)?;
 
-    if let Some(arg) = std::env::args().nth(2) {
-        verbose = arg == "verbose".to_string();
+    for issue in &report.issues {
+        println!("{}", issue);
+    }
+
+    println!(
+        "{} rows checked, {} problems found",
+        report.rows,
+        report.issues.len()
+    );
+
+    if !report.is_valid() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Writes the statement of the client as CSV to `stdout`
+fn statement(args: &StatementArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config {
+        currency: args.currency,
+        ..Config::default()
     };
+    let lines = payments::statement(std::fs::File::open(&args.input)?, args.client, &config)?;
+    let mut writer = csv::Writer::from_writer(std::io::stdout().lock());
+
+    for line in lines {
+        writer.serialize(line)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Writes the summary of the inputs to `stdout`, or the output file if one is given
+fn report(args: &ProcessArgs) -> Result<(), Box<dyn std::error::Error>> {
+    init_logger(args.log_level());
+
+    Ok(payments::report(&args.inputs, &args.config())?)
+}
+
+/// Prints every discrepancy between the accounts and the expected balances, exiting with an error status if there
+/// were any
+fn reconcile(args: &ReconcileArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config {
+        currency: args.currency,
+        ..Config::default()
+    };
+    let discrepancies = payments::reconcile(
+        std::slice::from_ref(&args.input),
+        std::fs::File::open(&args.expected)?,
+        &config,
+    )?;
+
+    for discrepancy in &discrepancies {
+        println!("{}", discrepancy);
+    }
+
+    println!("{} discrepancies found", discrepancies.len());
+
+    if !discrepancies.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Writes the accounts as they were at the transaction or time to `stdout`, or the output file if one is given
+fn balance_at(args: &BalanceAtArgs) -> Result<(), Box<dyn std::error::Error>> {
+    init_logger(args.process.log_level());
+    let at = match (args.tx, args.timestamp) {
+        (Some(id), _) => PointInTime::Transaction(id),
+        (None, Some(time)) => PointInTime::Timestamp(time),
+        (None, None) => unreachable!("clap requires --tx or --timestamp"),
+    };
+
+    Ok(payments::balance_at(
+        &args.process.inputs,
+        &args.process.config(),
+        at,
+    )?)
+}
+
+/// Serves until interrupted with Ctrl-C, then writes the snapshot
+#[cfg(any(feature = "grpc", feature = "http"))]
+fn serve(args: &ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    init_logger(LevelFilter::Info);
+    let config = Config {
+        snapshot: args.snapshot.clone(),
+        resume: args.resume.clone(),
+        currency: args.currency,
+        ..Config::default()
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    #[cfg(feature = "http")]
+    if let Some(port) = args.port {
+        let addr = std::net::SocketAddr::new(args.host, port);
+        runtime.block_on(payments::http::serve(addr, &config, shutdown()))?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "grpc")]
+    runtime.block_on(payments::grpc::serve(args.addr, &config, shutdown()))?;
+
+    Ok(())
+}
+
+/// Consumes until interrupted with Ctrl-C, then publishes the accounts a last time and writes the snapshot
+#[cfg(feature = "kafka")]
+fn consume(args: &ConsumeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    init_logger(LevelFilter::Info);
+    let kafka = payments::kafka::KafkaConfig {
+        brokers: args.brokers.clone(),
+        group_id: args.group.clone(),
+        topic: args.topic.clone(),
+        snapshot_topic: args.snapshot_topic.clone(),
+        snapshot_interval: std::time::Duration::from_secs(args.snapshot_interval),
+    };
+    let config = Config {
+        snapshot: args.snapshot.clone(),
+        resume: args.resume.clone(),
+        currency: args.currency,
+        ..Config::default()
+    };
+
+    Ok(
+        tokio::runtime::Runtime::new()?.block_on(payments::kafka::consume(
+            &kafka,
+            &config,
+            shutdown(),
+        ))?,
+    )
+}
+
+/// Watches until the process is interrupted. The state is written after every input, so nothing is lost by stopping
+#[cfg(feature = "watch")]
+fn watch(args: &WatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    init_logger(LevelFilter::Info);
+    let config = Config {
+        output: args.output.clone(),
+        snapshot: args.snapshot.clone(),
+        store: args.store.clone(),
+        format: args.format,
+        lenient: args.lenient,
+        currency: args.currency,
+        ..Config::default()
+    };
+    let stop = std::sync::atomic::AtomicBool::new(false);
+
+    Ok(payments::watch(&args.dir, &config, &stop)?)
+}
+
+#[cfg(any(feature = "grpc", feature = "http", feature = "kafka"))]
+async fn shutdown() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Sends diagnostics to `stderr`, so `stdout` only ever holds the accounts. `RUST_LOG` overrides the level the flags
+/// chose
+fn init_logger(level: LevelFilter) {
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            log::Level::Error => writeln!(buf, "Error: {}", record.args()),
+            log::Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .target(env_logger::Target::Stderr)
+        .init();
+}
+
+fn process(args: &ProcessArgs) -> Result<(), Box<dyn std::error::Error>> {
+    init_logger(args.log_level());
+    let config = args.config();
+
+    if args.interactive {
+        let stdin = std::io::stdin();
+        return Ok(payments::run_interactive(
+            &config,
+            stdin.lock(),
+            std::io::stdout(),
+        )?);
+    }
 
-    Ok(payments::run(&path, verbose)?)
+    Ok(payments::run(&args.inputs, &config)?)
 }
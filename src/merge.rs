@@ -0,0 +1,114 @@
+use crate::{process_row, Config, Sink, Timestamp};
+use anyhow::Error;
+use csv::{Reader, StringRecord, Writer};
+use log::info;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+
+/// An input being merged, holding the row it will give next
+struct Source<'a> {
+    input: &'a str,
+    reader: Reader<File>,
+    headers: StringRecord,
+    column: usize,
+    row: StringRecord,
+    /// The timestamp of `row`, or of the last row before it that had one
+    timestamp: Option<Timestamp>,
+}
+
+impl Source<'_> {
+    /// Reads the next row, returning `false` once the input is exhausted. A row with an empty timestamp, or one that
+    /// can't be parsed when lenient, keeps the timestamp of the row before it so it stays right after that row
+    fn advance(&mut self, config: &Config) -> Result<bool, Error> {
+        if !self.reader.read_record(&mut self.row)? {
+            return Ok(false);
+        }
+
+        let field = self.row.get(self.column).unwrap_or("");
+
+        if !field.is_empty() {
+            match field.parse() {
+                Ok(timestamp) => self.timestamp = Some(timestamp),
+                Err(_) if config.lenient => {}
+                Err(err) => {
+                    let line = self.row.position().map_or(0, |position| position.line());
+                    return Err(Error::msg(format!("{} line {}: {}", self.input, line, err)));
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Applies the rows of every input in timestamp order, as though the inputs were a single file. Each input is expected
+/// to already be in timestamp order, as one processor's export would be, so rows are never reordered within an input.
+/// Rows with the same timestamp in different inputs are applied in the order the inputs were given
+pub(crate) fn merge_by_timestamp<S: Sink>(
+    sink: &mut S,
+    inputs: &[String],
+    readers: Vec<Reader<File>>,
+    config: &Config,
+    mut echo: Option<&mut Writer<File>>,
+) -> Result<(), Error> {
+    let mut sources = Vec::with_capacity(readers.len());
+    let mut next = BinaryHeap::new();
+
+    for (input, mut reader) in inputs.iter().zip(readers) {
+        let headers = reader.headers()?.clone();
+        let column = headers
+            .iter()
+            .position(|header| header == "timestamp")
+            .unwrap_or_default();
+        let mut source = Source {
+            input,
+            reader,
+            headers,
+            column,
+            row: StringRecord::new(),
+            timestamp: None,
+        };
+
+        if source.advance(config)? {
+            next.push(Reverse((source.timestamp, sources.len())));
+        }
+
+        sources.push(source);
+    }
+
+    info!("Merging {} inputs by timestamp", sources.len());
+
+    let mut current = None;
+    let mut ignored = 0;
+
+    while let Some(Reverse((_, index))) = next.pop() {
+        let source = &mut sources[index];
+
+        if current != Some(index) {
+            sink.start_input(source.input, config)?;
+            current = Some(index);
+        }
+
+        if !process_row(
+            sink,
+            source.input,
+            &source.row,
+            &source.headers,
+            config,
+            echo.as_deref_mut(),
+        )? {
+            ignored += 1;
+        }
+
+        if source.advance(config)? {
+            next.push(Reverse((source.timestamp, index)));
+        }
+    }
+
+    if ignored > 0 {
+        info!("Ignored {} transactions filtered by type", ignored);
+    }
+
+    Ok(())
+}
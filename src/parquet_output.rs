@@ -0,0 +1,131 @@
+use crate::{Account, Amount, AmountFormat};
+use anyhow::Error;
+use arrow::array::{ArrayRef, BooleanArray, Decimal128Array, StringArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::sync::Arc;
+
+const DECIMAL_PRECISION: u8 = 38;
+
+/// Writes the accounts to a Parquet file, with amounts stored as decimals to the format's number of places. The
+/// currency of each account is written after its client if `currency_column` is set
+pub(crate) fn write_parquet(
+    accounts: &[Account],
+    path: &str,
+    format: AmountFormat,
+    currency_column: bool,
+) -> Result<(), Error> {
+    let decimal = DataType::Decimal128(DECIMAL_PRECISION, format.places as i8);
+    let mut fields = vec![Field::new("client", DataType::UInt64, false)];
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(UInt64Array::from_iter_values(
+        accounts.iter().map(|account| account.client),
+    ))];
+
+    if currency_column {
+        fields.push(Field::new("currency", DataType::Utf8, false));
+        columns.push(Arc::new(StringArray::from_iter_values(
+            accounts.iter().map(|account| account.currency.code()),
+        )));
+    }
+
+    fields.extend([
+        Field::new("available", decimal.clone(), false),
+        Field::new("held", decimal.clone(), false),
+        Field::new("total", decimal, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]);
+    columns.extend([
+        decimal_column(accounts, format, |account| account.available)?,
+        decimal_column(accounts, format, |account| account.held)?,
+        decimal_column(accounts, format, |account| account.total)?,
+        Arc::new(BooleanArray::from(
+            accounts
+                .iter()
+                .map(|account| account.status.is_locked())
+                .collect::<Vec<_>>(),
+        )),
+    ]);
+
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
+
+fn decimal_column(
+    accounts: &[Account],
+    format: AmountFormat,
+    amount: impl Fn(&Account) -> Amount,
+) -> Result<ArrayRef, Error> {
+    let array = Decimal128Array::from_iter_values(
+        accounts.iter().map(|account| format.units(amount(account))),
+    )
+    .with_precision_and_scale(DECIMAL_PRECISION, format.places as i8)?;
+
+    Ok(Arc::new(array))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccountStatus, Currency};
+    use arrow::array::AsArray;
+    use arrow::datatypes::{Decimal128Type, UInt64Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn parquet_round_trips_accounts() {
+        let path = std::env::temp_dir().join("payments_parquet_round_trip.parquet");
+        let accounts = vec![Account {
+            client: 70_000,
+            currency: Currency::default(),
+            account_number: None,
+            available: Amount::from_num(1.5),
+            held: Amount::from_num(0.25),
+            pending: Amount::ZERO,
+            total: Amount::from_num(1.75),
+            credit_limit: Amount::ZERO,
+            status: AccountStatus::ChargedBack,
+            source: None,
+            last_activity: None,
+        }];
+
+        write_parquet(
+            &accounts,
+            path.to_str().unwrap(),
+            AmountFormat::default(),
+            false,
+        )
+        .unwrap();
+
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batch = reader.next().unwrap().unwrap();
+
+        assert_eq!(
+            batch.column(0).as_primitive::<UInt64Type>().value(0),
+            70_000
+        );
+        assert_eq!(
+            batch.column(1).as_primitive::<Decimal128Type>().value(0),
+            15_000
+        );
+        assert_eq!(
+            batch.column(2).as_primitive::<Decimal128Type>().value(0),
+            2_500
+        );
+        assert_eq!(
+            batch.column(3).as_primitive::<Decimal128Type>().value(0),
+            17_500
+        );
+        assert!(batch.column(4).as_boolean().value(0));
+    }
+}
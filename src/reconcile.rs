@@ -0,0 +1,219 @@
+use crate::{process_inputs, reader_builder, Amount, ClientId, Config, Currency};
+use anyhow::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+
+/// A row of an expected balances file, in the same columns as the CSV output
+#[derive(Deserialize)]
+struct ExpectedRow {
+    client: ClientId,
+    #[serde(default)]
+    currency: Option<Currency>,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: String,
+}
+
+/// The balances of an account, as expected or as the engine left them
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Balances {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// A difference between the accounts the engine produced and the expected balances, found by [`reconcile`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Discrepancy {
+    /// The expected balances have an account the engine didn't produce
+    Missing {
+        client: ClientId,
+        currency: Currency,
+    },
+    /// The engine produced an account the expected balances don't have
+    Unexpected {
+        client: ClientId,
+        currency: Currency,
+    },
+    /// A field of an account differs, with both values written as in the output
+    Mismatch {
+        client: ClientId,
+        currency: Currency,
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Missing { client, currency } => {
+                write!(
+                    f,
+                    "client {} {}: expected account not found",
+                    client, currency
+                )
+            }
+            Discrepancy::Unexpected { client, currency } => {
+                write!(
+                    f,
+                    "client {} {}: account not in the expected balances",
+                    client, currency
+                )
+            }
+            Discrepancy::Mismatch {
+                client,
+                currency,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "client {} {}: {} expected {}, got {}",
+                client, currency, field, expected, actual
+            ),
+        }
+    }
+}
+
+/// Processes the inputs with the options in `config` and compares the resulting accounts to a CSV of expected
+/// balances with `client`, `available`, `held`, `total`, and `locked` columns, and a `currency` column for accounts in
+/// other currencies than the configured one. Amounts are compared at the output's decimal places, and `locked` can be
+/// written in any of the output's formats. Returns the discrepancies in client and currency order
+///
+/// ```
+/// let input = std::env::temp_dir().join("payments_reconcile_doc.csv");
+/// std::fs::write(&input, "type,client,tx,amount\ndeposit,1,1,5\ndeposit,2,2,3\n").unwrap();
+///
+/// let expected = "client,available,held,total,locked\n1,5,0,5,false\n2,4,0,4,false\n";
+/// let inputs = [input.to_string_lossy().into_owned()];
+/// let discrepancies =
+///     payments::reconcile(&inputs, expected.as_bytes(), &payments::Config::default()).unwrap();
+///
+/// assert_eq!(discrepancies.len(), 2);
+/// assert_eq!(discrepancies[0].to_string(), "client 2 USD: available expected 4, got 3");
+/// ```
+pub fn reconcile<R: Read>(
+    inputs: &[String],
+    expected: R,
+    config: &Config,
+) -> Result<Vec<Discrepancy>, Error> {
+    let mut balances: BTreeMap<(ClientId, Currency), (Option<Balances>, Option<Balances>)> =
+        BTreeMap::new();
+
+    for row in reader_builder().from_reader(expected).deserialize() {
+        let row: ExpectedRow = row?;
+        let currency = row.currency.unwrap_or(config.currency);
+        let locked = parse_locked(&row.locked)?;
+
+        balances.entry((row.client, currency)).or_default().0 = Some(Balances {
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            locked,
+        });
+    }
+
+    for account in process_inputs(inputs, config)?.accounts() {
+        balances
+            .entry((account.client, account.currency))
+            .or_default()
+            .1 = Some(Balances {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.status.is_locked(),
+        });
+    }
+
+    let format = config.amount_format();
+    let mut discrepancies = Vec::new();
+
+    for ((client, currency), balances) in balances {
+        let (expected, actual) = match balances {
+            (Some(expected), Some(actual)) => (expected, actual),
+            (Some(_), None) => {
+                discrepancies.push(Discrepancy::Missing { client, currency });
+                continue;
+            }
+            (None, _) => {
+                discrepancies.push(Discrepancy::Unexpected { client, currency });
+                continue;
+            }
+        };
+
+        let amounts = [
+            ("available", expected.available, actual.available),
+            ("held", expected.held, actual.held),
+            ("total", expected.total, actual.total),
+        ];
+
+        for &(field, expected, actual) in amounts.iter() {
+            if format.units(expected) != format.units(actual) {
+                discrepancies.push(Discrepancy::Mismatch {
+                    client,
+                    currency,
+                    field,
+                    expected: format.trimmed(expected).to_string(),
+                    actual: format.trimmed(actual).to_string(),
+                });
+            }
+        }
+
+        if expected.locked != actual.locked {
+            discrepancies.push(Discrepancy::Mismatch {
+                client,
+                currency,
+                field: "locked",
+                expected: expected.locked.to_string(),
+                actual: actual.locked.to_string(),
+            });
+        }
+    }
+
+    Ok(discrepancies)
+}
+
+/// Reads a `locked` value written in any of the output's formats
+fn parse_locked(locked: &str) -> Result<bool, Error> {
+    match locked.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(Error::msg(format!("Invalid locked value: {}", locked))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_are_compared_at_the_output_scale() {
+        let input = std::env::temp_dir().join("payments_reconcile_scale.csv");
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,1.23456\ndeposit,2,2,1\ndispute,2,2,\nchargeback,2,2,\n",
+        )
+        .unwrap();
+
+        let expected = "client,available,held,total,locked\n1,1.2346,0,1.2346,no\n2,0,0,0,0\n";
+        let inputs = [input.to_string_lossy().into_owned()];
+        let discrepancies = reconcile(&inputs, expected.as_bytes(), &Config::default()).unwrap();
+
+        assert_eq!(
+            discrepancies,
+            vec![Discrepancy::Mismatch {
+                client: 2,
+                currency: Currency::DEFAULT,
+                field: "locked",
+                expected: "false".to_string(),
+                actual: "true".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Limits on how much and how often each client can move money, and rules that look for suspicious patterns, for
+//! stopping abuse that the balance checks alone let through
+
+use crate::{
+    Account, Amount, ClientId, Currency, PaymentError, Timestamp, Transaction, TransactionType,
+};
+use anyhow::Error;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+/// How many seconds the transaction rate limit counts over
+const RATE_WINDOW_SECONDS: i64 = 60;
+
+/// The risk rules of a TOML file such as
+///
+/// ```toml
+/// max_tx_amount = "10000"
+/// daily_withdrawal_limit = "2500"
+/// max_tx_per_minute = 10
+/// ```
+///
+/// Amounts are written as strings so they're read with the engine's precision. Every rule is optional, including the
+/// built-in [`RiskRule`]s, given as tables:
+///
+/// ```toml
+/// [structuring]
+/// threshold = "10000"
+/// margin = "1000"
+/// count = 3
+/// window_seconds = 86400
+///
+/// [rapid_disputes]
+/// count = 3
+/// window_seconds = 3600
+/// ```
+#[derive(Debug, Default, Clone, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RiskLimits {
+    /// The largest amount a single deposit, withdrawal, or transfer may move
+    pub max_tx_amount: Option<Amount>,
+    /// The most a client may withdraw from an account in a single day, UTC, going by the withdrawals' timestamps.
+    /// Without timestamps, the whole run counts as one day
+    pub daily_withdrawal_limit: Option<Amount>,
+    /// The most deposits, withdrawals, and transfers a client may make in any 60 seconds. Only timestamped
+    /// transactions are counted
+    pub max_tx_per_minute: Option<u32>,
+    pub structuring: Option<Structuring>,
+    pub rapid_disputes: Option<RapidDisputes>,
+}
+
+impl RiskLimits {
+    pub fn from_toml(toml: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path)?;
+
+        Self::from_toml(&toml)
+            .map_err(|err| Error::msg(format!("Invalid risk rules in {}: {}", path.display(), err)))
+    }
+}
+
+/// What has to be remembered of each client's earlier transactions to enforce [`RiskLimits`]
+#[derive(Debug, Clone)]
+pub(crate) struct RiskTracker {
+    limits: RiskLimits,
+    /// The day of each account's latest withdrawal, as days since the Unix epoch, and how much was withdrawn that day
+    withdrawn: HashMap<(ClientId, Currency), (Option<i64>, Amount)>,
+    /// The timestamps of each client's deposits, withdrawals, and transfers in the last minute, oldest first
+    recent: HashMap<ClientId, VecDeque<Timestamp>>,
+}
+
+impl RiskTracker {
+    pub(crate) fn new(limits: RiskLimits) -> Self {
+        RiskTracker {
+            limits,
+            withdrawn: HashMap::new(),
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Rejects `tx` if applying it would break a daily withdrawal limit or the transaction rate limit. `tx` must
+    /// already have its currency
+    pub(crate) fn check(&self, tx: &Transaction) -> Result<(), PaymentError> {
+        if let (TransactionType::Withdraw, Some(amount), Some(limit), Some(currency)) = (
+            tx.tx_type,
+            tx.amount,
+            self.limits.daily_withdrawal_limit,
+            tx.currency,
+        ) {
+            let withdrawn = self.withdrawn_today(tx.client, currency, tx.timestamp);
+
+            if withdrawn
+                .checked_add(amount)
+                .is_none_or(|total| total > limit)
+            {
+                return Err(PaymentError::DailyWithdrawalLimitExceeded {
+                    client: tx.client,
+                    tx: tx.id,
+                    limit,
+                });
+            }
+        }
+
+        if let (
+            TransactionType::Deposit | TransactionType::Withdraw | TransactionType::Transfer,
+            Some(limit),
+            Some(now),
+        ) = (tx.tx_type, self.limits.max_tx_per_minute, tx.timestamp)
+        {
+            let count = self.recent.get(&tx.client).map_or(0, |recent| {
+                recent
+                    .iter()
+                    .filter(|&&earlier| within_window(now, earlier))
+                    .count()
+            });
+
+            if count >= limit as usize {
+                return Err(PaymentError::RateLimitExceeded {
+                    client: tx.client,
+                    tx: tx.id,
+                    limit,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts `tx` towards the limits, once it has been applied
+    pub(crate) fn record(&mut self, tx: &Transaction, currency: Currency) {
+        if let (TransactionType::Withdraw, Some(amount)) = (tx.tx_type, tx.amount) {
+            let withdrawn = self.withdrawn_today(tx.client, currency, tx.timestamp);
+            let day = tx.timestamp.map(Timestamp::day).or_else(|| {
+                self.withdrawn
+                    .get(&(tx.client, currency))
+                    .and_then(|&(day, _)| day)
+            });
+            self.withdrawn.insert(
+                (tx.client, currency),
+                (day, withdrawn.saturating_add(amount)),
+            );
+        }
+
+        if let (
+            TransactionType::Deposit | TransactionType::Withdraw | TransactionType::Transfer,
+            Some(_),
+            Some(now),
+        ) = (tx.tx_type, self.limits.max_tx_per_minute, tx.timestamp)
+        {
+            let recent = self.recent.entry(tx.client).or_default();
+
+            while recent
+                .front()
+                .is_some_and(|&earlier| !within_window(now, earlier))
+            {
+                recent.pop_front();
+            }
+
+            recent.push_back(now);
+        }
+    }
+
+    /// How much has been withdrawn from the account on the day of `timestamp`. A withdrawal without a timestamp counts
+    /// towards the same day as the account's latest withdrawal
+    fn withdrawn_today(
+        &self,
+        client: ClientId,
+        currency: Currency,
+        timestamp: Option<Timestamp>,
+    ) -> Amount {
+        match self.withdrawn.get(&(client, currency)) {
+            Some(&(day, withdrawn))
+                if timestamp.is_none() || timestamp.map(Timestamp::day) == day =>
+            {
+                withdrawn
+            }
+            _ => Amount::ZERO,
+        }
+    }
+}
+
+/// Whether `earlier` is within the rate limit's window before `now`
+fn within_window(now: Timestamp, earlier: Timestamp) -> bool {
+    now.seconds_since(earlier) < RATE_WINDOW_SECONDS
+}
+
+/// What a [`RiskRule`] decided about a transaction
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RuleOutcome {
+    /// The transaction is applied as normal
+    Allow,
+    /// The transaction is applied, and reported as suspicious for the given reason
+    Flag(String),
+    /// The transaction is rejected with [`PaymentError::RuleViolation`] for the given reason
+    Reject(String),
+}
+
+/// A check the engine consults before applying each transaction, registered with [`crate::Engine::add_rule`]
+pub trait RiskRule: Send {
+    /// Decides what to do with `tx`, given the account of its client in its currency as it was before. A client
+    /// without an account in that currency yet is given an empty one
+    fn evaluate(&self, account: &Account, tx: &Transaction) -> RuleOutcome;
+
+    /// Tells the rule about a transaction that was applied, with the account as it was left, for rules that look for
+    /// patterns across transactions. Does nothing by default
+    fn applied(&mut self, _account: &Account, _tx: &Transaction) {}
+}
+
+/// A transaction a [`RiskRule`] flagged as suspicious
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RuleFlag {
+    pub tx: u32,
+    pub client: ClientId,
+    pub reason: String,
+}
+
+/// Flags deposits just under a reporting threshold once a client has made `count` of them within `window_seconds`,
+/// the pattern of splitting a large deposit up to stay under the threshold. Deposits without a timestamp count as
+/// being within the window of each other
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Structuring {
+    pub threshold: Amount,
+    /// How far under the threshold a deposit can be and still count
+    pub margin: Amount,
+    pub count: usize,
+    pub window_seconds: i64,
+    /// The timestamps of each client's latest deposits just under the threshold, oldest first
+    #[serde(skip)]
+    recent: HashMap<ClientId, VecDeque<Option<Timestamp>>>,
+}
+
+impl Structuring {
+    pub fn new(threshold: Amount, margin: Amount, count: usize, window_seconds: i64) -> Self {
+        Structuring {
+            threshold,
+            margin,
+            count,
+            window_seconds,
+            recent: HashMap::new(),
+        }
+    }
+
+    fn counts(&self, tx: &Transaction) -> bool {
+        let floor = self
+            .threshold
+            .checked_sub(self.margin)
+            .unwrap_or(Amount::MIN);
+
+        match (tx.tx_type, tx.amount) {
+            (TransactionType::Deposit, Some(amount)) => amount >= floor && amount < self.threshold,
+            _ => false,
+        }
+    }
+}
+
+impl RiskRule for Structuring {
+    fn evaluate(&self, _account: &Account, tx: &Transaction) -> RuleOutcome {
+        if !self.counts(tx) {
+            return RuleOutcome::Allow;
+        }
+
+        let earlier = self.recent.get(&tx.client).map_or(0, |recent| {
+            count_within(recent, tx.timestamp, self.window_seconds)
+        });
+
+        if earlier + 1 >= self.count {
+            RuleOutcome::Flag(format!(
+                "{} deposits just under {} from client {}",
+                earlier + 1,
+                self.threshold,
+                tx.client
+            ))
+        } else {
+            RuleOutcome::Allow
+        }
+    }
+
+    fn applied(&mut self, _account: &Account, tx: &Transaction) {
+        if self.counts(tx) {
+            remember(
+                self.recent.entry(tx.client).or_default(),
+                tx.timestamp,
+                self.count,
+            );
+        }
+    }
+}
+
+/// Flags a dispute once a client has opened `count` disputes, counting it, within `window_seconds`. Disputes without
+/// a timestamp count as being within the window of each other
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RapidDisputes {
+    pub count: usize,
+    pub window_seconds: i64,
+    /// The timestamps of each client's latest disputes, oldest first
+    #[serde(skip)]
+    recent: HashMap<ClientId, VecDeque<Option<Timestamp>>>,
+}
+
+impl RapidDisputes {
+    pub fn new(count: usize, window_seconds: i64) -> Self {
+        RapidDisputes {
+            count,
+            window_seconds,
+            recent: HashMap::new(),
+        }
+    }
+}
+
+impl RiskRule for RapidDisputes {
+    fn evaluate(&self, _account: &Account, tx: &Transaction) -> RuleOutcome {
+        if tx.tx_type != TransactionType::Dispute {
+            return RuleOutcome::Allow;
+        }
+
+        let earlier = self.recent.get(&tx.client).map_or(0, |recent| {
+            count_within(recent, tx.timestamp, self.window_seconds)
+        });
+
+        if earlier + 1 >= self.count {
+            RuleOutcome::Flag(format!(
+                "{} disputes in quick succession from client {}",
+                earlier + 1,
+                tx.client
+            ))
+        } else {
+            RuleOutcome::Allow
+        }
+    }
+
+    fn applied(&mut self, _account: &Account, tx: &Transaction) {
+        if tx.tx_type == TransactionType::Dispute {
+            remember(
+                self.recent.entry(tx.client).or_default(),
+                tx.timestamp,
+                self.count,
+            );
+        }
+    }
+}
+
+/// The number of `recent` timestamps within `window_seconds` before `now`
+fn count_within(
+    recent: &VecDeque<Option<Timestamp>>,
+    now: Option<Timestamp>,
+    window_seconds: i64,
+) -> usize {
+    recent
+        .iter()
+        .filter(|&&earlier| match (now, earlier) {
+            (Some(now), Some(earlier)) => now.seconds_since(earlier) < window_seconds,
+            _ => true,
+        })
+        .count()
+}
+
+/// Adds `timestamp` to `recent`, keeping only the latest `count`, which are all a rule counting to `count` needs
+fn remember(recent: &mut VecDeque<Option<Timestamp>>, timestamp: Option<Timestamp>, count: usize) {
+    recent.push_back(timestamp);
+
+    while recent.len() > count {
+        recent.pop_front();
+    }
+}
@@ -0,0 +1,196 @@
+//! Recurring deposits and withdrawals, such as a salary or a standing order, expanded from a file of instructions into
+//! the transactions they make and interleaved with the inputs by timestamp
+
+use crate::{
+    reader_builder, Amount, ClientId, Config, Currency, MalformedRow, Sink, Timestamp, Transaction,
+    TransactionType,
+};
+use anyhow::Error;
+use csv::StringRecord;
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+/// How often a recurring transaction is made
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Cadence {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Cadence {
+    /// The time of the `n`th transaction, counting the one at `start` as the zeroth, or `None` past the last
+    /// representable timestamp. Months are counted from `start` rather than from the transaction before, so one made
+    /// on the 31st goes back to the 31st after a shorter month
+    fn occurrence(self, start: Timestamp, n: u32) -> Option<Timestamp> {
+        match self {
+            Cadence::Daily => start.plus_days(u64::from(n)),
+            Cadence::Weekly => start.plus_days(7 * u64::from(n)),
+            Cadence::Monthly => start.plus_months(n),
+        }
+    }
+}
+
+/// A row of a recurring instructions file, ex: `deposit,1,1000,2500,monthly,2024-01-25T09:00:00Z,2024-12-31T00:00:00Z`
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+pub struct RecurringInstruction {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    /// The id of the first transaction, with each one after taking the next id
+    pub tx: u32,
+    pub amount: Amount,
+    pub cadence: Cadence,
+    /// The time of the first transaction
+    pub start: Timestamp,
+    /// No transactions are made after this time
+    pub end: Timestamp,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+}
+
+impl RecurringInstruction {
+    /// Every transaction the instruction makes, in timestamp order. Only deposits and withdrawals can recur
+    pub fn transactions(&self) -> Result<Vec<Transaction>, Error> {
+        if !matches!(
+            self.tx_type,
+            TransactionType::Deposit | TransactionType::Withdraw
+        ) {
+            return Err(Error::msg(format!(
+                "Only deposits and withdrawals can recur, not {}",
+                self.tx_type
+            )));
+        }
+
+        let mut transactions = Vec::new();
+
+        for n in 0.. {
+            let timestamp = match self.cadence.occurrence(self.start, n) {
+                Some(timestamp) if timestamp <= self.end => timestamp,
+                _ => break,
+            };
+            let id = self.tx.checked_add(n).ok_or_else(|| {
+                Error::msg(format!(
+                    "Recurring transactions from id {} run out of ids",
+                    self.tx
+                ))
+            })?;
+            let tx = Transaction::new(self.tx_type, self.client, id, Some(self.amount))
+                .with_timestamp(timestamp);
+
+            transactions.push(match self.currency {
+                Some(currency) => tx.with_currency(currency),
+                None => tx,
+            });
+        }
+
+        Ok(transactions)
+    }
+}
+
+/// Reads the instructions at `path` and expands them into every transaction they make, in timestamp order, each with
+/// the line of the instruction that made it. Transactions with the same timestamp stay in the order of the instructions
+pub(crate) fn load(path: &str) -> Result<VecDeque<(Transaction, u64)>, Error> {
+    let mut reader = reader_builder().from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let mut row = StringRecord::new();
+    let mut scheduled = Vec::new();
+
+    while reader.read_record(&mut row)? {
+        let line = row.position().map_or(0, |position| position.line());
+        let transactions = row
+            .deserialize::<RecurringInstruction>(Some(&headers))
+            .map_err(Error::from)
+            .and_then(|instruction| instruction.transactions())
+            .map_err(|err| Error::msg(format!("{} line {}: {}", path, line, err)))?;
+
+        scheduled.extend(transactions.into_iter().map(|tx| (tx, line)));
+    }
+
+    scheduled.sort_by_key(|(tx, _)| tx.timestamp);
+
+    Ok(scheduled.into())
+}
+
+/// Passes transactions on to `sink`, first submitting every scheduled transaction timestamped before them. A scheduled
+/// transaction with the same timestamp as one from the inputs comes after it, as though the instructions were the last
+/// input. Transactions without a timestamp are passed on straight away
+pub(crate) struct Scheduled<'a, S> {
+    sink: &'a mut S,
+    path: &'a str,
+    pending: VecDeque<(Transaction, u64)>,
+    /// The input being read, to go back to once scheduled transactions have been submitted
+    input: Option<String>,
+}
+
+impl<'a, S: Sink> Scheduled<'a, S> {
+    pub(crate) fn new(
+        sink: &'a mut S,
+        path: &'a str,
+        pending: VecDeque<(Transaction, u64)>,
+    ) -> Self {
+        Scheduled {
+            sink,
+            path,
+            pending,
+            input: None,
+        }
+    }
+
+    /// Submits the scheduled transactions that are left, once the inputs have been read
+    pub(crate) fn finish(mut self, config: &Config) -> Result<(), Error> {
+        self.submit_due(None, config)
+    }
+
+    /// Submits the scheduled transactions timestamped before `until`, or every one if there's no `until`
+    fn submit_due(&mut self, until: Option<Timestamp>, config: &Config) -> Result<(), Error> {
+        let due = self
+            .pending
+            .iter()
+            .take_while(|(tx, _)| until.is_none_or(|until| tx.timestamp < Some(until)))
+            .count();
+
+        if due == 0 {
+            return Ok(());
+        }
+
+        self.sink.start_input(self.path, config)?;
+
+        for (tx, line) in self.pending.drain(..due) {
+            if config.allows(tx.tx_type) {
+                self.sink.submit(tx, config, self.path, line)?;
+            }
+        }
+
+        match &self.input {
+            Some(input) => self.sink.start_input(input, config),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: Sink> Sink for Scheduled<'_, S> {
+    fn start_input(&mut self, input: &str, config: &Config) -> Result<(), Error> {
+        self.input = Some(input.to_string());
+        self.sink.start_input(input, config)
+    }
+
+    fn submit(
+        &mut self,
+        tx: Transaction,
+        config: &Config,
+        input: &str,
+        line: u64,
+    ) -> Result<(), Error> {
+        if let Some(timestamp) = tx.timestamp {
+            self.submit_due(Some(timestamp), config)?;
+        }
+
+        self.sink.submit(tx, config, input, line)
+    }
+
+    fn skip(&mut self, row: MalformedRow) {
+        self.sink.skip(row);
+    }
+}
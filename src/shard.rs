@@ -0,0 +1,326 @@
+use crate::{
+    engine_from_config, read_inputs, Account, ClientId, Config, Currency, Engine, MalformedRow,
+    Sink, Transaction, TransactionType,
+};
+use anyhow::Error;
+use csv::Writer;
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// The number of transactions sent to a worker at a time. Sending each transaction on its own would spend more time on
+/// the channel than applying it
+const BATCH_SIZE: usize = 1024;
+
+/// The number of batches that may wait for each worker before reading the input blocks, so a slow shard can't make the
+/// whole input pile up in memory
+const QUEUED_BATCHES: usize = 4;
+
+/// A unit of work for a shard's worker
+enum Job {
+    /// Transactions to apply in order, each with its position in the inputs and the line it was read from
+    Apply(Vec<(u64, u64, Transaction)>),
+    /// The input file the transactions that follow come from
+    Input(String),
+}
+
+/// What a worker hands back once its shard is processed
+struct Shard {
+    engine: Engine,
+    /// The position in the inputs of the transaction that opened each account of the shard
+    opened: Vec<(u64, ClientId, Currency)>,
+}
+
+/// Reads the inputs and sends every transaction to the worker of its client's shard, in batches
+struct Dispatcher {
+    senders: Vec<SyncSender<Job>>,
+    batches: Vec<Vec<(u64, u64, Transaction)>>,
+    /// The position in the inputs of the next transaction
+    position: u64,
+    /// The rows skipped because they couldn't be parsed
+    malformed_rows: Vec<MalformedRow>,
+}
+
+impl Dispatcher {
+    fn send(&mut self, shard: usize, job: Job) -> Result<(), Error> {
+        self.senders[shard]
+            .send(job)
+            .map_err(|_| Error::msg(format!("The worker for shard {} stopped early", shard)))
+    }
+
+    fn send_batch(&mut self, shard: usize) -> Result<(), Error> {
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH_SIZE));
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.send(shard, Job::Apply(batch))
+    }
+
+    /// Sends every queued transaction
+    fn flush(&mut self) -> Result<(), Error> {
+        for shard in 0..self.senders.len() {
+            self.send_batch(shard)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Sink for Dispatcher {
+    /// Sends every queued transaction, followed by the input the transactions after them come from
+    fn start_input(&mut self, input: &str, _config: &Config) -> Result<(), Error> {
+        for shard in 0..self.senders.len() {
+            self.send_batch(shard)?;
+            self.send(shard, Job::Input(input.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn submit(
+        &mut self,
+        tx: Transaction,
+        _config: &Config,
+        _input: &str,
+        line: u64,
+    ) -> Result<(), Error> {
+        // A transfer applies to two clients, which may be in different shards
+        if tx.tx_type == TransactionType::Transfer {
+            return Err(Error::msg(format!(
+                "Transfer {} on line {} can't be processed in parallel",
+                tx.id, line
+            )));
+        }
+
+        let shard = (tx.client % self.senders.len() as u64) as usize;
+        self.batches[shard].push((self.position, line, tx));
+        self.position += 1;
+
+        if self.batches[shard].len() >= BATCH_SIZE {
+            self.send_batch(shard)?;
+        }
+
+        Ok(())
+    }
+
+    fn skip(&mut self, row: MalformedRow) {
+        self.malformed_rows.push(row);
+    }
+}
+
+/// Processes the inputs with the clients split into `threads` shards by client id, each applied by its own engine on
+/// its own thread, and merges the shards back into a single engine. As no transaction applies to more than one client,
+/// the accounts come out the same as processing the inputs in order, and in the same order. Parsing stays on the
+/// calling thread.
+///
+/// Options that depend on the order of transactions across clients, or that write the ledger to a single place, can't
+/// be split this way and are rejected
+pub(crate) fn process_sharded(
+    inputs: &[String],
+    config: &Config,
+    threads: usize,
+    echo: Option<&mut Writer<File>>,
+) -> Result<Engine, Error> {
+    let unsupported = [
+        (
+            config.dispute_expiry.is_some() || config.dispute_expiry_days.is_some(),
+            "dispute expiry",
+        ),
+        (config.dispute_report.is_some(), "a dispute report"),
+        (config.interest_apr_bps.is_some(), "interest accrual"),
+        (config.id_wraparound.is_some(), "id wraparound detection"),
+        (config.detect_gaps, "gap detection"),
+        (config.strict_order, "strict ordering"),
+        (
+            config.reject_out_of_order,
+            "rejecting out of order timestamps",
+        ),
+        (config.strict_clients, "strict client checking"),
+        (config.history_spill.is_some(), "a history spill file"),
+        (config.store.is_some(), "a store"),
+        (config.resume.is_some(), "resuming from a snapshot"),
+        (config.snapshot.is_some(), "writing a snapshot"),
+        (config.dump_state.is_some(), "dumping the ledger"),
+        (config.lock_audit.is_some(), "a lock audit"),
+        (config.conversion_report.is_some(), "a conversion report"),
+        (config.audit.is_some(), "an audit log"),
+    ];
+
+    if let Some((_, option)) = unsupported.iter().find(|(enabled, _)| *enabled) {
+        return Err(Error::msg(format!(
+            "Processing in parallel can't be combined with {}",
+            option
+        )));
+    }
+
+    // Each shard gets its share of the memory the options allow for
+    let shard_config = Config {
+        expected_rows: config.expected_rows.map(|rows| rows / threads),
+        history_limit: config.history_limit.map(|limit| limit.div_ceil(threads)),
+        ..config.clone()
+    };
+
+    let (shards, malformed_rows) = thread::scope(|scope| {
+        let mut senders = Vec::with_capacity(threads);
+        let mut workers = Vec::with_capacity(threads);
+
+        for _ in 0..threads {
+            let (sender, receiver) = sync_channel(QUEUED_BATCHES);
+            let shard_config = &shard_config;
+            senders.push(sender);
+            workers.push(scope.spawn(move || work(receiver, shard_config)));
+        }
+
+        let mut dispatcher = Dispatcher {
+            batches: senders
+                .iter()
+                .map(|_| Vec::with_capacity(BATCH_SIZE))
+                .collect(),
+            senders,
+            position: 0,
+            malformed_rows: Vec::new(),
+        };
+        let read =
+            read_inputs(&mut dispatcher, inputs, config, echo).and_then(|()| dispatcher.flush());
+        let malformed_rows = std::mem::take(&mut dispatcher.malformed_rows);
+
+        // Closing the channels lets the workers finish. A worker that failed stopped reading, which is the real cause
+        // of any error sending to it, so its error is returned first
+        drop(dispatcher);
+        let shards = workers
+            .into_iter()
+            .map(|worker| worker.join().expect("shard worker panicked"))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        read.map(|()| (shards, malformed_rows))
+    })?;
+
+    Ok(merge(shards, malformed_rows))
+}
+
+/// Applies the transactions of one shard until the dispatcher is done with it
+fn work(receiver: Receiver<Job>, config: &Config) -> Result<Shard, Error> {
+    let mut engine = engine_from_config(config)?;
+    let mut opened = Vec::new();
+    let mut input = String::new();
+
+    for job in receiver {
+        match job {
+            Job::Input(next) => {
+                if config.tag_source {
+                    engine.set_source(Some(next.clone()));
+                }
+
+                input = next;
+            }
+            Job::Apply(batch) => {
+                for (position, line, tx) in batch {
+                    let accounts = engine.accounts.len();
+                    engine.submit(tx, config, &input, line)?;
+
+                    if engine.accounts.len() > accounts {
+                        let account = &engine.accounts[accounts];
+                        opened.push((position, account.client, account.currency));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(Shard { engine, opened })
+}
+
+/// Combines the shards into one engine, with the accounts in the order they were opened across every shard
+fn merge(shards: Vec<Shard>, malformed_rows: Vec<MalformedRow>) -> Engine {
+    let mut merged = Engine::new();
+    let mut opened = Vec::new();
+    let mut accounts: HashMap<(ClientId, Currency), Account> = HashMap::new();
+
+    for mut shard in shards {
+        opened.extend(shard.opened);
+        merged.metrics.add(&shard.engine.metrics);
+        merged.rejections.append(&mut shard.engine.rejections);
+
+        for entry in shard.engine.history.iter() {
+            // The merged engine has no history limit, so pushing never has to evict
+            let _ = merged.history.push(entry.clone());
+        }
+
+        accounts.extend(
+            shard
+                .engine
+                .into_accounts()
+                .into_iter()
+                .map(|account| ((account.client, account.currency), account)),
+        );
+    }
+
+    opened.sort_unstable();
+
+    for (_, client, currency) in opened {
+        if let Some(account) = accounts.remove(&(client, currency)) {
+            merged.accounts.push(account);
+        }
+    }
+
+    for row in malformed_rows {
+        merged.skip(row);
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_input(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn shards_merge_to_the_same_accounts_in_the_same_order() {
+        let mut contents = String::from("type,client,tx,amount\n");
+        for id in 1..=5_000u32 {
+            // Clients are opened in a scrambled order, and some rows are rejected
+            let client = (id * 7919) % 97;
+            contents += &format!("deposit,{},{},{}.5\n", client, id, id % 13);
+            if id % 5 == 0 {
+                contents += &format!("withdraw,{},{},{}\n", client, id + 100_000, id % 17);
+            }
+            if id % 11 == 0 {
+                contents += &format!("dispute,{},{},\n", client, id);
+            }
+        }
+        let input = write_input("payments_sharded_input.csv", &contents);
+        let inputs = vec![input];
+        let config = Config::default();
+
+        let mut sequential = engine_from_config(&config).unwrap();
+        read_inputs(&mut sequential, &inputs, &config, None).unwrap();
+        let sharded = process_sharded(&inputs, &config, 4, None).unwrap();
+
+        assert_eq!(sharded.metrics, sequential.metrics);
+        assert_eq!(sharded.held_report(), sequential.held_report());
+        assert_eq!(sharded.into_accounts(), sequential.into_accounts());
+    }
+
+    #[test]
+    fn order_dependent_options_are_rejected() {
+        let config = Config {
+            dispute_expiry: Some(10),
+            ..Config::default()
+        };
+
+        let err = process_sharded(&[], &config, 2, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Processing in parallel can't be combined with dispute expiry"
+        );
+    }
+}
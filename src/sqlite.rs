@@ -0,0 +1,245 @@
+use crate::{
+    Account, AccountState, AccountStore, Engine, LedgerEntry, Store, Transaction, TransactionStore,
+};
+use anyhow::Error;
+use csv::StringRecord;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// The number of saves grouped into each SQLite transaction. Committing every save would make each one wait for the
+/// disk
+const SAVES_PER_COMMIT: usize = 10_000;
+
+/// Opens the SQLite database at `db_path` and processes the transactions returned by `query`. See
+/// [`process_sqlite_connection`] for the expected columns
+pub fn process_sqlite(db_path: &str, query: &str) -> Result<Vec<Account>, Error> {
+    let connection = Connection::open(db_path)?;
+    process_sqlite_connection(&connection, query)
+}
+
+/// Processes the transactions returned by `query`, which must select the `type`, `client`, `tx`, and `amount` columns
+/// in that order, ex: `SELECT type, client, tx, amount FROM transactions ORDER BY rowid`. Rejected transactions are
+/// skipped just like rejected rows of a CSV input
+pub fn process_sqlite_connection(
+    connection: &Connection,
+    query: &str,
+) -> Result<Vec<Account>, Error> {
+    let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    let mut statement = connection.prepare(query)?;
+    let mut rows = statement.query([])?;
+    let mut engine = Engine::new();
+
+    while let Some(row) = rows.next()? {
+        let mut record = StringRecord::new();
+
+        for index in 0..headers.len() {
+            record.push_field(&field_to_string(row.get_ref(index)?));
+        }
+
+        let _ = engine.apply(Transaction::from_record(&record, &headers)?);
+    }
+
+    Ok(engine.into_accounts())
+}
+
+/// Keeps an engine's accounts and transactions in a SQLite database, as JSON in an `accounts` and a `transactions`
+/// table. Saves are committed in groups, and whatever is left is committed by [`Store::flush`] or when the store is
+/// dropped
+pub struct SqliteStore {
+    connection: Connection,
+    /// The number of saves since the last commit
+    uncommitted: usize,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it and its tables if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Uses an already open database, creating the tables if needed
+    pub fn from_connection(connection: Connection) -> Result<Self, Error> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                 id INTEGER PRIMARY KEY,
+                 client INTEGER NOT NULL,
+                 currency TEXT NOT NULL,
+                 state TEXT NOT NULL,
+                 UNIQUE (client, currency)
+             );
+             CREATE TABLE IF NOT EXISTS transactions (
+                 tx INTEGER PRIMARY KEY,
+                 client INTEGER NOT NULL,
+                 state TEXT NOT NULL
+             );
+             BEGIN;",
+        )?;
+
+        Ok(SqliteStore {
+            connection,
+            uncommitted: 0,
+        })
+    }
+
+    fn saved(&mut self) -> Result<(), Error> {
+        self.uncommitted += 1;
+
+        if self.uncommitted >= SAVES_PER_COMMIT {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AccountStore for SqliteStore {
+    fn load_accounts(&mut self) -> Result<Vec<Account>, Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT state FROM accounts ORDER BY id")?;
+        let mut rows = statement.query([])?;
+        let mut accounts = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            let state: AccountState = serde_json::from_str(&row.get::<_, String>(0)?)?;
+            accounts.push(Account::from(state));
+        }
+
+        Ok(accounts)
+    }
+
+    fn save_account(&mut self, account: &Account) -> Result<(), Error> {
+        let state = serde_json::to_string(&AccountState::from(account))?;
+        self.connection
+            .prepare_cached(
+                "INSERT INTO accounts (client, currency, state) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (client, currency) DO UPDATE SET state = excluded.state",
+            )?
+            // SQLite integers are signed, so client ids above `i64::MAX` are stored as negative numbers
+            .execute(params![
+                account.client as i64,
+                account.currency.code(),
+                state
+            ])?;
+
+        self.saved()
+    }
+}
+
+impl TransactionStore for SqliteStore {
+    fn load_transaction(&mut self, id: u32) -> Result<Option<LedgerEntry>, Error> {
+        let state: Option<String> = self
+            .connection
+            .prepare_cached("SELECT state FROM transactions WHERE tx = ?1")?
+            .query_row([id], |row| row.get(0))
+            .optional()?;
+
+        match state {
+            Some(state) => Ok(Some(serde_json::from_str(&state)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_transaction(&mut self, entry: &LedgerEntry) -> Result<(), Error> {
+        let state = serde_json::to_string(entry)?;
+        self.connection
+            .prepare_cached(
+                "INSERT INTO transactions (tx, client, state) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (tx) DO UPDATE SET client = excluded.client, state = excluded.state",
+            )?
+            .execute(params![entry.id, entry.client as i64, state])?;
+
+        self.saved()
+    }
+}
+
+impl Store for SqliteStore {
+    fn flush(&mut self) -> Result<(), Error> {
+        self.connection.execute_batch("COMMIT; BEGIN;")?;
+        self.uncommitted = 0;
+
+        Ok(())
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        let _ = self.connection.execute_batch("COMMIT");
+    }
+}
+
+/// Renders a column the way it would appear in a CSV input, so rows go through the same parsing as files
+fn field_to_string(value: ValueRef<'_>) -> String {
+    match value {
+        ValueRef::Null => String::new(),
+        ValueRef::Integer(value) => value.to_string(),
+        ValueRef::Real(value) => value.to_string(),
+        ValueRef::Text(value) | ValueRef::Blob(value) => {
+            String::from_utf8_lossy(value).trim().to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Amount;
+
+    #[test]
+    fn store_resumes_from_database() {
+        let path = std::env::temp_dir().join("payments_sqlite_store.db");
+        let _ = std::fs::remove_file(&path);
+
+        let mut engine = Engine::new();
+        engine.set_store(SqliteStore::open(&path).unwrap()).unwrap();
+        engine
+            .process(Transaction::from_csv_line("deposit,1,1,10").unwrap())
+            .unwrap();
+        engine
+            .process(Transaction::from_csv_line("withdraw,1,2,4").unwrap())
+            .unwrap();
+        engine.flush_store().unwrap();
+        drop(engine);
+
+        let mut resumed = Engine::new();
+        resumed
+            .set_store(SqliteStore::open(&path).unwrap())
+            .unwrap();
+        resumed
+            .process(Transaction::from_csv_line("dispute,1,1,").unwrap())
+            .unwrap();
+
+        let account = resumed.accounts().next().unwrap();
+        assert_eq!(account.available(), Amount::from_num(-4));
+        assert_eq!(account.held(), Amount::from_num(10));
+        assert_eq!(account.total(), Amount::from_num(6));
+    }
+
+    #[test]
+    fn processes_transactions_from_table() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE transactions (type TEXT, client INTEGER, tx INTEGER, amount TEXT);
+                 INSERT INTO transactions VALUES ('deposit', 1, 1, '10.5');
+                 INSERT INTO transactions VALUES ('deposit', 2, 2, '3');
+                 INSERT INTO transactions VALUES ('withdraw', 1, 3, '0.5');
+                 INSERT INTO transactions VALUES ('dispute', 2, 2, NULL);",
+            )
+            .unwrap();
+
+        let accounts = process_sqlite_connection(
+            &connection,
+            "SELECT type, client, tx, amount FROM transactions ORDER BY rowid",
+        )
+        .unwrap();
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].client(), 1);
+        assert_eq!(accounts[0].total(), Amount::from_num(10));
+        assert_eq!(accounts[1].client(), 2);
+        assert_eq!(accounts[1].available(), Amount::from_num(0));
+        assert_eq!(accounts[1].held(), Amount::from_num(3));
+    }
+}
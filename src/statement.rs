@@ -0,0 +1,133 @@
+use crate::{
+    engine_from_config, reader_builder, Account, Amount, ClientId, Config, Currency, Transaction,
+    TransactionType,
+};
+use anyhow::Error;
+use csv::StringRecord;
+use serde::Serialize;
+use std::io::Read;
+
+/// A line of a client's statement, written by [`statement`]: a transaction that involved the client, whether it was
+/// applied, and the client's balances in one currency after it
+#[derive(Debug, Serialize, Clone, Eq, PartialEq)]
+pub struct StatementLine {
+    /// The line of the input the transaction was read from
+    pub line: u64,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub tx: u32,
+    pub amount: Option<Amount>,
+    /// `applied`, or the [code](crate::PaymentError::code) of the error the transaction was rejected with
+    pub result: &'static str,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+/// Replays a CSV input with the options in `config` and lists, in order, every transaction that involved `client`:
+/// their own transactions, whether or not they were applied, transfers they received, and anything else that changed
+/// their balances, such as disputes of transfers they received. A transaction that changed the client's balances in
+/// more than one currency, such as a conversion, gets a line for each. Rows that can't be parsed are skipped
+///
+/// ```
+/// let input = "type,client,tx,amount\ndeposit,1,1,5\ndeposit,2,2,3\nwithdraw,1,3,9\ndispute,1,1,\n";
+/// let lines = payments::statement(input.as_bytes(), 1, &payments::Config::default()).unwrap();
+///
+/// assert_eq!(lines.len(), 3);
+/// assert_eq!(lines[1].result, "insufficient_funds");
+/// assert_eq!(lines[2].held, payments::Amount::from_num(5));
+/// ```
+pub fn statement<R: Read>(
+    input: R,
+    client: ClientId,
+    config: &Config,
+) -> Result<Vec<StatementLine>, Error> {
+    let mut engine = engine_from_config(config)?;
+    let mut reader = reader_builder().from_reader(input);
+    let headers = reader.headers()?.clone();
+    let mut record = StringRecord::new();
+    let mut lines = Vec::new();
+
+    while reader.read_record(&mut record)? {
+        let tx = match Transaction::from_record(&record, &headers) {
+            Ok(tx) => tx,
+            Err(_) => continue,
+        };
+
+        let before: Vec<Account> = engine.accounts.of_client(client).cloned().collect();
+        let result = match engine.process(tx) {
+            Ok(()) => "applied",
+            Err(err) => err.code(),
+        };
+        let changed: Vec<&Account> = engine
+            .accounts
+            .of_client(client)
+            .filter(|account| !before.contains(account))
+            .collect();
+
+        let line = |account: Option<&Account>| StatementLine {
+            line: record.position().map_or(0, |position| position.line()),
+            tx_type: tx.tx_type,
+            tx: tx.id,
+            amount: tx.amount,
+            result,
+            currency: account.map_or(tx.currency.unwrap_or(config.currency), |account| {
+                account.currency
+            }),
+            available: account.map_or(Amount::ZERO, |account| account.available),
+            held: account.map_or(Amount::ZERO, |account| account.held),
+            total: account.map_or(Amount::ZERO, |account| account.total),
+            locked: account.is_some_and(|account| account.status.is_locked()),
+        };
+
+        if !changed.is_empty() {
+            lines.extend(changed.into_iter().map(|account| line(Some(account))));
+        } else if tx.client == client || tx.to_client == Some(client) {
+            let currency = tx.currency.unwrap_or(config.currency);
+            lines.push(line(engine.accounts.get(client, currency)));
+        }
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_include_transactions_that_changed_the_client_from_others() {
+        let input = "type,client,tx,amount,to_client
+deposit,1,1,10,
+deposit,2,2,5,
+transfer,1,3,4,2
+dispute,1,3,,
+withdraw,2,4,6,
+chargeback,1,3,,
+";
+        let lines = statement(input.as_bytes(), 2, &Config::default()).unwrap();
+        let summary: Vec<(u64, u32, &str, Amount, Amount)> = lines
+            .iter()
+            .map(|line| (line.line, line.tx, line.result, line.available, line.held))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (3, 2, "applied", Amount::from_num(5), Amount::ZERO),
+                (4, 3, "applied", Amount::from_num(9), Amount::ZERO),
+                (5, 3, "applied", Amount::from_num(5), Amount::from_num(4)),
+                (
+                    6,
+                    4,
+                    "insufficient_funds",
+                    Amount::from_num(5),
+                    Amount::from_num(4)
+                ),
+                (7, 3, "applied", Amount::from_num(5), Amount::ZERO),
+            ]
+        );
+    }
+}
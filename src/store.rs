@@ -0,0 +1,33 @@
+use crate::{Account, LedgerEntry};
+use anyhow::Error;
+
+/// Where an engine persists accounts as they change. See [`Store`]
+pub trait AccountStore {
+    /// Every stored account, in the order their clients were first seen
+    fn load_accounts(&mut self) -> Result<Vec<Account>, Error>;
+
+    /// Saves the current state of an account, replacing any earlier state of the same client's account
+    fn save_account(&mut self, account: &Account) -> Result<(), Error>;
+}
+
+/// Where an engine persists deposits and withdrawals, and their dispute state, as they change. See [`Store`]
+pub trait TransactionStore {
+    /// The stored deposit or withdrawal with `id`, if there is one
+    fn load_transaction(&mut self, id: u32) -> Result<Option<LedgerEntry>, Error>;
+
+    /// Saves the current state of a deposit or withdrawal, replacing any earlier state of the same id
+    fn save_transaction(&mut self, entry: &LedgerEntry) -> Result<(), Error>;
+}
+
+/// A durable home for an engine's accounts and transactions, set with [`crate::Engine::set_store`]. Without a store,
+/// which is the default, everything lives in memory for the life of the engine.
+///
+/// With a store, every account and transaction is saved as soon as a transaction changes it. The engine still keeps
+/// every account in memory, but transactions beyond the history limit are only kept in the store and loaded back when
+/// they are referenced again, so memory stays bounded however long the history grows
+pub trait Store: AccountStore + TransactionStore {
+    /// Makes every save so far durable, for stores that group writes
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::Error;
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, SecondsFormat, Utc};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// When a transaction happened, read as either RFC 3339, ex: `2024-03-01T09:30:00Z`, or whole seconds since the Unix
+/// epoch, ex: `1709285400`, and always written as RFC 3339 in UTC
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Timestamp(DateTime<Utc>);
+
+impl Timestamp {
+    /// The timestamp `seconds` after the Unix epoch, or `None` if it's out of range
+    pub fn from_unix(seconds: i64) -> Option<Self> {
+        DateTime::from_timestamp(seconds, 0).map(Timestamp)
+    }
+
+    /// The number of whole days from `earlier` to this timestamp, negative if `earlier` is later
+    pub fn days_since(self, earlier: Timestamp) -> i64 {
+        (self.0 - earlier.0).num_days()
+    }
+
+    /// The number of whole seconds from `earlier` to this timestamp, negative if `earlier` is later
+    pub fn seconds_since(self, earlier: Timestamp) -> i64 {
+        (self.0 - earlier.0).num_seconds()
+    }
+
+    /// Midnight UTC at the start of the next day, or `None` past the last representable day
+    pub(crate) fn next_day(self) -> Option<Timestamp> {
+        Timestamp::midnight(self.0.date_naive().succ_opt()?)
+    }
+
+    /// Midnight UTC at the start of the next month, or `None` past the last representable month
+    pub(crate) fn next_month(self) -> Option<Timestamp> {
+        let first = self.0.date_naive().with_day(1)?;
+        Timestamp::midnight(first.checked_add_months(Months::new(1))?)
+    }
+
+    /// The same time of day `days` days later, or `None` past the last representable timestamp
+    pub(crate) fn plus_days(self, days: u64) -> Option<Timestamp> {
+        self.0.checked_add_days(Days::new(days)).map(Timestamp)
+    }
+
+    /// The same time `months` months later, on the last day of the month if it's shorter, or `None` past the last
+    /// representable timestamp
+    pub(crate) fn plus_months(self, months: u32) -> Option<Timestamp> {
+        self.0
+            .checked_add_months(Months::new(months))
+            .map(Timestamp)
+    }
+
+    fn midnight(date: NaiveDate) -> Option<Timestamp> {
+        Some(Timestamp(date.and_hms_opt(0, 0, 0)?.and_utc()))
+    }
+
+    /// The UTC day of the timestamp, as the number of days since the Unix epoch
+    pub(crate) fn day(self) -> i64 {
+        self.0.timestamp().div_euclid(86_400)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    }
+}
+
+impl fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let parsed = match s.parse::<i64>() {
+            Ok(seconds) => Timestamp::from_unix(seconds),
+            Err(_) => DateTime::parse_from_rfc3339(s)
+                .ok()
+                .map(|time| Timestamp(time.with_timezone(&Utc))),
+        };
+
+        parsed.ok_or_else(|| Error::msg(format!("Invalid timestamp: {}", s)))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl Visitor<'_> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an RFC 3339 timestamp or seconds since the Unix epoch")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Timestamp, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Timestamp, E> {
+                Timestamp::from_unix(value)
+                    .ok_or_else(|| E::custom(format!("Invalid timestamp: {}", value)))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Timestamp, E> {
+                let seconds = i64::try_from(value)
+                    .map_err(|_| E::custom(format!("Invalid timestamp: {}", value)))?;
+                self.visit_i64(seconds)
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_read_as_rfc_3339_or_unix_seconds() {
+        let rfc: Timestamp = "2024-03-01T10:30:00+01:00".parse().unwrap();
+        let unix: Timestamp = "1709285400".parse().unwrap();
+
+        assert_eq!(rfc, unix);
+        assert_eq!(unix.to_string(), "2024-03-01T09:30:00Z");
+        assert!("2024-03-01".parse::<Timestamp>().is_err());
+    }
+}
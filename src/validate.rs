@@ -0,0 +1,232 @@
+use crate::{reader_builder, Amount, Transaction, TransactionType};
+use anyhow::Error;
+use csv::StringRecord;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+
+/// The columns every input needs for its rows to be processed
+const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// A problem with one line of an input, found by [`validate`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ValidationIssue {
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Everything [`validate`] found wrong with an input, in line order
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ValidationReport {
+    /// The number of rows checked, not counting the header
+    pub rows: u64,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn issue(&mut self, line: u64, message: impl Into<String>) {
+        self.issues.push(ValidationIssue {
+            line,
+            message: message.into(),
+        });
+    }
+}
+
+/// Checks every row of a CSV input without applying any of them. Reports rows that don't parse, deposits, withdrawals,
+/// transfers, and fees without a positive amount, deposits, withdrawals, and transfers reusing an earlier id,
+/// transfers without a `to_client`, and disputes, resolves, chargebacks, refunds, and settles of ids no earlier
+/// deposit, withdrawal, or transfer used
+///
+/// ```
+/// let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,1,-2.0\ndispute,1,7,\n";
+/// let report = payments::validate(input.as_bytes()).unwrap();
+///
+/// assert_eq!(report.rows, 3);
+/// assert_eq!(report.issues.len(), 3);
+/// assert_eq!(report.issues[0].line, 3);
+/// ```
+pub fn validate<R: Read>(input: R) -> Result<ValidationReport, Error> {
+    let mut reader = reader_builder().from_reader(input);
+    let headers = reader.headers()?.clone();
+    let mut report = ValidationReport::default();
+
+    for column in REQUIRED_COLUMNS.iter() {
+        if !headers.iter().any(|header| header == *column) {
+            report.issue(1, format!("Missing column {}", column));
+        }
+    }
+
+    // The line each deposit and withdrawal id was first used on
+    let mut ids = HashMap::new();
+    let mut row = StringRecord::new();
+
+    while reader.read_record(&mut row)? {
+        let line = row.position().map_or(0, |position| position.line());
+        report.rows += 1;
+
+        let tx = match Transaction::from_record(&row, &headers) {
+            Ok(tx) => tx,
+            Err(err) => {
+                report.issue(line, describe_parse_error(err));
+                continue;
+            }
+        };
+
+        if let TransactionType::Deposit
+        | TransactionType::Withdraw
+        | TransactionType::Transfer
+        | TransactionType::Fee
+        | TransactionType::Convert = tx.tx_type
+        {
+            match tx.amount {
+                None => report.issue(line, format!("{} has no amount", tx.tx_type)),
+                Some(amount) if amount <= Amount::ZERO => report.issue(
+                    line,
+                    format!("{} amount {} is not positive", tx.tx_type, amount.exact()),
+                ),
+                Some(_) => {}
+            }
+        }
+
+        match tx.tx_type {
+            // Fees, locks, unlocks, conversions, and credit limits don't reference other transactions, and their ids
+            // aren't disputable
+            TransactionType::Fee | TransactionType::Lock | TransactionType::Unlock => {}
+            TransactionType::Interest => {
+                report.issue(line, "interest is only credited by the engine")
+            }
+            TransactionType::SetLimit => match tx.amount {
+                None => report.issue(line, "set_limit has no amount"),
+                Some(limit) if limit < Amount::ZERO => report.issue(
+                    line,
+                    format!("set_limit amount {} is negative", limit.exact()),
+                ),
+                Some(_) => {}
+            },
+            TransactionType::Convert if tx.to_currency.is_none() => {
+                report.issue(line, "convert has no to_currency")
+            }
+            TransactionType::Convert => {}
+            TransactionType::Deposit | TransactionType::Withdraw | TransactionType::Transfer => {
+                if tx.tx_type == TransactionType::Transfer && tx.to_client.is_none() {
+                    report.issue(line, "transfer has no to_client");
+                }
+
+                match ids.entry(tx.id) {
+                    Entry::Occupied(first) => report.issue(
+                        line,
+                        format!(
+                            "Transaction id {} was already used on line {}",
+                            tx.id,
+                            first.get()
+                        ),
+                    ),
+                    Entry::Vacant(entry) => {
+                        entry.insert(line);
+                    }
+                }
+            }
+            _ if !ids.contains_key(&tx.id) => report.issue(
+                line,
+                format!(
+                    "{} references transaction {}, which no earlier deposit or withdrawal used",
+                    tx.tx_type, tx.id
+                ),
+            ),
+            _ => {}
+        }
+    }
+
+    Ok(report)
+}
+
+/// Describes why a row failed to parse, without the position the CSV reader adds as the report already has the line
+pub(crate) fn describe_parse_error(err: Error) -> String {
+    match err.downcast_ref::<csv::Error>().map(csv::Error::kind) {
+        Some(csv::ErrorKind::Deserialize { err, .. }) => err.to_string(),
+        _ => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(input: &str) -> Vec<String> {
+        validate(input.as_bytes())
+            .unwrap()
+            .issues
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid_input_has_no_issues() {
+        let report = validate(
+            "type,client,tx,amount\ndeposit,1,1,2.0\nwithdraw,1,2,1.0\ndispute,1,1,\nresolve,1,1,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert!(report.is_valid());
+        assert_eq!(report.rows, 4);
+    }
+
+    #[test]
+    fn reports_each_problem_with_its_line() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,x,2,1.0
+withdraw,1,3,0
+deposit,1,1,5.0
+chargeback,1,9,
+deposit,1,4,
+";
+
+        assert_eq!(
+            messages(input),
+            vec![
+                "line 3: field 1: invalid digit found in string",
+                "line 4: withdraw amount 0 is not positive",
+                "line 5: Transaction id 1 was already used on line 2",
+                "line 6: chargeback references transaction 9, which no earlier deposit or withdrawal used",
+                "line 7: deposit has no amount",
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers_need_a_destination_and_can_be_disputed() {
+        let input = "type,client,tx,amount,to_client
+deposit,1,1,5.0,
+transfer,1,2,2.0,
+transfer,1,3,1.0,2
+dispute,1,3,,
+";
+
+        assert_eq!(messages(input), vec!["line 3: transfer has no to_client"]);
+    }
+
+    #[test]
+    fn reports_missing_columns() {
+        assert_eq!(
+            messages("type,client,tx\ndeposit,1,1\n"),
+            vec![
+                "line 1: Missing column amount",
+                "line 2: deposit has no amount"
+            ]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Watches a directory for input files and processes each one as it arrives, for ingesting files that are dropped
+//! into a directory throughout the day rather than run as a batch
+
+use crate::{
+    engine_from_config, read_input, AccountRow, ClientId, Config, Currency, Engine, Format,
+    MalformedRow, Sink, Transaction,
+};
+use anyhow::Error;
+use csv::WriterBuilder;
+use log::{info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long the directory has to go without changes before new files are processed, so a file still being written
+/// isn't read halfway
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// The transactions of an input file, held back until the whole file has been read so a file that fails to parse
+/// leaves the accounts untouched
+#[derive(Default)]
+struct Staged {
+    transactions: Vec<(Transaction, u64)>,
+    malformed_rows: Vec<MalformedRow>,
+}
+
+impl Sink for Staged {
+    fn start_input(&mut self, _input: &str, _config: &Config) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn submit(
+        &mut self,
+        tx: Transaction,
+        _config: &Config,
+        _input: &str,
+        line: u64,
+    ) -> Result<(), Error> {
+        self.transactions.push((tx, line));
+        Ok(())
+    }
+
+    fn skip(&mut self, row: MalformedRow) {
+        self.malformed_rows.push(row);
+    }
+}
+
+/// Watches `dir` until `stop` is set, processing every input file in it, in name order, against the same accounts.
+/// Files already in the directory are processed first, and files that arrive later once the directory has settled.
+/// Input files are those with the extension of `config.format`, `.csv` or `.jsonl`.
+///
+/// Each file is read in full before any of it is applied, so a file that can't be read, such as one with a row that
+/// doesn't parse when the config isn't lenient, leaves the accounts as they were. Processed files are moved to a
+/// `processed` directory inside `dir`, and files that couldn't be read to a `failed` one. After each file, the accounts
+/// it changed are appended as CSV, with currency and `last_activity` columns, to `config.output` or `stdout`, any
+/// store is flushed, and the state is written to `config.snapshot` if there is one. The accounts continue from `config.resume` if there is
+/// one, or otherwise from `config.snapshot` if an earlier run left it
+pub fn watch(dir: &Path, config: &Config, stop: &AtomicBool) -> Result<(), Error> {
+    let processed_dir = dir.join("processed");
+    let failed_dir = dir.join("failed");
+    std::fs::create_dir_all(&processed_dir)?;
+    std::fs::create_dir_all(&failed_dir)?;
+
+    let mut engine = engine_from_config(config)?;
+    let resume = config.resume.as_ref().or_else(|| {
+        config
+            .snapshot
+            .as_ref()
+            .filter(|path| Path::new(path).exists())
+    });
+
+    if let Some(path) = resume {
+        engine.restore_state(BufReader::new(File::open(path)?))?;
+    }
+
+    let changed: Arc<Mutex<BTreeSet<(ClientId, Currency)>>> = Arc::default();
+    let recorder = Arc::clone(&changed);
+    engine.on_account_change(move |account| {
+        recorder
+            .lock()
+            .unwrap()
+            .insert((account.client, account.currency));
+    });
+
+    let mut output = Output::open(config)?;
+    let (sender, events) = channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("Watching {}", dir.display());
+
+    let mut pending = BTreeSet::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        pending.insert(entry?.path());
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        match events.recv_timeout(SETTLE_TIME) {
+            Ok(event) => {
+                let event = event?;
+
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    pending.extend(event.paths);
+                }
+
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        for path in std::mem::take(&mut pending) {
+            if !path.is_file() || !is_input(&path, config.format) {
+                continue;
+            }
+
+            let name = path.file_name().unwrap_or_default();
+
+            match process_file(&mut engine, &path, config) {
+                Ok(()) => std::fs::rename(&path, processed_dir.join(name))?,
+                Err(err) => {
+                    warn!("Failed to process {}: {}", path.display(), err);
+                    std::fs::rename(&path, failed_dir.join(name))?;
+                    continue;
+                }
+            }
+
+            let keys = std::mem::take(&mut *changed.lock().unwrap());
+            let accounts = keys
+                .into_iter()
+                .filter_map(|(client, currency)| engine.accounts.get(client, currency));
+            output.append(accounts, config)?;
+
+            engine.flush_store()?;
+
+            if let Some(path) = &config.snapshot {
+                save_snapshot(&engine, Path::new(path))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_input(path: &Path, format: Format) -> bool {
+    let extension = match format {
+        Format::Csv => "csv",
+        Format::Jsonl => "jsonl",
+    };
+
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+/// Reads every transaction of the file, then applies them. Only failing to read the file is an error, rejected
+/// transactions are logged as in any other run
+fn process_file(engine: &mut Engine, path: &Path, config: &Config) -> Result<(), Error> {
+    let input = path.to_string_lossy();
+    let mut staged = Staged::default();
+    read_input(&mut staged, &input, config, None)?;
+
+    for row in &staged.malformed_rows {
+        warn!("Skipped {}", row);
+    }
+
+    if config.tag_source {
+        engine.set_source(Some(input.to_string()));
+    }
+
+    for (tx, line) in staged.transactions {
+        engine.submit(tx, config, &input, line)?;
+    }
+
+    info!("Processed {}", input);
+
+    Ok(())
+}
+
+/// Writes the state to a temporary file next to `path` before replacing it, so stopping partway through never leaves
+/// a truncated snapshot to resume from
+fn save_snapshot(engine: &Engine, path: &Path) -> Result<(), Error> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    engine.save_state(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    std::fs::rename(&temp, path)?;
+
+    Ok(())
+}
+
+/// Where the changed accounts are appended, remembering whether the header has been written yet
+struct Output {
+    writer: Box<dyn Write>,
+    has_header: bool,
+}
+
+impl Output {
+    /// Opens the output file for appending, or `stdout` if there isn't one. A file that already has rows, such as
+    /// from an earlier run, isn't given another header
+    fn open(config: &Config) -> Result<Self, Error> {
+        match &config.output {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+                Ok(Output {
+                    has_header: file.metadata()?.len() > 0,
+                    writer: Box::new(file),
+                })
+            }
+            None => Ok(Output {
+                writer: Box::new(std::io::stdout()),
+                has_header: false,
+            }),
+        }
+    }
+
+    fn append<'a>(
+        &mut self,
+        accounts: impl Iterator<Item = &'a crate::Account>,
+        config: &Config,
+    ) -> Result<(), Error> {
+        let mut writer = WriterBuilder::new()
+            .has_headers(!self.has_header)
+            .from_writer(&mut self.writer);
+
+        for account in accounts {
+            let credit_column = config.credit_limits.is_some();
+            writer.serialize(AccountRow::new(account, config, true, credit_column, true))?;
+            self.has_header = true;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn files_dropped_into_the_directory_are_processed_and_moved() {
+        let dir = std::env::temp_dir().join("payments_watch");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1.csv"), "type,client,tx,amount\ndeposit,1,1,5\n").unwrap();
+
+        let config = Config {
+            output: Some(dir.join("accounts.out").to_string_lossy().into_owned()),
+            snapshot: Some(dir.join("state.json").to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let watching = {
+            let (dir, config, stop) = (dir.clone(), config.clone(), Arc::clone(&stop));
+            std::thread::spawn(move || watch(&dir, &config, &stop))
+        };
+
+        let wait_for = |path: PathBuf| {
+            while !path.exists() {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+
+        wait_for(dir.join("processed/1.csv"));
+        std::fs::write(
+            dir.join("2.csv"),
+            "type,client,tx,amount\nwithdraw,1,2,2\ndeposit,2,3,1\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("3.csv"), "type,client,tx,amount\ndeposit,x,4,1\n").unwrap();
+        wait_for(dir.join("processed/2.csv"));
+        wait_for(dir.join("failed/3.csv"));
+
+        stop.store(true, Ordering::Relaxed);
+        watching.join().unwrap().unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dir.join("accounts.out")).unwrap(),
+            "client,currency,available,held,total,locked,last_activity\n1,USD,5,0,5,false,\n1,USD,3,0,3,false,\n2,USD,1,0,1,false,\n"
+        );
+
+        let mut resumed = Engine::new();
+        resumed
+            .restore_state(File::open(dir.join("state.json")).unwrap())
+            .unwrap();
+        assert_eq!(resumed.accounts().count(), 2);
+    }
+}
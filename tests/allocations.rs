@@ -0,0 +1,99 @@
+use payments::Config;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Counts every allocation made by this test binary
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Held for the whole of each test, as allocations made by another test running at the same time would be counted
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn count_allocations(inputs: &[String], config: &Config) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    payments::run(inputs, config).unwrap();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn write_input(name: &str, rows: usize) -> String {
+    let path = std::env::temp_dir().join(name);
+    let mut input = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+    writeln!(input, "type,client,tx,amount").unwrap();
+
+    for id in 1..=rows {
+        match id % 4 {
+            3 => writeln!(input, "dispute,{},{},", (id - 1) % 10, id - 1),
+            0 => writeln!(input, "resolve,{},{},", (id - 2) % 10, id - 2),
+            _ => writeln!(input, "deposit,{},{},1.5", id % 10, id),
+        }
+        .unwrap();
+    }
+
+    path.to_str().unwrap().to_string()
+}
+
+/// Parsing a CSV row allocates on its own, so processing a row is compared against only counting it. Applying the
+/// transactions, including storing the disputable ones, should allocate next to nothing on top of parsing
+#[test]
+fn processing_allocates_no_more_than_parsing() {
+    const ROWS: usize = 20_000;
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+
+    let inputs = vec![write_input("payments_allocations.csv", ROWS)];
+    let config = Config::default();
+    let count_only = Config {
+        count_only: true,
+        ..config.clone()
+    };
+
+    let parsing = count_allocations(&inputs, &count_only);
+    let processing = count_allocations(&inputs, &config);
+
+    assert!(
+        processing <= parsing + ROWS / 100,
+        "{} allocations processing {} transactions, {} only parsing them",
+        processing,
+        ROWS,
+        parsing
+    );
+}
+
+#[test]
+fn expected_rows_avoids_growing_history() {
+    const ROWS: usize = 20_000;
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+
+    let inputs = vec![write_input("payments_expected_rows.csv", ROWS)];
+    let config = Config::default();
+    let reserved = Config {
+        expected_rows: Some(ROWS),
+        ..config.clone()
+    };
+
+    let growing = count_allocations(&inputs, &config);
+    let preallocated = count_allocations(&inputs, &reserved);
+
+    assert!(
+        preallocated < growing,
+        "{} allocations with --expected-rows, {} without",
+        preallocated,
+        growing
+    );
+}
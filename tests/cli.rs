@@ -1,10 +1,110 @@
 use assert_cmd::prelude::*;
 use predicates::prelude::*;
+use std::collections::BTreeMap;
 use std::process::Command;
 
+/// Half of the smallest unit in the output, so only differences below what the output can show are tolerated
+const AMOUNT_EPSILON: f64 = 0.00005;
+
+/// How an amount that isn't a number is reported, which depends on the amount type the crate was built with
+#[cfg(not(feature = "decimal"))]
+const INVALID_AMOUNT: &str = "parse error: invalid digit found in string";
+#[cfg(feature = "decimal")]
+const INVALID_AMOUNT: &str = "parse error: Invalid decimal: unknown character";
+
 fn cleanup() {
-    std::fs::remove_dir_all("./tests/output/").unwrap();
-    std::fs::create_dir("./tests/output/").unwrap();
+    let _ = std::fs::remove_dir_all("./tests/output/");
+    std::fs::create_dir_all("./tests/output/").unwrap();
+}
+
+/// Parses account CSV output into each client's available, held, and total funds and locked flag
+fn parse_accounts(accounts: &str) -> BTreeMap<u64, ([f64; 3], bool)> {
+    let mut reader = csv::Reader::from_reader(accounts.as_bytes());
+    let headers = reader.headers().unwrap().clone();
+    let field = |record: &csv::StringRecord, name: &str| {
+        let index = headers.iter().position(|header| header == name).unwrap();
+        record[index].to_string()
+    };
+
+    reader
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            let amounts = [
+                field(&record, "available").parse().unwrap(),
+                field(&record, "held").parse().unwrap(),
+                field(&record, "total").parse().unwrap(),
+            ];
+
+            (
+                field(&record, "client").parse().unwrap(),
+                (amounts, field(&record, "locked").parse().unwrap()),
+            )
+        })
+        .collect()
+}
+
+/// Asserts both CSVs hold the same clients with balances within `epsilon` of each other and the same locked flags,
+/// regardless of row order
+fn assert_accounts_eq(actual: &str, expected: &str, epsilon: f64) {
+    let actual = parse_accounts(actual);
+    let expected = parse_accounts(expected);
+
+    assert_eq!(
+        actual.keys().collect::<Vec<_>>(),
+        expected.keys().collect::<Vec<_>>(),
+        "clients differ"
+    );
+
+    for (client, (expected_amounts, expected_locked)) in &expected {
+        let (actual_amounts, actual_locked) = &actual[client];
+
+        for (index, name) in ["available", "held", "total"].iter().enumerate() {
+            assert!(
+                (actual_amounts[index] - expected_amounts[index]).abs() <= epsilon,
+                "client {} has {} {} but expected {}",
+                client,
+                name,
+                actual_amounts[index],
+                expected_amounts[index]
+            );
+        }
+
+        assert_eq!(
+            actual_locked, expected_locked,
+            "client {} locked flag differs",
+            client
+        );
+    }
+}
+
+#[test]
+fn assert_accounts_eq_ignores_order_and_small_differences() {
+    assert_accounts_eq(
+        "client,available,held,total,locked\n1,1.00001,0,1,false\n2,0,0,0,true\n",
+        "client,available,held,total,locked\n2,0,0,0,true\n1,1,0,1,false\n",
+        AMOUNT_EPSILON,
+    );
+}
+
+#[test]
+#[should_panic(expected = "client 1 has available 1.0001 but expected 1")]
+fn assert_accounts_eq_rejects_differences_beyond_epsilon() {
+    assert_accounts_eq(
+        "client,available,held,total,locked\n1,1.0001,0,1,false\n",
+        "client,available,held,total,locked\n1,1,0,1,false\n",
+        AMOUNT_EPSILON,
+    );
+}
+
+#[test]
+#[should_panic(expected = "client 1 locked flag differs")]
+fn assert_accounts_eq_rejects_different_locked_flags() {
+    assert_accounts_eq(
+        "client,available,held,total,locked\n1,1,0,1,true\n",
+        "client,available,held,total,locked\n1,1,0,1,false\n",
+        AMOUNT_EPSILON,
+    );
 }
 
 #[test]
@@ -16,6 +116,1529 @@ fn smoke_test() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = Command::cargo_bin("payments")?;
     cmd.arg("./tests/sample_transactions.csv");
 
+    let output = cmd.assert().success().get_output().stdout.clone();
+    assert_accounts_eq(&String::from_utf8(output)?, &expected, AMOUNT_EPSILON);
+
+    Ok(())
+}
+
+#[test]
+fn mmap_matches_default_output() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv").arg("--mmap");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn process_subcommand_matches_default_output() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("process").arg("./tests/sample_transactions.csv");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn threads_match_sequential_output() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--threads")
+        .arg("3");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn missing_inputs_print_usage_instead_of_panicking() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("payments")?;
+
+    cmd.assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("Usage: payments"))
+        .stderr(predicate::str::contains("panicked").not());
+
+    Ok(())
+}
+
+#[test]
+fn validate_reports_problems_without_printing_accounts() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_validate.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,1.0\nwithdraw,1,1,-1.0\ndispute,1,5,\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("validate").arg(&path);
+
+    cmd.assert()
+        .failure()
+        .code(1)
+        .stdout(predicate::str::similar(
+            "line 3: withdraw amount -1 is not positive
+line 3: Transaction id 1 was already used on line 2
+line 4: dispute references transaction 5, which no earlier deposit or withdrawal used
+3 rows checked, 3 problems found
+",
+        ));
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("validate").arg("./tests/sample_transactions.csv");
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "22 rows checked, 0 problems found\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn statement_lists_one_clients_transactions_in_order() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_statement.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,3.5\ndeposit,2,2,1\nwithdraw,1,3,5\ndispute,1,1,\nchargeback,1,1,\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("statement")
+        .arg("--client")
+        .arg("1")
+        .arg("--input")
+        .arg(&path);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "line,type,tx,amount,result,currency,available,held,total,locked
+2,deposit,1,3.5,applied,USD,3.5,0,3.5,false
+4,withdraw,3,5,insufficient_funds,USD,3.5,0,3.5,false
+5,dispute,1,,applied,USD,0,3.5,3.5,false
+6,chargeback,1,,applied,USD,0,0,0,true
+",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn inputs_with_timestamps_are_merged_in_timestamp_order() -> Result<(), Box<dyn std::error::Error>>
+{
+    let first = std::env::temp_dir().join("payments_merge_first.csv");
+    let second = std::env::temp_dir().join("payments_merge_second.csv");
+    std::fs::write(
+        &first,
+        "type,client,tx,amount,timestamp\ndeposit,1,1,10,2024-03-01T09:00:00Z\nwithdraw,1,2,12,2024-03-01T11:00:00Z\n",
+    )?;
+    std::fs::write(
+        &second,
+        "timestamp,type,client,tx,amount\n2024-03-01T10:00:00Z,deposit,1,3,5\n",
+    )?;
+
+    // One after another, the withdrawal would come before the second deposit and be rejected
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&first).arg(&second);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked,last_activity\n1,3,0,3,false,2024-03-01T11:00:00Z\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn report_writes_totals_of_the_inputs() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_report.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,3.5\ndeposit,2,2,1\nwithdraw,2,3,0.25\ndispute,1,1,\nchargeback,1,1,\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("report").arg(&path);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "name,value
+deposit,2
+withdraw,1
+dispute,1
+chargeback,1
+processed,5
+rejected,0
+deposited,4.5
+withdrawn,0.25
+disputes_opened,1
+disputes_resolved,0
+chargebacks,1
+locked_accounts,1
+total,0.75
+",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn balance_at_writes_the_accounts_as_of_a_transaction_or_time(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_balance_at.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount,timestamp
+deposit,1,1,5,2024-03-01T09:00:00Z
+deposit,2,2,3,2024-03-01T10:00:00Z
+dispute,1,1,,2024-03-01T11:00:00Z
+withdraw,2,3,1,2024-03-02T09:00:00Z
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("balance-at").arg("--tx").arg("2").arg(&path);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked,last_activity
+1,5,0,5,false,2024-03-01T09:00:00Z
+2,3,0,3,false,2024-03-01T10:00:00Z
+",
+    ));
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("balance-at")
+        .arg("--timestamp")
+        .arg("2024-03-01T23:59:59Z")
+        .arg(&path);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked,last_activity
+1,0,5,5,false,2024-03-01T11:00:00Z
+2,3,0,3,false,2024-03-01T10:00:00Z
+",
+    ));
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("balance-at").arg("--tx").arg("9").arg(&path);
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Transaction 9 was never applied"));
+
+    Ok(())
+}
+
+#[test]
+fn dispute_report_lists_open_disputes_with_their_age() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_dispute_aging.csv");
+    let report = std::env::temp_dir().join("payments_dispute_report.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,timestamp
+deposit,1,1,5,2024-03-01T09:00:00Z
+deposit,2,2,3,2024-03-01T10:00:00Z
+dispute,1,1,,2024-03-02T09:00:00Z
+dispute,2,2,,2024-03-20T09:00:00Z
+deposit,1,3,1,2024-04-05T09:00:00Z
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .args(["--dispute-expiry-days", "30", "--dispute-report"])
+        .arg(&report);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked,last_activity
+1,6,0,6,false,2024-04-05T09:00:00Z
+2,0,3,3,false,2024-03-20T09:00:00Z
+",
+    ));
+    assert_eq!(
+        std::fs::read_to_string(&report)?,
+        "tx,type,client,currency,amount,held,age,age_days,escalated\n2,deposit,2,USD,3,3,1,16,false\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn disputes_output_lists_transactions_still_under_dispute() -> Result<(), Box<dyn std::error::Error>>
+{
+    let input = std::env::temp_dir().join("payments_disputes_output_input.csv");
+    let output = std::env::temp_dir().join("payments_disputes_output.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount
+deposit,1,1,10
+withdraw,1,2,4
+deposit,2,3,2.5
+dispute,1,2,
+dispute,2,3,
+dispute,1,1,
+resolve,1,1,
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input).arg("--disputes-output").arg(&output);
+    cmd.assert().success();
+
+    assert_eq!(
+        std::fs::read_to_string(&output)?,
+        "tx,type,client,currency,amount,held,age,age_days,escalated
+2,withdraw,1,USD,4,4,3,,false
+3,deposit,2,USD,2.5,2.5,2,,false
+"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reconcile_prints_discrepancies_and_fails() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_reconcile_input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,3.5\ndeposit,2,2,1\nwithdraw,2,3,0.25\n",
+    )?;
+    let expected = std::env::temp_dir().join("payments_reconcile_expected.csv");
+    std::fs::write(
+        &expected,
+        "client,available,held,total,locked\n1,3.5,0,3.5,false\n2,1,0,1,false\n3,0,0,0,true\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("reconcile")
+        .arg("--expected")
+        .arg(&expected)
+        .arg("--input")
+        .arg(&input);
+
+    cmd.assert().failure().stdout(predicate::str::similar(
+        "client 2 USD: available expected 1, got 0.75
+client 2 USD: total expected 1, got 0.75
+client 3 USD: expected account not found
+3 discrepancies found
+",
+    ));
+
+    std::fs::write(
+        &expected,
+        "client,available,held,total,locked\n1,3.5,0,3.5,false\n2,0.75,0,0.75,false\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("reconcile")
+        .arg("--expected")
+        .arg(&expected)
+        .arg("--input")
+        .arg(&input);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar("0 discrepancies found\n"));
+
+    Ok(())
+}
+
+#[test]
+fn jsonl_input_matches_csv_output() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.jsonl")
+        .arg("--format")
+        .arg("jsonl");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn output_buffer_size_does_not_change_output() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+
+    for size in ["1", "64", "1048576"].iter() {
+        let mut cmd = Command::cargo_bin("payments")?;
+        cmd.arg("./tests/sample_transactions.csv")
+            .arg("--quiet")
+            .arg("--output-buffer-size")
+            .arg(size);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::similar(expected.clone()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn spilled_history_matches_default_output() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+    let path = std::env::temp_dir().join("payments_history_spill.jsonl");
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--quiet")
+        .arg("--history-limit")
+        .arg("1")
+        .arg("--history-spill")
+        .arg(&path);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn output_writes_accounts_to_file() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+    let path = std::env::temp_dir().join("payments_output.csv");
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--quiet")
+        .arg("--output")
+        .arg(&path);
+
+    cmd.assert().success().stdout(predicate::str::is_empty());
+    assert_eq!(std::fs::read_to_string(&path)?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn exclude_dispute_types() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = "client,available,held,total,locked
+1,1,0,1,false
+2,1.0001,0,1.0001,false
+3,5,0,5,false
+4,1,0,1,false
+5,100,0,100,false
+";
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--exclude")
+        .arg("dispute,resolve,chargeback");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn tag_source_names_last_input_per_client() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = "client,available,held,total,locked,source
+1,10,0,10,false,./tests/source_a.csv
+2,4,0,4,false,./tests/source_b.csv
+3,7,0,7,false,./tests/source_b.csv
+";
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/source_a.csv")
+        .arg("./tests/source_b.csv")
+        .arg("--tag-source");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn account_map_adds_external_keys() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = "client,account,available,held,total,locked
+1,ACC-0001,10,0,10,false
+2,ACC-0002,5,0,5,false
+";
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/source_a.csv")
+        .arg("--account-map")
+        .arg("./tests/account_map.csv");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn strict_clients_reports_potential_fraud() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_strict_clients.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,5\ndeposit,2,2,5\ndispute,2,1,\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&path).arg("--strict-clients");
+
+    cmd.assert().success().stderr(predicate::str::contains(
+        "Potential fraud: dispute of transaction 1 by client 2, which belongs to client 1",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn deferred_withdrawal_disputes_leave_totals_unchanged() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_withdrawal_disputes.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,5\nwithdraw,1,2,2\ndispute,1,2,\n",
+    )?;
+
+    for (mode, expected) in [
+        ("provisional", "1,3,2,5,false\n"),
+        ("deferred", "1,3,0,3,false\n"),
+    ]
+    .iter()
+    {
+        let mut cmd = Command::cargo_bin("payments")?;
+        cmd.arg(&path).arg("--withdrawal-dispute-mode").arg(mode);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::ends_with(*expected));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn resuming_from_snapshot_matches_single_run() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+    let input = std::fs::read_to_string("./tests/sample_transactions.csv")?;
+    let lines: Vec<&str> = input.lines().collect();
+
+    // Split the sample between a dispute and its chargeback, so the second day depends on the first day's history
+    let dir = std::env::temp_dir();
+    let (day1, day2, state) = (
+        dir.join("payments_resume_day1.csv"),
+        dir.join("payments_resume_day2.csv"),
+        dir.join("payments_resume_state.json"),
+    );
+    std::fs::write(&day1, lines[..10].join("\n"))?;
+    std::fs::write(&day2, [&lines[..1], &lines[10..]].concat().join("\n"))?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&day1).arg("--quiet").arg("--snapshot").arg(&state);
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&day2).arg("--quiet").arg("--resume").arg(&state);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn resubmitted_input_is_skipped_with_skip_duplicates() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let (input, state) = (
+        dir.join("payments_duplicates.csv"),
+        dir.join("payments_duplicates_state.json"),
+    );
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,5\nwithdraw,1,2,1\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--quiet")
+        .arg("--skip-duplicates")
+        .arg("--snapshot")
+        .arg(&state);
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--skip-duplicates")
+        .arg("--resume")
+        .arg(&state);
+
+    cmd.assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,4,0,4,false\n")
+        .stderr(predicate::str::contains(
+            "Skipped 2 transactions that were already applied",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn verbose_diagnostics_stay_out_of_stdout() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv").arg("-vv");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected))
+        .stderr(predicate::str::contains("Error: "))
+        .stderr(predicate::str::contains("applied Transaction"));
+
+    Ok(())
+}
+
+#[test]
+fn accounts_are_sorted_by_client_unless_no_sort() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_sort_input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,3,1,3\ndeposit,1,2,1\ndeposit,2,3,2\n",
+    )?;
+
+    for (flags, expected) in [
+        (
+            &[][..],
+            "client,available,held,total,locked\n1,1,0,1,false\n2,2,0,2,false\n3,3,0,3,false\n",
+        ),
+        (
+            &["--no-sort"][..],
+            "client,available,held,total,locked\n3,3,0,3,false\n1,1,0,1,false\n2,2,0,2,false\n",
+        ),
+    ]
+    .iter()
+    {
+        let mut cmd = Command::cargo_bin("payments")?;
+        cmd.arg(&input).args(*flags);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::similar(*expected));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn amounts_are_written_at_the_configured_precision() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_precision_input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,0.125\ndeposit,2,2,0.375\ndeposit,3,3,2.5\n",
+    )?;
+
+    for (rounding, expected) in [
+        (
+            "half-up",
+            "client,available,held,total,locked\n1,0.13,0,0.13,false\n2,0.38,0,0.38,false\n3,2.5,0,2.5,false\n",
+        ),
+        (
+            "bankers",
+            "client,available,held,total,locked\n1,0.12,0,0.12,false\n2,0.38,0,0.38,false\n3,2.5,0,2.5,false\n",
+        ),
+    ]
+    .iter()
+    {
+        let mut cmd = Command::cargo_bin("payments")?;
+        cmd.arg(&input)
+            .arg("--decimal-places")
+            .arg("2")
+            .arg("--rounding")
+            .arg(rounding);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::similar(*expected));
+    }
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--decimal-places")
+        .arg("0")
+        .arg("--rounding")
+        .arg("bankers")
+        .arg("--output-format")
+        .arg("ndjson");
+
+    cmd.assert().success().stdout(predicate::str::contains(
+        r#"{"client":3,"available":"2","held":"0","total":"2","locked":false,"withdrawable":"2"}"#,
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn fees_are_charged_down_to_the_floor() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_fee_input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdraw,1,2,5\nfee,1,3,6\nfee,1,4,2\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .args([
+            "--withdrawal-fee-bps",
+            "200",
+            "--withdrawal-fee-flat",
+            "0.1",
+        ])
+        .args(["--fee-floor", "-2"]);
+
+    // The withdrawal costs 5.2, and the last fee would leave -3.2 available
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked\n1,-1.2,0,-1.2,false\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn only_admins_unlock_accounts_and_every_lock_is_audited() -> Result<(), Box<dyn std::error::Error>>
+{
+    let input = std::env::temp_dir().join("payments_lock_input.csv");
+    let audit = std::env::temp_dir().join("payments_lock_audit.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,to_client,actor
+deposit,1,1,10,,
+deposit,2,2,10,,
+lock,1,3,,,
+unlock,1,4,,,
+lock,2,5,,,1
+lock,2,6,,,900
+withdraw,2,7,5,,
+unlock,1,8,,,900
+withdraw,1,9,5,,
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .args(["--admins", "900,901", "--lock-audit"])
+        .arg(&audit);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked\n1,5,0,5,false\n2,10,0,10,true\n",
+    ));
+    assert_eq!(
+        std::fs::read_to_string(&audit)?,
+        "tx,client,action,actor\n3,1,lock,1\n6,2,lock,900\n8,1,unlock,900\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn audit_log_is_appended_to_by_each_run() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_audit_input.csv");
+    let audit = std::env::temp_dir().join("payments_audit.jsonl");
+    let _ = std::fs::remove_file(&audit);
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,5\nwithdraw,1,2,9\n",
+    )?;
+
+    for _ in 0..2 {
+        let mut cmd = Command::cargo_bin("payments")?;
+        cmd.arg(&input).arg("--quiet").arg("--audit").arg(&audit);
+        cmd.assert().success();
+    }
+
+    let deposit = r#"{"tx":1,"type":"deposit","client":1,"currency":"USD","amount":"5","before":null,"after":{"available":"5","held":"0","total":"5","locked":false},"outcome":"applied"}"#;
+    let withdrawal = r#"{"tx":2,"type":"withdraw","client":1,"currency":"USD","amount":"9","before":{"available":"5","held":"0","total":"5","locked":false},"after":{"available":"5","held":"0","total":"5","locked":false},"outcome":"insufficient_funds"}"#;
+    assert_eq!(
+        std::fs::read_to_string(&audit)?,
+        [deposit, withdrawal, deposit, withdrawal].join("\n") + "\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn risk_rules_reject_and_report_transactions_that_break_them(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let input = dir.join("payments_risk_input.csv");
+    let rules = dir.join("payments_risk_rules.toml");
+    let report = dir.join("payments_risk_errors.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,100\ndeposit,1,2,100\nwithdraw,1,3,60\nwithdraw,1,4,50\ndeposit,2,5,200\n",
+    )?;
+    std::fs::write(
+        &rules,
+        "max_tx_amount = \"100\"\ndaily_withdrawal_limit = \"100\"\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--risk-rules")
+        .arg(&rules)
+        .arg("--errors")
+        .arg(&report);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked\n1,140,0,140,false\n",
+    ));
+
+    let report = std::fs::read_to_string(&report)?;
+    let codes: Vec<&str> = report
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split(',').nth(2))
+        .collect();
+    assert_eq!(
+        codes,
+        ["daily_withdrawal_limit_exceeded", "amount_exceeds_limit"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rapid_disputes_are_flagged_on_stderr() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let input = dir.join("payments_rapid_disputes_input.csv");
+    let rules = dir.join("payments_rapid_disputes.toml");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,timestamp\ndeposit,1,1,5,0\ndeposit,1,2,5,10\ndispute,1,1,,20\ndispute,1,2,,30\n",
+    )?;
+    std::fs::write(&rules, "[rapid_disputes]\ncount = 2\nwindow_seconds = 60\n")?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input).arg("--risk-rules").arg(&rules);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(
+            "client,available,held,total,locked,last_activity\n1,0,10,10,false,1970-01-01T00:00:30Z\n",
+        ))
+        .stderr(predicate::str::contains(
+            "Flagged transaction 2: 2 disputes in quick succession from client 1",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn credit_limits_let_withdrawals_go_negative() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let input = dir.join("payments_credit_input.csv");
+    let limits = dir.join("payments_credit_limits.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,actor\ndeposit,1,1,10\nwithdraw,1,2,40\ndeposit,2,3,10\nwithdraw,2,4,20\nset_limit,2,5,500\nset_limit,2,6,50,900\nwithdraw,2,7,20\n",
+    )?;
+    std::fs::write(&limits, "client,limit\n1,30\n3,100\n")?;
+
+    // Client 2 can't give themselves a credit line, only an admin can
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--credit-limits")
+        .arg(&limits)
+        .arg("--admins")
+        .arg("900");
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked,credit_limit\n1,-30,0,-30,false,30\n2,-10,0,-10,false,50\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn interest_is_credited_daily_and_reported() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let input = dir.join("payments_interest_input.csv");
+    let report = dir.join("payments_interest_report.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,timestamp
+deposit,1,1,1000,2024-01-01T12:00:00Z
+deposit,1,2,1,2024-01-03T06:00:00Z
+interest,1,3,1000000,2024-01-03T07:00:00Z
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--interest-apr-bps")
+        .arg("3650")
+        .arg("--interest-period")
+        .arg("daily")
+        .arg("--interest-report")
+        .arg(&report);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked,last_activity\n1,1003.001,0,1003.001,false,2024-01-03T06:00:00Z\n",
+    ));
+    assert_eq!(
+        std::fs::read_to_string(&report)?,
+        "tx,client,currency,period_end,balance,amount
+4294967295,1,USD,2024-01-02T00:00:00Z,1000,1
+4294967294,1,USD,2024-01-03T00:00:00Z,1001,1.001
+"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn recurring_transactions_are_interleaved_by_timestamp() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let input = dir.join("payments_recurring_input.csv");
+    let recurring = dir.join("payments_recurring_instructions.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,timestamp
+deposit,1,1,100,2024-01-01T00:00:00Z
+withdraw,1,2,2600,2024-01-15T00:00:00Z
+withdraw,1,3,100,2024-02-01T09:00:00Z
+",
+    )?;
+    std::fs::write(
+        &recurring,
+        "type,client,tx,amount,cadence,start,end
+deposit,1,1000,2500,monthly,2024-01-01T09:00:00Z,2024-02-15T00:00:00Z
+",
+    )?;
+
+    // The salary of Jan 1 pays for the withdrawal of Jan 15, while the one of Feb 1 comes after the withdrawal at the
+    // same time, which fails
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input).arg("--recurring").arg(&recurring);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked,last_activity\n1,2500,0,2500,false,2024-02-01T09:00:00Z\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn transfers_move_funds_and_are_reversed_by_chargeback() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_transfer_input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,to_client
+deposit,1,1,10,
+transfer,1,2,4,2
+transfer,2,3,5,1
+transfer,1,4,1,3
+dispute,1,4,,
+chargeback,1,4,,
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,available,held,total,locked\n1,6,0,6,true\n2,4,0,4,false\n3,0,0,0,false\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn accounts_in_other_currencies_get_a_currency_column() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_currency_input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,currency
+deposit,1,1,10,
+deposit,1,2,3,usd
+withdraw,1,3,4,USD
+dispute,1,2,,EUR
+dispute,1,1,,
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input).arg("--currency").arg("EUR");
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,currency,available,held,total,locked\n1,EUR,0,10,10,false\n1,USD,3,0,3,false\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn conversions_use_the_rates_file_and_report_spreads() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let (input, rates, report) = (
+        dir.join("payments_convert_input.csv"),
+        dir.join("payments_convert_rates.csv"),
+        dir.join("payments_convert_report.csv"),
+    );
+    std::fs::write(&rates, "from,to,rate,spread_bps\nUSD,EUR,0.9,100\n")?;
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,to_client,actor,currency,to_currency,rate
+deposit,1,1,100,,,,,
+convert,1,2,10,,,,EUR,
+convert,1,3,10,,,USD,EUR,0.95
+convert,1,4,10,,,USD,GBP,
+",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--rates")
+        .arg(&rates)
+        .arg("--conversion-report")
+        .arg(&report);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client,currency,available,held,total,locked\n1,EUR,18.41,0,18.41,false\n1,USD,80,0,80,false\n",
+    ));
+
+    assert_eq!(
+        std::fs::read_to_string(&report)?,
+        "tx,client,from,to,amount,rate,converted,spread
+2,1,USD,EUR,10,0.891,8.91,0.09
+3,1,USD,EUR,10,0.95,9.5,-0.5
+"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn quiet_suppresses_diagnostics() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string("./tests/expected_output.csv").unwrap();
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--verbose")
+        .arg("--quiet");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected))
+        .stderr(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn count_only_tallies_types_and_clients() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = "name,count
+deposit,8
+withdraw,4
+dispute,5
+resolve,3
+chargeback,2
+clients,5
+";
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--count-only");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    Ok(())
+}
+
+#[test]
+fn expected_rows_too_large_to_reserve_are_ignored() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--expected-rows")
+        .arg("100000000000000000");
+
+    cmd.assert().success().stderr(predicate::str::contains(
+        "Ignoring the expected number of rows, 100000000000000000",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn count_only_counts_rows_skipped_when_lenient() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_count_only_lenient.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,,2,5\nwithdraw,1,3,2\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input).arg("--count-only").arg("--lenient");
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "name,count\ndeposit,1\nwithdraw,1\nclients,1\nskipped,1\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn count_only_reads_jsonl_inputs() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("payments_count_only.jsonl");
+    std::fs::write(
+        &input,
+        r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}
+{"type": "withdraw", "client": 2, "tx": 2, "amount": "1"}
+
+{"type": "deposit", "client": 2, "tx": 3, "amount": "5"}
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--format")
+        .arg("jsonl")
+        .arg("--count-only");
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "name,count\ndeposit,2\nwithdraw,1\nclients,2\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn max_error_ratio_fails_run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/mostly_rejected.csv")
+        .arg("--max-error-ratio")
+        .arg("0.5");
+
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(
+            "3 of 5 transactions were rejected",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn blank_client_is_skipped_when_lenient() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = "client,available,held,total,locked
+1,10,0,10,false
+2,4,0,4,false
+";
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/blank_client.csv").arg("--lenient");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(expected));
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/blank_client.csv");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Line 3 is missing a value for client",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn malformed_rows_are_reported_when_lenient() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_malformed_rows.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,abc\nteleport,1,3,1\ndeposit,2,4,4\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&path).arg("--lenient");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::similar(
+            "client,available,held,total,locked\n1,10,0,10,false\n2,4,0,4,false\n",
+        ))
+        .stderr(predicate::str::contains("Skipped 2 malformed rows:"))
+        .stderr(predicate::str::contains(format!(
+            "{} line 3: {}",
+            path.display(),
+            INVALID_AMOUNT
+        )))
+        .stderr(predicate::str::contains(format!(
+            "{} line 4: Unknown transaction type: teleport",
+            path.display()
+        )));
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&path);
+
+    cmd.assert().failure().stdout(predicate::str::is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn errors_report_lists_rejections_by_line() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let input = dir.join("payments_errors_input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdraw,2,2,5\ndeposit,1,3,x\ndispute,1,9,\nwithdraw,1,4,20\n",
+    )?;
+    let expected = format!(
+        "input,line,code,reason,type,client,tx,amount
+{input},3,account_not_found,Account of client 2 not found for transaction 2,withdraw,2,2,5
+{input},4,malformed,{invalid},deposit,1,3,x
+{input},5,tx_not_found,Transaction 9 not found,dispute,1,9,
+{input},6,insufficient_funds,Insufficient funds for transaction 4 from client 1,withdraw,1,4,20
+",
+        input = input.display(),
+        invalid = INVALID_AMOUNT
+    );
+
+    // Sharded runs report in the same order as sequential ones
+    for threads in ["1", "2"].iter() {
+        let report = dir.join(format!("payments_errors_{}.csv", threads));
+
+        let mut cmd = Command::cargo_bin("payments")?;
+        cmd.arg(&input)
+            .arg("--lenient")
+            .arg("--threads")
+            .arg(threads)
+            .arg("--errors")
+            .arg(&report);
+
+        cmd.assert().success();
+        assert_eq!(std::fs::read_to_string(&report)?, expected);
+    }
+
+    let report = dir.join("payments_errors.json");
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg(&input)
+        .arg("--lenient")
+        .arg("--errors")
+        .arg(&report);
+
+    cmd.assert().success();
+
+    let rows: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&report)?)?;
+    assert_eq!(rows.as_array().unwrap().len(), 4);
+    assert_eq!(rows[3]["code"], "insufficient_funds");
+    assert_eq!(rows[3]["line"], 6);
+
+    Ok(())
+}
+
+#[test]
+fn echo_normalized_writes_canonical_transactions() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_echo_normalized.csv");
+    let expected = "type,client,tx,amount,to_client,actor,currency,to_currency,rate,timestamp
+deposit,1,1,1.5,,,,,,
+deposit,2,2,2,,,,,,
+withdraw,1,3,0.25,,,,,,
+dispute,1,1,,,,,,,
+resolve,1,1,,,,,,,
+";
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/messy_transactions.csv")
+        .arg("--echo-normalized")
+        .arg(&path);
+
+    cmd.assert().success();
+
+    assert_eq!(std::fs::read_to_string(&path)?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn echo_normalized_output_reads_back_in() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let input = dir.join("payments_echo_round_trip_input.csv");
+    let echoed = dir.join("payments_echo_round_trip_echoed.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount,actor\ndeposit,1,1,10\nset_limit,1,2,50,900\nwithdraw,1,3,40\n",
+    )?;
+
+    let run = |path: &std::path::Path, echo: bool| {
+        let mut cmd = Command::cargo_bin("payments").unwrap();
+        cmd.arg(path).args(["--admins", "900"]);
+        if echo {
+            cmd.arg("--echo-normalized").arg(&echoed);
+        }
+        cmd.output().unwrap()
+    };
+
+    let first = run(&input, true);
+    assert!(first.status.success());
+    assert!(std::fs::read_to_string(&echoed)?.contains("\nset_limit,1,2,50,,900,"));
+
+    let second = run(&echoed, false);
+    assert!(second.status.success());
+    assert_eq!(
+        String::from_utf8(second.stdout)?,
+        "client,available,held,total,locked,credit_limit\n1,-30,0,-30,false,50\n"
+    );
+    assert_eq!(second.stderr, first.stderr);
+
+    Ok(())
+}
+
+/// Runs the same input many times, several at once, and checks every run writes exactly the same bytes. Any
+/// difference is a correctness bug, such as output depending on hash map iteration order
+#[test]
+fn output_is_reproducible() -> Result<(), Box<dyn std::error::Error>> {
+    let run = |extra: &'static [&'static str]| {
+        std::thread::spawn(move || {
+            Command::cargo_bin("payments")
+                .unwrap()
+                .arg("./tests/sample_transactions.csv")
+                .arg("./tests/source_a.csv")
+                .arg("./tests/source_b.csv")
+                .args(extra)
+                .output()
+                .unwrap()
+                .stdout
+        })
+    };
+
+    for extra in [&[][..], &["--mmap"][..], &["--top", "3"][..]].iter() {
+        let runs: Vec<_> = (0..8).map(|_| run(extra)).collect();
+        let outputs: Vec<Vec<u8>> = runs.into_iter().map(|run| run.join().unwrap()).collect();
+
+        assert!(!outputs[0].is_empty());
+        assert!(outputs.iter().all(|output| *output == outputs[0]));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn dump_state_lists_ledger_entries() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_dump_state.json");
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/dispute_state.csv")
+        .arg("--dump-state")
+        .arg(&path);
+
+    cmd.assert().success();
+
+    let state: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    let expected = serde_json::json!([
+        {"type": "deposit", "client": 1, "tx": 1, "amount": "10", "currency": "USD", "dispute_status": "disputed", "held": "10", "deferred": "0", "refunded": false, "pending": false, "disputes": 1, "status": "disputed"},
+        {"type": "deposit", "client": 2, "tx": 2, "amount": "5", "currency": "USD", "dispute_status": "resolved", "held": "0", "deferred": "0", "refunded": false, "pending": false, "disputes": 1, "status": "resolved"},
+        {"type": "withdraw", "client": 2, "tx": 3, "amount": "1", "currency": "USD", "dispute_status": "none", "held": "0", "deferred": "0", "refunded": false, "pending": false, "disputes": 0, "status": "applied"},
+    ]);
+
+    assert_eq!(state, expected);
+
+    Ok(())
+}
+
+#[test]
+fn metrics_file_is_prometheus_text() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("payments_metrics.prom");
+
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--quiet")
+        .arg("--metrics-file")
+        .arg(&path);
+
+    cmd.assert().success();
+
+    let metrics = std::fs::read_to_string(&path)?;
+
+    for line in [
+        "# TYPE payments_transactions_total counter",
+        "payments_transactions_total{type=\"deposit\"} 8",
+        "payments_transactions_total{type=\"withdraw\"} 4",
+        "payments_transactions_total{type=\"dispute\"} 5",
+        "payments_transactions_total{type=\"resolve\"} 3",
+        "payments_transactions_total{type=\"chargeback\"} 2",
+        "payments_rejected_total 3",
+        "payments_locked_accounts 2",
+        "payments_held_funds 6.0001",
+    ]
+    .iter()
+    {
+        assert!(
+            metrics.lines().any(|l| l == *line),
+            "missing {:?} in:\n{}",
+            line,
+            metrics
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn json_output_includes_withdrawable() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--quiet")
+        .arg("--output-format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let accounts: serde_json::Value = serde_json::from_slice(&output)?;
+
+    assert_eq!(
+        accounts[3],
+        serde_json::json!({"client": 4, "available": "1.0000", "held": "0.0000", "total": "1.0000", "locked": true, "withdrawable": "0.0000"})
+    );
+    assert_eq!(
+        accounts[2],
+        serde_json::json!({"client": 3, "available": "5.0000", "held": "5.0000", "total": "10.0000", "locked": false, "withdrawable": "5.0000"})
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ndjson_output_writes_one_account_per_line() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("payments")?;
+    cmd.arg("./tests/sample_transactions.csv")
+        .arg("--quiet")
+        .arg("--output-format")
+        .arg("ndjson");
+
+    let output = String::from_utf8(cmd.assert().success().get_output().stdout.clone())?;
+    let accounts = output
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+
+    assert_eq!(accounts.len(), 5);
+    assert_eq!(
+        accounts[1],
+        serde_json::json!({"client": 2, "available": "0.0000", "held": "1.0001", "total": "1.0001", "locked": false, "withdrawable": "0.0000"})
+    );
+
+    Ok(())
+}
+
+#[test]
+fn locked_format_controls_locked_column() -> Result<(), Box<dyn std::error::Error>> {
+    for (format, locked, unlocked) in [
+        ("bool", "true", "false"),
+        ("int", "1", "0"),
+        ("yesno", "yes", "no"),
+    ]
+    .iter()
+    {
+        let expected = format!(
+            "client,available,held,total,locked
+1,0,0,0,{locked}
+2,0,1.0001,1.0001,{unlocked}
+3,5,5,10,{unlocked}
+4,1,0,1,{locked}
+5,100,0,100,{unlocked}
+",
+            locked = locked,
+            unlocked = unlocked
+        );
+
+        let mut cmd = Command::cargo_bin("payments")?;
+        cmd.arg("./tests/sample_transactions.csv")
+            .arg("--locked-format")
+            .arg(format);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::similar(expected));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn interactive_mode_reads_json_lines() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = assert_cmd::Command::cargo_bin("payments")?;
+    cmd.arg("--interactive")
+        .arg("--format")
+        .arg("jsonl")
+        .write_stdin(r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#);
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "client 1: available 10, held 0, total 10, locked false\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn interactive_mode_ignores_filtered_types() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = assert_cmd::Command::cargo_bin("payments")?;
+    cmd.arg("--interactive")
+        .arg("--exclude")
+        .arg("deposit")
+        .write_stdin("deposit,1,1,10\nprint\n");
+
+    cmd.assert().success().stdout(predicate::str::similar(
+        "Ignored transaction 1, deposit transactions are filtered out\n",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn interactive_prints_balances_after_each_line() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = "client 1: available 10, held 0, total 10, locked false
+client 1: available 7.5, held 0, total 7.5, locked false
+Error: Insufficient funds for transaction 3 from client 1
+client 2: available 3, held 0, total 3, locked false
+client,available,held,total,locked
+1,7.5,0,7.5,false
+2,3,0,3,false
+";
+
+    let mut cmd = assert_cmd::Command::cargo_bin("payments")?;
+    cmd.arg("--interactive").write_stdin(
+        "deposit,1,1,10
+withdraw,1,2,2.5
+withdraw,1,3,100
+
+deposit,2,4,3
+print
+quit
+deposit,2,5,3
+",
+    );
+
     cmd.assert()
         .success()
         .stdout(predicate::str::similar(expected));